@@ -0,0 +1,78 @@
+// Azure AD client-credentials tokens for outputs that authenticate with a bearer token
+// (Event Hubs, Storage, Logs Ingestion). Tokens are cached and refreshed shortly before expiry.
+
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Result};
+use log::{debug, error};
+use serde_json::Value;
+use crate::config::AadAuthSubConfig;
+
+const DEFAULT_AUTHORITY_HOST: &str = "https://login.microsoftonline.com";
+
+/// Refresh the token when less than this much lifetime remains.
+pub const REFRESH_MARGIN: Duration = Duration::from_secs(300);
+
+pub struct AadTokenProvider {
+    client: reqwest::Client,
+    token_url: String,
+    client_id: String,
+    client_secret: String,
+    scope: String,
+    token: Option<(String, Instant)>,
+}
+
+impl AadTokenProvider {
+    pub fn new(config: &AadAuthSubConfig, scope: &str, client: reqwest::Client) -> Result<Self> {
+        let secret = config.get_secret().map_err(|e| anyhow!(e))?;
+        let authority = config.authority_host.as_deref()
+            .unwrap_or(DEFAULT_AUTHORITY_HOST)
+            .trim_end_matches('/');
+        Ok(AadTokenProvider {
+            client,
+            token_url: format!("{}/{}/oauth2/v2.0/token", authority, config.tenant_id),
+            client_id: config.client_id.clone(),
+            client_secret: secret,
+            scope: scope.to_string(),
+            token: None,
+        })
+    }
+
+    /// Return a cached bearer token, requesting a new one if none is cached or it is about
+    /// to expire.
+    pub async fn get_token(&mut self) -> Result<String> {
+        if let Some((token, expires_at)) = &self.token {
+            if Instant::now() + REFRESH_MARGIN < *expires_at {
+                return Ok(token.clone());
+            }
+        }
+
+        debug!("Requesting AAD token for scope {}", self.scope);
+        let params = [
+            ("grant_type", "client_credentials"),
+            ("client_id", self.client_id.as_str()),
+            ("client_secret", self.client_secret.as_str()),
+            ("scope", self.scope.as_str())];
+        let response = self.client
+            .post(&self.token_url)
+            .form(&params)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let text = response.text().await?;
+            let msg = format!("Received error response requesting AAD token for {}: {}", self.scope, text);
+            error!("{}", msg);
+            return Err(anyhow!("{}", msg));
+        }
+
+        let json: Value = response.json().await?;
+        let token = json.get("access_token")
+            .and_then(|t| t.as_str())
+            .ok_or_else(|| anyhow!("AAD token response did not contain an access_token"))?
+            .to_string();
+        let expires_in = json.get("expires_in")
+            .and_then(|e| e.as_u64().or_else(|| e.as_str().and_then(|s| s.parse().ok())))
+            .unwrap_or(3600);
+        self.token = Some((token.clone(), Instant::now() + Duration::from_secs(expires_in)));
+        Ok(token)
+    }
+}
@@ -0,0 +1,208 @@
+// Alerts for operators when collection fails, with `on_error`. After every run the summary is
+// checked for:
+// - tenant_failed: a tenant's collector could not start, for another reason than its login
+// - login_failing: a tenant's login failed in `login_failures` consecutive runs (also of
+//   separate processes), alerted once until a login succeeds again
+// - delivery_failed: an output spooled or dropped logs it could not deliver
+// Every alert runs `exec` and/or is POSTed to `url`, failures to alert are only logged.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use log::{error, info};
+use serde_json::json;
+use tokio::process::Command;
+use crate::config::{Config, OnErrorSubConfig};
+use crate::run_summary::RunSummary;
+use crate::tls;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// Consecutive failed logins per tenant, in the working directory
+const STATE_FILE: &str = "alert_state.json";
+const DEFAULT_LOGIN_FAILURES: u32 = 3;
+pub const DEFAULT_MESSAGE: &str = "Office 365 collector {event} for tenant {tenant_id}: {details}";
+
+#[derive(Debug, PartialEq)]
+pub struct Alert {
+    pub event: &'static str,
+    pub tenant_id: String,
+    pub details: String,
+}
+
+/// Consecutive failed logins per tenant, kept in the working directory so they also add up over
+/// single runs started by a scheduler.
+#[derive(Default)]
+pub struct Alerts {
+    login_failures: HashMap<String, u32>,
+    path: Option<PathBuf>,
+}
+
+impl Alerts {
+
+    pub fn load(config: &Config) -> Self {
+        let path = Path::new(&config.get_working_dir()).join(STATE_FILE);
+        let login_failures = fs::read_to_string(&path).ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+        Alerts { login_failures, path: Some(path) }
+    }
+
+    /// Send the alerts of a finished run, if on_error is configured.
+    pub async fn run_ended(&mut self, config: &Config, summary: &RunSummary) {
+        let Some(on_error) = &config.on_error else {
+            return
+        };
+        let alerts = self.check(on_error, summary);
+        if let Some(path) = &self.path {
+            let saved = serde_json::to_string(&self.login_failures).map_err(std::io::Error::from)
+                .and_then(|content| fs::write(path, content));
+            if let Err(e) = saved {
+                error!("Could not save {}: {}", path.display(), e);
+            }
+        }
+        for alert in alerts {
+            info!("Alerting {} for tenant {}", alert.event, alert.tenant_id);
+            if let Err(e) = send(on_error, config, &alert).await {
+                error!("Could not send {} alert for tenant {}: {}", alert.event, alert.tenant_id, e);
+            }
+        }
+    }
+
+    fn check(&mut self, on_error: &OnErrorSubConfig, summary: &RunSummary) -> Vec<Alert> {
+        let threshold = on_error.login_failures.unwrap_or(DEFAULT_LOGIN_FAILURES);
+        let mut alerts = Vec::new();
+        for tenant in &summary.tenants {
+            let tenant_id = &tenant.record.tenant_id;
+            if tenant.login_failed {
+                let failures = self.login_failures.entry(tenant_id.clone()).or_insert(0);
+                *failures += 1;
+                if *failures == threshold {
+                    alerts.push(Alert {
+                        event: "login_failing",
+                        tenant_id: tenant_id.clone(),
+                        details: format!("login failed in {} consecutive runs: {}", failures,
+                                         tenant.error.as_deref().unwrap_or_default()),
+                    });
+                }
+                continue
+            }
+            self.login_failures.remove(tenant_id);
+            if let Some(error) = &tenant.error {
+                alerts.push(Alert { event: "tenant_failed", tenant_id: tenant_id.clone(), details: error.clone() });
+            }
+            let undelivered: Vec<String> = tenant.outputs.iter()
+                .filter(|output| output.logs_spooled > 0 || output.logs_dropped > 0)
+                .map(|output| format!("{} spooled {} and dropped {} logs", output.name, output.logs_spooled,
+                                      output.logs_dropped))
+                .collect();
+            if !undelivered.is_empty() {
+                alerts.push(Alert { event: "delivery_failed", tenant_id: tenant_id.clone(), details: undelivered.join(", ") });
+            }
+        }
+        alerts
+    }
+}
+
+/// Fill in the placeholders of a message or exec argument.
+fn render(template: &str, alert: &Alert, time: &str) -> String {
+    template
+        .replace("{event}", alert.event)
+        .replace("{tenant_id}", &alert.tenant_id)
+        .replace("{details}", &alert.details)
+        .replace("{time}", time)
+}
+
+async fn send(on_error: &OnErrorSubConfig, config: &Config, alert: &Alert) -> Result<()> {
+    let time = Utc::now().to_rfc3339();
+    let message = render(on_error.message.as_deref().unwrap_or(DEFAULT_MESSAGE), alert, &time);
+    if let Some(command) = &on_error.exec {
+        let args: Vec<String> = on_error.args.iter().map(|arg| render(arg, alert, &time)).collect();
+        let mut child = Command::new(command)
+            .args(&args)
+            .env("ALERT_EVENT", alert.event)
+            .env("ALERT_TENANT_ID", &alert.tenant_id)
+            .env("ALERT_DETAILS", &alert.details)
+            .env("ALERT_MESSAGE", &message)
+            .env("ALERT_TIME", &time)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| anyhow!("Could not start '{}': {}", command, e))?;
+        let status = tokio::time::timeout(TIMEOUT, child.wait()).await
+            .map_err(|_| anyhow!("'{}' did not finish within {}s", command, TIMEOUT.as_secs()))??;
+        if !status.success() {
+            return Err(anyhow!("'{}' exited with {}", command, status))
+        }
+    }
+    if let Some(url) = &on_error.url {
+        let body = match on_error.format.as_deref().unwrap_or("json") {
+            // Incoming webhooks of both accept a plain text message
+            "slack" | "teams" => json!({"text": message}),
+            _ => json!({
+                "event": alert.event,
+                "tenant_id": alert.tenant_id,
+                "details": alert.details,
+                "message": message,
+                "time": time,
+            }),
+        };
+        let mut request = tls::http_client(config.tls.as_ref())?.post(url).timeout(TIMEOUT).json(&body);
+        for (name, value) in &on_error.headers {
+            request = request.header(name, value);
+        }
+        request.send().await?.error_for_status()?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_structures::RunStatistics;
+    use crate::run_ledger::RunRecord;
+    use crate::run_summary::{OutputSummary, TenantSummary};
+
+    fn tenant(tenant_id: &str, error: Option<&str>, login_failed: bool, outputs: Vec<OutputSummary>) -> TenantSummary {
+        let record = RunRecord::new(tenant_id, Utc::now(), Vec::new(), &RunStatistics::default(), 0, false);
+        let mut summary = TenantSummary::new(record, outputs, error.map(|e| e.to_string()));
+        summary.login_failed = login_failed;
+        summary
+    }
+
+    #[test]
+    fn test_check() {
+        let on_error: OnErrorSubConfig = serde_yaml::from_str("{url: 'https://hooks.example', login_failures: 2}").unwrap();
+        let mut alerts = Alerts::default();
+        let run = |login_failed: bool| RunSummary::new(Utc::now(), vec![
+            tenant("t1", login_failed.then_some("Could not log in: invalid secret"), login_failed, Vec::new()),
+            tenant("t2", Some("Unknown api_type"), false, Vec::new()),
+            tenant("t3", None, false, vec![OutputSummary { name: "graylog".to_string(), logs_spooled: 10, ..Default::default() }]),
+        ]);
+
+        let first = alerts.check(&on_error, &run(true));
+        assert_eq!(first.iter().map(|a| (a.event, a.tenant_id.as_str())).collect::<Vec<_>>(),
+                   vec![("tenant_failed", "t2"), ("delivery_failed", "t3")]);
+        assert_eq!(first[1].details, "graylog spooled 10 and dropped 0 logs");
+
+        // Alerted once the login failed login_failures times in a row, and not again
+        let second = alerts.check(&on_error, &run(true));
+        assert_eq!(second[0], Alert { event: "login_failing", tenant_id: "t1".to_string(),
+                                      details: "login failed in 2 consecutive runs: Could not log in: invalid secret".to_string() });
+        assert_eq!(alerts.check(&on_error, &run(true)).len(), 2);
+        alerts.check(&on_error, &run(false));
+        alerts.check(&on_error, &run(true));
+        assert_eq!(alerts.check(&on_error, &run(true)).len(), 3);
+    }
+
+    #[test]
+    fn test_render() {
+        let alert = Alert { event: "tenant_failed", tenant_id: "t1".to_string(), details: "boom".to_string() };
+        assert_eq!(render(DEFAULT_MESSAGE, &alert, "now"), "Office 365 collector tenant_failed for tenant t1: boom");
+        assert_eq!(render("{time} {event}", &alert, "now"), "now tenant_failed");
+    }
+}
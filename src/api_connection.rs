@@ -1,66 +1,192 @@
 use std::collections::HashMap;
-use std::time::Duration;
+use std::sync::{Arc, Mutex as StdMutex, OnceLock};
+use std::time::{Duration, Instant};
 use reqwest;
+use reqwest::StatusCode;
 use log::{debug, warn, error, info};
-use reqwest::header::{AUTHORIZATION, CONTENT_TYPE, HeaderMap};
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE, HeaderMap, HeaderValue};
 use serde_json;
 use futures::{SinkExt, StreamExt};
 use futures::channel::mpsc::{Receiver, Sender};
 use crate::config::Config;
-use crate::data_structures::{ArbitraryJson, JsonList, StatusMessage, GetBlobConfig, GetContentConfig, AuthResult,
-                             ContentToRetrieve, CliArgs, FileWriter};
+use crate::data_structures::{JsonList, StatusMessage, GetBlobConfig, GetContentConfig, AuthResult,
+                             ContentToRetrieve, CliArgs, FileWriter, ContentResult, QueueDepths};
+use crate::content_queue::OldestFirst;
 use crate::known_blobs_cache::SharedKnownBlobsCache;
-use anyhow::{anyhow, Result};
+use crate::page_cursors::PageCursors;
+use crate::known_logs::{self, KnownLogs};
+use crate::log_filter::LogFilters;
+use crate::transform::LogTransform;
+use crate::state::parse_api_time;
+use chrono::{DateTime, Utc};
+use crate::routing::Router;
+use crate::aad_auth::REFRESH_MARGIN;
+use crate::throttle::{self, retry_after, Throttle, MAX_THROTTLED_ATTEMPTS};
+use crate::tls;
+use crate::webhook;
+use crate::client_assertion::{ClientCertificate, CLIENT_ASSERTION_TYPE};
+use anyhow::{anyhow, Context, Result};
 use serde_json::Value;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 
 
-/// Return a logged in API connection object. Use the Headers value to make API requests.
+/// Return a logged in API connection object. Use the token to make API requests.
+///
+/// The token and HTTP client of a tenant are kept for the whole process, so in daemon mode a run
+/// reuses those of the previous one and only logs in again once the token expires. They are made
+/// anew when the tenant's settings, cloud, TLS or gzip settings changed, e.g. after a config
+/// reload.
 pub async fn get_api_connection(args: CliArgs, config: Config, tenant: crate::config::TenantConfig) -> Result<ApiConnection> {
 
+    let endpoints = tenant.get_endpoints(&config.api_types).map_err(|e| anyhow!(e))?;
+    let graph_endpoint = match &config.graph {
+        Some(_) => Some(tenant.get_graph_endpoint(&config.api_types).map_err(|e| anyhow!(e))?),
+        None => None,
+    };
+    let gzip = config.collect.as_ref().and_then(|c| c.gzip).unwrap_or(true);
+    let settings = format!("{:?}", (&tenant, &endpoints, &graph_endpoint, &config.tls, gzip));
+    let cached = connections().lock().unwrap().get(&tenant.tenant_id)
+        .filter(|cached| cached.settings == settings)
+        .cloned();
+    let connection = match cached {
+        Some(cached) => {
+            debug!("Reusing the API connection of tenant {}", tenant.tenant_id);
+            cached
+        },
+        None => {
+            let client = tls::api_client(config.tls.as_ref(), gzip)?;
+            let graph_token = graph_endpoint.map(|graph_endpoint| {
+                SharedToken::new(tenant.clone(), (endpoints.0.clone(), graph_endpoint), client.clone())
+            });
+            CachedConnection {
+                settings,
+                token: SharedToken::new(tenant.clone(), endpoints, client.clone()),
+                graph_token,
+                client,
+            }
+        },
+    };
     let mut api = ApiConnection {
         args,
         config,
+        token: connection.token.clone(),
+        graph_token: connection.graph_token.clone(),
         tenant,
-        headers: HeaderMap::new(),
+        client: connection.client.clone(),
     };
-    api.login().await?;
+    api.login().await.context(LoginFailed)?;
+    connections().lock().unwrap().insert(api.tenant.tenant_id.clone(), connection);
     Ok(api)
 }
 
+/// Token and HTTP client of a tenant, with the settings they were made for.
+#[derive(Clone)]
+struct CachedConnection {
+    settings: String,
+    token: SharedToken,
+    graph_token: Option<SharedToken>,
+    client: reqwest::Client,
+}
 
-/// Abstraction of an API connection to Azure Management APIs. Can be used to login to the API
-/// which sets the headers. These headers can then be used to make authenticated requests.
+fn connections() -> &'static StdMutex<HashMap<String, CachedConnection>> {
+    static CONNECTIONS: OnceLock<StdMutex<HashMap<String, CachedConnection>>> = OnceLock::new();
+    CONNECTIONS.get_or_init(|| StdMutex::new(HashMap::new()))
+}
+
+/// Context of errors logging in to the Management API, to tell them apart from other reasons a
+/// collector could not start.
+#[derive(Debug)]
+pub struct LoginFailed;
+
+impl std::fmt::Display for LoginFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Could not log in")
+    }
+}
+
+
+/// Bearer token of a tenant, shared by the API connection and all download tasks of a run. It
+/// is refreshed shortly before it expires and after the API rejects it with a 401, so runs
+/// (e.g. multi-day backfills) can last longer than a single token.
 #[derive(Clone)]
-pub struct ApiConnection {
-    pub args: CliArgs,
-    pub config: Config,
-    pub tenant: crate::config::TenantConfig,
-    pub headers: HeaderMap,
+pub struct SharedToken {
+    tenant: crate::config::TenantConfig,
+    /// Login and Management API endpoints
+    endpoints: (String, String),
+    client: reqwest::Client,
+    token: Arc<tokio::sync::Mutex<Option<(HeaderValue, Instant)>>>,
 }
-impl ApiConnection {
-    /// Use tenant_id, client_id and secret_key to request a bearer token and store it in
-    /// our headers. Must be called once before requesting any content.
-    pub async fn login(&mut self) -> Result<()> {
+
+impl SharedToken {
+    pub fn new(tenant: crate::config::TenantConfig, endpoints: (String, String), client: reqwest::Client) -> Self {
+        SharedToken { tenant, endpoints, client, token: Arc::new(tokio::sync::Mutex::new(None)) }
+    }
+
+    /// Management API endpoint of the tenant's cloud
+    pub fn resource_endpoint(&self) -> &str {
+        &self.endpoints.1
+    }
+
+    /// Headers for an API request, logging in again if the token is missing or about to
+    /// expire. Concurrent callers wait for a single refresh.
+    pub async fn headers(&self) -> Result<HeaderMap> {
+        let mut token = self.token.lock().await;
+        let valid = token.as_ref()
+            .filter(|(_, expires_at)| Instant::now() + REFRESH_MARGIN < *expires_at)
+            .map(|(header, _)| header.clone());
+        let header = match valid {
+            Some(header) => header,
+            None => {
+                let span = tracing::info_span!("request_token", resource = %self.endpoints.1,
+                                               error = tracing::field::Empty);
+                let (header, expires_at) = self.request_token().instrument(span.clone()).await
+                    .inspect_err(|e| { span.record("error", e.to_string()); })?;
+                *token = Some((header.clone(), expires_at));
+                header
+            }
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, "application/x-www-form-urlencoded".parse().unwrap());
+        headers.insert(AUTHORIZATION, header);
+        Ok(headers)
+    }
+
+    /// Drop the token the API answered 401 to, so the next request logs in again. A token
+    /// that was already replaced by another task is kept.
+    pub async fn invalidate(&self, rejected: &HeaderMap) {
+        let mut token = self.token.lock().await;
+        if token.as_ref().map(|(header, _)| Some(header)) == Some(rejected.get(AUTHORIZATION)) {
+            warn!("Access token for tenant {} was rejected, logging in again.", self.tenant.tenant_id);
+            *token = None;
+        }
+    }
+
+    /// Use tenant_id, client_id and the client secret or certificate to request a bearer token.
+    async fn request_token(&self) -> Result<(HeaderValue, Instant)> {
         info!("Logging in to Office Management API for tenant {}.", self.tenant.tenant_id);
 
-        let (login_endpoint, resource_endpoint) = self.tenant.get_endpoints();
+        let (login_endpoint, resource_endpoint) = &self.endpoints;
         let auth_url = format!("{}/{}/oauth2/token", login_endpoint, self.tenant.tenant_id);
 
-        let secret = self.tenant.get_secret().map_err(|e| anyhow!(e))?;
         let client_id = &self.tenant.client_id;
+        let mut params = vec![
+            ("grant_type", "client_credentials".to_string()),
+            ("client_id", client_id.clone()),
+            ("resource", resource_endpoint.clone())];
+        if let Some(certificate_path) = &self.tenant.certificate_path {
+            let certificate = ClientCertificate::load(certificate_path,
+                                                      self.tenant.private_key_path.as_deref(),
+                                                      self.tenant.certificate_thumbprint.as_deref())?;
+            params.push(("client_assertion_type", CLIENT_ASSERTION_TYPE.to_string()));
+            params.push(("client_assertion", certificate.assertion(client_id, &auth_url)?));
+        } else {
+            params.push(("client_secret", self.tenant.get_secret().map_err(|e| anyhow!(e))?));
+        }
 
-        let params = [
-            ("grant_type", "client_credentials"),
-            ("client_id", client_id.as_str()),
-            ("client_secret", secret.as_str()),
-            ("resource", resource_endpoint.as_str())];
-
-        self.headers.insert(CONTENT_TYPE, "application/x-www-form-urlencoded".parse().unwrap());
-
-        let login_client = reqwest::Client::new();
-        let response = login_client
+        let response = self.client
             .post(auth_url)
-            .headers(self.headers.clone())
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
             .form(&params)
             .send()
             .await?;
@@ -71,33 +197,56 @@ impl ApiConnection {
             return Err(anyhow!("{}", msg));
         }
         let json = response.json::<AuthResult>().await?;
+        let expires_in = json.expires_in.as_ref()
+            .and_then(|e| e.as_u64().or_else(|| e.as_str().and_then(|s| s.parse().ok())))
+            .unwrap_or(3600);
         let token = format!("bearer {}", json.access_token);
-        self.headers.insert(AUTHORIZATION, token.parse().unwrap());
         info!("Successfully logged in to Office Management API.");
+        Ok((token.parse()?, Instant::now() + Duration::from_secs(expires_in)))
+    }
+}
+
+
+/// Abstraction of an API connection to Azure Management APIs. Can be used to login to the API
+/// which sets the token. The token can then be used to make authenticated requests.
+#[derive(Clone)]
+pub struct ApiConnection {
+    pub args: CliArgs,
+    pub config: Config,
+    pub tenant: crate::config::TenantConfig,
+    pub token: SharedToken,
+    /// Token for Microsoft Graph, when collecting from it
+    pub graph_token: Option<SharedToken>,
+    /// Uses the global TLS settings
+    pub client: reqwest::Client,
+}
+impl ApiConnection {
+    /// Request a bearer token, so invalid credentials are reported before any content is
+    /// requested. The token is refreshed automatically afterwards.
+    pub async fn login(&mut self) -> Result<()> {
+        self.token.headers().await?;
         Ok(())
     }
 
     fn get_base_url(&self) -> String {
-        let (_, resource_endpoint) = self.tenant.get_endpoints();
-        format!("{}/api/v1.0/{}/activity/feed", resource_endpoint, self.tenant.tenant_id)
+        format!("{}/api/v1.0/{}/activity/feed", self.token.resource_endpoint(), self.tenant.tenant_id)
     }
 
-    pub async fn get_feeds(&self) -> Result<Vec<String>> {
+    /// All subscriptions of the tenant, as returned by subscriptions/list.
+    pub async fn list_subscriptions(&self) -> Result<JsonList> {
 
         let url = format!("{}/subscriptions/list", self.get_base_url());
-        let client = reqwest::Client::new();
-        let result: Vec<HashMap<String, Value>> = client
+        let response = self.client
             .get(url)
-            .headers(self.headers.clone())
+            .headers(self.token.headers().await?)
             .header("content-length", 0)
             .send()
-            .await?
-            .json()
             .await?;
-        Ok(result.iter()
-            .filter(|x| x.get("status").unwrap() == "enabled")
-            .map(|x|x.get("contentType").unwrap().as_str().unwrap().to_string())
-            .collect())
+        if !response.status().is_success() {
+            let text = response.text().await?;
+            return Err(anyhow!("Received error response listing audit feed subscriptions: {}", text))
+        }
+        Ok(response.json().await?)
     }
 
     pub async fn set_subscription(&self, content_type: String, enable: bool) -> Result<()> {
@@ -109,13 +258,14 @@ impl ApiConnection {
                           content_type
         );
         debug!("Subscribing to {} feed.", content_type);
-        let client = reqwest::Client::new();
-        let response = client
+        let mut request = self.client
             .post(url)
-            .headers(self.headers.clone())
-            .header("content-length", 0)
-            .send()
-            .await?;
+            .headers(self.token.headers().await?);
+        request = match (&self.config.webhook, enable) {
+            (Some(webhook), true) => request.json(&webhook::subscription_body(webhook)),
+            _ => request.header("content-length", 0),
+        };
+        let response = request.send().await?;
         if !response.status().is_success() {
             let text = response.text().await?;
             let msg = format!("Received error response subscribing to audit feed {}: {}", content_type, text);
@@ -125,23 +275,30 @@ impl ApiConnection {
         Ok(())
     }
 
+    /// Subscribe to the audit feeds on the first run of the tenant, and again when the content
+    /// types or the webhook changed. Subscriptions stopped in between are started again by the
+    /// Resubscriber once their listing fails.
+    pub async fn ensure_subscribed(&self) -> Result<()> {
+        static SUBSCRIBED: OnceLock<StdMutex<HashMap<String, String>>> = OnceLock::new();
+
+        let subscribed = SUBSCRIBED.get_or_init(|| StdMutex::new(HashMap::new()));
+        let settings = format!("{:?}", (self.config.get_subscriptions(), &self.config.webhook));
+        if subscribed.lock().unwrap().get(&self.tenant.tenant_id) == Some(&settings) {
+            debug!("Already subscribed to the audit feeds of tenant {}", self.tenant.tenant_id);
+            return Ok(())
+        }
+        self.subscribe_to_feeds().await?;
+        subscribed.lock().unwrap().insert(self.tenant.tenant_id.clone(), settings);
+        Ok(())
+    }
+
     pub async fn subscribe_to_feeds(&self) -> Result<()> {
 
         info!("Subscribing to audit feeds.");
         let mut content_types = self.config.get_subscriptions();
 
-        let client = reqwest::Client::new();
         info!("Getting current audit feed subscriptions.");
-        let url = format!("{}/subscriptions/list", self.get_base_url());
-        let result: Vec<HashMap<String, Value>> = client
-            .get(url)
-            .headers(self.headers.clone())
-            .header("content-length", 0)
-            .send()
-            .await?
-            .json()
-            .await?;
-        for subscription in result {
+        for subscription in self.list_subscriptions().await? {
             let status = subscription
                 .get("status")
                 .expect("No status in JSON")
@@ -149,7 +306,10 @@ impl ApiConnection {
                 .unwrap()
                 .to_string()
                 .to_lowercase();
-            if status == "enabled" {
+            // Starting an enabled subscription again registers the webhook on it
+            let webhook_registered = self.config.webhook.as_ref()
+                .is_none_or(|webhook| webhook::is_registered(webhook, &subscription));
+            if status == "enabled" && webhook_registered {
                 let content_type = subscription
                     .get("contentType")
                     .expect("No contentType in JSON")
@@ -182,7 +342,7 @@ impl ApiConnection {
 
         let mut urls_to_get: Vec<(String, String)> = Vec::new();
         let content_to_get = self.config.get_subscriptions();
-        let publisher_id = self.args.publisher_id.clone();
+        let publisher_id = self.tenant.get_publisher_id(self.args.publisher_id.as_deref());
 
         for content_type in content_to_get {
             let content_runs = runs.get(&content_type).unwrap();
@@ -208,7 +368,8 @@ impl ApiConnection {
 /// Get available content blobs to retrieve.
 ///
 /// MEMORY FIX: Async version that runs on shared runtime.
-/// Accepts SharedKnownBlobsCache (Arc-wrapped) instead of HashMap.
+/// Accepts SharedKnownBlobsCache (Arc-wrapped) instead of HashMap, checked live so blobs
+/// retrieved or listed earlier in the same run are skipped.
 pub async fn get_content_blobs_async(config: GetBlobConfig, blobs_rx: Receiver<(String, String)>,
                                known_blobs: SharedKnownBlobsCache) {
 
@@ -219,24 +380,38 @@ pub async fn get_content_blobs_async(config: GetBlobConfig, blobs_rx: Receiver<(
         let mut status_tx = config.status_tx.clone();
         let content_tx = config.content_tx.clone();
         let client = config.client.clone();
-        let headers = config.headers.clone();
+        let token = config.token.clone();
+        let throttle = config.throttle.clone();
         let content_type = content_type.clone();
         let url = url.clone();
         let known_blobs = known_blobs.clone();
+        let cursors = config.cursors.clone();
+        let resubscriber = config.resubscriber.clone();
         let duplicate = config.duplicate;
+        let queues = config.queues.clone();
+        let cancel = config.cancel.clone();
+        let span = tracing::info_span!("list_content", content_type = %content_type,
+                                       error = tracing::field::Empty);
         async move {
-            match client
-                .get(url.clone())
-                .timeout(Duration::from_secs(5))
-                .headers(headers.clone()).send().await {
+            let Some((_permit, response)) = until_stopped(&cancel, async {
+                let permit = throttle.start_request(throttle::CONTENT_LISTING).await;
+                (permit, get_with_backoff(&client, &url, Duration::from_secs(5), &token, &throttle,
+                                          throttle::CONTENT_LISTING, &mut status_tx).await)
+            }).await else {
+                return
+            };
+            match response {
                 Ok(resp) => {
                     if resp.status().is_success() {
                         handle_blob_response(resp, blobs_tx, status_tx, content_tx, blob_error_tx,
-                                             content_type, url, &known_blobs, duplicate).await;
+                                             content_type, url, &known_blobs, &cursors, duplicate, &queues).await;
                     } else {
                         if let Ok(text) = resp.text().await {
                             if text.to_lowercase().contains("too many request") {
                                 status_tx.send(StatusMessage::BeingThrottled).await.unwrap();
+                            } else if is_subscription_disabled(&text) {
+                                // Retried like any failed listing once the subscription is started
+                                resubscriber.restart(&content_type, &text).await;
                             } else {
                                 error!("Err getting blob response {}", text);
                             }
@@ -246,31 +421,135 @@ pub async fn get_content_blobs_async(config: GetBlobConfig, blobs_rx: Receiver<(
                 },
                 Err(e) => {
                     error!("Err getting blob response {}", e);
+                    tracing::Span::current().record("error", e.to_string());
                     handle_blob_response_error(status_tx, blob_error_tx, content_type, url).await;
                 }
             }
-        }
+        }.instrument(span)
     }).await;
     debug!("Exit blob thread");
 }
 
 
+/// Whether a content listing failed because the subscription of its content type is missing
+/// (AF20022) or disabled (AF20023), e.g. after it was stopped or expired.
+fn is_subscription_disabled(response: &str) -> bool {
+    response.contains("AF20022") || response.contains("AF20023")
+}
+
+
+/// Starts subscriptions that content listings report as disabled, once per content type and run.
+#[derive(Clone)]
+pub struct Resubscriber {
+    api: ApiConnection,
+    /// Whether starting the subscription of a content type succeeded
+    restarted: Arc<tokio::sync::Mutex<HashMap<String, bool>>>,
+}
+
+impl Resubscriber {
+    pub fn new(api: ApiConnection) -> Self {
+        Resubscriber { api, restarted: Arc::new(tokio::sync::Mutex::new(HashMap::new())) }
+    }
+
+    /// Start the subscription, unless that was already attempted. Concurrent listings of the
+    /// content type wait for the first attempt.
+    pub async fn restart(&self, content_type: &str, response: &str) {
+        let tenant_id = &self.api.tenant.tenant_id;
+        let mut restarted = self.restarted.lock().await;
+        match restarted.get(content_type) {
+            Some(true) => {
+                error!("Listing {} for tenant {} still fails after starting its subscription again: {}",
+                       content_type, tenant_id, response);
+                return
+            },
+            Some(false) => {
+                error!("Subscription to {} for tenant {} is disabled and could not be started, \
+                        check the app registration's permissions and the audit log settings of the tenant: {}",
+                       content_type, tenant_id, response);
+                return
+            },
+            None => (),
+        }
+        warn!("Subscription to {} for tenant {} is disabled, starting it again: {}", content_type, tenant_id, response);
+        let result = self.api.set_subscription(content_type.to_string(), true).await;
+        match &result {
+            Ok(()) => info!("Started subscription to {} for tenant {} again", content_type, tenant_id),
+            Err(e) => error!("Could not start subscription to {} for tenant {}: {}", content_type, tenant_id, e),
+        }
+        restarted.insert(content_type.to_string(), result.is_ok());
+    }
+}
+
+
+/// Await a request unless the run is stopped first, see MessageLoopConfig::cancel. Requests
+/// waiting for a permit, the rate limit or a throttling backoff are dropped then, leaving their
+/// content for the next run. A response that arrived is still processed, as the request timeout
+/// bounds reading its body.
+pub async fn until_stopped<T>(cancel: &CancellationToken, request: impl std::future::Future<Output = T>)
+    -> Option<T> {
+    tokio::select! {
+        result = request => Some(result),
+        _ = cancel.cancelled() => {
+            tracing::Span::current().record("error", "run stopped");
+            None
+        },
+    }
+}
+
+
+/// GET an API URL. A throttled request waits for the backoff of its endpoint and is sent again,
+/// up to MAX_THROTTLED_ATTEMPTS times. A 401 drops the token, so the next request logs in again.
+pub async fn get_with_backoff(client: &reqwest::Client, url: &str, timeout: Duration, token: &SharedToken,
+                          throttle: &Throttle, endpoint: &'static str, status_tx: &mut Sender<StatusMessage>)
+    -> Result<reqwest::Response> {
+
+    let mut attempt = 0;
+    loop {
+        throttle.wait(endpoint).await;
+        let headers = token.headers().await?;
+        let resp = client.get(url).timeout(timeout).headers(headers.clone()).send().await?;
+        match resp.status() {
+            StatusCode::TOO_MANY_REQUESTS if attempt < MAX_THROTTLED_ATTEMPTS => {
+                attempt += 1;
+                let delay = throttle.throttled(endpoint, retry_after(resp.headers())).await;
+                warn!("Being rate limited on {}, retrying in {:.1}s.", endpoint, delay.as_secs_f32());
+                if let Err(e) = status_tx.send(StatusMessage::BeingThrottled).await {
+                    error!("Could not send status message: {}", e);
+                }
+            },
+            StatusCode::UNAUTHORIZED => {
+                token.invalidate(&headers).await;
+                return Ok(resp)
+            },
+            status => {
+                if status.is_success() {
+                    throttle.succeeded(endpoint).await;
+                }
+                return Ok(resp)
+            }
+        }
+    }
+}
+
+
 /// Deal with the response of a successful content blob request.
+#[allow(clippy::too_many_arguments)]
 async fn handle_blob_response(
     resp: reqwest::Response, blobs_tx: Sender<(String, String)>,
     mut status_tx: Sender<StatusMessage>, content_tx: Sender<ContentToRetrieve>,
     mut blob_error_tx: Sender<(String, String)>, content_type: String, url: String,
-    known_blobs: &SharedKnownBlobsCache, duplicate: usize) {
+    known_blobs: &SharedKnownBlobsCache, cursors: &PageCursors, duplicate: usize, queues: &QueueDepths) {
 
-    handle_blob_response_paging(&resp, blobs_tx, status_tx.clone(), content_type.clone()).await;
+    handle_blob_response_paging(&resp, blobs_tx, status_tx.clone(), content_type.clone(), &url, cursors).await;
 
     match resp.text().await {
         Ok(text) => {
             match serde_json::from_str::<Vec<HashMap<String, Value>>>(text.as_str()) {
                 Ok(i) => {
                     handle_blob_response_content_uris(status_tx, content_tx, content_type, i, known_blobs,
-                                                      duplicate)
+                                                      &url, cursors, duplicate, queues)
                         .await;
+                    cursors.listed(&url);
                 },
                 Err(e) => {
                     warn!("Error getting blob JSON {}", e);
@@ -306,12 +585,13 @@ async fn handle_blob_response(
 /// Determine if a content blob response header contains a reference to another page of blobs.
 async fn handle_blob_response_paging(
     resp: &reqwest::Response, mut blobs_tx: Sender<(String, String)>,
-    mut status_tx: Sender<StatusMessage>, content_type: String) {
+    mut status_tx: Sender<StatusMessage>, content_type: String, url: &str, cursors: &PageCursors) {
 
     let next_or_not = resp.headers().get("NextPageUri");
     match next_or_not {
         Some(i) => {
             let new_url = i.to_str().unwrap().to_string();
+            cursors.next_page(url, &new_url);
             blobs_tx.send((content_type.clone(), new_url)).await.unwrap_or_else(
                 |e| panic!("Could not send found blob, channel closed?: {}", e)
             );
@@ -327,10 +607,11 @@ async fn handle_blob_response_paging(
 
 
 /// Send the URIs of content to retrieve over the content_tx channel.
+#[allow(clippy::too_many_arguments)]
 async fn handle_blob_response_content_uris(
     mut status_tx: Sender<StatusMessage>, mut content_tx: Sender<ContentToRetrieve>,
     content_type: String, content_json: JsonList, known_blobs: &SharedKnownBlobsCache,
-    duplicate: usize) {
+    page: &str, cursors: &PageCursors, duplicate: usize, queues: &QueueDepths) {
 
     for json_dict in content_json.into_iter() {
         if json_dict.contains_key("contentUri") == false {
@@ -340,7 +621,7 @@ async fn handle_blob_response_content_uris(
                 .to_string()
                 .strip_prefix('"').unwrap().strip_suffix('"').unwrap()
                 .to_string();
-            if known_blobs.contains(&content_id).await {
+            if !known_blobs.claim(&content_id).await {
                 continue
             }
             let url = json_dict
@@ -352,18 +633,21 @@ async fn handle_blob_response_content_uris(
                 .to_string()
                 .strip_prefix('"').unwrap().strip_suffix('"').unwrap()
                 .to_string();
+            cursors.found(page, &content_id);
             let content_to_retrieve = ContentToRetrieve {
                 expiration, content_type: content_type.clone(), content_id, url};
 
             if duplicate <= 1 {
                 content_tx.send(content_to_retrieve).await.unwrap_or_else(
                     |e| panic!("Could not send found content, channel closed?: {}", e));
+                queues.content.sent();
                 status_tx.send(StatusMessage::FoundNewContentBlob).await.unwrap_or_else(
                     |e| panic!("Could not send status update, channel closed?: {}", e));
             } else {
                 for _ in 0..duplicate {
                     content_tx.send(content_to_retrieve.clone()).await.unwrap_or_else(
                         |e| panic!("Could not send found content, channel closed?: {}", e));
+                    queues.content.sent();
                     status_tx.send(StatusMessage::FoundNewContentBlob).await.unwrap_or_else(
                         |e| panic!("Could not send status update, channel closed?: {}", e));
                 }
@@ -393,34 +677,58 @@ async fn handle_blob_response_error(
 ///
 /// MEMORY FIX: Each download task now processes the response INLINE — parsing from bytes,
 /// filtering, and writing directly to file via the shared FileWriter. Only a log count
-/// (usize) flows through the result channel, not multi-MB response bodies.
+/// (usize) flows through the result channel, not multi-MB response bodies. Blobs are downloaded
+/// oldest first, see content_queue.rs.
 pub async fn get_content_async(config: GetContentConfig, content_rx: Receiver<ContentToRetrieve>) {
 
-    content_rx.for_each_concurrent(config.threads, |content_to_retrieve| {
+    OldestFirst::new(content_rx, config.queued).for_each_concurrent(config.threads, |content_to_retrieve| {
+        config.queues.content.received();
         let client = config.client.clone();
-        let headers = config.headers.clone();
+        let token = config.token.clone();
+        let throttle = config.throttle.clone();
         let result_tx = config.result_tx.clone();
-        let status_tx = config.status_tx.clone();
+        let mut status_tx = config.status_tx.clone();
         let content_error_tx = config.content_error_tx.clone();
         let max_size = config.max_response_size;
         let file_writer = config.file_writer.clone();
         let filters = config.filters.clone();
+        let transform = config.transform.clone();
+        let router = config.router.clone();
+        let forward_logs = config.forward_logs;
+        let known_logs = config.known_logs.clone();
+        let queues = config.queues.clone();
+        let cancel = config.cancel.clone();
+        let span = tracing::info_span!("download_content", content_type = %content_to_retrieve.content_type,
+                                       content_id = %content_to_retrieve.content_id,
+                                       logs = tracing::field::Empty, error = tracing::field::Empty);
         async move {
-            match client.get(content_to_retrieve.url.clone())
-                .timeout(Duration::from_secs(3))
-                .headers(headers)
-                .send()
-                .await {
+            let Some((_permit, response)) = until_stopped(&cancel, async {
+                let permit = throttle.start_request(throttle::CONTENT_DOWNLOAD).await;
+                (permit, get_with_backoff(&client, &content_to_retrieve.url, Duration::from_secs(3), &token,
+                                          &throttle, throttle::CONTENT_DOWNLOAD, &mut status_tx).await)
+            }).await else {
+                return
+            };
+            match response {
                 Ok(resp) => {
                     handle_content_response(resp, result_tx, status_tx, content_error_tx,
-                        content_to_retrieve, max_size, &file_writer, &filters).await;
+                        content_to_retrieve, max_size, &LogProcessing {
+                            file_writer: &file_writer,
+                            filters: &filters,
+                            transform: &transform,
+                            router: &router,
+                            forward_logs,
+                            known_logs: known_logs.as_deref(),
+                        }, &queues).await;
                 },
-                Err(_) => {
+                Err(e) => {
+                    debug!("Err getting content {}: {}", content_to_retrieve.url, e);
+                    tracing::Span::current().record("error", e.to_string());
                     handle_content_response_error(status_tx, content_error_tx, content_to_retrieve)
                         .await;
                 }
             }
-        }
+        }.instrument(span)
     }).await;
     info!("Exit content thread");
 }
@@ -437,15 +745,16 @@ pub async fn get_content_async(config: GetContentConfig, content_rx: Receiver<Co
 ///      filter + write each log directly to file → send only count through channel
 ///      Peak per response: ~40MB (body + parsed JSON, body freed immediately)
 ///      Channel holds 500 × ~200 bytes = 100KB
+#[allow(clippy::too_many_arguments)]
 async fn handle_content_response(
     mut resp: reqwest::Response,
-    mut result_tx: Sender<(usize, ContentToRetrieve)>,
+    mut result_tx: Sender<ContentResult>,
     mut status_tx: Sender<StatusMessage>,
     mut content_error_tx: Sender<ContentToRetrieve>,
     content_to_retrieve: ContentToRetrieve,
     max_response_size: Option<usize>,
-    file_writer: &FileWriter,
-    filters: &HashMap<String, ArbitraryJson>,
+    processing: &LogProcessing<'_>,
+    queues: &QueueDepths,
 ) {
     if !resp.status().is_success() {
         match content_error_tx.send(content_to_retrieve).await {
@@ -456,6 +765,7 @@ async fn handle_content_response(
             },
             _=> (),
         }
+        tracing::Span::current().record("error", resp.status().to_string());
         if let Ok(text) = resp.text().await {
             if text.to_lowercase().contains("too many request") {
                 match status_tx.send(StatusMessage::BeingThrottled).await {
@@ -537,76 +847,167 @@ async fn handle_content_response(
     // parsed it AGAIN with serde_json::from_str creating a 3-5x larger Value tree.
     //
     // New code: parse once from &[u8], drop body immediately, process inline.
-    let log_count = match serde_json::from_slice::<Vec<Value>>(&body) {
+    let processed = match parse_logs(body) {
         Ok(logs) => {
-            // Free the raw bytes IMMEDIATELY — they are no longer needed
-            drop(body);
-
-            let content_type = &content_to_retrieve.content_type;
-            let type_filters = filters.get(content_type);
-            let mut count = 0;
-
-            for log in logs {
-                // Apply filters (same logic as old handle_log)
-                if let Some(content_filters) = type_filters {
-                    let mut skip = false;
-                    if let Value::Object(ref map) = log {
-                        for (k, v) in content_filters.iter() {
-                            if let Some(val) = map.get(k) {
-                                if val != v { skip = true; break; }
-                            }
-                        }
+            process_logs(logs, &content_to_retrieve.content_type, processing)
+        }
+        Err(e) => {
+            // Retried like a failed download, and not known until it was parsed and delivered
+            warn!("Could not parse content {}: {}", content_to_retrieve.content_id, e);
+            tracing::Span::current().record("error", e.to_string());
+            if content_error_tx.send(content_to_retrieve).await.is_err() {
+                status_tx.send(StatusMessage::ErrorContentBlob).await.unwrap_or_else(
+                    |e| panic!("Could not send status update, channel closed?: {}", e)
+                );
+            }
+            return;
+        }
+    };
+
+    tracing::Span::current().record("logs", processed.count);
+
+    // Send only the COUNT through the channel — plus the logs if other interfaces need them
+    let result = ContentResult {
+        count: processed.count,
+        logs: processed.logs,
+        content_type: content_to_retrieve.content_type.clone(),
+        content: Some(content_to_retrieve),
+        latest: processed.latest,
+        write_failures: processed.write_failures,
+        known_log_ids: processed.known_log_ids,
+    };
+    result_tx.send(result).await.unwrap_or_else(
+        |e| panic!("Could not send result, channel closed?: {}", e)
+    );
+    queues.results.sent();
+    status_tx.send(StatusMessage::RetrievedContentBlob).await.unwrap();
+}
+
+
+/// Parse the logs of a content blob, freeing the body once parsed. Built with the simd-json
+/// feature it is parsed with simd-json, which modifies the body in place, otherwise with
+/// serde_json.
+#[cfg(feature = "simd-json")]
+fn parse_logs(mut body: Vec<u8>) -> Result<Vec<Value>> {
+    Ok(simd_json::serde::from_slice(&mut body)?)
+}
+
+#[cfg(not(feature = "simd-json"))]
+fn parse_logs(body: Vec<u8>) -> Result<Vec<Value>> {
+    Ok(serde_json::from_slice(&body)?)
+}
+
+
+/// What process_logs does with the logs it is given, and where it writes them.
+pub struct LogProcessing<'a> {
+    pub file_writer: &'a FileWriter,
+    pub filters: &'a LogFilters,
+    pub transform: &'a LogTransform,
+    pub router: &'a Router,
+    /// Return the logs, so they can be passed on to the interfaces
+    pub forward_logs: bool,
+    /// Set with skipKnownLogs
+    pub known_logs: Option<&'a KnownLogs>,
+}
+
+
+/// What process_logs made of the logs it was given.
+pub struct ProcessedLogs {
+    /// Logs kept after filtering
+    pub count: usize,
+    /// The logs, if `forward_logs` is set so they can be passed on to the interfaces
+    pub logs: JsonList,
+    /// Latest CreationTime among them
+    pub latest: Option<DateTime<Utc>>,
+    /// Logs that could not be written to the file output
+    pub write_failures: usize,
+    /// IDs the logs added to `known_logs`
+    pub known_log_ids: Vec<String>,
+}
+
+
+/// Filter and transform the logs of a content type, add the OriginFeed field and write them to the file
+/// output, skipping known logs. Also used for logs from Microsoft Graph.
+pub fn process_logs(logs: Vec<Value>, content_type: &str, processing: &LogProcessing<'_>) -> ProcessedLogs {
+
+    let LogProcessing { file_writer, filters, transform, router, forward_logs, known_logs } = *processing;
+
+    let mut forwarded: JsonList = Vec::new();
+    let mut latest: Option<DateTime<Utc>> = None;
+    let file_routed = router.is_routed("file");
+    let writes_file = file_writer.writes(content_type);
+    // Written as one batch, see FileWriter::write_logs
+    let mut file_lines = Vec::new();
+    let mut known_log_ids = Vec::new();
+    let mut count = 0;
+
+    for log in logs {
+        // Apply filters (same logic as old handle_log)
+        if let Value::Object(map) = &log {
+            if !filters.accepts(content_type, map) {
+                continue;
+            }
+        }
+
+        // Serialize with OriginFeed field added inline.
+        // We avoid mutating the Value (which would require Object variant match)
+        // by building the output string directly.
+        match log {
+            Value::Object(mut map) => {
+                if let Some(known_logs) = known_logs {
+                    if !known_logs.first_seen(&map) {
+                        filters.count_dropped("known_logs");
+                        continue;
                     }
-                    if skip { continue; }
+                    known_log_ids.extend(known_logs::log_id(&map).map(str::to_string));
                 }
-
-                // Serialize with OriginFeed field added inline.
-                // We avoid mutating the Value (which would require Object variant match)
-                // by building the output string directly.
-                match log {
-                    Value::Object(mut map) => {
-                        map.insert("OriginFeed".to_string(),
-                                   Value::String(content_type.to_string()));
-                        match serde_json::to_string(&Value::Object(map)) {
-                            Ok(json_line) => {
-                                if let Err(e) = file_writer.write_log(content_type, &json_line) {
-                                    warn!("Failed to write log to file: {}", e);
-                                }
-                                count += 1;
-                            }
-                            Err(e) => warn!("Failed to serialize log: {}", e),
+                let creation_time = map.get("CreationTime").and_then(|t| t.as_str()).and_then(parse_api_time);
+                latest = latest.max(creation_time);
+                if !transform.apply(content_type, &mut map) {
+                    continue;
+                }
+                map.insert("OriginFeed".to_string(),
+                           Value::String(content_type.to_string()));
+                let json_line = match transform.for_file(content_type, &map) {
+                    Some(file_log) => serde_json::to_string(&file_log),
+                    None => serde_json::to_string(&map),
+                };
+                match json_line {
+                    Ok(json_line) => {
+                        if writes_file && (!file_routed || router.accepts("file", content_type, &|k| map.get(k))) {
+                            file_lines.push((json_line, creation_time));
+                        }
+                        count += 1;
+                        if forward_logs {
+                            forwarded.push(map.into_iter().collect());
                         }
                     }
-                    _ => {
-                        // Non-object log entry (unexpected but handle gracefully)
-                        match serde_json::to_string(&log) {
-                            Ok(json_line) => {
-                                if let Err(e) = file_writer.write_log(content_type, &json_line) {
-                                    warn!("Failed to write log to file: {}", e);
-                                }
-                                count += 1;
-                            }
-                            Err(e) => warn!("Failed to serialize log: {}", e),
+                    Err(e) => warn!("Failed to serialize log: {}", e),
+                }
+            }
+            _ => {
+                // Non-object log entry (unexpected but handle gracefully)
+                match serde_json::to_string(&log) {
+                    Ok(json_line) => {
+                        if writes_file && router.accepts("file", content_type, &|_| None) {
+                            file_lines.push((json_line, None));
                         }
+                        count += 1;
                     }
+                    Err(e) => warn!("Failed to serialize log: {}", e),
                 }
-                // Each Value is dropped here — no accumulation
             }
-            count
         }
+        // Each Value is dropped here — no accumulation
+    }
+    let write_failures = match file_writer.write_logs(content_type, &file_lines) {
+        Ok(()) => 0,
         Err(e) => {
-            warn!("Skipped content that could not be parsed: {} - {}",
-                  content_to_retrieve.content_id, e);
-            drop(body);
-            0
+            warn!("Failed to write {} logs to file: {}", file_lines.len(), e);
+            file_lines.len()
         }
     };
-
-    // Send only the COUNT through the channel — not the data
-    result_tx.send((log_count, content_to_retrieve)).await.unwrap_or_else(
-        |e| panic!("Could not send result, channel closed?: {}", e)
-    );
-    status_tx.send(StatusMessage::RetrievedContentBlob).await.unwrap();
+    ProcessedLogs { count, logs: forwarded, latest, write_failures, known_log_ids }
 }
 
 
@@ -625,3 +1026,98 @@ async fn handle_content_response_error(
         _=> (),
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token_with(header: &str, expires_in: Duration) -> SharedToken {
+        let tenant = serde_yaml::from_str("{tenant_id: t, client_id: c, client_secret: s}").unwrap();
+        let token = SharedToken::new(tenant, Default::default(), reqwest::Client::new());
+        *token.token.try_lock().unwrap() = Some((header.parse().unwrap(), Instant::now() + expires_in));
+        token
+    }
+
+    #[tokio::test]
+    async fn test_connection_reused() {
+        use clap::Parser;
+        let args = CliArgs::try_parse_from(["collector", "--config", "config.yaml"]).unwrap();
+        let config: Config = serde_yaml::from_str("output: {}").unwrap();
+        let tenant: crate::config::TenantConfig = serde_yaml::from_str(
+            "{tenant_id: reused, client_id: c, client_secret_path: /nonexistent}").unwrap();
+        let endpoints = tenant.get_endpoints(&config.api_types).unwrap();
+        let token = SharedToken { tenant: tenant.clone(), ..token_with("bearer a", Duration::from_secs(3600)) };
+        connections().lock().unwrap().insert("reused".to_string(), CachedConnection {
+            settings: format!("{:?}", (&tenant, &endpoints, &None::<String>, &config.tls, true)),
+            token,
+            graph_token: None,
+            client: reqwest::Client::new(),
+        });
+
+        // Logged in with the token of the previous run, without requesting one
+        let api = get_api_connection(args.clone(), config.clone(), tenant.clone()).await.unwrap();
+        assert_eq!(api.token.headers().await.unwrap()[AUTHORIZATION], "bearer a");
+
+        // Changed credentials need a new token
+        let changed = crate::config::TenantConfig { client_id: "other".to_string(), ..tenant };
+        assert!(get_api_connection(args, config, changed).await.is_err());
+    }
+
+    #[test]
+    fn test_parse_logs() {
+        let body = r#"[{"Id": "1", "RecordType": 15, "Nested": {"a": [1.5, null, true]}}, {"Id": "2\u00e9"}]"#;
+        let logs = parse_logs(body.as_bytes().to_vec()).unwrap();
+        assert_eq!(logs, serde_json::from_str::<Vec<Value>>(body).unwrap());
+        assert_eq!(logs[1]["Id"], "2é");
+        assert!(parse_logs(b"[{\"Id\": ".to_vec()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_until_stopped() {
+        let cancel = CancellationToken::new();
+        assert_eq!(until_stopped(&cancel, async { 1 }).await, Some(1));
+        let stopper = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            stopper.cancel();
+        });
+        let started = Instant::now();
+        assert_eq!(until_stopped(&cancel, tokio::time::sleep(Duration::from_secs(60))).await, None);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_is_subscription_disabled() {
+        assert!(is_subscription_disabled(
+            r#"{"error":{"code":"AF20022","message":"No subscription found for the specified content type"}}"#));
+        assert!(is_subscription_disabled(
+            r#"{"error":{"code":"AF20023","message":"The subscription was disabled by an administrator."}}"#));
+        assert!(!is_subscription_disabled(r#"{"error":{"code":"AF20055","message":"Start time and end time must both be specified"}}"#));
+    }
+
+    #[tokio::test]
+    async fn test_shared_token() {
+        let token = token_with("bearer a", Duration::from_secs(3600));
+        let headers = token.headers().await.unwrap();
+        assert_eq!(headers[AUTHORIZATION], "bearer a");
+
+        // A 401 for a token that was already replaced does not drop the new one
+        let mut stale = HeaderMap::new();
+        stale.insert(AUTHORIZATION, "bearer old".parse().unwrap());
+        token.invalidate(&stale).await;
+        assert!(token.token.lock().await.is_some());
+        token.invalidate(&headers).await;
+        assert!(token.token.lock().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_shared_token_refreshes_before_expiry() {
+        // Expiring within the refresh margin, so a new token is requested (and fails offline)
+        let token = token_with("bearer a", Duration::from_secs(60));
+        let tenant: crate::config::TenantConfig = serde_yaml::from_str(
+            "{tenant_id: t, client_id: c, client_secret_path: /nonexistent}").unwrap();
+        let token = SharedToken { tenant, ..token };
+        assert!(token.headers().await.is_err());
+    }
+}
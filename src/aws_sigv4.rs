@@ -0,0 +1,167 @@
+// AWS Signature Version 4 request signing for outputs that talk to AWS APIs (S3, Firehose).
+// Credentials come from the output config or the standard AWS_* environment variables.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sha2::{Digest, Sha256};
+
+/// Characters AWS expects to be left unencoded in a canonical URI path.
+const PATH_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-').remove(b'_').remove(b'.').remove(b'~').remove(b'/');
+
+#[derive(Clone, Debug)]
+pub struct AwsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl AwsCredentials {
+    /// Use the configured keys if present, otherwise fall back to AWS_ACCESS_KEY_ID,
+    /// AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN from the environment.
+    pub fn resolve(access_key_id: Option<&String>, secret_access_key: Option<&String>,
+                   session_token: Option<&String>) -> Result<Self> {
+        if let (Some(key), Some(secret)) = (access_key_id, secret_access_key) {
+            return Ok(AwsCredentials {
+                access_key_id: key.clone(),
+                secret_access_key: secret.clone(),
+                session_token: session_token.cloned(),
+            });
+        }
+        let key = std::env::var("AWS_ACCESS_KEY_ID")
+            .map_err(|_| anyhow!("No AWS access key configured and AWS_ACCESS_KEY_ID is not set"))?;
+        let secret = std::env::var("AWS_SECRET_ACCESS_KEY")
+            .map_err(|_| anyhow!("No AWS secret key configured and AWS_SECRET_ACCESS_KEY is not set"))?;
+        Ok(AwsCredentials {
+            access_key_id: key,
+            secret_access_key: secret,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+/// A request to sign. `headers` are the headers the caller will send besides host and the
+/// x-amz-* headers added by signing; they are included in the signature.
+pub struct SigningRequest<'a> {
+    pub method: &'a str,
+    pub host: &'a str,
+    pub path: &'a str,
+    pub query: &'a str,
+    pub headers: &'a [(&'a str, &'a str)],
+    pub payload: &'a [u8],
+}
+
+/// Sign a request and return the headers that must be added to it (x-amz-date,
+/// x-amz-content-sha256, optional x-amz-security-token and Authorization).
+pub fn sign(credentials: &AwsCredentials, region: &str, service: &str, request: &SigningRequest,
+            now: DateTime<Utc>) -> Vec<(String, String)> {
+
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let payload_hash = hex(&Sha256::digest(request.payload));
+
+    let mut headers: Vec<(String, String)> = request.headers.iter()
+        .map(|(k, v)| (k.to_lowercase(), v.trim().to_string()))
+        .collect();
+    headers.push(("host".to_string(), request.host.to_string()));
+    headers.push(("x-amz-content-sha256".to_string(), payload_hash.clone()));
+    headers.push(("x-amz-date".to_string(), amz_date.clone()));
+    if let Some(token) = &credentials.session_token {
+        headers.push(("x-amz-security-token".to_string(), token.clone()));
+    }
+    headers.sort();
+
+    let canonical_headers: String = headers.iter()
+        .map(|(k, v)| format!("{}:{}\n", k, v))
+        .collect();
+    let signed_headers = headers.iter()
+        .map(|(k, _)| k.as_str())
+        .collect::<Vec<&str>>()
+        .join(";");
+    let canonical_request = format!("{}\n{}\n{}\n{}\n{}\n{}",
+                                    request.method,
+                                    encode_path(request.path),
+                                    request.query,
+                                    canonical_headers,
+                                    signed_headers,
+                                    payload_hash);
+
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}",
+                                 amz_date, scope, hex(&Sha256::digest(canonical_request.as_bytes())));
+    let key = signing_key(&credentials.secret_access_key, &date, region, service);
+    let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+
+    let mut result = vec![
+        ("x-amz-date".to_string(), amz_date),
+        ("x-amz-content-sha256".to_string(), payload_hash),
+    ];
+    if let Some(token) = &credentials.session_token {
+        result.push(("x-amz-security-token".to_string(), token.clone()));
+    }
+    result.push(("Authorization".to_string(),
+                 format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                         credentials.access_key_id, scope, signed_headers, signature)));
+    result
+}
+
+/// URI-encode a path the way SigV4 expects, leaving '/' separators intact.
+pub fn encode_path(path: &str) -> String {
+    utf8_percent_encode(path, PATH_ENCODE_SET).to_string()
+}
+
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let k_date = hmac_sha256(format!("AWS4{}", secret).as_bytes(), date.as_bytes());
+    let k_region = hmac_sha256(&k_date, region.as_bytes());
+    let k_service = hmac_sha256(&k_region, service.as_bytes());
+    hmac_sha256(&k_service, b"aws4_request")
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signing_key() {
+        // Example from the AWS SigV4 documentation
+        let key = signing_key("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20120215", "us-east-1", "iam");
+        assert_eq!(hex(&key), "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d");
+    }
+
+    #[test]
+    fn test_encode_path() {
+        assert_eq!(encode_path("/tenant 1/Audit.General/a~b.json.gz"), "/tenant%201/Audit.General/a~b.json.gz");
+    }
+
+    #[test]
+    fn test_sign_headers() {
+        let credentials = AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "secret".to_string(),
+            session_token: Some("token".to_string()),
+        };
+        let request = SigningRequest {
+            method: "PUT", host: "bucket.s3.eu-west-1.amazonaws.com", path: "/key.json.gz",
+            query: "", headers: &[("Content-Type", "application/gzip")], payload: b"data",
+        };
+        let now = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        let headers = sign(&credentials, "eu-west-1", "s3", &request, now);
+        let auth = &headers.iter().find(|(k, _)| k == "Authorization").unwrap().1;
+        assert!(auth.starts_with("AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20240101/eu-west-1/s3/aws4_request, \
+                                  SignedHeaders=content-type;host;x-amz-content-sha256;x-amz-date;x-amz-security-token, \
+                                  Signature="));
+        assert!(headers.iter().any(|(k, v)| k == "x-amz-security-token" && v == "token"));
+    }
+}
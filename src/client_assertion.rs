@@ -0,0 +1,163 @@
+// Certificate based Azure AD authentication: instead of a client secret the token request
+// carries a JWT client assertion signed (RS256) with the private key of a certificate that is
+// registered on the app. The assertion's x5t header is the base64url SHA-1 thumbprint of the
+// certificate, which Azure AD uses to pick the key to verify with.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+use ring::rand::SystemRandom;
+use ring::signature::{RsaKeyPair, RSA_PKCS1_SHA256};
+use rustls_pemfile::Item;
+use serde_json::json;
+use crate::systemd;
+
+pub const CLIENT_ASSERTION_TYPE: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+/// Lifetime of an assertion, Azure AD only needs it for the token request itself.
+const ASSERTION_LIFETIME_SECONDS: u64 = 600;
+
+pub struct ClientCertificate {
+    key_pair: RsaKeyPair,
+    /// base64url encoded SHA-1 thumbprint
+    x5t: String,
+}
+
+impl ClientCertificate {
+
+    /// Load a PEM certificate and its RSA private key (PKCS#8 or PKCS#1, unencrypted). The key
+    /// may be in the certificate file. Without a certificate in the file, `thumbprint` (hex, as
+    /// shown in the Azure portal) is required.
+    pub fn load(certificate_path: &str, key_path: Option<&str>, thumbprint: Option<&str>) -> Result<Self> {
+
+        let lower = certificate_path.to_lowercase();
+        if lower.ends_with(".pfx") || lower.ends_with(".p12") {
+            return Err(anyhow!("PKCS#12 certificates are not supported, convert {} to PEM with: \
+                                openssl pkcs12 -in {} -out cert.pem -nodes", certificate_path, certificate_path));
+        }
+        let (certificate, mut key) = read_pem(certificate_path)?;
+        if let Some(key_path) = key_path {
+            key = read_pem(key_path)?.1;
+        }
+        let key = key.ok_or_else(|| anyhow!("No unencrypted RSA private key found in {}",
+                                             key_path.unwrap_or(certificate_path)))?;
+        let key_pair = match key {
+            PrivateKey::Pkcs8(der) => RsaKeyPair::from_pkcs8(&der),
+            PrivateKey::Pkcs1(der) => RsaKeyPair::from_der(&der),
+        }.map_err(|e| anyhow!("Invalid RSA private key: {}", e))?;
+
+        let thumbprint = match (thumbprint, certificate) {
+            (Some(hex), _) => decode_hex(hex)?,
+            (None, Some(der)) => digest(&SHA1_FOR_LEGACY_USE_ONLY, &der).as_ref().to_vec(),
+            (None, None) => return Err(anyhow!("No certificate found in {}, set certificate_thumbprint",
+                                               certificate_path)),
+        };
+        Ok(ClientCertificate {
+            key_pair,
+            x5t: BASE64_URL_SAFE_NO_PAD.encode(thumbprint),
+        })
+    }
+
+    /// Signed client assertion for `client_id`, valid for the token endpoint `audience`.
+    pub fn assertion(&self, client_id: &str, audience: &str) -> Result<String> {
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let header = json!({"alg": "RS256", "typ": "JWT", "x5t": self.x5t});
+        let claims = json!({
+            "aud": audience,
+            "iss": client_id,
+            "sub": client_id,
+            "jti": uuid::Uuid::new_v4().to_string(),
+            "nbf": now,
+            "iat": now,
+            "exp": now + ASSERTION_LIFETIME_SECONDS,
+        });
+        let message = format!("{}.{}",
+                              BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?),
+                              BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?));
+        let mut signature = vec![0; self.key_pair.public().modulus_len()];
+        self.key_pair.sign(&RSA_PKCS1_SHA256, &SystemRandom::new(), message.as_bytes(), &mut signature)
+            .map_err(|_| anyhow!("Could not sign client assertion"))?;
+        Ok(format!("{}.{}", message, BASE64_URL_SAFE_NO_PAD.encode(signature)))
+    }
+}
+
+
+enum PrivateKey {
+    Pkcs8(Vec<u8>),
+    Pkcs1(Vec<u8>),
+}
+
+/// First certificate and RSA private key in a PEM file.
+fn read_pem(path: &str) -> Result<(Option<Vec<u8>>, Option<PrivateKey>)> {
+    let file = File::open(systemd::credential_path(path).map_err(|e| anyhow!(e))?)
+        .map_err(|e| anyhow!("Could not open {}: {}", path, e))?;
+    let mut reader = BufReader::new(file);
+    let (mut certificate, mut key) = (None, None);
+    for item in rustls_pemfile::read_all(&mut reader) {
+        match item.map_err(|e| anyhow!("Could not parse {}: {}", path, e))? {
+            Item::X509Certificate(der) if certificate.is_none() => certificate = Some(der.to_vec()),
+            Item::Pkcs8Key(der) if key.is_none() => key = Some(PrivateKey::Pkcs8(der.secret_pkcs8_der().to_vec())),
+            Item::Pkcs1Key(der) if key.is_none() => key = Some(PrivateKey::Pkcs1(der.secret_pkcs1_der().to_vec())),
+            _ => (),
+        }
+    }
+    Ok((certificate, key))
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    let hex: String = hex.chars().filter(|c| !c.is_whitespace() && *c != ':').collect();
+    if !hex.len().is_multiple_of(2) {
+        return Err(anyhow!("Invalid certificate thumbprint '{}'", hex));
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16)
+            .map_err(|_| anyhow!("Invalid certificate thumbprint '{}'", hex)))
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{UnparsedPublicKey, KeyPair, RSA_PKCS1_2048_8192_SHA256};
+    use serde_json::Value;
+
+    fn testdata(name: &str) -> String {
+        format!("{}/testdata/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    #[test]
+    fn test_assertion() {
+        let certificate = ClientCertificate::load(
+            &testdata("client_cert.pem"), Some(&testdata("client_key.pem")), None).unwrap();
+        let assertion = certificate.assertion("client-id", "https://login.example/tenant/oauth2/token").unwrap();
+        let parts: Vec<&str> = assertion.split('.').collect();
+        assert_eq!(parts.len(), 3);
+
+        let header: Value = serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(parts[0]).unwrap()).unwrap();
+        assert_eq!(header["alg"], "RS256");
+        assert_eq!(BASE64_URL_SAFE_NO_PAD.decode(header["x5t"].as_str().unwrap()).unwrap().len(), 20);
+        let claims: Value = serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(parts[1]).unwrap()).unwrap();
+        assert_eq!(claims["sub"], "client-id");
+        assert_eq!(claims["aud"], "https://login.example/tenant/oauth2/token");
+
+        let public_key = UnparsedPublicKey::new(&RSA_PKCS1_2048_8192_SHA256,
+                                                certificate.key_pair.public_key().as_ref().to_vec());
+        let message = format!("{}.{}", parts[0], parts[1]);
+        public_key.verify(message.as_bytes(), &BASE64_URL_SAFE_NO_PAD.decode(parts[2]).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_thumbprint_and_missing_key() {
+        let certificate = ClientCertificate::load(&testdata("client_key.pem"), None, Some("0A:1B:2C")).unwrap();
+        assert_eq!(certificate.x5t, BASE64_URL_SAFE_NO_PAD.encode([0x0a, 0x1b, 0x2c]));
+        assert!(ClientCertificate::load(&testdata("client_key.pem"), None, None).is_err());
+        assert!(ClientCertificate::load(&testdata("client_cert.pem"), None, None).is_err());
+        assert!(ClientCertificate::load("cert.pfx", None, None).is_err());
+    }
+}
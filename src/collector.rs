@@ -1,30 +1,63 @@
-use std::collections::HashMap;
-use std::ops::Div;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
-use anyhow::Result;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
 use log::{warn, error, info};
-use futures::SinkExt;
+use futures::{SinkExt, StreamExt};
 use futures::channel::mpsc::channel;
 use futures::channel::mpsc::{Sender, Receiver};
 use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::Mutex;
 use tokio::time::sleep;
+use tracing::Instrument;
+use tokio_util::sync::CancellationToken;
 use crate::data_structures;
+use crate::graph::{self, GraphSource};
 use crate::api_connection;
 use crate::api_connection::ApiConnection;
-use crate::config::{Config, ContentTypesSubConfig};
-use crate::data_structures::{ArbitraryJson, CliArgs, ContentToRetrieve, FileWriter, RunState};
-use crate::state::StateManager;
-use crate::known_blobs_cache::{KnownBlobsCache, SharedKnownBlobsCache};
+use crate::config::{Config, ContentTypesSubConfig, MAX_LOOKBACK_HOURS};
+use crate::data_structures::{CliArgs, ContentResult, ContentToRetrieve, FileWriter, QueueDepths, RunState};
+use crate::file_rotation::RotationPolicy;
+use crate::routing::Router;
+use crate::run_ledger;
+use crate::lag::LagSummary;
+use crate::run_summary::{OutputSummary, TenantSummary};
+use crate::state::{next_last_log_time, StateManager};
+use crate::throttle::{RateLimiter, Throttle, DEFAULT_MAX_REQUESTS_PER_SECOND};
+use crate::known_blobs_cache::SharedKnownBlobsCache;
+use crate::known_logs::KnownLogs;
+use crate::log_filter::LogFilters;
+use crate::transform::LogTransform;
+use crate::logging;
+use crate::page_cursors::{self, PageCursors};
+use crate::pause;
+use crate::interfaces::interface::Interface;
+use crate::interfaces::azure_blob_interface::AzureBlobInterface;
+use crate::interfaces::azure_oms_interface::OmsInterface;
+use crate::interfaces::event_hub_interface::EventHubInterface;
+use crate::interfaces::exec_interface::ExecInterface;
+use crate::interfaces::firehose_interface::FirehoseInterface;
+use crate::interfaces::file_interface::FileInterface;
+use crate::interfaces::fluentd_interface::FluentdInterface;
+use crate::interfaces::graylog_interface::GraylogInterface;
+use crate::interfaces::logs_ingestion_interface::LogsIngestionInterface;
+use crate::interfaces::qradar_interface::QRadarInterface;
+use crate::interfaces::output::Output;
+use crate::interfaces::retry::{send_with_retry, RetryPolicy};
+use crate::interfaces::s3_interface::S3Interface;
+use crate::interfaces::spool::Spool;
+use crate::interfaces::stdout_interface::StdoutInterface;
+#[cfg(unix)]
+use crate::interfaces::wazuh_interface::WazuhInterface;
 
 
 /// # Office Audit Log Collector
 ///
 /// MEMORY FIX: The collector no longer buffers response bodies in channels or caches.
 /// Download tasks process responses inline: parse from bytes → filter → write to file.
-/// The monitor loop only receives log counts and updates known_blobs for dedup.
+/// The monitor loop only receives log counts and updates known_blobs for dedup. When
+/// interfaces other than the file output are configured, download tasks also pass the
+/// parsed logs, which are cached here and fanned out to the interfaces when the cache is full.
 ///
 /// TASK LIFECYCLE FIX: Spawned background tasks are tracked and aborted on cleanup.
 /// Without this, the blob collector task hangs forever (self-referential channel)
@@ -32,12 +65,38 @@ use crate::known_blobs_cache::{KnownBlobsCache, SharedKnownBlobsCache};
 pub struct Collector {
     config: Config,
     tenant_id: String,
-    result_rx: Receiver<(usize, ContentToRetrieve)>,
+    result_rx: Receiver<ContentResult>,
     stats_rx: Receiver<(usize, usize, usize, usize)>,
     kill_tx: tokio::sync::mpsc::Sender<bool>,
     known_blobs: SharedKnownBlobsCache,
+    /// Retrieved content blobs with logs waiting in output buffers, see Collector::settle
+    unconfirmed: UnconfirmedBlobs,
     saved: usize,
     file_writer: Arc<FileWriter>,
+    /// Interface outputs, each buffering the logs routed to it
+    outputs: Vec<Output>,
+    router: Arc<Router>,
+    /// Shared with the download tasks, counting the logs suppressed and sampled out
+    filters: LogFilters,
+    /// Depths of the channels, shared with the download tasks
+    queues: Arc<QueueDepths>,
+    transform: Arc<LogTransform>,
+    /// Progress of the content listings, saved when the run ends
+    cursors: Arc<PageCursors>,
+    /// Latest CreationTime collected per content type, saved as state when the run ends
+    latest: HashMap<String, chrono::DateTime<chrono::Utc>>,
+    /// Progress of the run, shared with the message loop
+    state: Arc<Mutex<RunState>>,
+    started: chrono::DateTime<chrono::Utc>,
+    /// Time windows of the run, for the run ledger
+    windows: Vec<run_ledger::RunWindow>,
+    timed_out: bool,
+    /// Seconds the run may take, see Config::get_tenant_timeout
+    timeout_seconds: u64,
+    /// Content blobs retrieved, for max_blobs_per_run
+    blobs_retrieved: usize,
+    /// Stopped at max_blobs_per_run or max_logs_per_run
+    limit_reached: bool,
     /// Handles to spawned background tasks. Must be aborted on cleanup to prevent leaks.
     task_handles: Vec<tokio::task::JoinHandle<()>>,
 }
@@ -48,23 +107,51 @@ impl Collector {
                      config: Config,
                      tenant: crate::config::TenantConfig,
                      runs: HashMap<String, Vec<(String, String)>>,
+                     notified: Vec<ContentToRetrieve>,
                      state: Arc<Mutex<RunState>>,
                      _interactive_sender: Option<UnboundedSender<Vec<String>>>
     ) -> Result<Collector> {
 
         info!("Initializing collector for tenant {}.", tenant.tenant_id);
+        let started = chrono::Utc::now();
 
         // Initialize collector threads
         let tenant_id = tenant.tenant_id.clone();
+        let timeout_seconds = config.get_tenant_timeout(&tenant);
+        let cache_size = config.collect.as_ref()
+            .and_then(|c| c.cache_size)
+            .unwrap_or(DEFAULT_CACHE_SIZE);
+        let cache_bytes = config.collect.as_ref()
+            .and_then(|c| c.cache_bytes.as_deref())
+            .map(Config::parse_size)
+            .unwrap_or(DEFAULT_CACHE_BYTES);
+        let outputs = build_interfaces(&args, &config, &tenant_id)?
+            .into_iter()
+            .map(|(name, interface)| Output::new(name, interface, &config, &tenant_id, cache_size, cache_bytes))
+            .collect::<Result<Vec<Output>>>()?;
+        let router = Arc::new(Router::new(&config.routing, &tenant_id).map_err(|e| anyhow!(e))?);
+        let transform = Arc::new(LogTransform::new(&config, &tenant).map_err(|e| anyhow!(e))?);
+        let graph_sources = graph::sources(&config)?;
         let api = api_connection::get_api_connection(args.clone(), config.clone(), tenant).await?;
-        api.subscribe_to_feeds().await?;
+        api.ensure_subscribed().await?;
 
         // Load known blobs using memory-efficient LRU cache
-        let working_dir = config.get_working_dir();
-        let known_blobs_path = Path::new(&working_dir).join("known_blobs");
-        let known_blobs_cache = KnownBlobsCache::load_from_file(&known_blobs_path);
+        let known_blobs_cache = StateManager::for_config(&config).load_known_blobs(&tenant_id);
         info!("Loaded {} known blobs into LRU cache", known_blobs_cache.len());
         let known_blobs = SharedKnownBlobsCache::from_cache(known_blobs_cache);
+        let cursors = Arc::new(PageCursors::for_config(&config, &tenant_id));
+
+        // Blobs announced by webhook notifications since the last run
+        let mut new_notified = Vec::new();
+        for content in notified {
+            if known_blobs.claim(&content.content_id).await {
+                new_notified.push(content);
+            }
+        }
+        if !new_notified.is_empty() {
+            info!("Retrieving {} blobs from webhook notifications", new_notified.len());
+        }
+        let notified = new_notified;
 
         // Get content types/subscriptions
         let content_types_config = if let Some(ref collect) = config.collect {
@@ -82,29 +169,27 @@ impl Collector {
 
         // Create the shared FileWriter for direct-to-disk writing
         let file_writer = if let Some(ref file_config) = config.output.file {
+            let policy = RotationPolicy::from_config(file_config);
             if file_config.separate_by_content_type.unwrap_or(false) {
+                let mut content_types = config.get_subscriptions();
+                content_types.extend(graph::content_types(&config));
+                if config.heartbeat.unwrap_or(false) {
+                    content_types.push(HEARTBEAT_CONTENT_TYPE.to_string());
+                }
                 let paths = FileWriter::build_separated_paths(
                     &file_config.path,
-                    &config.get_subscriptions(),
+                    &content_types,
                 );
-                Arc::new(FileWriter::new_separated(paths))
+                Arc::new(FileWriter::new_separated(paths, &policy)?)
             } else {
-                Arc::new(FileWriter::new_unified(&file_config.path))
+                Arc::new(FileWriter::new_unified(&file_config.path, &policy)?)
             }
         } else {
             Arc::new(FileWriter::new_noop())
         };
 
         // Build filters for inline processing in download tasks
-        let filters = if let Some(ref collect) = config.collect {
-            if let Some(ref filter_config) = collect.filter {
-                filter_config.get_filters()
-            } else {
-                HashMap::new()
-            }
-        } else {
-            HashMap::new()
-        };
+        let filters = LogFilters::new(&config).map_err(|e| anyhow!(e))?;
 
         let (result_rx, stats_rx, kill_tx, task_handles) =
             get_available_content(api,
@@ -112,93 +197,189 @@ impl Collector {
                                   runs.clone(),
                                   &config,
                                   known_blobs.clone(),
-                                  state,
+                                  cursors.clone(),
+                                  state.clone(),
                                   file_writer.clone(),
-                                  filters).await;
-
+                                  filters.clone(),
+                                  transform.clone(),
+                                  router.clone(),
+                                  !outputs.is_empty(),
+                                  notified,
+                                  graph_sources).await;
+
+        let queues = state.lock().await.queues.clone();
+        let unconfirmed = UnconfirmedBlobs::new(KnownLogs::for_config(&config, &tenant_id));
         let collector = Collector {
             config,
+            queues,
             tenant_id,
             result_rx,
             stats_rx,
             known_blobs,
+            unconfirmed,
             saved: 0,
             kill_tx,
             file_writer,
+            outputs,
+            router,
+            filters,
+            transform,
+            cursors,
+            latest: HashMap::new(),
+            windows: run_ledger::windows(&runs),
+            state,
+            started,
+            timed_out: false,
+            timeout_seconds,
+            blobs_retrieved: 0,
+            limit_reached: false,
             task_handles,
         };
         Ok(collector)
     }
 
-    /// Monitor all started content retrieval threads.
+    /// Monitor all started content retrieval threads, returning the summary of the run.
     /// MEMORY FIX: No longer processes JSON data — only receives log counts.
-    pub async fn monitor(&mut self) {
-
-        let start = Instant::now();
-        const DEFAULT_TIMEOUT_MINUTES: usize = 30;
+    pub async fn monitor(&mut self) -> TenantSummary {
 
-        loop {
-            let timeout_minutes = if let Some(ref collect) = self.config.collect {
-                collect.global_timeout.unwrap_or(DEFAULT_TIMEOUT_MINUTES)
-            } else {
-                DEFAULT_TIMEOUT_MINUTES
-            };
-
-            let elapsed_minutes = start.elapsed().as_secs().div(60) as usize;
-            if timeout_minutes > 0 && elapsed_minutes >= timeout_minutes {
-                warn!(
-                    "Global timeout expired after {} minutes. Requesting collector stop.",
-                    elapsed_minutes
-                );
-                let _ = self.kill_tx.send(true).await;
-                sleep(Duration::from_secs(2)).await;
-                break;
+        let timeout_seconds = self.timeout_seconds;
+        let timeout = async {
+            match timeout_seconds {
+                0 => std::future::pending().await,
+                seconds => sleep(Duration::from_secs(seconds)).await,
             }
+        };
+        tokio::pin!(timeout);
+        // Pause checks and partial batches of outputs with a flush_interval
+        let mut housekeeping = tokio::time::interval(HOUSEKEEPING_INTERVAL);
+        housekeeping.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
 
-            if self.check_stats().await {
-                break
+        loop {
+            tokio::select! {
+                Some(result) = self.result_rx.next() => {
+                    self.queues.results.received();
+                    self.handle_content(result).await;
+                    if let Some(limit) = run_limit_reached(&self.config, self.blobs_retrieved, self.saved) {
+                        info!("Reached {} of tenant {}, stopping the run. The rest is collected by the next runs.",
+                              limit, self.tenant_id);
+                        self.limit_reached = true;
+                        self.stop().await;
+                        break
+                    }
+                },
+                stats = self.stats_rx.next() => {
+                    if let Some(stats) = stats {
+                        self.report_stats(stats);
+                    }
+                    break
+                },
+                _ = &mut timeout => {
+                    warn!("Global timeout expired after {} seconds. Requesting collector stop.", timeout_seconds);
+                    self.timed_out = true;
+                    self.stop().await;
+                    break
+                },
+                _ = housekeeping.tick() => {
+                    if pause::is_paused(&self.config) || pause::is_tenant_paused(&self.tenant_id) {
+                        info!("Collection paused, stopping the run and saving its state.");
+                        self.stop().await;
+                        break
+                    }
+                    for output in self.outputs.iter_mut() {
+                        output.send_if_due().await;
+                    }
+                    self.settle(Vec::new()).await;
+                    self.state.lock().await.buffered_bytes = self.buffered_bytes();
+                },
             }
-
-            self.check_results().await;
-
-            sleep(Duration::from_millis(10)).await;
         }
         self.check_all_results().await;
-        self.end_run().await;
+        self.end_run().await
+    }
+
+    /// Ask the message loop to stop, giving it STOP_GRACE to do so.
+    async fn stop(&mut self) {
+        let _ = self.kill_tx.send(true).await;
+        let _ = tokio::time::timeout(STOP_GRACE, self.stats_rx.next()).await;
     }
 
-    pub async fn end_run(&mut self) {
-        // Flush all file writers to ensure all data is on disk
+    pub async fn end_run(&mut self) -> TenantSummary {
+        let stats = self.state.lock().await.stats;
+        let mut record = run_ledger::RunRecord::new(&self.tenant_id, self.started, std::mem::take(&mut self.windows),
+                                                    &stats, self.saved, self.timed_out);
+        info!("Most blobs waiting: {} to be downloaded, {} for the outputs",
+              self.queues.content.max(), self.queues.results.max());
+        let (suppressed, sampled_out) = (self.filters.suppressed(), self.filters.sampled_out());
+        let mut filtered = self.filters.filtered();
+        if let Some((dropped, errors)) = self.transform.plugin_counts() {
+            info!("Plugin dropped {} logs and failed on {}", dropped, errors);
+            if dropped > 0 {
+                filtered.insert("plugin".to_string(), dropped);
+            }
+        }
+        record.limit_reached = self.limit_reached;
+        record.logs_filtered = suppressed.values().chain(sampled_out.values()).chain(filtered.values()).sum();
+        if self.config.heartbeat.unwrap_or(false) {
+            self.send_heartbeat(&record).await;
+        }
+
+        // Rotate the files that are due and write what the file output could not
         self.file_writer.flush_all();
+        let file_summary = self.retry_file_writes().await;
+        let file_written = file_summary.as_ref().is_none_or(|summary| summary.logs_dropped == 0);
+        let retried = self.unconfirmed.file_retried(file_written);
+
+        // Send spooled batches of recovered interfaces and whatever is left in the buffers
+        for output in self.outputs.iter_mut() {
+            output.replay_spool().await;
+            output.flush().await;
+        }
+        self.settle(retried).await;
+        self.unconfirmed.abandon();
 
         // Save known blobs
-        let working_dir = self.config.get_working_dir();
-        let known_blobs_path = Path::new(&working_dir).join("known_blobs");
-        if let Err(e) = self.known_blobs.save_to_file(&known_blobs_path).await {
+        let state_manager = StateManager::for_config(&self.config);
+        if let Err(e) = state_manager.save_known_blobs(&self.tenant_id, &self.known_blobs).await {
             error!("Failed to save known blobs: {}", e);
         } else {
-            info!("Saved {} known blobs to file", self.known_blobs.len().await);
+            info!("Saved {} known blobs", self.known_blobs.len().await);
         }
-
-        // Update state with current time for only_future_events
+        // Update state with the latest collected logs for only_future_events, up to the first
+        // window the run did not finish
+        let now = chrono::Utc::now();
         if self.config.only_future_events.unwrap_or(false) {
-            let working_dir = self.config.get_working_dir();
-            let state_manager = StateManager::new(&working_dir);
-            let now = chrono::Utc::now();
+            let lag = self.config.get_state_safety_lag();
 
             for subscription in self.config.get_subscriptions() {
+                let previous = state_manager.load_state(&self.tenant_id, &subscription)
+                    .map(|state| state.last_log_time);
+                let last_log_time = next_last_log_time(self.latest.get(&subscription).copied(), previous,
+                                                       self.cursors.unfinished_since(&subscription), lag, now);
+                self.cursors.prune_completed(&subscription, last_log_time);
                 let state = crate::state::TenantSubscriptionState {
-                    last_log_time: now,
+                    last_log_time,
                     last_run: now,
                     first_run: false,
                 };
                 if let Err(e) = state_manager.save_state(&self.tenant_id, &subscription, &state) {
                     error!("Failed to update state for {}/{}: {}", self.tenant_id, subscription, e);
                 } else {
-                    info!("Updated state for {}/{}: last_log_time={}", self.tenant_id, subscription, now);
+                    info!("Updated state for {}/{}: last_log_time={}", self.tenant_id, subscription, last_log_time);
                 }
             }
         }
+        let retention = now - chrono::Duration::try_hours(MAX_LOOKBACK_HOURS).unwrap();
+        for subscription in self.config.get_subscriptions() {
+            self.cursors.prune_completed(&subscription, retention);
+        }
+        // Listings the run did not finish continue from here next run
+        self.cursors.save();
+
+        if let Some(ledger) = &self.config.ledger {
+            if let Err(e) = run_ledger::append(ledger, &self.config, &record) {
+                error!("Failed to write run ledger: {}", e);
+            }
+        }
 
         // CRITICAL: Abort AND await background tasks to prevent memory leaks.
         // The blob collector task has a self-referential channel (blobs_tx/blobs_rx)
@@ -210,53 +391,219 @@ impl Collector {
             handle.abort();
             let _ = handle.await; // Wait for tokio to fully drop task state
         }
+        let outputs = file_summary.into_iter()
+            .chain(self.outputs.iter().map(|output| output.summary.clone()))
+            .collect();
+        let mut summary = TenantSummary::new(record, outputs, None);
+        summary.lag = self.ingestion_lag();
+        summary.suppressed = suppressed;
+        for (rule, count) in &summary.suppressed {
+            info!("Suppressed {} logs by suppress rule {}", count, rule);
+        }
+        summary.sampled_out = sampled_out;
+        for (rule, count) in &summary.sampled_out {
+            info!("Sampled out {} logs by sample rule {}", count, rule);
+        }
+        summary.filtered = filtered;
+        for (rule, count) in &summary.filtered {
+            info!("Filtered out {} logs by {}", count, rule);
+        }
+        for output in summary.outputs.iter().filter(|output| output.logs_oversized > 0) {
+            info!("{} logs were over the oversized max_size of {}", output.logs_oversized, output.name);
+        }
+        summary
     }
 
-    /// MEMORY FIX: Now receives (usize, ContentToRetrieve) — a count, not data.
-    pub async fn check_results(&mut self) -> usize {
-        if let Ok(Some((count, content))) = self.result_rx.try_next() {
-            self.handle_content(count, content).await
+    /// Retry the logs the file output could not write this run, with the retry settings of the
+    /// "file" output, and spool those that still fail if a spool is configured. Spooled logs are
+    /// written first, so the file receives them in order. Returns what the file output did this
+    /// run, if there is one.
+    async fn retry_file_writes(&mut self) -> Option<OutputSummary> {
+        self.config.output.file.as_ref()?;
+        let mut summary = OutputSummary { name: "file".to_string(), ..Default::default() };
+        self.write_failed_logs(&mut summary).await;
+        summary.logs_sent = self.file_writer.take_written();
+        let overflow = self.file_writer.take_overflow();
+        if overflow > 0 {
+            error!("Dropped {} logs that could not be written to the file output, more than could be kept to retry",
+                   overflow);
+            summary.logs_dropped += overflow;
+        }
+        Some(summary)
+    }
+
+    async fn write_failed_logs(&mut self, summary: &mut OutputSummary) {
+        let mut interface = FileInterface::new(self.file_writer.clone());
+        let spool = Spool::from_config(&self.config, "file", &self.tenant_id).unwrap_or_else(|e| {
+            error!("{}", e);
+            None
+        });
+        let replayed = match &spool {
+            Some(spool) => spool.replay("file", &mut interface).await,
+            None => true,
+        };
+        let failed = self.file_writer.take_failed();
+        if failed.is_empty() {
+            return
+        }
+        let count = failed.len();
+        warn!("Retrying {} logs that could not be written to the file output", count);
+        let undelivered = if replayed {
+            let policy = RetryPolicy::for_output(&self.config.retry, "file");
+            match send_with_retry("file", &mut interface, failed, &policy).await {
+                Ok(()) => {
+                    info!("Wrote {} logs to the file output on retry", count);
+                    return
+                },
+                Err(logs) => logs,
+            }
         } else {
-            0
+            failed
+        };
+        match spool.map(|spool| spool.store(&undelivered)) {
+            Some(Ok(())) => {
+                warn!("Spooled {} logs for the file output", count);
+                summary.logs_spooled += count;
+            },
+            Some(Err(e)) => {
+                error!("Could not spool {} logs for the file output, dropping them: {}", count, e);
+                summary.logs_dropped += count;
+            },
+            None => {
+                error!("Dropping {} logs that could not be written to the file output", count);
+                summary.logs_dropped += count;
+            },
+        }
+    }
+
+    /// Lag of the logs delivered this run by output and content type, logging it as well.
+    fn ingestion_lag(&self) -> BTreeMap<String, BTreeMap<String, LagSummary>> {
+        let mut lag = BTreeMap::new();
+        lag.insert("file".to_string(), self.file_writer.lag.summary());
+        for output in &self.outputs {
+            lag.insert(output.name.to_string(), output.lag.summary());
         }
+        lag.retain(|_, content_types| !content_types.is_empty());
+        for (output, content_types) in &lag {
+            for (content_type, summary) in content_types {
+                info!("Ingestion lag of {} to {}: avg {}s, p50 {}s, p95 {}s, p99 {}s, max {}s ({} logs)",
+                      content_type, output, summary.avg_seconds, summary.p50_seconds, summary.p95_seconds,
+                      summary.p99_seconds, summary.max_seconds, summary.logs);
+            }
+        }
+        lag
+    }
+
+    /// Write the heartbeat of the run like a retrieved log: to the file output and to the
+    /// outputs it is routed to.
+    async fn send_heartbeat(&mut self, record: &run_ledger::RunRecord) {
+        let processing = api_connection::LogProcessing {
+            file_writer: &self.file_writer,
+            filters: &LogFilters::default(),
+            transform: &self.transform,
+            router: &self.router,
+            forward_logs: !self.outputs.is_empty(),
+            known_logs: None,
+        };
+        let processed = api_connection::process_logs(vec![record.heartbeat()], HEARTBEAT_CONTENT_TYPE,
+                                                     &processing);
+        let result = ContentResult {
+            count: 0,
+            logs: processed.logs,
+            content_type: HEARTBEAT_CONTENT_TYPE.to_string(),
+            content: None,
+            latest: None,
+            write_failures: processed.write_failures,
+            known_log_ids: processed.known_log_ids,
+        };
+        self.handle_content(result).await;
     }
 
     pub async fn check_all_results(&mut self) -> usize {
         let mut amount = 0;
-        while let Ok(Some((count, content))) = self.result_rx.try_next() {
-            amount += self.handle_content(count, content).await;
+        while let Ok(Some(result)) = self.result_rx.try_next() {
+            self.queues.results.received();
+            amount += self.handle_content(result).await;
         }
         amount
     }
 
-    /// MEMORY FIX: No JSON parsing here. Just update known_blobs for dedup and track count.
-    async fn handle_content(&mut self, count: usize, content: ContentToRetrieve) -> usize {
-        self.known_blobs.insert(content.content_id.clone(), &content.expiration).await;
+    /// MEMORY FIX: No JSON parsing here. Track count and buffer forwarded logs for the outputs
+    /// they are routed to. The blob is known for dedup once they are delivered, see settle.
+    async fn handle_content(&mut self, result: ContentResult) -> usize {
+        let ContentResult { count, logs, content_type, content, latest, write_failures, known_log_ids } = result;
+        if let Some(latest) = latest {
+            let entry = self.latest.entry(content_type.clone()).or_insert(latest);
+            *entry = (*entry).max(latest);
+        }
+        let mut touched = Vec::new();
+        if let Some(content) = &content {
+            self.blobs_retrieved += 1;
+            self.cursors.retrieved(&content.content_id);
+            // Logs the file output could not write are retried at the end of the run, the blob
+            // settles after that
+            self.unconfirmed.retrieved(content, write_failures > 0, known_log_ids);
+            touched.push(content.content_id.clone());
+        }
         self.saved += count;
+        self.state.lock().await.logs_saved = self.saved;
+        for log in logs {
+            let log = Arc::new(log);
+            let accepting: Vec<usize> = self.outputs.iter().enumerate()
+                .filter(|(_, output)| self.router.accepts(output.name, &content_type, &|k| log.get(k)))
+                .map(|(i, _)| i)
+                .collect();
+            if let Some(content) = &content {
+                for i in &accepting {
+                    if self.outputs[*i].hold(&content.content_id) {
+                        self.unconfirmed.held(&content.content_id);
+                    }
+                }
+            }
+            // Outputs share the log, see Output::add
+            if let Some((last, others)) = accepting.split_last() {
+                for i in others {
+                    self.outputs[*i].add(log.clone(), &content_type).await;
+                }
+                self.outputs[*last].add(log, &content_type).await;
+            }
+        }
+        self.settle(touched).await;
+        self.state.lock().await.buffered_bytes = self.buffered_bytes();
         count
     }
 
-    pub async fn check_stats(&mut self) -> bool {
-        if let Ok(Some((found,
-                        successful,
-                        retried,
-                        failed))) = self.stats_rx.try_next() {
-
-            // Flush file writer to ensure all data is on disk before reporting stats
-            self.file_writer.flush_all();
-
-            let output = self.get_output_string(
-                found,
-                successful,
-                failed,
-                retried,
-                self.saved,
-            );
-            info!("{}", output);
-            true
-        } else {
-            false
+    /// Mark retrieved content blobs known once every output they have logs for delivered or
+    /// spooled them, and the file output wrote or spooled them, if need be on retry at the end of
+    /// the run. Blobs with logs an output dropped are not, so a later run retrieves them again. `touched` are blobs to check besides those of the buffers sent.
+    async fn settle(&mut self, mut touched: Vec<String>) {
+        for output in self.outputs.iter_mut() {
+            for (content_id, delivered) in output.take_settled() {
+                self.unconfirmed.released(&content_id, delivered);
+                touched.push(content_id);
+            }
         }
+        for (content_id, expiration) in self.unconfirmed.take_settled(touched) {
+            self.known_blobs.insert(content_id, &expiration).await;
+        }
+    }
+
+    fn buffered_bytes(&self) -> usize {
+        self.outputs.iter().map(Output::buffered_bytes).sum()
+    }
+
+    fn report_stats(&self, (found, successful, retried, failed): (usize, usize, usize, usize)) {
+        // Flush file writer to ensure all data is on disk before reporting stats
+        self.file_writer.flush_all();
+
+        let output = self.get_output_string(
+            found,
+            successful,
+            failed,
+            retried,
+            self.saved,
+        );
+        info!("{}", output);
     }
 
     fn get_output_string(&self, found: usize, successful: usize, failed: usize, retried: usize,
@@ -275,51 +622,281 @@ Logs saved: {}",
 }
 
 
+/// Retrieved content blobs whose logs wait in output buffers, until they settle as delivered or
+/// dropped.
+struct UnconfirmedBlobs {
+    blobs: HashMap<String, Unconfirmed>,
+    /// Set with skipKnownLogs, the IDs of logs that were not delivered are forgotten again
+    known_logs: Option<Arc<KnownLogs>>,
+}
+
+/// The max_blobs_per_run or max_logs_per_run limit a run reached after retrieving `blobs` with
+/// `logs` saved, if any. Like a timed out run, a stopped run leaves the windows it did not finish
+/// to the next runs, see page_cursors.rs.
+fn run_limit_reached(config: &Config, blobs: usize, logs: usize) -> Option<&'static str> {
+    if config.max_blobs_per_run.is_some_and(|max| blobs >= max) {
+        Some("max_blobs_per_run")
+    } else if config.max_logs_per_run.is_some_and(|max| logs >= max) {
+        Some("max_logs_per_run")
+    } else {
+        None
+    }
+}
+
+/// A retrieved content blob whose logs wait in output buffers.
+struct Unconfirmed {
+    expiration: String,
+    /// Output buffers holding logs of the blob
+    holds: usize,
+    /// An output dropped logs of the blob
+    dropped: bool,
+    /// The file output could not write logs of the blob, they are retried at the end of the run
+    file_failed: bool,
+    /// IDs the logs of the blob added to the known logs
+    known_log_ids: Vec<String>,
+}
+
+impl UnconfirmedBlobs {
+
+    fn new(known_logs: Option<Arc<KnownLogs>>) -> Self {
+        UnconfirmedBlobs { blobs: HashMap::new(), known_logs }
+    }
+
+    fn retrieved(&mut self, content: &ContentToRetrieve, file_failed: bool, known_log_ids: Vec<String>) {
+        self.blobs.insert(content.content_id.clone(), Unconfirmed {
+            expiration: content.expiration.clone(), holds: 0, dropped: false, file_failed, known_log_ids,
+        });
+    }
+
+    /// An output buffer holds logs of the blob.
+    fn held(&mut self, content_id: &str) {
+        if let Some(blob) = self.blobs.get_mut(content_id) {
+            blob.holds += 1;
+        }
+    }
+
+    /// An output buffer holding logs of the blob delivered or dropped them.
+    fn released(&mut self, content_id: &str, delivered: bool) {
+        if let Some(blob) = self.blobs.get_mut(content_id) {
+            blob.holds = blob.holds.saturating_sub(1);
+            blob.dropped |= !delivered;
+        }
+    }
+
+    /// The logs the file output could not write were retried, returning the blobs they were of.
+    fn file_retried(&mut self, written: bool) -> Vec<String> {
+        let mut retried = Vec::new();
+        for (content_id, blob) in self.blobs.iter_mut().filter(|(_, blob)| blob.file_failed) {
+            blob.file_failed = false;
+            blob.dropped |= !written;
+            retried.push(content_id.clone());
+        }
+        retried
+    }
+
+    /// Remove the blobs of `content_ids` no output buffer holds logs of anymore, returning the
+    /// delivered ones with their expiration. The logs of the others are forgotten, so they are
+    /// collected again when the blob is retrieved again.
+    fn take_settled(&mut self, content_ids: Vec<String>) -> Vec<(String, String)> {
+        let mut delivered = Vec::new();
+        for content_id in content_ids {
+            if self.blobs.get(&content_id).is_none_or(|blob| blob.holds > 0 || blob.file_failed) {
+                continue
+            }
+            let blob = self.blobs.remove(&content_id).unwrap();
+            if blob.dropped {
+                warn!("Logs of content {} were not delivered, it will be retrieved again", content_id);
+                self.forget(&blob);
+            } else {
+                delivered.push((content_id, blob.expiration));
+            }
+        }
+        delivered
+    }
+
+    /// Give up on the blobs that did not settle by the end of the run.
+    fn abandon(&mut self) {
+        for (content_id, blob) in std::mem::take(&mut self.blobs) {
+            warn!("Logs of content {} were not confirmed delivered, it will be retrieved again", content_id);
+            self.forget(&blob);
+        }
+    }
+
+    fn forget(&self, blob: &Unconfirmed) {
+        if let Some(known_logs) = &self.known_logs {
+            known_logs.forget(&blob.known_log_ids);
+        }
+    }
+}
+
+/// Content type (OriginFeed) of heartbeat logs
+pub const HEARTBEAT_CONTENT_TYPE: &str = "Collector.Heartbeat";
+
+/// Default amount of logs buffered per output before they are sent to its interface.
+const DEFAULT_CACHE_SIZE: usize = 500_000;
+/// Bytes of logs an output buffers at most by default
+const DEFAULT_CACHE_BYTES: usize = 256 * 1024 * 1024;
+
+/// Default capacities of the channels of found content listing pages, of found blobs and of
+/// downloaded blobs when their logs are not forwarded to outputs.
+const DEFAULT_BLOBS_CAPACITY: usize = 2000;
+const DEFAULT_CONTENT_CAPACITY: usize = 2000;
+const DEFAULT_RESULTS_CAPACITY: usize = 500;
+
+/// How often a running collector checks for a pause and sends partial batches that are due.
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a stopped run waits for the message loop to report its statistics.
+const STOP_GRACE: Duration = Duration::from_secs(2);
+
+
+/// Create the interfaces for all configured outputs, except file output which is written
+/// inline by the download tasks through the FileWriter.
+fn build_interfaces(args: &CliArgs, config: &Config, tenant_id: &str)
+    -> Result<Vec<(&'static str, Box<dyn Interface + Send>)>> {
+
+    let mut interfaces: Vec<(&'static str, Box<dyn Interface + Send>)> = Vec::new();
+    if config.output.stdout.is_some() {
+        interfaces.push(("stdout", Box::new(StdoutInterface::new())));
+    }
+    if config.output.graylog.is_some() {
+        interfaces.push(("graylog", Box::new(GraylogInterface::new(config.clone())?)));
+    }
+    if config.output.fluentd.is_some() {
+        interfaces.push(("fluentd", Box::new(FluentdInterface::new(config.clone())?)));
+    }
+    if config.output.oms.is_some() {
+        warn!("The azureLogAnalytics output uses the deprecated HTTP Data Collector API, \
+               consider migrating to the logs_ingestion output.");
+        interfaces.push(("azureLogAnalytics", Box::new(OmsInterface::new(config.clone(), args.oms_key.clone())?)));
+    }
+    if config.output.logs_ingestion.is_some() {
+        interfaces.push(("logs_ingestion", Box::new(LogsIngestionInterface::new(config.clone())?)));
+    }
+    if config.output.event_hub.is_some() {
+        interfaces.push(("event_hub", Box::new(EventHubInterface::new(config.clone(), tenant_id.to_string())?)));
+    }
+    if config.output.s3.is_some() {
+        interfaces.push(("s3", Box::new(S3Interface::new(config.clone(), tenant_id.to_string())?)));
+    }
+    if config.output.azure_blob.is_some() {
+        interfaces.push(("azure_blob", Box::new(AzureBlobInterface::new(config.clone(), tenant_id.to_string())?)));
+    }
+    if config.output.firehose.is_some() {
+        interfaces.push(("firehose", Box::new(FirehoseInterface::new(config.clone())?)));
+    }
+    if config.output.qradar.is_some() {
+        interfaces.push(("qradar", Box::new(QRadarInterface::new(config.clone())?)));
+    }
+    if config.output.exec.is_some() {
+        interfaces.push(("exec", Box::new(ExecInterface::new(config.clone())?)));
+    }
+    if config.output.wazuh.is_some() {
+        #[cfg(unix)]
+        interfaces.push(("wazuh", Box::new(WazuhInterface::new(config.clone())?)));
+        #[cfg(not(unix))]
+        return Err(anyhow::anyhow!("The wazuh output requires a Unix socket and is not supported on this platform"));
+    }
+    Ok(interfaces)
+}
+
+
 /// Initialize channels for inter-task communication.
 ///
-/// MEMORY FIX: result channel now carries (usize, ContentToRetrieve) not (String, ContentToRetrieve).
-/// FileWriter and filters are passed through to GetContentConfig for inline processing.
+/// MEMORY FIX: result channel now carries ContentResult (a count) not (String, ContentToRetrieve).
+/// FileWriter, filters and transform are passed through to GetContentConfig for inline processing.
+#[allow(clippy::too_many_arguments)]
 fn initialize_channels(
     api: ApiConnection, content_types: ContentTypesSubConfig,
     runs: HashMap<String, Vec<(String, String)>>, config: &Config,
+    cursors: Arc<PageCursors>,
     file_writer: Arc<FileWriter>,
-    filters: HashMap<String, ArbitraryJson>)
+    filters: LogFilters,
+    transform: Arc<LogTransform>,
+    router: Arc<Router>,
+    forward_logs: bool,
+    notified: Vec<ContentToRetrieve>,
+    graph_sources: Vec<&'static GraphSource>,
+    queues: Arc<QueueDepths>)
     -> (data_structures::GetBlobConfig,
         data_structures::GetContentConfig,
         data_structures::MessageLoopConfig,
+        Option<data_structures::GetGraphConfig>,
         Receiver<(String, String)>,
         Receiver<ContentToRetrieve>,
-        Receiver<(usize, ContentToRetrieve)>,
+        Receiver<ContentResult>,
         Receiver<(usize, usize, usize, usize)>,
         tokio::sync::mpsc::Sender<bool>) {
 
-    let urls = api.create_base_urls(runs);
+    let poll = config.webhook.as_ref().and_then(|w| w.poll).unwrap_or(true);
+    let urls = if poll {
+        // Listings the previous run did not finish replace their part of the runs
+        let subscriptions = config.get_subscriptions();
+        let resumed: Vec<_> = cursors.resumed().iter()
+            .filter(|cursor| subscriptions.contains(&cursor.content_type))
+            .cloned()
+            .collect();
+        if !resumed.is_empty() {
+            info!("Resuming {} interrupted content listing(s)", resumed.len());
+        }
+        // Windows completed by an earlier, interrupted run are not listed again
+        let cuts: Vec<_> = resumed.iter()
+            .filter_map(|cursor| cursor.window())
+            .chain(cursors.completed().iter().cloned())
+            .collect();
+        let mut runs = page_cursors::trim_runs(runs, &cuts);
+        if let Some(chunks) = config.catchup_chunks_per_run.filter(|_| config.only_future_events.unwrap_or(false)) {
+            page_cursors::pace_runs(&mut runs, chunks, &cursors);
+        }
+        let mut urls = api.create_base_urls(runs);
+        urls.extend(resumed.into_iter().map(|cursor| (cursor.content_type, cursor.url)));
+        for (content_type, url) in &urls {
+            cursors.start(content_type, url);
+        }
+        urls
+    } else {
+        Vec::new()
+    };
 
     let (status_tx, status_rx):
         (Sender<data_structures::StatusMessage>,
          Receiver<data_structures::StatusMessage>) = channel(2000);
 
+    let max_threads = config.collect.as_ref()
+        .and_then(|c| c.max_threads)
+        .unwrap_or(10);
+    let capacities = config.collect.as_ref().and_then(|c| c.channels.clone()).unwrap_or_default();
+
     let (blobs_tx, blobs_rx):
         (Sender<(String, String)>,
-         Receiver<(String, String)>) = channel(2000);
+         Receiver<(String, String)>) = channel(capacities.blobs.unwrap_or(DEFAULT_BLOBS_CAPACITY));
 
     let (blob_error_tx, blob_error_rx):
         (Sender<(String, String)>,
          Receiver<(String, String)>) = channel(2000);
 
+    // Listings wait while this many found blobs wait to be downloaded, besides as many the
+    // downloads choose the oldest from
+    let content_capacity = capacities.content.unwrap_or(DEFAULT_CONTENT_CAPACITY);
     let (content_tx, content_rx):
         (Sender<ContentToRetrieve>,
-         Receiver<ContentToRetrieve>) = channel(2000);
+         Receiver<ContentToRetrieve>) = channel(content_capacity);
 
     let (content_error_tx, content_error_rx):
         (Sender<ContentToRetrieve>,
          Receiver<ContentToRetrieve>) = channel(2000);
 
     // MEMORY FIX: Channel now carries (count, metadata) not (full_response_body, metadata).
-    // Capacity 500 is generous — each item is ~200 bytes (usize + ContentToRetrieve).
+    // Capacity 500 is generous — each item is ~200 bytes (usize + ContentToRetrieve) unless
+    // logs are forwarded to interfaces. Then an item holds the logs of a whole blob, so downloads
+    // wait for the outputs once every download task has a blob waiting.
+    let results_capacity = capacities.results.unwrap_or(match forward_logs {
+        true => max_threads,
+        false => DEFAULT_RESULTS_CAPACITY,
+    });
     let (result_tx, result_rx):
-        (Sender<(usize, ContentToRetrieve)>,
-         Receiver<(usize, ContentToRetrieve)>) = channel(500);
+        (Sender<ContentResult>,
+         Receiver<ContentResult>) = channel(results_capacity);
 
     let (stats_tx, stats_rx):
         (Sender<(usize, usize, usize, usize)>,
@@ -327,10 +904,9 @@ fn initialize_channels(
 
     let (kill_tx, kill_rx): (tokio::sync::mpsc::Sender<bool>,
                              tokio::sync::mpsc::Receiver<bool>) = tokio::sync::mpsc::channel(10);
+    // Requests in flight are dropped once the message loop is killed
+    let cancel = CancellationToken::new();
 
-    let max_threads = config.collect.as_ref()
-        .and_then(|c| c.max_threads)
-        .unwrap_or(10);
     let duplicate = config.collect.as_ref()
         .and_then(|c| c.duplicate)
         .unwrap_or(1);
@@ -338,20 +914,67 @@ fn initialize_channels(
         .and_then(|c| c.retries)
         .unwrap_or(3);
 
-    let client = reqwest::Client::new();
+    let client = api.client.clone();
+    let max_rate = config.collect.as_ref()
+        .and_then(|c| c.max_requests_per_second)
+        .unwrap_or(DEFAULT_MAX_REQUESTS_PER_SECOND);
+    let mut throttle = Throttle::new(RateLimiter::for_tenant(&api.tenant.tenant_id, max_rate), max_threads);
+    if let Some(per_minute) = config.collect.as_ref().and_then(|c| c.max_requests_per_minute) {
+        throttle = throttle.with_global_limit(RateLimiter::global(per_minute / 60.0));
+    }
+    let known_logs = KnownLogs::for_config(config, &api.tenant.tenant_id);
 
     let blob_config = data_structures::GetBlobConfig {
         client: client.clone(),
-        headers: api.headers.clone(),
+        token: api.token.clone(),
+        throttle: throttle.clone(),
         status_tx: status_tx.clone(), blobs_tx: blobs_tx.clone(),
         blob_error_tx: blob_error_tx.clone(), content_tx: content_tx.clone(),
+        cursors: cursors.clone(),
+        resubscriber: api_connection::Resubscriber::new(api.clone()),
         threads: max_threads,
         duplicate,
+        queues: queues.clone(),
+        cancel: cancel.clone(),
+    };
+
+    let graph_source_count = graph_sources.len();
+    let graph_config = match &api.graph_token {
+        Some(token) if !graph_sources.is_empty() => {
+            let hours_to_collect = config.collect.as_ref()
+                .and_then(|c| c.hours_to_collect)
+                .unwrap_or(24);
+            let delay = config.graph.as_ref()
+                .and_then(|g| g.delay.clone())
+                .unwrap_or_else(|| graph::DEFAULT_DELAY.to_string());
+            Some(data_structures::GetGraphConfig {
+                client: client.clone(),
+                token: token.clone(),
+                throttle: throttle.clone(),
+                result_tx: result_tx.clone(),
+                status_tx: status_tx.clone(),
+                file_writer: file_writer.clone(),
+                filters: filters.clone(),
+                transform: transform.clone(),
+                router: router.clone(),
+                forward_logs,
+                sources: graph_sources,
+                tenant_id: api.tenant.tenant_id.clone(),
+                state_manager: Arc::new(StateManager::for_config(config)),
+                known_logs: known_logs.clone(),
+                default_start: chrono::Utc::now() - chrono::Duration::try_hours(hours_to_collect).unwrap(),
+                delay: chrono::Duration::try_seconds(Config::parse_interval(&delay) as i64).unwrap(),
+                queues: queues.clone(),
+                cancel: cancel.clone(),
+            })
+        },
+        _ => None,
     };
 
     let content_config = data_structures::GetContentConfig {
         client: client.clone(),
-        headers: api.headers.clone(),
+        token: api.token.clone(),
+        throttle: throttle.clone(),
         result_tx: result_tx.clone(),
         content_error_tx: content_error_tx.clone(),
         status_tx: status_tx.clone(),
@@ -359,6 +982,13 @@ fn initialize_channels(
         max_response_size: config.get_max_size_bytes(),
         file_writer,
         filters,
+        transform,
+        router,
+        forward_logs,
+        known_logs,
+        queues,
+        queued: content_capacity,
+        cancel: cancel.clone(),
     };
 
     let message_loop_config = data_structures::MessageLoopConfig {
@@ -366,14 +996,18 @@ fn initialize_channels(
         blobs_tx: blobs_tx.clone(),
         stats_tx: stats_tx.clone(),
         urls,
+        graph_sources: graph_source_count,
+        notified,
         content_error_rx,
         status_rx,
         blob_error_rx,
         content_types,
         retries,
         kill_rx,
+        cursors,
+        cancel,
     };
-    (blob_config, content_config, message_loop_config, blobs_rx, content_rx, result_rx,
+    (blob_config, content_config, message_loop_config, graph_config, blobs_rx, content_rx, result_rx,
             stats_rx, kill_tx)
 }
 
@@ -382,15 +1016,22 @@ fn initialize_channels(
 ///
 /// MEMORY FIX: Accepts FileWriter and filters to pass through to content download tasks.
 /// TASK LIFECYCLE FIX: Returns task handles so they can be aborted on cleanup.
+#[allow(clippy::too_many_arguments)]
 async fn get_available_content(api: ApiConnection,
                          content_types: ContentTypesSubConfig,
                          runs: HashMap<String, Vec<(String, String)>>,
                          config: &Config,
                          known_blobs: SharedKnownBlobsCache,
+                         cursors: Arc<PageCursors>,
                          state: Arc<Mutex<RunState>>,
                          file_writer: Arc<FileWriter>,
-                         filters: HashMap<String, ArbitraryJson>)
-                         -> (Receiver<(usize, ContentToRetrieve)>,
+                         filters: LogFilters,
+                         transform: Arc<LogTransform>,
+                         router: Arc<Router>,
+                         forward_logs: bool,
+                         notified: Vec<ContentToRetrieve>,
+                         graph_sources: Vec<&'static GraphSource>)
+                         -> (Receiver<ContentResult>,
                              Receiver<(usize, usize, usize, usize)>,
                              tokio::sync::mpsc::Sender<bool>,
                              Vec<tokio::task::JoinHandle<()>>) {
@@ -398,15 +1039,19 @@ async fn get_available_content(api: ApiConnection,
     let (blob_config,
         content_config,
         message_loop_config,
+        graph_config,
         blobs_rx,
         content_rx,
         result_rx,
         stats_rx,
-        kill_tx) = initialize_channels(api, content_types, runs, config, file_writer, filters);
+        kill_tx) = initialize_channels(api, content_types, runs, config, cursors, file_writer, filters,
+                                       transform, router, forward_logs, notified, graph_sources,
+                                       state.lock().await.queues.clone());
 
     let task_handles = spawn_blob_collector(blob_config,
                          content_config,
                          message_loop_config,
+                         graph_config,
                          blobs_rx,
                          content_rx,
                          known_blobs,
@@ -418,10 +1063,12 @@ async fn get_available_content(api: ApiConnection,
 
 /// Spawn async tasks for collectors on the existing Tokio runtime.
 /// Returns JoinHandles so tasks can be aborted on cleanup (prevents 42MB/cycle leak).
+#[allow(clippy::too_many_arguments)]
 fn spawn_blob_collector(
     blob_config: data_structures::GetBlobConfig,
     content_config: data_structures::GetContentConfig,
     message_loop_config: data_structures::MessageLoopConfig,
+    graph_config: Option<data_structures::GetGraphConfig>,
     blobs_rx: Receiver<(String, String)>,
     content_rx: Receiver<ContentToRetrieve>,
     known_blobs: SharedKnownBlobsCache,
@@ -429,19 +1076,25 @@ fn spawn_blob_collector(
 
     info!("Spawning collector tasks on shared runtime");
 
-    let h1 = tokio::spawn(async move {
+    let h1 = tokio::spawn(logging::inherit(async move {
         api_connection::get_content_blobs_async(blob_config, blobs_rx, known_blobs).await;
-    });
+    }.in_current_span()));
 
-    let h2 = tokio::spawn(async move {
+    let h2 = tokio::spawn(logging::inherit(async move {
         api_connection::get_content_async(content_config, content_rx).await;
-    });
+    }.in_current_span()));
 
-    let h3 = tokio::spawn(async move {
+    let h3 = tokio::spawn(logging::inherit(async move {
         message_loop(message_loop_config, state).await;
-    });
+    }.in_current_span()));
 
-    vec![h1, h2, h3]
+    let mut handles = vec![h1, h2, h3];
+    if let Some(graph_config) = graph_config {
+        handles.push(tokio::spawn(logging::inherit(async move {
+            graph::get_graph_logs_async(graph_config).await;
+        }.in_current_span())));
+    }
+    handles
 }
 
 
@@ -453,129 +1106,136 @@ pub async fn message_loop(mut config: data_structures::MessageLoopConfig,
         config.blobs_tx.clone().send((content_type, base_url)).await.unwrap();
         state.lock().await.awaiting_content_types += 1;
     }
-
-    let mut rate_limit_backoff_started: Option<Instant> = None;
+    // Graph sources report FinishedContentBlobs when done, see graph.rs
+    state.lock().await.awaiting_content_types += config.graph_sources;
+    let queues = state.lock().await.queues.clone();
+    for content in config.notified.drain(..) {
+        config.content_tx.send(content).await.unwrap();
+        queues.content.sent();
+        state.lock().await.awaiting_content_blobs += 1;
+        state.lock().await.stats.blobs_found += 1;
+    }
+    // Nothing to retrieve, e.g. when only retrieving webhook notifications and none came in
+    let nothing_to_retrieve = check_done(&mut state).await;
 
     const MAX_RETRY_ENTRIES: usize = 50_000;
     let mut retry_map: lru::LruCache<String, usize> =
         lru::LruCache::new(std::num::NonZeroUsize::new(MAX_RETRY_ENTRIES).unwrap());
 
     loop {
-
-        if let Some(t) = rate_limit_backoff_started {
-            if t.elapsed().as_secs() >= 30 {
-                rate_limit_backoff_started = None;
-                state.lock().await.rate_limited = false;
-                info!("Release rate limit");
-            }
-        }
-
-        if let Ok(msg) = config.kill_rx.try_recv() {
-            if msg {
-                info!("Stopping collector.");
-                break
-            }
+        if nothing_to_retrieve {
+            info!("No content to retrieve.");
+            config.content_tx.close_channel();
+            break
         }
 
-        if let Ok(Some(msg)) = config.status_rx.try_next() {
-            match msg {
-                data_structures::StatusMessage::FoundNewContentBlob => {
-                    state.lock().await.awaiting_content_blobs +=1;
-                    state.lock().await.stats.blobs_found += 1;
-                },
-                data_structures::StatusMessage::FinishedContentBlobs => {
-                    let new_content_types = state.lock().await.awaiting_content_types.saturating_sub(1);
-                    state.lock().await.awaiting_content_types = new_content_types;
-                    if check_done(&mut state).await {
-                        break
-                    }
-                },
-                data_structures::StatusMessage::RetrievedContentBlob => {
-                    state.lock().await.awaiting_content_blobs -= 1;
-                    state.lock().await.stats.blobs_successful += 1;
-                    if check_done(&mut state).await {
-                        config.content_tx.close_channel();
-                        break;
-                    }
-                },
-                data_structures::StatusMessage::ErrorContentBlob => {
-                    state.lock().await.awaiting_content_blobs -= 1;
-                    state.lock().await.stats.blobs_error += 1;
-                    if check_done(&mut state).await {
-                        config.content_tx.close_channel();
-                        break;
-                    }
+        tokio::select! {
+            Some(kill) = config.kill_rx.recv() => {
+                if kill {
+                    info!("Stopping collector.");
+                    config.cancel.cancel();
+                    break
                 }
-                data_structures::StatusMessage::BeingThrottled => {
-                    if rate_limit_backoff_started.is_none() {
-                        warn!("Being rate limited, backing off 30 seconds.");
+            },
+
+            Some(msg) = config.status_rx.next() => {
+                match msg {
+                    data_structures::StatusMessage::FoundNewContentBlob => {
+                        state.lock().await.awaiting_content_blobs +=1;
+                        state.lock().await.stats.blobs_found += 1;
+                    },
+                    data_structures::StatusMessage::FinishedContentBlobs => {
+                        let new_content_types = state.lock().await.awaiting_content_types.saturating_sub(1);
+                        state.lock().await.awaiting_content_types = new_content_types;
+                        if check_done(&mut state).await {
+                            break
+                        }
+                    },
+                    data_structures::StatusMessage::RetrievedContentBlob => {
+                        state.lock().await.rate_limited = false;
+                        state.lock().await.awaiting_content_blobs -= 1;
+                        state.lock().await.stats.blobs_successful += 1;
+                        if check_done(&mut state).await {
+                            config.content_tx.close_channel();
+                            break;
+                        }
+                    },
+                    data_structures::StatusMessage::ErrorContentBlob => {
+                        state.lock().await.awaiting_content_blobs -= 1;
+                        state.lock().await.stats.blobs_error += 1;
+                        if check_done(&mut state).await {
+                            config.content_tx.close_channel();
+                            break;
+                        }
+                    }
+                    // Throttled requests wait and retry in the download tasks, see throttle.rs
+                    data_structures::StatusMessage::BeingThrottled => {
                         state.lock().await.rate_limited = true;
-                        rate_limit_backoff_started = Some(Instant::now());
                     }
                 }
-            }
-        }
-
-        if let Ok(Some((content_type, url))) = config.blob_error_rx.try_next() {
-            if let Some(retries_left) = retry_map.get_mut(&url) {
-                if *retries_left == 0 {
-                    error!("Gave up on blob {}", url);
-                    retry_map.pop(&url);
-                    state.lock().await.awaiting_content_types -= 1;
-                    state.lock().await.stats.blobs_error += 1;
-                    if check_done(&mut state).await {
-                        break;
-                    }
-                } else {
-                    if rate_limit_backoff_started.is_none() {
+            },
+
+            Some((content_type, url)) = config.blob_error_rx.next() => {
+                if let Some(retries_left) = retry_map.get_mut(&url) {
+                    if *retries_left == 0 {
+                        error!("Gave up on blob {}", url);
+                        retry_map.pop(&url);
+                        config.cursors.abandon(&url);
+                        state.lock().await.awaiting_content_types -= 1;
+                        state.lock().await.stats.blobs_error += 1;
+                        if check_done(&mut state).await {
+                            break;
+                        }
+                    } else {
                         *retries_left -= 1;
+                        let retries = *retries_left;
+                        state.lock().await.stats.blobs_retried += 1;
+                        warn!("Retry blob {} {}", retries, url);
+                        config.blobs_tx.send((content_type, url)).await.unwrap();
                     }
-                    let retries = *retries_left;
+                } else {
+                    retry_map.put(url.clone(), config.retries - 1);
                     state.lock().await.stats.blobs_retried += 1;
-                    warn!("Retry blob {} {}", retries, url);
+                    warn!("Retry blob {} {}", config.retries - 1, url);
                     config.blobs_tx.send((content_type, url)).await.unwrap();
                 }
-            } else {
-                retry_map.put(url.clone(), config.retries - 1);
-                state.lock().await.stats.blobs_retried += 1;
-                warn!("Retry blob {} {}", config.retries - 1, url);
-                config.blobs_tx.send((content_type, url)).await.unwrap();
-            }
-        };
+            },
 
-        if let Ok(Some(content)) = config.content_error_rx.try_next() {
-            state.lock().await.stats.blobs_retried += 1;
-            if let Some(retries_left) = retry_map.get_mut(&content.url) {
-                if *retries_left == 0 {
-                    error!("Gave up on content {}", content.url);
-                    retry_map.pop(&content.url);
-                    state.lock().await.awaiting_content_blobs -= 1;
-                    state.lock().await.stats.blobs_error += 1;
-                    if check_done(&mut state).await {
-                        config.content_tx.close_channel();
-                        break;
-                    }
-                } else {
-                    if rate_limit_backoff_started.is_none() {
+            Some(content) = config.content_error_rx.next() => {
+                state.lock().await.stats.blobs_retried += 1;
+                if let Some(retries_left) = retry_map.get_mut(&content.url) {
+                    if *retries_left == 0 {
+                        error!("Gave up on content {}", content.url);
+                        retry_map.pop(&content.url);
+                        config.cursors.retrieved(&content.content_id);
+                        state.lock().await.awaiting_content_blobs -= 1;
+                        state.lock().await.stats.blobs_error += 1;
+                        if check_done(&mut state).await {
+                            config.content_tx.close_channel();
+                            break;
+                        }
+                    } else {
                         *retries_left -= 1;
+                        let retries = *retries_left;
+                        warn!("Retry content {} {}", retries, content.url);
+                        config.content_tx.send(content).await.unwrap();
+                        queues.content.sent();
                     }
-                    let retries = *retries_left;
-                    warn!("Retry content {} {}", retries, content.url);
+                } else {
+                    retry_map.put(content.url.to_string(), config.retries - 1);
+                    state.lock().await.stats.blobs_retried += 1;
+                    warn!("Retry content {} {}", config.retries - 1, content.url);
                     config.content_tx.send(content).await.unwrap();
+                    queues.content.sent();
                 }
-            } else {
-                retry_map.put(content.url.to_string(), config.retries - 1);
-                state.lock().await.stats.blobs_retried += 1;
-                warn!("Retry content {} {}", config.retries - 1, content.url);
-                config.content_tx.send(content).await.unwrap();
-            }
-        }
+            },
 
-        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            else => break,
+        }
     }
 
+    // Results are sent before the status that reports them, so the collector has them all
     let stats = state.lock().await.stats.clone();
-    sleep(Duration::from_secs(3)).await;
     config.stats_tx.send((
         stats.blobs_found,
         stats.blobs_successful,
@@ -588,3 +1248,185 @@ async fn check_done(state: &mut Arc<Mutex<RunState>>) -> bool {
     let blobs = state.lock().await.awaiting_content_blobs;
     types == 0 && blobs == 0
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use serde_json::json;
+    use crate::data_structures::Caches;
+
+    struct Interface(bool);
+
+    #[async_trait]
+    impl crate::interfaces::interface::Interface for Interface {
+        async fn send_logs(&mut self, _logs: Caches) -> Result<()> {
+            if self.0 { Ok(()) } else { Err(anyhow!("unavailable")) }
+        }
+    }
+
+    fn blob() -> ContentToRetrieve {
+        ContentToRetrieve {
+            content_type: "Audit.General".to_string(),
+            content_id: "a".to_string(),
+            expiration: "2024-02-07T00:00:00.000Z".to_string(),
+            url: String::new(),
+        }
+    }
+
+    /// Retrieve the blob and send its logs through an output, returning the logs that were not
+    /// skipped as known and whether the blob was delivered.
+    async fn retrieve(unconfirmed: &mut UnconfirmedBlobs, known_logs: &KnownLogs, delivers: bool) -> (usize, bool) {
+        let config: Config = serde_yaml::from_str("{output: {}, retry: {default: {max_retries: 0}}}").unwrap();
+        let processing = api_connection::LogProcessing {
+            file_writer: &FileWriter::new_noop(),
+            filters: &LogFilters::default(),
+            transform: &LogTransform::default(),
+            router: &Router::default(),
+            forward_logs: true,
+            known_logs: Some(known_logs),
+        };
+        let logs = vec![json!({"Id": "1"}), json!({"Id": "2"})];
+        let processed = api_connection::process_logs(logs, "Audit.General", &processing);
+        let content = blob();
+        unconfirmed.retrieved(&content, false, processed.known_log_ids);
+
+        let mut output = Output::new("fluentd", Box::new(Interface(delivers)), &config, "tenant", 10, usize::MAX)
+            .unwrap();
+        if output.hold(&content.content_id) {
+            unconfirmed.held(&content.content_id);
+        }
+        for log in processed.logs {
+            output.add(Arc::new(log), &content.content_type).await;
+        }
+        output.flush().await;
+        let mut touched = Vec::new();
+        for (content_id, delivered) in output.take_settled() {
+            unconfirmed.released(&content_id, delivered);
+            touched.push(content_id);
+        }
+        (processed.count, !unconfirmed.take_settled(touched).is_empty())
+    }
+
+    #[tokio::test]
+    async fn test_undelivered_logs_are_forgotten() {
+        let known_logs = Arc::new(KnownLogs::new(10));
+        let mut unconfirmed = UnconfirmedBlobs::new(Some(known_logs.clone()));
+        // The output fails, so the blob is retrieved again and its logs are not skipped as known
+        assert_eq!(retrieve(&mut unconfirmed, &known_logs, false).await, (2, false));
+        assert_eq!(retrieve(&mut unconfirmed, &known_logs, true).await, (2, true));
+        // Delivered logs are known, a blob without other logs settles right away
+        assert_eq!(retrieve(&mut unconfirmed, &known_logs, true).await, (0, true));
+
+        // Blobs that did not settle by the end of the run are forgotten as well
+        known_logs.forget(&["1".to_string(), "2".to_string()]);
+        let processing = api_connection::LogProcessing {
+            file_writer: &FileWriter::new_noop(),
+            filters: &LogFilters::default(),
+            transform: &LogTransform::default(),
+            router: &Router::default(),
+            forward_logs: false,
+            known_logs: Some(&known_logs),
+        };
+        let processed = api_connection::process_logs(vec![json!({"Id": "1"})], "Audit.General", &processing);
+        unconfirmed.retrieved(&blob(), false, processed.known_log_ids);
+        unconfirmed.held("a");
+        unconfirmed.abandon();
+        assert!(known_logs.first_seen(json!({"Id": "1"}).as_object().unwrap()));
+    }
+
+    const WINDOWS: [&str; 2] = [
+        "https://manage.office.com/api/v1.0/t/activity/feed/subscriptions/content?contentType=Audit.Exchange\
+         &startTime=2024-01-01T06:00:00&endTime=2024-01-01T12:00:00",
+        "https://manage.office.com/api/v1.0/t/activity/feed/subscriptions/content?contentType=Audit.Exchange\
+         &startTime=2024-01-01T12:00:00&endTime=2024-01-01T18:00:00",
+    ];
+
+    /// Retrieve blobs of two windows with `logs` each, as monitor does until the run reaches a
+    /// limit. Returns the blobs retrieved, the limit reached and the last_log_time to save.
+    fn limited_run(config: &str, logs: usize) -> (usize, Option<&'static str>, chrono::DateTime<chrono::Utc>) {
+        let dir = tempfile::tempdir().unwrap();
+        let config: Config = serde_yaml::from_str(&format!("{{output: {{}}, workingDir: {}, {}}}",
+                                                           dir.path().display(), config)).unwrap();
+        let time = |s: &str| crate::state::parse_api_time(s).unwrap();
+        let cursors = PageCursors::for_config(&config, "t");
+        let blobs = [("a", WINDOWS[0], "2024-01-01T07:00:00"), ("b", WINDOWS[0], "2024-01-01T11:00:00"),
+                     ("c", WINDOWS[1], "2024-01-01T13:00:00"), ("d", WINDOWS[1], "2024-01-01T17:00:00")];
+        for window in WINDOWS {
+            cursors.start("Audit.Exchange", window);
+        }
+        for (content_id, window, _) in blobs {
+            cursors.found(window, content_id);
+        }
+        for window in WINDOWS {
+            cursors.listed(window);
+        }
+
+        let (mut retrieved, mut saved, mut latest) = (0, 0, None);
+        let mut limit = None;
+        for (content_id, _, creation_time) in blobs {
+            cursors.retrieved(content_id);
+            retrieved += 1;
+            saved += logs;
+            latest = latest.max(Some(time(creation_time)));
+            limit = run_limit_reached(&config, retrieved, saved);
+            if limit.is_some() {
+                break
+            }
+        }
+        let last_log_time = next_last_log_time(latest, None, cursors.unfinished_since("Audit.Exchange"),
+                                               chrono::Duration::zero(), time("2024-01-01T19:00:00"));
+        (retrieved, limit, last_log_time)
+    }
+
+    #[test]
+    fn test_run_limit() {
+        let time = |s: &str| crate::state::parse_api_time(s).unwrap();
+        let config: Config = serde_yaml::from_str("{output: {}}").unwrap();
+        assert_eq!(run_limit_reached(&config, 1_000_000, 1_000_000), None);
+
+        // The run stops at the third blob, in the second window: the state stays at the end of the
+        // first window, not at the latest log collected
+        assert_eq!(limited_run("max_blobs_per_run: 3", 1),
+                   (3, Some("max_blobs_per_run"), time("2024-01-01T12:00:00")));
+        assert_eq!(limited_run("max_logs_per_run: 250", 100),
+                   (3, Some("max_logs_per_run"), time("2024-01-01T12:00:00")));
+        // Both windows finished
+        assert_eq!(limited_run("max_blobs_per_run: 4", 1),
+                   (4, Some("max_blobs_per_run"), time("2024-01-01T17:00:00")));
+        assert_eq!(limited_run("max_blobs_per_run: 5, max_logs_per_run: 1000", 1),
+                   (4, None, time("2024-01-01T17:00:00")));
+        // Whichever limit is reached first
+        assert_eq!(limited_run("max_blobs_per_run: 2, max_logs_per_run: 1000", 100).1, Some("max_blobs_per_run"));
+        assert_eq!(limited_run("max_blobs_per_run: 3, max_logs_per_run: 150", 100).1, Some("max_logs_per_run"));
+    }
+
+    #[test]
+    fn test_file_retry() {
+        let known_logs = Arc::new(KnownLogs::new(10));
+        let mut unconfirmed = UnconfirmedBlobs::new(Some(known_logs.clone()));
+        // The file output could not write logs of "a" and "b", the blobs wait for the retry
+        let (mut a, mut b) = (blob(), blob());
+        b.content_id = "b".to_string();
+        unconfirmed.retrieved(&a, true, vec!["1".to_string()]);
+        unconfirmed.retrieved(&b, false, vec!["2".to_string()]);
+        unconfirmed.held("b");
+        assert!(unconfirmed.take_settled(vec!["a".to_string()]).is_empty());
+
+        // The retry wrote them
+        assert_eq!(unconfirmed.file_retried(true), vec!["a".to_string()]);
+        assert_eq!(unconfirmed.file_retried(true), Vec::<String>::new());
+        assert_eq!(unconfirmed.take_settled(vec!["a".to_string(), "b".to_string()]),
+                   vec![("a".to_string(), a.expiration.clone())]);
+
+        // The retry did not write them
+        a.content_id = "c".to_string();
+        unconfirmed.retrieved(&a, true, vec!["1".to_string()]);
+        known_logs.first_seen(json!({"Id": "1"}).as_object().unwrap());
+        assert_eq!(unconfirmed.file_retried(false), vec!["c".to_string()]);
+        assert!(unconfirmed.take_settled(vec!["c".to_string()]).is_empty());
+        assert!(unconfirmed.blobs.contains_key("b") && !unconfirmed.blobs.contains_key("c"));
+        assert!(known_logs.first_seen(json!({"Id": "1"}).as_object().unwrap()));
+    }
+}
@@ -0,0 +1,501 @@
+// Management subcommands. They run instead of a collection, print their results to stdout and
+// exit with a non-zero code when anything failed.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use std::io::Write;
+use std::path::Path;
+use crate::api_connection::get_api_connection;
+use crate::aws_sigv4::AwsCredentials;
+use crate::client_assertion::ClientCertificate;
+use crate::config::{Config, TenantConfig};
+use crate::data_structures::{ArbitraryJson, CliArgs, Command, StateAction, SubscriptionAction};
+use crate::graph;
+use crate::sample_config;
+use crate::state::{parse_api_time, StateManager, TenantSubscriptionState};
+
+pub async fn run(command: Command, args: CliArgs, config: Config) -> Result<()> {
+    match command {
+        Command::Subscriptions { action, tenant, content_type } =>
+            subscriptions(action, tenant.as_deref(), content_type, args, config).await,
+        Command::State { action } => state(action, &config),
+        Command::GenerateConfig { minimal, output } => generate_config(minimal, output.as_deref()),
+    }
+}
+
+/// List, start or stop the subscriptions of the selected tenants. Start and stop default to the
+/// configured subscriptions.
+async fn subscriptions(action: SubscriptionAction, tenant: Option<&str>, content_types: Vec<String>,
+                       args: CliArgs, config: Config) -> Result<()> {
+
+    let tenants = select_tenants(&config, tenant)?;
+    let content_types = if content_types.is_empty() { config.get_subscriptions() } else { content_types };
+    let mut failed = 0;
+
+    for tenant in tenants {
+        let tenant_id = tenant.tenant_id.clone();
+        let api = match get_api_connection(args.clone(), config.clone(), tenant).await {
+            Ok(api) => api,
+            Err(e) => {
+                eprintln!("{}: could not log in: {}", tenant_id, e);
+                failed += 1;
+                continue
+            }
+        };
+        match action {
+            SubscriptionAction::List => match api.list_subscriptions().await {
+                Ok(subscriptions) => for subscription in subscriptions {
+                    println!("{}", format_subscription(&tenant_id, &subscription));
+                },
+                Err(e) => {
+                    eprintln!("{}: {}", tenant_id, e);
+                    failed += 1;
+                }
+            },
+            SubscriptionAction::Start | SubscriptionAction::Stop => {
+                let start = action == SubscriptionAction::Start;
+                for content_type in &content_types {
+                    match api.set_subscription(content_type.clone(), start).await {
+                        Ok(()) => println!("{}\t{}\t{}", tenant_id, content_type,
+                                           if start { "started" } else { "stopped" }),
+                        Err(e) => {
+                            eprintln!("{}: {}", tenant_id, e);
+                            failed += 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!("{} subscription request(s) failed", failed))
+    }
+    Ok(())
+}
+
+/// Show, reset or set the state of the selected tenants and subscriptions. The changes are used
+/// by the next run; a run in progress overwrites them when it ends.
+fn state(action: StateAction, config: &Config) -> Result<()> {
+
+    config.get_state_backend().map_err(|e| anyhow!(e))?;
+    let state_manager = StateManager::for_config(config);
+    let (tenant, subscription) = match &action {
+        StateAction::Show { tenant } => (tenant.as_deref(), None),
+        StateAction::Reset { tenant, subscription } => (Some(tenant.as_str()), subscription.as_deref()),
+        StateAction::Set { tenant, subscription, .. } => (tenant.as_deref(), subscription.as_deref()),
+    };
+    let tenants = select_tenants(config, tenant)?;
+    let subscriptions = select_subscriptions(config, subscription)?;
+    let last_log_time = match &action {
+        StateAction::Set { last_log_time, .. } => Some(parse_time(last_log_time)?),
+        _ => None,
+    };
+
+    let mut failed = 0;
+    for tenant in &tenants {
+        for subscription in &subscriptions {
+            let result = match &action {
+                StateAction::Show { .. } => {
+                    println!("{}", format_state(&tenant.tenant_id, subscription,
+                                                state_manager.load_state(&tenant.tenant_id, subscription).as_ref()));
+                    Ok(())
+                },
+                StateAction::Reset { .. } => state_manager.delete_state(&tenant.tenant_id, subscription)
+                    .map(|deleted| if deleted {
+                        println!("{}\t{}\treset", tenant.tenant_id, subscription);
+                    }),
+                StateAction::Set { .. } => {
+                    let last_log_time = last_log_time.unwrap();
+                    let state = match state_manager.load_state(&tenant.tenant_id, subscription) {
+                        Some(state) => TenantSubscriptionState { last_log_time, ..state },
+                        None => TenantSubscriptionState { last_log_time, last_run: Utc::now(), first_run: false },
+                    };
+                    state_manager.save_state(&tenant.tenant_id, subscription, &state)
+                        .map(|_| println!("{}", format_state(&tenant.tenant_id, subscription, Some(&state))))
+                },
+            };
+            if let Err(e) = result {
+                eprintln!("{}: {}: {}", tenant.tenant_id, subscription, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!("{} state change(s) failed", failed))
+    }
+    Ok(())
+}
+
+/// The configured subscriptions and Graph sources, or only the given one.
+fn select_subscriptions(config: &Config, subscription: Option<&str>) -> Result<Vec<String>> {
+    let mut subscriptions = config.get_subscriptions();
+    subscriptions.extend(graph::content_types(config));
+    let Some(subscription) = subscription else {
+        return Ok(subscriptions)
+    };
+    subscriptions.into_iter()
+        .find(|s| s.eq_ignore_ascii_case(subscription))
+        .map(|s| vec![s])
+        .ok_or_else(|| anyhow!("Subscription {} is not configured", subscription))
+}
+
+/// A UTC time in RFC 3339, or without time zone as in the API.
+fn parse_time(time: &str) -> Result<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(time).map(|time| time.with_timezone(&Utc)).ok()
+        .or_else(|| parse_api_time(time))
+        .ok_or_else(|| anyhow!("Invalid time '{}', expected e.g. 2024-01-31T12:00:00Z", time))
+}
+
+/// Write the sample config to stdout or to a new file, see sample_config.rs.
+pub fn generate_config(minimal: bool, output: Option<&str>) -> Result<()> {
+    let sample = sample_config::render(minimal);
+    let Some(path) = output else {
+        print!("{}", sample);
+        return Ok(())
+    };
+    let mut file = std::fs::OpenOptions::new().write(true).create_new(true).open(path)
+        .map_err(|e| anyhow!("Could not create {}: {}", path, e))?;
+    file.write_all(sample.as_bytes())?;
+    eprintln!("Sample config written to {}", path);
+    Ok(())
+}
+
+/// Pre-flight check of a config file for `--check-config`: validate it, resolve the credentials
+/// of tenants and outputs and make sure the working directory is writable. Prints a line per
+/// check; secrets are resolved but never printed.
+pub fn check_config(args: &CliArgs) -> Result<()> {
+    let config = match Config::load_for(args) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("FAILED\t{}", e);
+            return Err(anyhow!("Config check failed"))
+        }
+    };
+    println!("ok\tconfig {}", args.config.as_deref().unwrap_or_default());
+
+    let mut failed = 0;
+    for (check, result) in config_checks(&config) {
+        match result {
+            Ok(()) => println!("ok\t{}", check),
+            Err(e) => {
+                println!("FAILED\t{}: {}", check, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!("{} config check(s) failed", failed))
+    }
+    Ok(())
+}
+
+fn config_checks(config: &Config) -> Vec<(String, Result<(), String>)> {
+    let mut checks = Vec::new();
+    for tenant in &config.tenants {
+        let result = match &tenant.certificate_path {
+            Some(certificate_path) => ClientCertificate::load(certificate_path, tenant.private_key_path.as_deref(),
+                                                              tenant.certificate_thumbprint.as_deref())
+                .map(|_| ()).map_err(|e| e.to_string()),
+            None => tenant.get_secret().map(|_| ()),
+        };
+        checks.push((format!("tenant {} credentials", tenant.tenant_id), result));
+    }
+
+    let output = &config.output;
+    if let Some(event_hub) = &output.event_hub {
+        let result = match (event_hub.get_connection_string(), &event_hub.aad) {
+            (Err(e), _) => Err(e),
+            (Ok(None), None) => Err("Either connection_string or aad must be set".to_string()),
+            (Ok(_), Some(aad)) => aad.get_secret().map(|_| ()),
+            (Ok(Some(_)), None) => Ok(()),
+        };
+        checks.push(("output event_hub credentials".to_string(), result));
+    }
+    if let Some(aad) = output.azure_blob.as_ref().and_then(|blob| blob.aad.as_ref()) {
+        checks.push(("output azure_blob credentials".to_string(), aad.get_secret().map(|_| ())));
+    }
+    if let Some(logs_ingestion) = &output.logs_ingestion {
+        checks.push(("output logs_ingestion credentials".to_string(), logs_ingestion.aad.get_secret().map(|_| ())));
+    }
+    if let Some(s3) = &output.s3 {
+        let result = AwsCredentials::resolve(s3.access_key_id.as_ref(), s3.secret_access_key.as_ref(),
+                                             s3.session_token.as_ref());
+        checks.push(("output s3 credentials".to_string(), result.map(|_| ()).map_err(|e| e.to_string())));
+    }
+    if let Some(firehose) = &output.firehose {
+        let result = AwsCredentials::resolve(firehose.access_key_id.as_ref(), firehose.secret_access_key.as_ref(),
+                                             firehose.session_token.as_ref());
+        checks.push(("output firehose credentials".to_string(), result.map(|_| ()).map_err(|e| e.to_string())));
+    }
+
+    let working_dir = config.get_working_dir();
+    let mut check = format!("working directory {}", working_dir);
+    let result = check_writable(Path::new(&working_dir)).map(|existing| {
+        if existing != Path::new(&working_dir) {
+            check += &format!(" (does not exist, created in {} by the first run)", existing.display());
+        }
+    });
+    checks.push((check, result));
+    checks
+}
+
+/// Write and remove a file in the directory, or in its nearest existing parent if it does not
+/// exist yet, returning the directory checked. Nothing is created.
+fn check_writable(dir: &Path) -> Result<&Path, String> {
+    let existing = dir.ancestors()
+        .map(|dir| if dir.as_os_str().is_empty() { Path::new(".") } else { dir })
+        .find(|dir| dir.exists())
+        .unwrap_or(dir);
+    if !existing.is_dir() {
+        return Err(format!("{} is not a directory", existing.display()))
+    }
+    let probe = existing.join(format!(".check_config_{}", std::process::id()));
+    std::fs::write(&probe, b"").map_err(|e| format!("{} not writable: {}", existing.display(), e))?;
+    std::fs::remove_file(&probe).map_err(|e| format!("could not remove {}: {}", probe.display(), e))?;
+    Ok(existing)
+}
+
+/// Tenant, subscription, last_log_time and last_run, tab separated.
+fn format_state(tenant_id: &str, subscription: &str, state: Option<&TenantSubscriptionState>) -> String {
+    match state {
+        Some(state) => format!("{}\t{}\t{}\t{}", tenant_id, subscription,
+                               state.last_log_time.to_rfc3339(), state.last_run.to_rfc3339()),
+        None => format!("{}\t{}\t-\t-", tenant_id, subscription),
+    }
+}
+
+/// The configured tenants, or only the given one.
+fn select_tenants(config: &Config, tenant_id: Option<&str>) -> Result<Vec<TenantConfig>> {
+    let Some(tenant_id) = tenant_id else {
+        return Ok(config.tenants.clone())
+    };
+    config.tenants.iter()
+        .find(|tenant| tenant.tenant_id.eq_ignore_ascii_case(tenant_id))
+        .map(|tenant| vec![tenant.clone()])
+        .ok_or_else(|| anyhow!("Tenant {} is not configured", tenant_id))
+}
+
+/// Tenant, content type, status and webhook of a subscription, tab separated.
+fn format_subscription(tenant_id: &str, subscription: &ArbitraryJson) -> String {
+    let field = |name: &str| subscription.get(name).and_then(|v| v.as_str()).unwrap_or("-").to_string();
+    let webhook = subscription.get("webhook")
+        .filter(|webhook| !webhook.is_null())
+        .map(|webhook| format!("webhook {} ({})",
+                               webhook.get("address").and_then(|v| v.as_str()).unwrap_or("-"),
+                               webhook.get("status").and_then(|v| v.as_str()).unwrap_or("-")))
+        .unwrap_or_else(|| "-".to_string());
+    format!("{}\t{}\t{}\t{}", tenant_id, field("contentType"), field("status"), webhook)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_subscriptions_command() {
+        let args = CliArgs::try_parse_from(["collector", "--config", "config.yaml", "subscriptions", "start",
+                                            "--tenant", "t1", "--content-type", "Audit.Exchange"]).unwrap();
+        match args.command {
+            Some(Command::Subscriptions { action, tenant, content_type }) => {
+                assert_eq!(action, SubscriptionAction::Start);
+                assert_eq!(tenant.as_deref(), Some("t1"));
+                assert_eq!(content_type, vec!["Audit.Exchange"]);
+            },
+            _ => panic!("Expected the subscriptions command"),
+        }
+        assert!(CliArgs::try_parse_from(["collector", "--config", "config.yaml", "subscriptions", "pause"]).is_err());
+        assert!(CliArgs::try_parse_from(["collector", "--config", "config.yaml"]).unwrap().command.is_none());
+    }
+
+    #[test]
+    fn test_parse_state_command() {
+        let args = CliArgs::try_parse_from(["collector", "--config", "config.yaml", "state", "set",
+                                            "--last-log-time", "2024-01-31T12:00:00Z", "--tenant", "t1"]).unwrap();
+        assert_eq!(args.command.unwrap(), Command::State { action: StateAction::Set {
+            last_log_time: "2024-01-31T12:00:00Z".to_string(), tenant: Some("t1".to_string()), subscription: None,
+        }});
+        // Resetting needs a tenant
+        assert!(CliArgs::try_parse_from(["collector", "--config", "config.yaml", "state", "reset"]).is_err());
+    }
+
+    #[test]
+    fn test_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let config: Config = serde_yaml::from_str(&format!(r#"
+workingDir: {}
+tenants:
+  - {{tenant_id: t1, client_id: a}}
+subscriptions: [Audit.Exchange, Audit.General]
+output: {{}}
+"#, dir.path().display())).unwrap();
+        let state_manager = StateManager::for_config(&config);
+
+        state(StateAction::Set { last_log_time: "2024-01-31T12:00:00".to_string(), tenant: None,
+                                 subscription: None }, &config).unwrap();
+        assert_eq!(state_manager.load_state("t1", "Audit.General").unwrap().last_log_time,
+                   parse_time("2024-01-31T12:00:00Z").unwrap());
+        state(StateAction::Reset { tenant: "T1".to_string(), subscription: Some("audit.exchange".to_string()) },
+              &config).unwrap();
+        assert!(state_manager.load_state("t1", "Audit.Exchange").is_none());
+        assert!(state_manager.load_state("t1", "Audit.General").is_some());
+        assert_eq!(format_state("t1", "Audit.Exchange", None), "t1\tAudit.Exchange\t-\t-");
+
+        assert!(state(StateAction::Reset { tenant: "t2".to_string(), subscription: None }, &config).is_err());
+        assert!(state(StateAction::Set { last_log_time: "yesterday".to_string(), tenant: None, subscription: None },
+                      &config).is_err());
+    }
+
+    #[test]
+    fn test_config_checks() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret_path = dir.path().join("secret");
+        std::fs::write(&secret_path, "s3cr3t\n").unwrap();
+        let config: Config = serde_yaml::from_str(&format!(r#"
+workingDir: {}
+tenants:
+  - {{tenant_id: t1, client_id: a, client_secret_path: {}}}
+  - {{tenant_id: t2, client_id: b, client_secret_path: {}}}
+output:
+  logs_ingestion:
+    endpoint: https://dce.example
+    dcr_immutable_id: dcr-1
+    stream_name: Custom-Office365Audit_CL
+    aad: {{tenant_id: t, client_id: c, client_secret: s}}
+"#, dir.path().join("state").display(), secret_path.display(), dir.path().join("missing").display())).unwrap();
+        let checks = config_checks(&config);
+        let failed: Vec<&str> = checks.iter().filter(|(_, result)| result.is_err()).map(|(check, _)| check.as_str()).collect();
+        assert_eq!(checks.len(), 4);
+        assert_eq!(failed, vec!["tenant t2 credentials"]);
+        // The missing working directory is reported, not created
+        assert!(checks[3].0.ends_with(&format!("(does not exist, created in {} by the first run)",
+                                               dir.path().display())));
+        assert!(!dir.path().join("state").exists());
+        std::fs::create_dir(dir.path().join("state")).unwrap();
+        assert_eq!(config_checks(&config)[3].0, format!("working directory {}", dir.path().join("state").display()));
+        std::fs::write(dir.path().join("file"), "").unwrap();
+        assert!(check_writable(&dir.path().join("file").join("state")).is_err());
+        // The secret itself never ends up in the report
+        assert!(checks.iter().all(|(check, result)| !check.contains("s3cr3t") &&
+            result.as_ref().err().is_none_or(|e| !e.contains("s3cr3t"))));
+
+        let args = CliArgs::try_parse_from(["collector", "--config", "config.yaml", "--check-config"]).unwrap();
+        assert!(args.check_config);
+    }
+
+    #[test]
+    fn test_overrides() {
+        let mut config: Config = serde_yaml::from_str(r#"
+output:
+  graylog: {address: siem.example, port: 12201}
+routing:
+  - {outputs: [graylog]}
+log: {path: /var/log/collector.log, debug: true}
+"#).unwrap();
+        let args = CliArgs::try_parse_from(["collector", "--config", "config.yaml", "--working-dir", "/tmp/investigation",
+                                            "--interval", "1m", "--hours-to-collect", "72", "--output-file", "out.json",
+                                            "--log-level", "trace"]).unwrap();
+        config.apply_overrides(&args).unwrap();
+        assert_eq!(config.get_working_dir(), "/tmp/investigation");
+        assert_eq!(config.get_interval_seconds(), 60);
+        assert_eq!(config.collect.as_ref().unwrap().hours_to_collect, Some(72));
+        assert_eq!(config.output.file.as_ref().unwrap().path, "out.json");
+        assert!(config.output.graylog.is_none() && config.routing.is_empty());
+        assert_eq!(config.log.as_ref().unwrap().get_level(), Ok(log::LevelFilter::Trace));
+        assert_eq!(config.log.as_ref().unwrap().path, "/var/log/collector.log");
+
+        let args = CliArgs::try_parse_from(["collector", "--config", "config.yaml", "--interval", "often"]).unwrap();
+        assert!(config.apply_overrides(&args).unwrap_err().starts_with("--interval"));
+        let args = CliArgs::try_parse_from(["collector", "--config", "config.yaml", "--log-level", "loud"]).unwrap();
+        assert!(config.apply_overrides(&args).is_err());
+    }
+
+    #[test]
+    fn test_tenant_overrides() {
+        let config: Config = serde_yaml::from_str(r#"
+tenants:
+  - {tenant_id: Tenant-A, client_id: a}
+  - {tenant_id: tenant-b, client_id: b}
+  - {tenant_id: tenant-c, client_id: c}
+output: {}
+"#).unwrap();
+        let overridden = |cli: &[&str]| -> Result<Vec<String>, String> {
+            let args = CliArgs::try_parse_from(["collector", "--config", "config.yaml"].iter().chain(cli)).unwrap();
+            let mut config = config.clone();
+            config.apply_overrides(&args)?;
+            Ok(config.tenants.iter().map(|tenant| tenant.client_id.clone()).collect())
+        };
+        assert_eq!(overridden(&[]).unwrap(), vec!["a", "b", "c"]);
+        assert_eq!(overridden(&["--tenant", "tenant-a", "--tenant", "tenant-c"]).unwrap(), vec!["a", "c"]);
+        assert_eq!(overridden(&["--skip-tenant", "TENANT-B"]).unwrap(), vec!["a", "c"]);
+        assert!(overridden(&["--tenant", "tenant-d"]).is_err());
+
+        // A subcommand's own --tenant is not the collection filter
+        let args = CliArgs::try_parse_from(["collector", "--config", "config.yaml", "state", "show", "--tenant", "t1"]).unwrap();
+        assert!(args.tenant.is_empty());
+    }
+
+    #[test]
+    fn test_content_type_override() {
+        let config: Config = serde_yaml::from_str(r#"
+subscriptions: [Audit.Exchange, Audit.General]
+collect: {contentTypes: {Audit.SharePoint: true}}
+graph: {sources: [signIns], delay: 10m}
+output: {}
+"#).unwrap();
+        let overridden = |cli: &[&str]| -> Result<Config, String> {
+            let args = CliArgs::try_parse_from(["collector", "--config", "config.yaml"].iter().chain(cli)).unwrap();
+            let mut config = config.clone();
+            config.apply_overrides(&args).map(|_| config)
+        };
+        let dlp = overridden(&["--content-type", "dlp.all"]).unwrap();
+        assert_eq!((dlp.get_subscriptions(), dlp.graph.is_none()), (vec!["DLP.All".to_string()], true));
+
+        let graph = overridden(&["--content-type", "Graph.DirectoryAudits"]).unwrap();
+        assert!(graph.get_subscriptions().is_empty());
+        assert_eq!(graph::content_types(&graph), vec!["Graph.DirectoryAudits"]);
+        assert_eq!(graph.graph.unwrap().delay.as_deref(), Some("10m"));
+
+        assert!(overridden(&["--content-type", "Audit.Teams"]).unwrap_err().contains("Audit.Exchange"));
+    }
+
+    #[test]
+    fn test_generate_config() {
+        let args = CliArgs::try_parse_from(["collector", "generate-config", "--minimal"]).unwrap();
+        assert_eq!(args.command.unwrap(), Command::GenerateConfig { minimal: true, output: None });
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.yaml");
+        generate_config(false, path.to_str()).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), sample_config::render(false));
+        // An existing config is never overwritten
+        assert!(generate_config(true, path.to_str()).is_err());
+    }
+
+    #[test]
+    fn test_select_tenants() {
+        let config: Config = serde_yaml::from_str(r#"
+tenants:
+  - {tenant_id: Tenant-A, client_id: a}
+  - {tenant_id: tenant-b, client_id: b}
+output: {}
+"#).unwrap();
+        assert_eq!(select_tenants(&config, None).unwrap().len(), 2);
+        assert_eq!(select_tenants(&config, Some("tenant-a")).unwrap()[0].client_id, "a");
+        assert!(select_tenants(&config, Some("tenant-c")).is_err());
+    }
+
+    #[test]
+    fn test_format_subscription() {
+        let subscription: ArbitraryJson = serde_json::from_value(json!({
+            "contentType": "Audit.Exchange", "status": "enabled",
+            "webhook": {"address": "https://collector.example.com/notify", "status": "enabled"}})).unwrap();
+        assert_eq!(format_subscription("t1", &subscription),
+                   "t1\tAudit.Exchange\tenabled\twebhook https://collector.example.com/notify (enabled)");
+        let subscription: ArbitraryJson = serde_json::from_value(json!({
+            "contentType": "DLP.All", "status": "disabled", "webhook": null})).unwrap();
+        assert_eq!(format_subscription("t1", &subscription), "t1\tDLP.All\tdisabled\t-");
+    }
+}
@@ -1,25 +1,92 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsString;
 use std::fs::File;
-use std::io::{BufReader, LineWriter, Read, Write};
+use std::io::{LineWriter, Read, Write};
 use std::path::Path;
 use chrono::{DateTime, NaiveDateTime, Utc};
-use log::warn;
+use log::{warn, LevelFilter};
 use serde_derive::Deserialize;
-use crate::data_structures::ArbitraryJson;
+use crate::data_structures::{ArbitraryJson, CliArgs};
+use crate::formatters::LogFormat;
+use crate::log_filter::LogFilter;
+use crate::recordtype_filter::RecordTypeFilter;
+use crate::redis::RedisClient;
+use crate::sqlite::SqliteStore;
 
 /// Microsoft Office 365 Management API retains audit logs for 7 days.
 /// Any attempt to fetch logs older than this will return empty results or errors.
 /// We use 6 days 23 hours as a safe maximum to avoid edge cases.
 pub const MAX_LOOKBACK_HOURS: i64 = 167;  // 6 days 23 hours
 
+pub const DEFAULT_STATE_SAFETY_LAG: &str = "5m";
+
+/// Object size and age at which the object outputs (s3, azure_blob) upload by default
+const DEFAULT_OBJECT_FLUSH_SIZE: &str = "8M";
+const DEFAULT_OBJECT_FLUSH_INTERVAL: &str = "5m";
+
+#[derive(Clone, Debug)]
+pub enum StateBackend {
+    /// State, cursor and known_blobs files in the working directory
+    File,
+    /// State and known blobs in Redis, shared by collector instances
+    Redis(RedisClient),
+    /// State, known blobs and cursors in one SQLite database
+    Sqlite(SqliteStore),
+}
+
+/// api_type values that need no `api_types` entry
+const BUILTIN_API_TYPES: [&str; 5] = ["commercial", "gcc", "gcc-high", "dod", "china"];
+
+/// Content types of the Management API that can be subscribed to
+pub const CONTENT_TYPES: [&str; 5] = ["Audit.AzureActiveDirectory", "Audit.Exchange", "Audit.SharePoint",
+                                      "Audit.General", "DLP.All"];
+
 
 #[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub enabled: Option<bool>,
     pub interval: Option<String>,  // e.g., "5m", "1h", "30s"
     pub curl_max_size: Option<String>,  // e.g., "1M", "500K", "2G"
     pub only_future_events: Option<bool>,
+    /// Kept between the latest collected CreationTime and the saved last_log_time, e.g. "5m"
+    pub state_safety_lag: Option<String>,
+    /// Each run starts this long before the saved last_log_time, e.g. "15m"
+    pub lookback_overlap: Option<String>,
+    /// History the first run collects with only_future_events, e.g. "24h"
+    pub initial_lookback: Option<String>,
+    /// Furthest a run catches up after downtime, e.g. "48h"
+    pub max_catchup: Option<String>,
+    /// 24 hour windows a run lists per content type while catching up
+    pub catchup_chunks_per_run: Option<usize>,
+    /// Content blobs a tenant's run retrieves at most, the rest is left for the next runs
+    pub max_blobs_per_run: Option<usize>,
+    /// Logs a tenant's run saves at most, checked after every content blob
+    pub max_logs_per_run: Option<usize>,
+    /// Tenants collected at the same time, the others wait for one of them to finish
+    pub max_concurrent_tenants: Option<usize>,
+    /// Delay between starting the collectors of consecutive tenants, e.g. "2s"
+    pub tenant_stagger: Option<String>,
+    /// Up to this much random delay before each tenant's collector starts, e.g. "30s"
+    pub schedule_jitter: Option<String>,
+    /// Sync state and known_blobs files to disk when saving them
+    pub state_fsync: Option<bool>,
+    /// Where state and known blobs are kept, see get_state_backend
+    pub state_backend: Option<String>,
+    /// Connection of the redis state backend
+    pub redis: Option<RedisSubConfig>,
+    /// Database of the sqlite state backend
+    pub sqlite: Option<SqliteSubConfig>,
+    /// Load more tenants from a CSV file or an HTTP endpoint, see tenant_source.rs
+    pub tenant_source: Option<TenantSourceSubConfig>,
+    /// Record every run, see run_ledger.rs
+    pub ledger: Option<LedgerSubConfig>,
+    /// Send a heartbeat log to the outputs for every tenant and run
+    pub heartbeat: Option<bool>,
+    /// JSON summary of the latest run, see run_summary.rs
+    pub run_summary: Option<String>,
+    /// Alert operators when tenants fail, see alerts.rs
+    pub on_error: Option<OnErrorSubConfig>,
     #[serde(rename = "workingDir")]
     pub working_dir: Option<String>,  // Directory for state files and known_blobs
     pub log: Option<LogSubConfig>,
@@ -28,18 +95,402 @@ pub struct Config {
     #[serde(default)]
     pub subscriptions: Vec<String>,  // Default to empty vec, Dynamic content types
     pub collect: Option<CollectSubConfig>,  // Now optional, using new structure
-    pub output: OutputSubConfig
+    pub output: OutputSubConfig,
+    /// Added to every log, e.g. {customer: ACME}, see transform.rs
+    #[serde(default)]
+    pub add_fields: HashMap<String, serde_json::Value>,
+    /// Fields holding JSON text to parse into JSON, by content type, see transform.rs
+    #[serde(default)]
+    pub parse_json: HashMap<String, Vec<String>>,
+    /// Fields to drop or keep by content type, see transform.rs
+    #[serde(default)]
+    pub projection: HashMap<String, ProjectionSubConfig>,
+    /// Field renames by output name or "default", see transform.rs
+    #[serde(default)]
+    pub rename: HashMap<String, HashMap<String, String>>,
+    /// Whether to flatten nested fields into dotted keys, by output name or "default"
+    #[serde(default)]
+    pub flatten: HashMap<String, bool>,
+    /// Hash, truncate or replace personal data for selected outputs, see redact.rs
+    #[serde(default)]
+    pub redact: Vec<RedactSubConfig>,
+    /// Drop the logs of noisy users and addresses, see suppress.rs
+    #[serde(default)]
+    pub suppress: Vec<SuppressSubConfig>,
+    /// Filters and computed fields by content type, see expression.rs
+    #[serde(default)]
+    pub expressions: HashMap<String, ExpressionsSubConfig>,
+    /// Lua script called for every log, see plugin.rs
+    pub plugin: Option<PluginSubConfig>,
+    /// Keep only a share of high volume logs, see sample.rs
+    #[serde(default)]
+    pub sample: Vec<SampleSubConfig>,
+    /// Send matching logs only to selected outputs, see routing.rs
+    #[serde(default)]
+    pub routing: Vec<RouteSubConfig>,
+    /// Retries of failed interface batches, by output name or "default"
+    #[serde(default)]
+    pub retry: HashMap<String, RetrySubConfig>,
+    /// Keep batches that could not be delivered on disk, see interfaces/spool.rs
+    pub spool: Option<SpoolSubConfig>,
+    /// Size limit of a log and what to do with bigger ones, by output name or "default",
+    /// see interfaces/oversized.rs
+    #[serde(default)]
+    pub oversized: HashMap<String, OversizedSubConfig>,
+    /// Batch size and flush interval of interface outputs, by output name or "default"
+    #[serde(default)]
+    pub batching: HashMap<String, BatchSubConfig>,
+    /// TLS settings of the Office API client and HTTP outputs, defaults for socket outputs
+    pub tls: Option<TlsSubConfig>,
+    /// Receive content notifications instead of only polling, see webhook.rs
+    pub webhook: Option<WebhookSubConfig>,
+    /// Local HTTP API to control the daemon, see control.rs
+    pub control_api: Option<ControlApiSubConfig>,
+    /// Additional clouds tenants can select with api_type, by name
+    #[serde(default)]
+    pub api_types: HashMap<String, ApiTypeSubConfig>,
+    /// Also collect Entra ID logs and security alerts from Microsoft Graph, see graph.rs
+    pub graph: Option<GraphSubConfig>,
+    /// Export traces of the collection pipeline over OTLP, see telemetry.rs
+    pub tracing: Option<TracingSubConfig>,
+    /// Tenant IDs of --tenant, only these are collected if any
+    #[serde(skip)]
+    pub only_tenants: Vec<String>,
+    /// Tenant IDs of --skip-tenant
+    #[serde(skip)]
+    pub skip_tenants: Vec<String>,
 }
 impl Config {
 
-    pub fn new(path: String) -> Self {
+    /// Read and validate a config file. All problems found are reported at once, with their
+    /// YAML path and line.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let yaml = std::fs::read_to_string(path)
+            .map_err(|e| format!("Config path {} could not be opened: {}", path, e))?;
+        let config: Config = serde_yaml::from_str(&yaml)
+            .map_err(|e| format!("Config {} could not be parsed: {}", path, e))?;
+        let problems = config.validate(&yaml);
+        if !problems.is_empty() {
+            return Err(format!("Config {} is invalid:\n  {}", path, problems.join("\n  ")))
+        }
+        Ok(config)
+    }
 
-        let open_file = File::open(path)
-            .unwrap_or_else(|e| panic!("Config path could not be opened: {}", e.to_string()));
-        let reader = BufReader::new(open_file);
-        let config: Config = serde_yaml::from_reader(reader)
-            .unwrap_or_else(|e| panic!("Config could not be parsed: {}", e.to_string()));
-        config
+    /// The config file of the command line, with the overrides of its options applied.
+    pub fn load_for(args: &CliArgs) -> Result<Self, String> {
+        let path = args.config.as_deref().ok_or("--config is required")?;
+        let mut config = Self::load(path)?;
+        config.apply_overrides(args)?;
+        Ok(config)
+    }
+
+    /// Apply the command line options that override the config, for ad-hoc runs without editing
+    /// it.
+    pub fn apply_overrides(&mut self, args: &CliArgs) -> Result<(), String> {
+        if let Some(working_dir) = &args.working_dir {
+            self.working_dir = Some(working_dir.clone());
+        }
+        if let Some(interval) = &args.interval {
+            Self::try_parse_interval(interval).map_err(|e| format!("--interval: {}", e))?;
+            self.interval = Some(interval.clone());
+        }
+        if let Some(hours) = args.hours_to_collect {
+            self.collect.get_or_insert_with(Default::default).hours_to_collect = Some(hours);
+        }
+        if let Some(path) = &args.output_file {
+            // Only to this file, logs of an investigation should not end up in the SIEM
+            self.output = OutputSubConfig {
+                file: Some(FileOutputSubConfig {
+                    path: path.clone(), separate_by_content_type: None, separator: None, compress: None,
+                    rotate_size: None, rotate_interval: None, retention: None,
+                }),
+                ..Default::default()
+            };
+            self.routing.clear();
+        }
+        if !args.content_type.is_empty() {
+            self.select_content_types(&args.content_type).map_err(|e| format!("--content-type: {}", e))?;
+        }
+        if !args.tenant.is_empty() || !args.skip_tenant.is_empty() {
+            self.only_tenants = args.tenant.clone();
+            self.skip_tenants = args.skip_tenant.clone();
+            if self.tenant_source.is_none() {
+                if let Some(missing) = self.only_tenants.iter()
+                    .find(|id| !self.tenants.iter().any(|tenant| tenant.tenant_id.eq_ignore_ascii_case(id))) {
+                    return Err(format!("--tenant: tenant {} is not configured", missing))
+                }
+            }
+            self.filter_tenants();
+        }
+        if let Some(level) = &args.log_level {
+            let log = self.log.get_or_insert_with(Default::default);
+            log.level = Some(level.clone());
+            log.get_level().map_err(|e| format!("--log-level: {}", e))?;
+        }
+        Ok(())
+    }
+
+    /// Only collect these content types, whether configured or not. Management API content types
+    /// replace the subscriptions and Graph ones (Graph.SignIns, ...) the Graph sources.
+    fn select_content_types(&mut self, content_types: &[String]) -> Result<(), String> {
+        let mut subscriptions = Vec::new();
+        let mut graph_sources = Vec::new();
+        for content_type in content_types {
+            if let Some(subscription) = CONTENT_TYPES.iter().find(|c| c.eq_ignore_ascii_case(content_type)) {
+                subscriptions.push(subscription.to_string());
+            } else if let Some(source) = crate::graph::SOURCES.iter().find(|s| s.content_type.eq_ignore_ascii_case(content_type)) {
+                graph_sources.push(source.name.to_string());
+            } else {
+                let mut known: Vec<&str> = CONTENT_TYPES.to_vec();
+                known.extend(crate::graph::SOURCES.iter().map(|source| source.content_type));
+                return Err(format!("unknown content type '{}', must be one of: {}", content_type, known.join(", ")))
+            }
+        }
+        self.subscriptions = subscriptions;
+        // Legacy content types would be used when there are no subscriptions
+        if let Some(collect) = &mut self.collect {
+            collect.content_types = ContentTypesSubConfig::default();
+        }
+        self.graph = match graph_sources.is_empty() {
+            true => None,
+            false => Some(GraphSubConfig {
+                sources: graph_sources,
+                delay: self.graph.as_ref().and_then(|graph| graph.delay.clone()),
+            }),
+        };
+        Ok(())
+    }
+
+    /// Drop the tenants not selected with --tenant and --skip-tenant.
+    pub fn filter_tenants(&mut self) {
+        let listed = |ids: &[String], tenant: &TenantConfig| ids.iter().any(|id| id.eq_ignore_ascii_case(&tenant.tenant_id));
+        let (only, skip) = (&self.only_tenants, &self.skip_tenants);
+        self.tenants.retain(|tenant| (only.is_empty() || listed(only, tenant)) && !listed(skip, tenant));
+    }
+
+    /// Problems serde cannot find: malformed durations and sizes, tenants without credentials
+    /// and unknown subscriptions, state backends, clouds and Graph sources.
+    pub fn validate(&self, yaml: &str) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut report = |path: String, message: String| {
+            match find_line(yaml, &path) {
+                Some(line) => problems.push(format!("{} (line {}): {}", path, line, message)),
+                None => problems.push(format!("{}: {}", path, message)),
+            }
+        };
+
+        let mut durations: Vec<(String, &Option<String>)> = vec![
+            ("interval".to_string(), &self.interval),
+            ("state_safety_lag".to_string(), &self.state_safety_lag),
+            ("lookback_overlap".to_string(), &self.lookback_overlap),
+            ("initial_lookback".to_string(), &self.initial_lookback),
+            ("max_catchup".to_string(), &self.max_catchup),
+        ];
+        durations.push(("tenant_stagger".to_string(), &self.tenant_stagger));
+        durations.push(("schedule_jitter".to_string(), &self.schedule_jitter));
+        let mut sizes: Vec<(String, &Option<String>)> = vec![("curl_max_size".to_string(), &self.curl_max_size)];
+        let global_timeout = match self.collect.as_ref().and_then(|collect| collect.global_timeout.as_ref()) {
+            Some(Timeout::Duration(duration)) => Some(duration.clone()),
+            _ => None,
+        };
+        durations.push(("collect.globalTimeout".to_string(), &global_timeout));
+        let tenant_timeouts: Vec<Option<String>> = self.tenants.iter()
+            .map(|tenant| match &tenant.global_timeout {
+                Some(Timeout::Duration(duration)) => Some(duration.clone()),
+                _ => None,
+            })
+            .collect();
+        for (i, timeout) in tenant_timeouts.iter().enumerate() {
+            durations.push((format!("tenants[{}].global_timeout", i), timeout));
+        }
+        if let Some(collect) = &self.collect {
+            sizes.push(("collect.cacheBytes".to_string(), &collect.cache_bytes));
+        }
+        if let Some(graph) = &self.graph {
+            durations.push(("graph.delay".to_string(), &graph.delay));
+        }
+        if let Some(ledger) = &self.ledger {
+            durations.push(("ledger.rotate_interval".to_string(), &ledger.rotate_interval));
+            sizes.push(("ledger.rotate_size".to_string(), &ledger.rotate_size));
+        }
+        for (name, batching) in &self.batching {
+            durations.push((format!("batching.{}.flush_interval", name), &batching.flush_interval));
+            sizes.push((format!("batching.{}.batch_bytes", name), &batching.batch_bytes));
+        }
+        for (name, retry) in &self.retry {
+            durations.push((format!("retry.{}.initial_backoff", name), &retry.initial_backoff));
+            durations.push((format!("retry.{}.max_backoff", name), &retry.max_backoff));
+        }
+        if let Some(spool) = &self.spool {
+            sizes.push(("spool.max_size".to_string(), &spool.max_size));
+        }
+        for (name, oversized) in &self.oversized {
+            sizes.push((format!("oversized.{}.max_size", name), &oversized.max_size));
+        }
+        if let Some(file) = &self.output.file {
+            durations.push(("output.file.rotate_interval".to_string(), &file.rotate_interval));
+            sizes.push(("output.file.rotate_size".to_string(), &file.rotate_size));
+        }
+        if let Some(s3) = &self.output.s3 {
+            durations.push(("output.s3.flush_interval".to_string(), &s3.flush_interval));
+            sizes.push(("output.s3.flush_size".to_string(), &s3.flush_size));
+        }
+        if let Some(azure_blob) = &self.output.azure_blob {
+            durations.push(("output.azure_blob.flush_interval".to_string(), &azure_blob.flush_interval));
+            sizes.push(("output.azure_blob.flush_size".to_string(), &azure_blob.flush_size));
+        }
+        for (path, value) in durations {
+            if let Some(Err(e)) = value.as_deref().map(Self::try_parse_interval) {
+                report(path, e);
+            }
+        }
+        for (path, value) in sizes {
+            if let Some(Err(e)) = value.as_deref().map(Self::try_parse_size) {
+                report(path, e);
+            }
+        }
+
+        for (i, tenant) in self.tenants.iter().enumerate() {
+            for (field, problem) in tenant.validate(&self.api_types) {
+                match field {
+                    Some(field) => report(format!("tenants[{}].{}", i, field), problem),
+                    None => report(format!("tenants[{}]", i), problem),
+                }
+            }
+        }
+        if let Some(collect) = &self.collect {
+            if let Err(e) = collect.get_log_filters() {
+                report("collect.filter".to_string(), e);
+            }
+            for content_type in collect.record_types.keys().chain(collect.operations.keys()) {
+                if !CONTENT_TYPES.contains(&content_type.as_str()) {
+                    let section = if collect.record_types.contains_key(content_type) { "record_types" } else { "operations" };
+                    report(format!("collect.{}", section), format!("unknown subscription '{}', must be one of: {}",
+                                                                   content_type, CONTENT_TYPES.join(", ")));
+                }
+            }
+        }
+        if let Err(e) = crate::routing::Router::new(&self.routing, "") {
+            report("routing".to_string(), e);
+        }
+        if let Err(e) = crate::suppress::Suppress::new(&self.suppress) {
+            report("suppress".to_string(), e);
+        }
+        for (content_type, expressions) in &self.expressions {
+            let sources = expressions.filter.iter().map(|filter| ("filter".to_string(), filter))
+                .chain(expressions.fields.iter().map(|(name, field)| (format!("fields.{}", name), field)));
+            for (name, source) in sources {
+                if let Err(e) = crate::expression::Expression::parse(source) {
+                    report("expressions".to_string(), format!("{} {}: {}", content_type, name, e));
+                }
+            }
+        }
+        if let Some(Err(e)) = self.plugin.as_ref().map(crate::plugin::Plugin::new) {
+            report("plugin.lua".to_string(), e);
+        }
+        if let Err(e) = crate::sample::Sample::new(&self.sample) {
+            report("sample".to_string(), e);
+        }
+        if let Err(e) = crate::redact::Redaction::for_output(&self.redact, "file") {
+            report("redact".to_string(), e);
+        }
+        for output in crate::routing::OUTPUT_NAMES {
+            if let Err(e) = crate::transform::Renames::for_output(self, output) {
+                report("rename".to_string(), e);
+                break
+            }
+        }
+        for output in self.flatten.keys() {
+            if output != "default" && !crate::routing::OUTPUT_NAMES.contains(&output.as_str()) {
+                report("flatten".to_string(), format!("unknown output '{}', must be default or one of: {}",
+                                                      output, crate::routing::OUTPUT_NAMES.join(", ")));
+            }
+        }
+        for output in self.oversized.keys() {
+            if output != "default" && !crate::routing::OUTPUT_NAMES.contains(&output.as_str()) {
+                report("oversized".to_string(), format!("unknown output '{}', must be default or one of: {}",
+                                                        output, crate::routing::OUTPUT_NAMES.join(", ")));
+            }
+        }
+        for (content_type, projection) in &self.projection {
+            let fields = projection.keep_fields.iter().flatten().chain(&projection.drop_fields);
+            for field in fields.filter(|field| field.split('.').any(str::is_empty)) {
+                report("projection".to_string(), format!("{}: invalid field '{}'", content_type, field));
+            }
+        }
+        for (content_type, fields) in &self.parse_json {
+            for field in fields.iter().filter(|field| field.split('.').any(str::is_empty)) {
+                report("parse_json".to_string(), format!("{}: invalid field '{}'", content_type, field));
+            }
+        }
+        if let Some(channels) = self.collect.as_ref().and_then(|collect| collect.channels.as_ref()) {
+            for (name, capacity) in [("blobs", channels.blobs), ("content", channels.content), ("results", channels.results)] {
+                if capacity == Some(0) {
+                    report(format!("collect.channels.{}", name), "must be at least 1".to_string());
+                }
+            }
+        }
+        if let Some(rate) = self.collect.as_ref().and_then(|collect| collect.max_requests_per_minute) {
+            if rate.is_nan() || rate <= 0.0 {
+                report("collect.max_requests_per_minute".to_string(), "must be greater than 0".to_string());
+            }
+        }
+        for (name, limit) in [("max_concurrent_tenants", self.max_concurrent_tenants),
+                              ("max_blobs_per_run", self.max_blobs_per_run),
+                              ("max_logs_per_run", self.max_logs_per_run)] {
+            if limit == Some(0) {
+                report(name.to_string(), "must be at least 1".to_string());
+            }
+        }
+        if let Some(Err(e)) = self.log.as_ref().map(|log| log.get_level()) {
+            report("log.level".to_string(), e);
+        }
+        if let Some(source) = &self.tenant_source {
+            if source.csv.is_some() == source.url.is_some() {
+                report("tenant_source".to_string(), "set either csv or url".to_string());
+            }
+        }
+        if let Some(on_error) = &self.on_error {
+            if on_error.exec.is_none() && on_error.url.is_none() {
+                report("on_error".to_string(), "set exec, url or both".to_string());
+            }
+            if let Some(url) = &on_error.url {
+                if !url.starts_with("http://") && !url.starts_with("https://") {
+                    report("on_error.url".to_string(), format!("'{}' is not an http(s) URL", url));
+                }
+            }
+            if let Some(format) = &on_error.format {
+                if !["json", "slack", "teams"].contains(&format.as_str()) {
+                    report("on_error.format".to_string(), format!("unknown format '{}', must be json, slack or teams", format));
+                }
+            }
+            if on_error.login_failures == Some(0) {
+                report("on_error.login_failures".to_string(), "must be at least 1".to_string());
+            }
+        }
+        if let Some(listen) = self.control_api.as_ref().and_then(|control_api| control_api.listen.as_ref()) {
+            if listen.parse::<std::net::SocketAddr>().is_err() {
+                report("control_api.listen".to_string(), format!("'{}' is not an address like 127.0.0.1:8080", listen));
+            }
+        }
+        if let Some(tracing) = &self.tracing {
+            if !tracing.endpoint.starts_with("http://") && !tracing.endpoint.starts_with("https://") {
+                report("tracing.endpoint".to_string(), format!("'{}' is not an http(s) URL", tracing.endpoint));
+            }
+        }
+        for (i, subscription) in self.subscriptions.iter().enumerate() {
+            if !CONTENT_TYPES.contains(&subscription.as_str()) {
+                report(format!("subscriptions[{}]", i), format!("unknown subscription '{}', must be one of: {}",
+                                                               subscription, CONTENT_TYPES.join(", ")));
+            }
+        }
+        if let Err(e) = self.get_state_backend() {
+            report("state_backend".to_string(), e);
+        }
+        if let Err(e) = crate::graph::sources(self) {
+            report("graph.sources".to_string(), e.to_string());
+        }
+        problems
     }
 
     pub fn is_enabled(&self) -> bool {
@@ -65,32 +516,160 @@ impl Config {
         }
     }
 
-    fn parse_interval(s: &str) -> u64 {
-        let s = s.trim();
-        if s.ends_with('s') {
-            s[..s.len()-1].parse().unwrap_or(300)
-        } else if s.ends_with('m') {
-            s[..s.len()-1].parse::<u64>().unwrap_or(5) * 60
-        } else if s.ends_with('h') {
-            s[..s.len()-1].parse::<u64>().unwrap_or(1) * 3600
-        } else if s.ends_with('d') {
-            s[..s.len()-1].parse::<u64>().unwrap_or(1) * 86400
-        } else {
-            s.parse().unwrap_or(300)  // Assume seconds if no unit
+    /// Whether an output gets flattened logs, by its name or the "default" entry.
+    pub fn get_flatten(&self, output: &str) -> bool {
+        self.flatten.get(output).or(self.flatten.get("default")).copied().unwrap_or(false)
+    }
+
+    /// Batch size and flush interval (seconds) of an output, settings missing for it are
+    /// taken from the "default" entry. The object outputs (s3, azure_blob) upload every batch
+    /// as objects, so their flush_size and flush_interval come before the "default" entry.
+    pub fn get_batching(&self, output: &str) -> (Option<usize>, Option<u64>, Option<usize>) {
+        let default = self.batching.get("default").cloned().unwrap_or_default();
+        let specific = self.batching.get(output).cloned().unwrap_or_default();
+        let (object_size, object_interval) = match output {
+            "s3" => self.output.s3.as_ref().map(|s3| (s3.flush_size.clone(), s3.flush_interval.clone())),
+            "azure_blob" => self.output.azure_blob.as_ref()
+                .map(|blob| (blob.flush_size.clone(), blob.flush_interval.clone())),
+            _ => None,
+        }.map(|(size, interval)| (size.or(Some(DEFAULT_OBJECT_FLUSH_SIZE.to_string())),
+                                 interval.or(Some(DEFAULT_OBJECT_FLUSH_INTERVAL.to_string()))))
+            .unwrap_or_default();
+        let batch_size = specific.batch_size.or(default.batch_size);
+        let flush_interval = specific.flush_interval.or(object_interval).or(default.flush_interval)
+            .map(|s| Self::parse_interval(&s));
+        let batch_bytes = specific.batch_bytes.or(object_size).or(default.batch_bytes)
+            .map(|s| Self::parse_size(&s));
+        (batch_size, flush_interval, batch_bytes)
+    }
+
+    /// How long the collector of the tenant at `index` waits before it starts: the stagger of
+    /// the tenants before it plus `random` (0 to 1) of the jitter, so tenants do not all log in
+    /// and list content in the same second.
+    pub fn get_start_delay(&self, index: usize, random: f64) -> std::time::Duration {
+        let stagger = self.tenant_stagger.as_deref().map(Self::parse_interval).unwrap_or(0);
+        let jitter = self.schedule_jitter.as_deref().map(Self::parse_interval).unwrap_or(0);
+        std::time::Duration::from_secs(stagger.saturating_mul(index as u64))
+            + std::time::Duration::from_secs(jitter).mul_f64(random.clamp(0.0, 1.0))
+    }
+
+    /// Seconds a run of a tenant may take: its own global_timeout, or collect.globalTimeout.
+    /// 0 for no limit.
+    pub fn get_tenant_timeout(&self, tenant: &TenantConfig) -> u64 {
+        match &tenant.global_timeout {
+            Some(timeout) => timeout.seconds(),
+            None => self.collect.as_ref().map(|collect| collect.get_global_timeout()).unwrap_or(30 * 60),
         }
     }
 
-    fn parse_size(s: &str) -> usize {
-        let s = s.trim().to_uppercase();
-        if s.ends_with('K') {
-            s[..s.len()-1].parse::<usize>().unwrap_or(1024) * 1024
-        } else if s.ends_with('M') {
-            s[..s.len()-1].parse::<usize>().unwrap_or(1) * 1024 * 1024
-        } else if s.ends_with('G') {
-            s[..s.len()-1].parse::<usize>().unwrap_or(1) * 1024 * 1024 * 1024
+    /// Longest a run of all tenants can take when every tenant runs into its global timeout,
+    /// None when that is disabled for any tenant.
+    pub fn get_max_run_seconds(&self) -> Option<u64> {
+        let timeouts: Vec<u64> = if self.tenants.is_empty() {
+            vec![self.collect.as_ref().map(|collect| collect.get_global_timeout()).unwrap_or(30 * 60)]
         } else {
-            s.parse().unwrap_or(1024 * 1024)  // Assume bytes if no unit
+            self.tenants.iter().map(|tenant| self.get_tenant_timeout(tenant)).collect()
+        };
+        if timeouts.contains(&0) {
+            return None
         }
+        let timeout = timeouts.into_iter().max().unwrap_or_default();
+        let tenants = self.tenants.len().max(1);
+        let batches = match self.max_concurrent_tenants {
+            Some(max) => tenants.div_ceil(max.max(1)),
+            None => 1,
+        };
+        let last_start = self.get_start_delay(tenants - 1, 1.0).as_secs();
+        Some(timeout * batches as u64 + last_start)
+    }
+
+    /// Safety lag subtracted from the latest collected CreationTime before it is saved as state.
+    pub fn get_state_safety_lag(&self) -> chrono::Duration {
+        let lag = Self::parse_interval(self.state_safety_lag.as_deref().unwrap_or(DEFAULT_STATE_SAFETY_LAG));
+        chrono::Duration::try_seconds(lag as i64).unwrap_or_default()
+    }
+
+    /// Overlap of a run's window with the previous run, none unless configured.
+    pub fn get_lookback_overlap(&self) -> chrono::Duration {
+        let overlap = self.lookback_overlap.as_deref().map(Self::parse_interval).unwrap_or(0);
+        chrono::Duration::try_seconds(overlap as i64).unwrap_or_default()
+    }
+
+    /// How far back the first run of a tenant goes with only_future_events, 1 second unless
+    /// configured. Capped at the retention of the API.
+    pub fn get_initial_lookback(&self) -> chrono::Duration {
+        let max = chrono::Duration::try_hours(MAX_LOOKBACK_HOURS).unwrap();
+        let Some(lookback) = self.initial_lookback.as_deref() else {
+            return chrono::Duration::try_seconds(1).unwrap()
+        };
+        let lookback = chrono::Duration::try_seconds(Self::parse_interval(lookback) as i64).unwrap_or(max);
+        if lookback > max {
+            warn!("initial_lookback is capped to {} hours, Microsoft only retains audit logs for 7 days",
+                  MAX_LOOKBACK_HOURS);
+            return max
+        }
+        lookback
+    }
+
+    /// Seconds of a duration such as "30s", "5m", "1h30m", "7d" or plain seconds. Values that
+    /// passed validation always parse, anything else is 5 minutes.
+    pub fn parse_interval(s: &str) -> u64 {
+        Self::try_parse_interval(s).unwrap_or(300)
+    }
+
+    pub fn try_parse_interval(s: &str) -> Result<u64, String> {
+        let invalid = || format!("invalid duration '{}', expected e.g. 30s, 5m, 1h30m or 7d", s.trim());
+        let trimmed = s.trim();
+        if trimmed.is_empty() {
+            return Err(invalid())
+        }
+        if trimmed.bytes().all(|b| b.is_ascii_digit()) {
+            return trimmed.parse().map_err(|_| invalid())
+        }
+
+        let mut total: u64 = 0;
+        let mut rest = trimmed;
+        while !rest.is_empty() {
+            let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+            let number: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+            let mut units = rest[digits..].trim_start().chars();
+            let multiplier = match units.next() {
+                Some('s') => 1,
+                Some('m') => 60,
+                Some('h') => 3600,
+                Some('d') => 86400,
+                Some(unit) => return Err(format!("invalid duration '{}', unit '{}' must be s, m, h or d", trimmed, unit)),
+                None => return Err(invalid()),
+            };
+            total = number.checked_mul(multiplier).and_then(|seconds| total.checked_add(seconds))
+                .ok_or_else(invalid)?;
+            rest = units.as_str().trim_start();
+        }
+        Ok(total)
+    }
+
+    /// Bytes of a size such as "500K", "1M", "2G" or plain bytes. Values that passed
+    /// validation always parse, anything else is 1M.
+    pub fn parse_size(s: &str) -> usize {
+        Self::try_parse_size(s).unwrap_or(1024 * 1024)
+    }
+
+    pub fn try_parse_size(s: &str) -> Result<usize, String> {
+        let s = s.trim();
+        let (number, unit) = match s.char_indices().last() {
+            Some((i, unit)) if unit.is_ascii_alphabetic() => (&s[..i], unit.to_ascii_uppercase()),
+            _ => (s, 'B'),
+        };
+        let multiplier = match unit {
+            'B' => 1,
+            'K' => 1024,
+            'M' => 1024 * 1024,
+            'G' => 1024 * 1024 * 1024,
+            _ => return Err(format!("invalid size '{}', unit must be K, M or G", s)),
+        };
+        number.trim().parse::<usize>().ok()
+            .and_then(|number| number.checked_mul(multiplier))
+            .ok_or_else(|| format!("invalid size '{}', expected e.g. 500K, 1M or 2G", s))
     }
 
     pub fn get_subscriptions(&self) -> Vec<String> {
@@ -104,6 +683,19 @@ impl Config {
         }
     }
 
+    /// The configured state backend.
+    pub fn get_state_backend(&self) -> Result<StateBackend, String> {
+        match self.state_backend.as_deref().map(str::to_lowercase).as_deref() {
+            None | Some("file") => Ok(StateBackend::File),
+            Some("redis") => {
+                let redis = self.redis.as_ref().ok_or("state_backend 'redis' needs a 'redis' section")?;
+                Ok(StateBackend::Redis(RedisClient::new(redis)?))
+            },
+            Some("sqlite") => Ok(StateBackend::Sqlite(SqliteStore::new(self.sqlite.as_ref(), &self.get_working_dir()))),
+            Some(other) => Err(format!("Unknown state_backend '{}', must be 'file', 'redis' or 'sqlite'", other)),
+        }
+    }
+
     pub fn get_working_dir(&self) -> String {
         // Check top-level workingDir first, then fall back to collect.workingDir
         if let Some(ref dir) = self.working_dir {
@@ -134,6 +726,9 @@ impl Config {
 
         let start_time_base = if let Some(from) = start_from {
             // Check if the provided start time is older than the retention window
+            let max_catchup_time = self.max_catchup.as_deref()
+                .and_then(|max| chrono::Duration::try_seconds(Self::parse_interval(max) as i64))
+                .map(|max| end_time - max);
             if from < max_lookback_time {
                 let hours_old = (end_time - from).num_hours();
                 warn!(
@@ -142,7 +737,12 @@ impl Config {
                      Capping start time to {} hours ago to avoid futile API requests.",
                     hours_old, MAX_LOOKBACK_HOURS
                 );
-                max_lookback_time
+                max_catchup_time.map_or(max_lookback_time, |max| max.max(max_lookback_time))
+            } else if let Some(max_catchup_time) = max_catchup_time.filter(|max| from < *max) {
+                warn!("last_log_time is {} hours old, only catching up the last {} (max_catchup), \
+                       older logs are skipped", (end_time - from).num_hours(),
+                      self.max_catchup.as_deref().unwrap_or_default());
+                max_catchup_time
             } else {
                 // Use provided start time (from state's last_log_time)
                 from
@@ -242,32 +842,97 @@ impl Config {
 }
 
 #[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct TenantConfig {
     pub tenant_id: String,
     pub client_id: String,
     pub client_secret: Option<String>,
     pub client_secret_path: Option<String>,
-    pub api_type: Option<String>,  // commercial, gcc, gcc-high
+    pub certificate_path: Option<String>,  // PEM, used instead of a client secret
+    pub private_key_path: Option<String>,
+    pub certificate_thumbprint: Option<String>,
+    pub api_type: Option<String>,  // commercial, gcc, gcc-high, dod, china or an api_types entry
+    pub publisher_id: Option<String>,  // PublisherIdentifier of content requests, default tenant_id
+    /// Added to every log of the tenant, over the global add_fields
+    #[serde(default)]
+    pub add_fields: HashMap<String, serde_json::Value>,
+    /// Customer name added to every log as TenantName
+    pub display_name: Option<String>,
+    /// Added to every log as TenantTags
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Overrides collect.globalTimeout for this tenant
+    pub global_timeout: Option<Timeout>,
 }
 
 impl TenantConfig {
-    pub fn get_endpoints(&self) -> (String, String) {
+
+    /// Missing IDs and credentials and an unknown api_type, with the field they concern.
+    pub fn validate(&self, api_types: &HashMap<String, ApiTypeSubConfig>) -> Vec<(Option<&'static str>, String)> {
+        let mut problems = Vec::new();
+        if self.tenant_id.trim().is_empty() {
+            problems.push((Some("tenant_id"), "must not be empty".to_string()));
+        }
+        if self.client_id.trim().is_empty() {
+            problems.push((Some("client_id"), "must not be empty".to_string()));
+        }
+        if self.certificate_path.is_none() && self.client_secret.is_none() && self.client_secret_path.is_none() {
+            problems.push((None, "no credentials, set client_secret, client_secret_path or certificate_path".to_string()));
+        }
+        if let Err(e) = self.get_endpoints(api_types) {
+            problems.push((Some("api_type"), e));
+        }
+        problems
+    }
+    /// Login and Management API endpoints of the tenant's cloud. Clouds configured under
+    /// `api_types` take precedence over the built-in ones.
+    pub fn get_endpoints(&self, api_types: &HashMap<String, ApiTypeSubConfig>) -> Result<(String, String), String> {
         let api_type = self.api_type.as_deref().unwrap_or("commercial");
-        match api_type {
-            "commercial" => (
-                "https://login.microsoftonline.com".to_string(),
-                "https://manage.office.com".to_string()
-            ),
-            "gcc" => (
-                "https://login.microsoftonline.com".to_string(),
-                "https://manage-gcc.office.com".to_string()
-            ),
-            "gcc-high" => (
-                "https://login.microsoftonline.us".to_string(),
-                "https://manage.office365.us".to_string()
-            ),
-            _ => panic!("Invalid api_type: {}. Must be 'commercial', 'gcc', or 'gcc-high'", api_type)
+        if let Some(custom) = api_types.get(api_type) {
+            return Ok((custom.login_endpoint.trim_end_matches('/').to_string(),
+                       custom.resource_endpoint.trim_end_matches('/').to_string()))
         }
+        let (login_endpoint, resource_endpoint) = match api_type {
+            "commercial" => ("https://login.microsoftonline.com", "https://manage.office.com"),
+            "gcc" => ("https://login.microsoftonline.com", "https://manage-gcc.office.com"),
+            "gcc-high" => ("https://login.microsoftonline.us", "https://manage.office365.us"),
+            "dod" => ("https://login.microsoftonline.us", "https://manage.protection.apps.mil"),
+            "china" => ("https://login.chinacloudapi.cn", "https://manage.office365.cn"),
+            _ => {
+                let mut names: Vec<&str> = BUILTIN_API_TYPES.to_vec();
+                names.extend(api_types.keys().map(|name| name.as_str()));
+                return Err(format!("Invalid api_type '{}' for tenant {}. Must be one of: {}",
+                                   api_type, self.tenant_id, names.join(", ")))
+            }
+        };
+        Ok((login_endpoint.to_string(), resource_endpoint.to_string()))
+    }
+
+    /// Microsoft Graph endpoint of the tenant's cloud.
+    pub fn get_graph_endpoint(&self, api_types: &HashMap<String, ApiTypeSubConfig>) -> Result<String, String> {
+        let api_type = self.api_type.as_deref().unwrap_or("commercial");
+        if let Some(custom) = api_types.get(api_type) {
+            return custom.graph_endpoint.as_ref()
+                .map(|endpoint| endpoint.trim_end_matches('/').to_string())
+                .ok_or_else(|| format!("api_types entry '{}' has no graph_endpoint", api_type))
+        }
+        let endpoint = match api_type {
+            "commercial" | "gcc" => "https://graph.microsoft.com",
+            "gcc-high" => "https://graph.microsoft.us",
+            "dod" => "https://dod-graph.microsoft.us",
+            "china" => "https://microsoftgraph.chinacloudapi.cn",
+            _ => return Err(format!("Invalid api_type '{}' for tenant {}", api_type, self.tenant_id)),
+        };
+        Ok(endpoint.to_string())
+    }
+
+    /// PublisherIdentifier sent with content requests. The API throttles per publisher, so by
+    /// default every tenant uses its own ID and gets its own quota.
+    pub fn get_publisher_id(&self, fallback: Option<&str>) -> String {
+        self.publisher_id.as_deref()
+            .or(fallback)
+            .unwrap_or(&self.tenant_id)
+            .to_string()
     }
 
     pub fn get_secret(&self) -> Result<String, String> {
@@ -276,6 +941,7 @@ impl TenantConfig {
         }
 
         if let Some(secret_path) = &self.client_secret_path {
+            let secret_path = &crate::systemd::credential_path(secret_path)?;
             match std::fs::read_to_string(secret_path) {
                 Ok(content) => Ok(content.trim().to_string()),
                 Err(e) => Err(format!("Failed to read secret from {}: {}", secret_path, e))
@@ -286,33 +952,189 @@ impl TenantConfig {
     }
 }
 
+/// Endpoints of a cloud not built in, e.g. `api_types: {sovereign: {login_endpoint: ..., resource_endpoint: ...}}`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ApiTypeSubConfig {
+    pub login_endpoint: String,
+    pub resource_endpoint: String,
+    /// Only needed to collect from Microsoft Graph
+    pub graph_endpoint: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GraphSubConfig {
+    /// directoryAudits, signIns and/or alerts
+    pub sources: Vec<String>,
+    /// How long to wait for logs to become available in Graph, e.g. "5m" (default)
+    pub delay: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RedisSubConfig {
+    /// redis://[[user]:password@]host[:port][/database]
+    pub url: String,
+    /// Prefix of all keys, "office365" by default
+    pub key_prefix: Option<String>,
+}
+
 #[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SqliteSubConfig {
+    /// Database file, <workingDir>/office365-state.sqlite by default
+    pub path: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TenantSourceSubConfig {
+    /// CSV file with a header row of tenant fields (tenant_id, client_id, client_secret_path, ...)
+    pub csv: Option<String>,
+    /// URL returning a JSON array of tenants, or an object with a "tenants" array
+    pub url: Option<String>,
+    /// Headers sent with the request, e.g. Authorization
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LedgerSubConfig {
+    /// Defaults to run_ledger.jsonl in the working directory
+    pub path: Option<String>,
+    pub compress: Option<bool>,
+    pub rotate_size: Option<String>,
+    pub rotate_interval: Option<String>,
+    pub retention: Option<usize>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
 pub struct LogSubConfig {
+    #[serde(default)]
     pub path: String,
+    #[serde(default)]
     pub debug: bool,
+    /// error, warn, info, debug or trace, takes precedence over debug
+    pub level: Option<String>,
+    /// Directory of per-tenant log files, lines of a tenant's collector go there instead of path
+    pub tenant_dir: Option<String>,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+impl LogSubConfig {
+    pub fn get_level(&self) -> Result<LevelFilter, String> {
+        match &self.level {
+            Some(level) => level.parse()
+                .map_err(|_| format!("invalid log level '{}', must be error, warn, info, debug or trace", level)),
+            None if self.debug => Ok(LevelFilter::Debug),
+            None => Ok(LevelFilter::Info),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
 pub struct CollectSubConfig {
     #[serde(rename = "workingDir")]
     pub working_dir: Option<String>,
     #[serde(rename = "cacheSize")]
     pub cache_size: Option<usize>,
-    #[serde(rename = "contentTypes")]
+    /// Bytes of logs an output buffers at most before sending them, e.g. "256M". Default: 256M
+    #[serde(rename = "cacheBytes")]
+    pub cache_bytes: Option<String>,
+    /// Legacy, replaced by `subscriptions`
+    #[serde(default, rename = "contentTypes")]
     pub content_types: ContentTypesSubConfig,
     #[serde(rename = "maxThreads")]
     pub max_threads: Option<usize>,
+    /// Minutes, or a duration such as "1h30m". 0 never times out
     #[serde(rename = "globalTimeout")]
-    pub global_timeout: Option<usize>,
+    pub global_timeout: Option<Timeout>,
     pub retries: Option<usize>,
     #[serde(rename = "hoursToCollect")]
     pub hours_to_collect: Option<i64>,
+    /// Skip logs with an Id that was written before, see known_logs.rs
     #[serde(rename = "skipKnownLogs")]
     pub skip_known_logs: Option<bool>,
+    /// Log IDs remembered for skipKnownLogs per tenant
+    pub max_known_logs: Option<usize>,
     pub filter: Option<FilterSubConfig>,
+    /// RecordTypes to collect by subscription, see recordtype_filter.rs
+    #[serde(default)]
+    pub record_types: HashMap<String, RecordTypesSubConfig>,
+    /// Operations to collect by subscription, with * and ? wildcards
+    #[serde(default)]
+    pub operations: HashMap<String, OperationsSubConfig>,
     pub duplicate: Option<usize>,
+    /// Upper limit of API requests per second and tenant, lowered while being throttled
+    pub max_requests_per_second: Option<f64>,
+    /// Upper limit of Office Management API requests per minute of all tenants together
+    pub max_requests_per_minute: Option<f64>,
+    /// Ask the API for gzip compressed responses. Default: true
+    pub gzip: Option<bool>,
+    /// Capacities of the channels between listing, downloading and the outputs
+    pub channels: Option<ChannelsSubConfig>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelsSubConfig {
+    /// Content listing pages waiting to be requested. Default: 2000
+    pub blobs: Option<usize>,
+    /// Found blobs waiting to be downloaded. Default: 2000
+    pub content: Option<usize>,
+    /// Downloaded blobs waiting for the outputs. Default: maxThreads when logs are sent to
+    /// outputs other than file, 500 otherwise
+    pub results: Option<usize>,
 }
-#[derive(Deserialize, Copy, Clone, Debug)]
+impl CollectSubConfig {
+    /// Seconds a run may take, 30 minutes by default and 0 for no limit.
+    pub fn get_global_timeout(&self) -> u64 {
+        self.global_timeout.as_ref().map(Timeout::seconds).unwrap_or(30 * 60)
+    }
+
+    /// Filters of the logs of each content type, from `filter` and `record_types`.
+    pub fn get_log_filters(&self) -> Result<HashMap<String, LogFilter>, String> {
+        let mut filters = match &self.filter {
+            Some(filter) => LogFilter::for_content_types(&filter.get_filters())?,
+            None => HashMap::new(),
+        };
+        for (content_type, record_types) in &self.record_types {
+            let filter = filters.remove(content_type).unwrap_or_default();
+            filters.insert(content_type.clone(), filter.with_record_types(RecordTypeFilter::new(record_types)));
+        }
+        for (content_type, operations) in &self.operations {
+            let filter = filters.remove(content_type).unwrap_or_default();
+            let filter = filter.with_operations(operations.include.as_deref(), &operations.exclude)
+                .map_err(|e| format!("{}.{}", content_type, e))?;
+            filters.insert(content_type.clone(), filter);
+        }
+        Ok(filters)
+    }
+}
+
+/// Legacy settings count minutes, newer ones take a duration
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Timeout {
+    Minutes(u64),
+    Duration(String),
+}
+
+impl Timeout {
+    /// Seconds of the timeout, 0 for none.
+    pub fn seconds(&self) -> u64 {
+        match self {
+            Timeout::Minutes(minutes) => minutes.saturating_mul(60),
+            Timeout::Duration(duration) => Config::parse_interval(duration),
+        }
+    }
+}
+
+#[derive(Deserialize, Copy, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
 pub struct ContentTypesSubConfig {
     #[serde(rename = "Audit.General")]
     pub general: Option<bool>,
@@ -348,6 +1170,7 @@ impl ContentTypesSubConfig {
 }
 
 #[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct FilterSubConfig {
     #[serde(rename = "Audit.General")]
     pub general: Option<ArbitraryJson>,
@@ -383,39 +1206,844 @@ impl FilterSubConfig {
     }
 }
 
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RecordTypesSubConfig {
+    /// Only collect logs of these RecordTypes. Default: all
+    pub include: Option<Vec<i32>>,
+    /// Never collect logs of these RecordTypes
+    #[serde(default)]
+    pub exclude: Vec<i32>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct OperationsSubConfig {
+    /// Only collect logs of these Operations, e.g. "FileDeleted" or "Set-*". Default: all
+    pub include: Option<Vec<String>>,
+    /// Never collect logs of these Operations
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectionSubConfig {
+    /// Only keep these fields, dot separated for nested fields
+    pub keep_fields: Option<Vec<String>>,
+    /// Remove these fields, after keep_fields
+    #[serde(default)]
+    pub drop_fields: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RedactAction {
+    /// SHA-256, or HMAC-SHA256 with the rule's key, as hex
+    Hash,
+    /// Keep the first `length` characters
+    Truncate,
+    /// Replace with `replacement`
+    Replace,
+}
+
 #[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RedactSubConfig {
+    /// Fields to redact, dot separated for nested fields
+    pub fields: Vec<String>,
+    pub action: RedactAction,
+    /// Only redact logs sent to these outputs. Default: all
+    #[serde(default)]
+    pub outputs: Vec<String>,
+    /// Only redact logs of these content types. Default: all
+    #[serde(default)]
+    pub content_types: Vec<String>,
+    /// Key of the HMAC for hash, so hashes cannot be reversed by guessing
+    pub key: Option<String>,
+    /// Characters kept by truncate
+    pub length: Option<usize>,
+    /// Value of replace. Default: "REDACTED"
+    pub replacement: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ExpressionsSubConfig {
+    /// Only keep logs for which this is true
+    pub filter: Option<String>,
+    /// Fields to add, by name, computed from the log
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PluginSubConfig {
+    /// Path of the Lua script defining process(log, content_type)
+    pub lua: String,
+    /// Only call the script for logs of these content types. Default: all
+    #[serde(default)]
+    pub content_types: Vec<String>,
+    /// Time budget of each call. Default: 10
+    pub timeout_ms: Option<u64>,
+    /// Memory the script may use. Default: 64
+    pub max_memory_mb: Option<usize>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SampleSubConfig {
+    /// Named in the counts of sampled out logs
+    pub name: String,
+    /// Share of the matching logs to keep, 0 to 100
+    pub percent: f64,
+    /// Only sample logs of these content types. Default: all
+    #[serde(default)]
+    pub content_types: Vec<String>,
+    /// Only sample logs of these Operations, * and ? wildcards. Default: all
+    #[serde(default)]
+    pub operations: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SuppressSubConfig {
+    /// Named in the counts of suppressed logs
+    pub name: String,
+    /// UserIds with * and ? wildcards, e.g. "svc-*@contoso.com"
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// Client IP addresses and CIDR ranges
+    #[serde(default)]
+    pub ips: Vec<String>,
+    /// Only suppress logs of these content types. Default: all
+    #[serde(default)]
+    pub content_types: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RetrySubConfig {
+    pub max_retries: Option<u32>,
+    pub initial_backoff: Option<String>,  // e.g., "1s"
+    pub max_backoff: Option<String>,  // e.g., "1m"
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OversizedAction {
+    /// Remove drop_fields, then the largest other fields, until the log fits
+    Truncate,
+    /// Write the log to the dead_letter file instead of sending it
+    DeadLetter,
+    /// Send the log anyway, only counting it
+    Pass,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct OversizedSubConfig {
+    /// Largest serialized log, e.g. "8K"
+    pub max_size: Option<String>,
+    /// Default: truncate
+    pub action: Option<OversizedAction>,
+    /// Fields truncate removes first, in order
+    #[serde(default)]
+    pub drop_fields: Vec<String>,
+    /// JSON lines file of dead_letter. Default: <workingDir>/<output>_dead_letter.json
+    pub dead_letter: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BatchSubConfig {
+    /// Logs per batch. Default: collect.cacheSize
+    pub batch_size: Option<usize>,
+    /// Bytes of logs per batch, e.g. "10M". Default: collect.cacheBytes
+    pub batch_bytes: Option<String>,
+    /// Send a partial batch after this long, e.g. "10s". Default: only at the end of a run
+    pub flush_interval: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SpoolSubConfig {
+    pub directory: Option<String>,  // Default: <workingDir>/spool
+    pub max_size: Option<String>,  // Per output and tenant, e.g. "1G"
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RouteSubConfig {
+    /// Output names as used under `output` (file, graylog, logs_ingestion, ...)
+    pub outputs: Vec<String>,
+    /// Only these content types, all if empty
+    #[serde(default)]
+    pub content_types: Vec<String>,
+    /// Only these tenant IDs, all if empty
+    #[serde(default)]
+    pub tenants: Vec<String>,
+    /// Only logs whose fields equal these values
+    #[serde(default, rename = "match")]
+    pub matches: ArbitraryJson,
+    /// Only logs passing this filter, with the values and operators of collect.filter
+    #[serde(default)]
+    pub filter: ArbitraryJson,
+    /// Only logs for which this expression is true, see expression.rs
+    pub expression: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
 pub struct OutputSubConfig {
     pub file: Option<FileOutputSubConfig>,
     pub graylog: Option<GraylogOutputSubConfig>,
     pub fluentd: Option<FluentdOutputSubConfig>,
     #[serde(rename = "azureLogAnalytics")]
     pub oms: Option<OmsOutputSubConfig>,
+    pub event_hub: Option<EventHubOutputSubConfig>,
+    pub s3: Option<S3OutputSubConfig>,
+    pub azure_blob: Option<AzureBlobOutputSubConfig>,
+    pub logs_ingestion: Option<LogsIngestionOutputSubConfig>,
+    pub firehose: Option<FirehoseOutputSubConfig>,
+    pub qradar: Option<QRadarOutputSubConfig>,
+    pub stdout: Option<StdoutOutputSubConfig>,
+    pub wazuh: Option<WazuhOutputSubConfig>,
+    pub exec: Option<ExecOutputSubConfig>,
 }
 
 #[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct FileOutputSubConfig {
     pub path: String,
     #[serde(rename = "separateByContentType")]
     pub separate_by_content_type: Option<bool>,
     pub separator: Option<String>,
+    /// Gzip files when they are rotated
+    pub compress: Option<bool>,
+    pub rotate_size: Option<String>,  // e.g. "100M"
+    pub rotate_interval: Option<String>,  // e.g. "1h"
+    /// Number of rotated files to keep per output file, unlimited if unset
+    pub retention: Option<usize>,
 }
 
+/// No options yet, enabled with `stdout: {}`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct StdoutOutputSubConfig {}
+
 #[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct WazuhOutputSubConfig {
+    /// Defaults to /var/ossec/queue/sockets/queue
+    pub socket_path: Option<String>,
+    /// Location field of the queue message, defaults to "office365"
+    pub location: Option<String>,
+    /// Wrap logs as {"integration":"office365","office365":{...}} for the built-in Wazuh
+    /// Office 365 rules, defaults to true
+    pub wrap: Option<bool>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ExecOutputSubConfig {
+    /// Program to start, receives JSONL on stdin
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct GraylogOutputSubConfig {
     pub address: String,
     pub port: u16,
+    /// "json" (default, GELF style) or "cef"
+    pub format: Option<LogFormat>,
+    /// "tcp" (default) or "udp"
+    pub protocol: Option<GraylogProtocol>,
+    /// TCP only
+    pub tls: Option<TlsSubConfig>,
+    /// TCP message delimiter, "null" (default for json) or "newline" (default for cef)
+    pub framing: Option<Framing>,
+    /// UDP only: gzip messages (default true) and the maximum chunk size (default 1420)
+    pub compress: Option<bool>,
+    pub max_chunk_size: Option<usize>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GraylogProtocol {
+    Tcp,
+    Udp,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Framing {
+    Null,
+    Newline,
 }
 
 #[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct FluentdOutputSubConfig {
     #[serde(rename = "tenantName")]
     pub tenant_name: String,
     pub address: String,
     pub port: u16,
+    pub tls: Option<TlsSubConfig>,
+    /// Shared key of the in_forward <security> section, enables the handshake
+    pub shared_key: Option<String>,
+    /// <user> credentials, if the input has user_auth enabled
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Hostname presented in the handshake. Default: HOSTNAME environment variable
+    pub hostname: Option<String>,
+    /// Wait for the server to acknowledge every chunk. Default: false
+    pub require_ack: Option<bool>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct QRadarOutputSubConfig {
+    pub address: String,
+    pub port: u16,
+    /// Connect over TLS when set
+    pub tls: Option<TlsSubConfig>,
+    /// "leef" (default) or "cef"
+    pub format: Option<LogFormat>,
+    /// Product version in the LEEF/CEF header, defaults to "1.0"
+    pub product_version: Option<String>,
+}
+
+/// TLS settings, globally for the Office API and HTTP outputs and per socket output. Without
+/// ca_file the public webpki roots are trusted.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSubConfig {
+    /// PEM bundle of CAs to trust instead of the public roots
+    pub ca_file: Option<String>,
+    /// Name to verify the server certificate against, defaults to the address
+    pub server_name: Option<String>,
+    /// Accept any server certificate, only for testing
+    pub insecure_skip_verify: Option<bool>,
+    /// "1.2" (default) or "1.3"
+    pub min_version: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct OnErrorSubConfig {
+    /// Program run for every alert, with ALERT_* environment variables
+    pub exec: Option<String>,
+    /// Arguments of exec, with the same placeholders as message
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// URL every alert is POSTed to
+    pub url: Option<String>,
+    /// Body of url requests: json (default), slack or teams
+    pub format: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Alert text with {event}, {tenant_id}, {details} and {time} placeholders
+    pub message: Option<String>,
+    /// Consecutive runs a tenant's login fails before alerting. Default: 3
+    pub login_failures: Option<u32>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TracingSubConfig {
+    /// Base URL of an OTLP/HTTP receiver, e.g. http://otel-collector:4318
+    pub endpoint: String,
+    /// Sent with every export, e.g. an API key of the tracing backend
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// service.name of the traces. Default: office365-log-collector
+    pub service_name: Option<String>,
 }
 
 #[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookSubConfig {
+    /// Public HTTPS URL the API sends notifications to
+    pub address: String,
+    /// Local address to listen on. Default: 0.0.0.0:8443
+    pub listen: Option<String>,
+    /// Sent back by the API in the Webhook-AuthID header, notifications without it are rejected
+    pub auth_id: Option<String>,
+    /// Also list available content every run. Default: true
+    pub poll: Option<bool>,
+    /// PEM certificate and key to serve HTTPS directly instead of behind a reverse proxy
+    pub tls_certificate: Option<String>,
+    pub tls_key: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ControlApiSubConfig {
+    /// Local address to listen on. Default: 127.0.0.1:8080
+    pub listen: Option<String>,
+    /// Required as `Authorization: Bearer <token>` by every request
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct OmsOutputSubConfig {
     #[serde(rename = "workspaceId")]
     pub workspace_id: String,
+    /// Custom log type by content type, e.g. "Audit.Exchange": "O365Exchange", or by ASIM
+    /// schema with asim. Azure adds the _CL suffix. Default: the content type with '.' replaced
+    /// by '_', or O365_ASim<schema> with asim.
+    #[serde(default)]
+    pub log_types: HashMap<String, String>,
+    /// Log field used as TimeGenerated. Default: CreationTime (EventStartTime with asim)
+    pub time_generated_field: Option<String>,
+    /// Normalize logs to the ASIM AuditEvent/Authentication schemas. Default: false
+    pub asim: Option<bool>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct EventHubOutputSubConfig {
+    /// SAS connection string, e.g. "Endpoint=sb://ns.servicebus.windows.net/;SharedAccessKeyName=..;
+    /// SharedAccessKey=..;EntityPath=hub". Either this or `aad` must be set.
+    pub connection_string: Option<String>,
+    pub connection_string_path: Option<String>,
+    /// Namespace FQDN (ns.servicebus.windows.net), required when using AAD auth.
+    pub namespace: Option<String>,
+    /// Event Hub name, overrides EntityPath from the connection string.
+    pub event_hub: Option<String>,
+    pub aad: Option<AadAuthSubConfig>,
+}
+
+impl EventHubOutputSubConfig {
+    pub fn get_connection_string(&self) -> Result<Option<String>, String> {
+        if let Some(connection_string) = &self.connection_string {
+            return Ok(Some(connection_string.clone()));
+        }
+
+        if let Some(path) = &self.connection_string_path {
+            match std::fs::read_to_string(path) {
+                Ok(content) => Ok(Some(content.trim().to_string())),
+                Err(e) => Err(format!("Failed to read connection string from {}: {}", path, e))
+            }
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct S3OutputSubConfig {
+    pub bucket: String,
+    pub region: String,
+    /// Endpoint of S3-compatible storage (e.g. "https://minio.local:9000"). Defaults to AWS.
+    pub endpoint: Option<String>,
+    /// Address the bucket in the path instead of the host name. Defaults to true when a
+    /// custom endpoint is set.
+    pub path_style: Option<bool>,
+    /// Object key, supports {tenant}, {content_type}, {date}, {hour}, {timestamp} and {uuid}.
+    pub key_template: Option<String>,
+    pub compress: Option<bool>,
+    pub flush_size: Option<String>,  // e.g. "8M"
+    pub flush_interval: Option<String>,  // e.g. "5m"
+    /// Falls back to AWS_ACCESS_KEY_ID / AWS_SECRET_ACCESS_KEY / AWS_SESSION_TOKEN
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    pub session_token: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct FirehoseOutputSubConfig {
+    pub delivery_stream: String,
+    pub region: String,
+    /// Defaults to https://firehose.<region>.amazonaws.com
+    pub endpoint: Option<String>,
+    /// Falls back to AWS_ACCESS_KEY_ID / AWS_SECRET_ACCESS_KEY / AWS_SESSION_TOKEN
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    pub session_token: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AzureBlobOutputSubConfig {
+    pub account: String,
+    pub container: String,
+    /// Defaults to https://<account>.blob.core.windows.net
+    pub endpoint: Option<String>,
+    /// SAS token with create/write permission on the container. Either this or `aad` must be set.
+    pub sas_token: Option<String>,
+    pub aad: Option<AadAuthSubConfig>,
+    /// "hourly" or "daily" (default)
+    pub partitioning: Option<String>,
+    pub prefix: Option<String>,
+    pub compress: Option<bool>,
+    pub flush_size: Option<String>,  // e.g. "8M"
+    pub flush_interval: Option<String>,  // e.g. "5m"
+}
+
+/// Azure Monitor Logs Ingestion API (data collection rules), the successor of the HTTP Data
+/// Collector API used by `azureLogAnalytics`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LogsIngestionOutputSubConfig {
+    /// Data collection endpoint, e.g. "https://my-dce-abcd.westeurope-1.ingest.monitor.azure.com"
+    pub endpoint: String,
+    /// Immutable ID of the data collection rule ("dcr-...")
+    pub dcr_immutable_id: String,
+    /// Stream declared in the DCR, e.g. "Custom-Office365Audit_CL"
+    pub stream_name: String,
+    /// Optional stream per content type, or per ASIM schema with asim, overriding stream_name
+    #[serde(default)]
+    pub streams: HashMap<String, String>,
+    pub aad: AadAuthSubConfig,
+    /// Normalize logs to the ASIM AuditEvent/Authentication schemas. Default: false
+    pub asim: Option<bool>,
+}
+
+/// Azure AD app registration used by outputs that authenticate with a bearer token instead of
+/// a shared key.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AadAuthSubConfig {
+    pub tenant_id: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub client_secret_path: Option<String>,
+    /// Login endpoint, defaults to https://login.microsoftonline.com
+    pub authority_host: Option<String>,
+}
+
+impl AadAuthSubConfig {
+    pub fn get_secret(&self) -> Result<String, String> {
+        if let Some(secret) = &self.client_secret {
+            return Ok(secret.clone());
+        }
+
+        if let Some(secret_path) = &self.client_secret_path {
+            let secret_path = &crate::systemd::credential_path(secret_path)?;
+            match std::fs::read_to_string(secret_path) {
+                Ok(content) => Ok(content.trim().to_string()),
+                Err(e) => Err(format!("Failed to read secret from {}: {}", secret_path, e))
+            }
+        } else {
+            Err("Either client_secret or client_secret_path must be provided".to_string())
+        }
+    }
+}
+
+
+/// Line (1-based) of a path like `tenants[1].client_id` in block style YAML, None when it
+/// cannot be found, e.g. in flow style.
+fn find_line(yaml: &str, path: &str) -> Option<usize> {
+    let lines: Vec<&str> = yaml.lines().collect();
+    let indent = |line: &str| line.len() - line.trim_start().len();
+    let ignored = |line: &str| line.trim().is_empty() || line.trim_start().starts_with('#');
+    // Line of the current node and the column its children must be indented beyond
+    let mut position: Option<(usize, usize)> = None;
+
+    for segment in path.split('.') {
+        let (key, index) = match segment.split_once('[') {
+            Some((key, index)) => (key, Some(index.trim_end_matches(']').parse::<usize>().ok()?)),
+            None => (segment, None),
+        };
+        // Keys of a list item can start on the item's own line
+        let (start, parent) = match position {
+            Some((line, column)) => (line, Some(column)),
+            None => (0, None),
+        };
+        let mut found = None;
+        let mut child_column = None;
+        for (i, line) in lines.iter().enumerate().skip(start) {
+            if ignored(line) {
+                continue
+            }
+            if i > start && parent.is_some_and(|parent| indent(line) <= parent) {
+                break
+            }
+            let (mut column, mut content) = (indent(line), line.trim_start());
+            while let Some(rest) = content.strip_prefix("- ") {
+                column += 2 + rest.len() - rest.trim_start().len();
+                content = rest.trim_start();
+            }
+            if i == start && parent.is_some_and(|parent| column <= parent) {
+                continue
+            }
+            // Only direct children, not keys of the same name further down
+            if column != *child_column.get_or_insert(column) {
+                continue
+            }
+            if content.trim_start_matches(['"', '\'']).strip_prefix(key)
+                .is_some_and(|rest| rest.trim_start_matches(['"', '\'']).starts_with(':')) {
+                found = Some((i, column));
+                break
+            }
+        }
+        let (mut line, mut column) = found?;
+        if let Some(index) = index {
+            // Items of the sequence under the key
+            let mut items = lines.iter().enumerate().skip(line + 1)
+                .filter(|(_, l)| !ignored(l))
+                .take_while(|(_, l)| indent(l) > column || (indent(l) == column && l.trim_start().starts_with('-')))
+                .filter(|(_, l)| l.trim_start().starts_with('-'));
+            let (first, first_line) = items.next()?;
+            let item_indent = indent(first_line);
+            let (item, _) = std::iter::once((first, first_line))
+                .chain(items.filter(|(_, l)| indent(l) == item_indent))
+                .nth(index)?;
+            line = item;
+            column = item_indent;
+        }
+        position = Some((line, column));
+    }
+    position.map(|(line, _)| line + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_endpoints() {
+        let tenant = |api_type: &str| -> TenantConfig {
+            serde_yaml::from_str(&format!("{{tenant_id: t, client_id: c, api_type: {}}}", api_type)).unwrap()
+        };
+        let api_types: HashMap<String, ApiTypeSubConfig> = serde_yaml::from_str(
+            "{sovereign: {login_endpoint: 'https://login.example/', resource_endpoint: 'https://manage.example'}}").unwrap();
+
+        assert_eq!(tenant("dod").get_endpoints(&api_types).unwrap(),
+                   ("https://login.microsoftonline.us".to_string(), "https://manage.protection.apps.mil".to_string()));
+        assert_eq!(tenant("china").get_endpoints(&HashMap::new()).unwrap().1, "https://manage.office365.cn");
+        assert_eq!(tenant("sovereign").get_endpoints(&api_types).unwrap(),
+                   ("https://login.example".to_string(), "https://manage.example".to_string()));
+        assert_eq!(tenant("dod").get_graph_endpoint(&api_types).unwrap(), "https://dod-graph.microsoft.us");
+        assert!(tenant("sovereign").get_graph_endpoint(&api_types).is_err());
+        let error = tenant("moon").get_endpoints(&api_types).unwrap_err();
+        assert!(error.contains("'moon'") && error.contains("sovereign"));
+    }
+
+    #[test]
+    fn test_get_publisher_id() {
+        let tenant: TenantConfig = serde_yaml::from_str("{tenant_id: t, client_id: c}").unwrap();
+        assert_eq!(tenant.get_publisher_id(None), "t");
+        assert_eq!(tenant.get_publisher_id(Some("cli")), "cli");
+        let tenant = TenantConfig { publisher_id: Some("msp-customer".to_string()), ..tenant };
+        assert_eq!(tenant.get_publisher_id(Some("cli")), "msp-customer");
+    }
+
+    #[test]
+    fn test_state_durations() {
+        let config: Config = serde_yaml::from_str("{output: {}}").unwrap();
+        assert_eq!(config.get_lookback_overlap(), chrono::Duration::zero());
+        assert_eq!(config.get_state_safety_lag().num_minutes(), 5);
+        let config: Config = serde_yaml::from_str("{output: {}, lookback_overlap: 15m, state_safety_lag: 30s}").unwrap();
+        assert_eq!(config.get_lookback_overlap().num_minutes(), 15);
+        assert_eq!(config.get_state_safety_lag().num_seconds(), 30);
+    }
+
+    #[test]
+    fn test_max_catchup() {
+        let config: Config = serde_yaml::from_str(
+            "{output: {}, subscriptions: [Audit.Exchange], max_catchup: 48h}").unwrap();
+        let runs = config.get_needed_runs_from(Some(Utc::now() - chrono::Duration::try_hours(100).unwrap()));
+        let start = DateTime::parse_from_rfc3339(&runs["Audit.Exchange"][0].0).unwrap();
+        assert!((Utc::now() - start.with_timezone(&Utc)).num_minutes() <= 48 * 60);
+        assert_eq!(runs["Audit.Exchange"].len(), 2);
+    }
+
+    #[test]
+    fn test_get_initial_lookback() {
+        let config = |yaml: &str| -> Config { serde_yaml::from_str(yaml).unwrap() };
+        assert_eq!(config("{output: {}}").get_initial_lookback().num_seconds(), 1);
+        assert_eq!(config("{output: {}, initial_lookback: 24h}").get_initial_lookback().num_hours(), 24);
+        assert_eq!(config("{output: {}, initial_lookback: 30d}").get_initial_lookback().num_hours(), MAX_LOOKBACK_HOURS);
+    }
+
+    #[test]
+    fn test_get_state_backend() {
+        let config = |yaml: &str| -> Config { serde_yaml::from_str(yaml).unwrap() };
+        assert!(matches!(config("{output: {}}").get_state_backend(), Ok(StateBackend::File)));
+        assert!(matches!(config("{output: {}, state_backend: File}").get_state_backend(), Ok(StateBackend::File)));
+        assert!(matches!(config("{output: {}, state_backend: redis, redis: {url: 'redis://localhost'}}")
+            .get_state_backend(), Ok(StateBackend::Redis(_))));
+        assert!(config("{output: {}, state_backend: redis}").get_state_backend().is_err());
+        assert!(matches!(config("{output: {}, state_backend: sqlite, workingDir: /var/lib/o365}").get_state_backend(),
+            Ok(StateBackend::Sqlite(store)) if store.path() == Path::new("/var/lib/o365/office365-state.sqlite")));
+        assert!(matches!(config("{output: {}, state_backend: SQLite, sqlite: {path: /data/state.db}}").get_state_backend(),
+            Ok(StateBackend::Sqlite(store)) if store.path() == Path::new("/data/state.db")));
+        assert!(config("{output: {}, state_backend: etcd}").get_state_backend().is_err());
+    }
+
+    #[test]
+    fn test_try_parse() {
+        assert_eq!(Config::try_parse_interval("90"), Ok(90));
+        assert_eq!(Config::try_parse_interval(" 5m "), Ok(300));
+        assert_eq!(Config::try_parse_interval("7d"), Ok(7 * 86400));
+        assert_eq!(Config::try_parse_interval("1h30m"), Ok(5400));
+        assert_eq!(Config::try_parse_interval("1d 2h 3m 4s"), Ok(93784));
+        assert!(Config::try_parse_interval("5 minutes").is_err());
+        assert!(Config::try_parse_interval("1h30").is_err());
+        assert!(Config::try_parse_interval("").is_err());
+        assert!(Config::try_parse_interval("99999999999999999999d").is_err());
+        assert!(Config::try_parse_interval("1.5h").is_err());
+        assert!(Config::try_parse_interval("h").is_err());
+        assert_eq!(Config::parse_interval("soon"), 300);
+        assert_eq!(Config::try_parse_size("500k"), Ok(500 * 1024));
+        assert_eq!(Config::try_parse_size("2G"), Ok(2 * 1024 * 1024 * 1024));
+        assert_eq!(Config::try_parse_size("4096"), Ok(4096));
+        assert!(Config::try_parse_size("1T").is_err());
+        assert!(Config::try_parse_size("M").is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let yaml = "\
+interval: 5 minutes
+tenants:
+  - tenant_id: t1
+    client_id: c1
+    client_secret: s
+  # no credentials
+  - tenant_id: t2
+    client_id: c2
+    api_type: moon
+subscriptions:
+  - Audit.Exchange
+  - Audit.Exhange
+output:
+  file:
+    path: out.json
+    rotate_size: 10X
+";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.validate(yaml), vec![
+            "interval (line 1): invalid duration '5 minutes', expected e.g. 30s, 5m, 1h30m or 7d".to_string(),
+            "output.file.rotate_size (line 16): invalid size '10X', unit must be K, M or G".to_string(),
+            "tenants[1] (line 7): no credentials, set client_secret, client_secret_path or certificate_path".to_string(),
+            format!("tenants[1].api_type (line 9): {}", config.tenants[1].get_endpoints(&HashMap::new()).unwrap_err()),
+            "subscriptions[1] (line 12): unknown subscription 'Audit.Exhange', must be one of: \
+             Audit.AzureActiveDirectory, Audit.Exchange, Audit.SharePoint, Audit.General, DLP.All".to_string(),
+        ]);
+
+        let yaml = "{output: {}, max_concurrent_tenants: 0, max_logs_per_run: 0}";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.validate(yaml), vec!["max_concurrent_tenants: must be at least 1".to_string(),
+                                               "max_logs_per_run: must be at least 1".to_string()]);
+
+        let yaml = "{output: {}, tenant_source: {csv: tenants.csv, url: 'https://provisioning.example'}}";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.validate(yaml), vec!["tenant_source: set either csv or url".to_string()]);
+
+        let yaml = "{output: {}, on_error: {format: email}}";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.validate(yaml), vec!["on_error: set exec, url or both".to_string(),
+                                               "on_error.format: unknown format 'email', must be json, slack or teams".to_string()]);
+
+        let yaml = "{output: {}, tracing: {endpoint: 'otel-collector:4318'}}";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.validate(yaml), vec!["tracing.endpoint: 'otel-collector:4318' is not an http(s) URL".to_string()]);
+
+        let yaml = "{output: {}, collect: {filter: {Audit.Exchange: {UserId: {regex: '(svc'}}}}}";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.validate(yaml).len(), 1);
+        assert!(config.validate(yaml)[0].starts_with("collect.filter: Audit.Exchange.UserId: invalid pattern"));
+
+        let yaml = "{output: {}, collect: {record_types: {Audit.Exhange: {include: [1]}}}}";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.validate(yaml).len(), 1);
+        assert!(config.validate(yaml)[0].starts_with("collect.record_types: unknown subscription 'Audit.Exhange'"));
+
+        let yaml = "{output: {}, state_backend: redis}";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.validate(yaml).len(), 1);
+        assert!(config.validate(yaml)[0].starts_with("state_backend: "));
+    }
+
+    #[test]
+    fn test_unknown_fields() {
+        let error = serde_yaml::from_str::<Config>("output: {}\ntenants:\n  - tenant_id: t\n    client_id: c\n    client_secert: s\n")
+            .unwrap_err().to_string();
+        assert!(error.contains("tenants[0]") && error.contains("client_secert") && error.contains("line 5"), "{}", error);
+    }
+
+    #[test]
+    fn test_get_start_delay() {
+        let config: Config = serde_yaml::from_str("{output: {}}").unwrap();
+        assert_eq!(config.get_start_delay(5, 0.5), std::time::Duration::ZERO);
+        let config: Config = serde_yaml::from_str("{output: {}, tenant_stagger: 2s, schedule_jitter: 1m}").unwrap();
+        assert_eq!(config.get_start_delay(0, 0.0).as_secs(), 0);
+        assert_eq!(config.get_start_delay(3, 0.0).as_secs(), 6);
+        assert_eq!(config.get_start_delay(3, 0.5).as_secs(), 36);
+    }
+
+    #[test]
+    fn test_max_run_seconds() {
+        let config = |yaml: &str| -> Config { serde_yaml::from_str(yaml).unwrap() };
+        let tenants = "[{tenant_id: a, client_id: c}, {tenant_id: b, client_id: c}, {tenant_id: c, client_id: c}]";
+        assert_eq!(config(&format!("{{output: {{}}, tenants: {}}}", tenants)).get_max_run_seconds(), Some(1800));
+        assert_eq!(config(&format!("{{output: {{}}, tenants: {}, max_concurrent_tenants: 2, tenant_stagger: 10s, \
+                                    collect: {{globalTimeout: 1}}}}", tenants)).get_max_run_seconds(), Some(140));
+        assert_eq!(config("{output: {}, collect: {globalTimeout: 0}}").get_max_run_seconds(), None);
+        // The slowest tenant sets the limit
+        let tenants = "[{tenant_id: a, client_id: c, global_timeout: 2h}, {tenant_id: b, client_id: c}]";
+        assert_eq!(config(&format!("{{output: {{}}, tenants: {}}}", tenants)).get_max_run_seconds(), Some(7200));
+        let tenants = "[{tenant_id: a, client_id: c, global_timeout: 0}, {tenant_id: b, client_id: c}]";
+        assert_eq!(config(&format!("{{output: {{}}, tenants: {}}}", tenants)).get_max_run_seconds(), None);
+    }
+
+    #[test]
+    fn test_object_batching() {
+        let config: Config = serde_yaml::from_str(r#"
+            output:
+              s3: {bucket: b, region: eu-west-1, flush_size: 16M}
+              azure_blob: {account: a, container: c}
+            batching:
+              default: {batch_size: 1000, batch_bytes: 1M, flush_interval: 10s}
+              azure_blob: {flush_interval: 1m}
+        "#).unwrap();
+        assert_eq!(config.get_batching("s3"), (Some(1000), Some(300), Some(16 * 1024 * 1024)));
+        assert_eq!(config.get_batching("azure_blob"), (Some(1000), Some(60), Some(8 * 1024 * 1024)));
+        assert_eq!(config.get_batching("graylog"), (Some(1000), Some(10), Some(1024 * 1024)));
+    }
+
+    #[test]
+    fn test_global_timeout() {
+        let collect = |yaml: &str| -> CollectSubConfig { serde_yaml::from_str(yaml).unwrap() };
+        assert_eq!(collect("{}").get_global_timeout(), 1800);
+        assert_eq!(collect("{globalTimeout: 45}").get_global_timeout(), 2700);
+        assert_eq!(collect("{globalTimeout: 1h30m}").get_global_timeout(), 5400);
+        assert_eq!(collect("{globalTimeout: 0}").get_global_timeout(), 0);
+        let yaml = "{output: {}, collect: {globalTimeout: 90 minutes}}";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.validate(yaml).len(), 1);
+
+        let yaml = "{output: {}, collect: {globalTimeout: 10m}, tenants: [
+            {tenant_id: a, client_id: c, client_secret: s, global_timeout: 2h},
+            {tenant_id: b, client_id: c, client_secret: s, global_timeout: 5},
+            {tenant_id: c, client_id: c, client_secret: s}]}";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        let timeouts: Vec<u64> = config.tenants.iter().map(|tenant| config.get_tenant_timeout(tenant)).collect();
+        assert_eq!(timeouts, [7200, 300, 600]);
+        assert!(config.validate(yaml).is_empty());
+        let yaml = "{output: {}, tenants: [{tenant_id: a, client_id: c, client_secret: s, global_timeout: 2 hours}]}";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert!(config.validate(yaml)[0].starts_with("tenants[0].global_timeout"), "{:?}", config.validate(yaml));
+    }
+
+    #[test]
+    fn test_find_line() {
+        let yaml = "output:\n  file:\n    path: a\nlog:\n  path: b\nretry:\n  default:\n    max_backoff: 1m\n";
+        assert_eq!(find_line(yaml, "log.path"), Some(5));
+        assert_eq!(find_line(yaml, "output.file.path"), Some(3));
+        assert_eq!(find_line(yaml, "output.path"), None);
+        assert_eq!(find_line(yaml, "retry.default.max_backoff"), Some(8));
+        assert_eq!(find_line("{output: {}}", "output"), None);
+    }
 }
@@ -0,0 +1,127 @@
+// Order in which found content blobs are downloaded. Listings of all content types feed one
+// channel in whatever order pagination yields; the download tasks take the oldest blob waiting
+// instead, so under throttling or a global timeout the blobs closest to their expiry 7 days
+// after they were created are collected first.
+//
+// Blobs expire a fixed time after contentCreated, so the oldest is the one expiring first.
+// Up to `capacity` blobs are taken from the channel to choose from, which keeps the listings
+// waiting for the downloads (see collect.channels).
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use chrono::{DateTime, Utc};
+use futures::channel::mpsc::Receiver;
+use futures::{Stream, StreamExt};
+use crate::data_structures::ContentToRetrieve;
+use crate::state::parse_api_time;
+
+/// Stream of the blobs of a channel, oldest first.
+pub struct OldestFirst {
+    content_rx: Receiver<ContentToRetrieve>,
+    waiting: BinaryHeap<Reverse<Queued>>,
+    capacity: usize,
+    /// Blobs taken from the channel so far, so blobs expiring at once keep their order
+    taken: u64,
+    closed: bool,
+}
+
+struct Queued {
+    /// Blobs without a valid expiration go last
+    expiration: DateTime<Utc>,
+    order: u64,
+    content: ContentToRetrieve,
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.expiration, self.order).cmp(&(other.expiration, other.order))
+    }
+}
+
+impl OldestFirst {
+    pub fn new(content_rx: Receiver<ContentToRetrieve>, capacity: usize) -> Self {
+        OldestFirst { content_rx, waiting: BinaryHeap::new(), capacity: capacity.max(1), taken: 0, closed: false }
+    }
+}
+
+impl Stream for OldestFirst {
+    type Item = ContentToRetrieve;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        while !self.closed && self.waiting.len() < self.capacity {
+            match self.content_rx.poll_next_unpin(cx) {
+                Poll::Ready(Some(content)) => {
+                    let expiration = parse_api_time(&content.expiration).unwrap_or(DateTime::<Utc>::MAX_UTC);
+                    let order = self.taken;
+                    self.taken += 1;
+                    self.waiting.push(Reverse(Queued { expiration, order, content }));
+                },
+                Poll::Ready(None) => self.closed = true,
+                Poll::Pending => break,
+            }
+        }
+        match self.waiting.pop() {
+            Some(Reverse(queued)) => Poll::Ready(Some(queued.content)),
+            None if self.closed => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::SinkExt;
+
+    fn content(content_id: &str, expiration: &str) -> ContentToRetrieve {
+        ContentToRetrieve {
+            content_type: "Audit.Exchange".to_string(),
+            content_id: content_id.to_string(),
+            expiration: expiration.to_string(),
+            url: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oldest_first() {
+        let (mut content_tx, content_rx) = futures::channel::mpsc::channel(10);
+        for (content_id, expiration) in [("new", "2024-01-08T10:00:00.000Z"), ("old", "2024-01-07T10:00:00.000Z"),
+                                         ("invalid", "soon"), ("new2", "2024-01-08T10:00:00.000Z"),
+                                         ("oldest", "2024-01-07T09:00:00Z")] {
+            content_tx.send(content(content_id, expiration)).await.unwrap();
+        }
+        drop(content_tx);
+        let ids: Vec<String> = OldestFirst::new(content_rx, 10).map(|content| content.content_id).collect().await;
+        assert_eq!(ids, ["oldest", "old", "new", "new2", "invalid"]);
+    }
+
+    #[tokio::test]
+    async fn test_capacity() {
+        let (mut content_tx, content_rx) = futures::channel::mpsc::channel(10);
+        for (content_id, expiration) in [("c", "2024-01-08T03:00:00Z"), ("b", "2024-01-08T02:00:00Z"),
+                                         ("a", "2024-01-08T01:00:00Z")] {
+            content_tx.send(content(content_id, expiration)).await.unwrap();
+        }
+        drop(content_tx);
+        // "a" is not taken from the channel before the first blob is chosen
+        let ids: Vec<String> = OldestFirst::new(content_rx, 2).map(|content| content.content_id).collect().await;
+        assert_eq!(ids, ["b", "a", "c"]);
+    }
+}
@@ -0,0 +1,359 @@
+// Local HTTP control API of the daemon, for orchestration that drives the collector instead of
+// editing its config and restarting it. With a `control_api` section the daemon listens (on
+// localhost by default) for:
+//
+//   GET  /tenants               status of every tenant: paused, running with its progress and
+//                               the summary of its last run (as in run_summary.rs)
+//   GET  /tenants/{id}          status of one tenant
+//   POST /tenants/{id}/run      collect the tenant now, or right after the run in progress
+//   POST /tenants/{id}/pause    stop the tenant's run in progress and skip it until resumed
+//   POST /tenants/{id}/resume
+//   POST /reload                read the config file again, used from the next run on
+//
+// Responses are JSON. With a `token` every request needs an `Authorization: Bearer <token>`
+// header. Requests are read like webhook notifications, one per connection.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+use crate::config::{Config, ControlApiSubConfig};
+use crate::data_structures::{CliArgs, RunState};
+use crate::pause;
+use crate::run_summary::RunSummary;
+use crate::webhook::{read_request, Request};
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+
+/// What the control API shares with the daemon loop.
+#[derive(Clone, Default)]
+pub struct Control {
+    state: Arc<Mutex<ControlState>>,
+    run_requested: Arc<Notify>,
+}
+
+#[derive(Default)]
+struct ControlState {
+    /// Config of the latest run, for the tenants it has
+    config: Option<Config>,
+    /// Progress of the tenants being collected, by tenant ID
+    running: HashMap<String, Arc<tokio::sync::Mutex<RunState>>>,
+    /// Summary of the latest run of each tenant, by lowercase tenant ID
+    last_runs: HashMap<String, Value>,
+    /// Tenant IDs to collect as soon as possible
+    requested: Vec<String>,
+    /// Config read by /reload, for the next run
+    reloaded: Option<Config>,
+    next_run: Option<DateTime<Utc>>,
+}
+
+impl Control {
+
+    /// The config of the coming run, tenant_source tenants included.
+    pub fn set_config(&self, config: &Config) {
+        self.state.lock().unwrap().config = Some(config.clone());
+    }
+
+    /// The config read by the latest /reload since the previous call, if any.
+    pub fn take_reloaded(&self) -> Option<Config> {
+        self.state.lock().unwrap().reloaded.take()
+    }
+
+    /// Record that the tenants of config are being collected, returning their run states for
+    /// run_collection_for_all_tenants to update.
+    pub fn run_started(&self, config: &Config) -> HashMap<String, Arc<tokio::sync::Mutex<RunState>>> {
+        let run_states: HashMap<_, _> = config.tenants.iter()
+            .map(|tenant| (tenant.tenant_id.clone(), Arc::default()))
+            .collect();
+        self.state.lock().unwrap().running.extend(run_states.clone());
+        run_states
+    }
+
+    pub fn run_ended(&self, summary: &RunSummary) {
+        let mut state = self.state.lock().unwrap();
+        for tenant in &summary.tenants {
+            state.running.remove(&tenant.record.tenant_id);
+            let last_run = serde_json::to_value(tenant).unwrap_or(Value::Null);
+            state.last_runs.insert(tenant.record.tenant_id.to_lowercase(), last_run);
+        }
+    }
+
+    /// Wait until the next scheduled run or until runs are requested, returning the tenant IDs
+    /// requested (none when it is time for the scheduled run).
+    pub async fn wait_for_requests(&self, next_run: tokio::time::Instant, next_run_time: DateTime<Utc>) -> Vec<String> {
+        self.state.lock().unwrap().next_run = Some(next_run_time);
+        loop {
+            let requested = std::mem::take(&mut self.state.lock().unwrap().requested);
+            if !requested.is_empty() {
+                return requested
+            }
+            tokio::select! {
+                _ = tokio::time::sleep_until(next_run) => return Vec::new(),
+                _ = self.run_requested.notified() => {},
+            }
+        }
+    }
+
+    /// The configured tenant ID matching tenant_id, which may differ in case.
+    fn find_tenant(&self, tenant_id: &str) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state.config.as_ref()?.tenants.iter()
+            .find(|tenant| tenant.tenant_id.eq_ignore_ascii_case(tenant_id))
+            .map(|tenant| tenant.tenant_id.clone())
+    }
+
+    async fn tenant_status(&self, tenant_id: &str) -> Value {
+        let (running, last_run, run_requested) = {
+            let state = self.state.lock().unwrap();
+            (state.running.get(tenant_id).cloned(),
+             state.last_runs.get(&tenant_id.to_lowercase()).cloned(),
+             state.requested.iter().any(|id| id == tenant_id))
+        };
+        let progress = match running {
+            Some(run_state) => {
+                let run_state = run_state.lock().await;
+                json!({
+                    "blobs_found": run_state.stats.blobs_found,
+                    "blobs_successful": run_state.stats.blobs_successful,
+                    "blobs_failed": run_state.stats.blobs_error,
+                    "blobs_retried": run_state.stats.blobs_retried,
+                    "blobs_awaiting": run_state.awaiting_content_blobs,
+                    "logs_saved": run_state.logs_saved,
+                    "rate_limited": run_state.rate_limited,
+                    "content_queued": run_state.queues.content.depth(),
+                    "results_queued": run_state.queues.results.depth(),
+                    "buffered_bytes": run_state.buffered_bytes,
+                })
+            },
+            None => Value::Null,
+        };
+        json!({
+            "tenant_id": tenant_id,
+            "paused": pause::is_tenant_paused(tenant_id),
+            "running": !progress.is_null(),
+            "run_requested": run_requested,
+            "progress": progress,
+            "last_run": last_run.unwrap_or(Value::Null),
+        })
+    }
+
+    async fn status(&self) -> Value {
+        let (tenant_ids, paused, next_run) = {
+            let state = self.state.lock().unwrap();
+            let tenant_ids: Vec<String> = state.config.iter()
+                .flat_map(|config| config.tenants.iter().map(|tenant| tenant.tenant_id.clone()))
+                .collect();
+            (tenant_ids, state.config.as_ref().is_some_and(pause::is_paused), state.next_run)
+        };
+        let mut tenants = Vec::new();
+        for tenant_id in tenant_ids {
+            tenants.push(self.tenant_status(&tenant_id).await);
+        }
+        json!({"paused": paused, "next_run": next_run, "tenants": tenants})
+    }
+
+    /// Status code and JSON body of the response to a request.
+    async fn handle(&self, request: &Request, args: &CliArgs) -> (u16, Value) {
+        let path = request.path.split('?').next().unwrap_or_default();
+        let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["tenants"]) => (200, self.status().await),
+            ("GET", ["tenants", tenant_id]) => match self.find_tenant(tenant_id) {
+                Some(tenant_id) => (200, self.tenant_status(&tenant_id).await),
+                None => not_found(tenant_id),
+            },
+            ("POST", ["tenants", tenant_id, action]) => {
+                let Some(tenant_id) = self.find_tenant(tenant_id) else {
+                    return not_found(tenant_id)
+                };
+                match *action {
+                    "run" if pause::is_tenant_paused(&tenant_id) =>
+                        (409, json!({"error": format!("Tenant {} is paused", tenant_id)})),
+                    "run" => {
+                        let mut state = self.state.lock().unwrap();
+                        if !state.requested.contains(&tenant_id) {
+                            state.requested.push(tenant_id.clone());
+                        }
+                        self.run_requested.notify_one();
+                        info!("Run of tenant {} requested through the control API", tenant_id);
+                        (202, json!({"tenant_id": tenant_id, "run_requested": true}))
+                    },
+                    "pause" | "resume" => {
+                        let paused = *action == "pause";
+                        if pause::set_tenant_paused(&tenant_id, paused) {
+                            info!("Tenant {} {} through the control API", tenant_id, if paused { "paused" } else { "resumed" });
+                        }
+                        (200, json!({"tenant_id": tenant_id, "paused": paused}))
+                    },
+                    _ => (404, json!({"error": format!("Unknown action '{}'", action)})),
+                }
+            },
+            ("POST", ["reload"]) => match Config::load_for(args) {
+                Ok(config) => {
+                    info!("Config reloaded through the control API, used from the next run on");
+                    let tenants = config.tenants.len();
+                    self.state.lock().unwrap().reloaded = Some(config);
+                    (200, json!({"reloaded": true, "tenants": tenants}))
+                },
+                Err(e) => {
+                    warn!("Config not reloaded: {}", e);
+                    (400, json!({"error": e}))
+                }
+            },
+            _ => (404, json!({"error": format!("No {} {}", request.method, path)})),
+        }
+    }
+}
+
+fn not_found(tenant_id: &str) -> (u16, Value) {
+    (404, json!({"error": format!("Tenant {} is not configured", tenant_id)}))
+}
+
+/// Answer control requests until the process exits.
+pub async fn serve(config: ControlApiSubConfig, args: CliArgs, control: Control) -> Result<()> {
+
+    let listen = config.listen.clone().unwrap_or_else(|| DEFAULT_LISTEN.to_string());
+    let listener = TcpListener::bind(&listen).await
+        .map_err(|e| anyhow!("Could not listen for control requests on {}: {}", listen, e))?;
+    info!("Listening for control requests on {}", listen);
+    let token = Arc::new(config.token);
+    let args = Arc::new(args);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Could not accept control connection: {}", e);
+                continue
+            }
+        };
+        let (token, args, control) = (token.clone(), args.clone(), control.clone());
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, token.as_deref(), &args, &control).await {
+                debug!("Control connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(stream: S, token: Option<&str>, args: &CliArgs,
+                                                              control: &Control) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+    let (status, body) = match read_request(&mut stream).await {
+        Ok(request) if token.is_some_and(|token| request.headers.get("authorization") != Some(&format!("Bearer {}", token))) => {
+            warn!("Rejected control request with a wrong or missing token");
+            (401, json!({"error": "Unauthorized"}))
+        },
+        Ok(request) => control.handle(&request, args).await,
+        Err(e) => {
+            debug!("Invalid control request: {}", e);
+            (400, json!({"error": "Bad Request"}))
+        }
+    };
+    let reason = match status {
+        200 => "OK",
+        202 => "Accepted",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        409 => "Conflict",
+        _ => "Bad Request",
+    };
+    let body = body.to_string();
+    let response = format!("HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                           status, reason, body.len(), body);
+    let stream = stream.get_mut();
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use tokio::io::AsyncReadExt;
+    use crate::data_structures::RunStatistics;
+    use crate::run_ledger::RunRecord;
+    use crate::run_summary::TenantSummary;
+
+    async fn request(raw: &str, token: Option<&str>, control: &Control) -> (String, Value) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        client_write.write_all(raw.as_bytes()).await.unwrap();
+        let args = CliArgs::parse_from(["collector"]);
+        handle_connection(server, token, &args, control).await.unwrap();
+        let mut response = String::new();
+        client_read.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), serde_json::from_str(body).unwrap())
+    }
+
+    fn get(method: &str, path: &str) -> String {
+        format!("{} {} HTTP/1.1\r\nHost: collector\r\nAuthorization: Bearer secret\r\nContent-Length: 0\r\n\r\n", method, path)
+    }
+
+    #[tokio::test]
+    async fn test_control() {
+        let config: Config = serde_yaml::from_str("{output: {}, tenants: [\
+            {tenant_id: Tenant-Control-A, client_id: c, client_secret: s},
+            {tenant_id: tenant-control-b, client_id: c, client_secret: s}]}").unwrap();
+        let control = Control::default();
+        control.set_config(&config);
+        let token = Some("secret");
+
+        let (status, _) = request("GET /tenants HTTP/1.1\r\n\r\n", token, &control).await;
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+        let (status, body) = request(&get("GET", "/tenants"), token, &control).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body["tenants"].as_array().unwrap().len(), 2);
+        assert_eq!(body["tenants"][0]["running"], false);
+        assert_eq!(request(&get("GET", "/tenants/unknown"), token, &control).await.0, "HTTP/1.1 404 Not Found");
+
+        // Running, then done
+        let run_states = control.run_started(&config);
+        run_states["Tenant-Control-A"].lock().await.logs_saved = 42;
+        run_states["Tenant-Control-A"].lock().await.buffered_bytes = 1024;
+        let queues = run_states["Tenant-Control-A"].lock().await.queues.clone();
+        queues.content.sent();
+        queues.content.sent();
+        queues.content.received();
+        let (_, body) = request(&get("GET", "/tenants/tenant-control-a"), token, &control).await;
+        assert_eq!(body["tenant_id"], "Tenant-Control-A");
+        assert_eq!(body["progress"]["logs_saved"], 42);
+        assert_eq!((body["progress"]["content_queued"].as_u64(), body["progress"]["results_queued"].as_u64()),
+                   (Some(1), Some(0)));
+        assert_eq!(body["progress"]["buffered_bytes"], 1024);
+        assert_eq!(queues.content.max(), 2);
+        let record = RunRecord::new("Tenant-Control-A", Utc::now(), Vec::new(), &RunStatistics::default(), 42, false);
+        control.run_ended(&RunSummary::new(Utc::now(), vec![TenantSummary::new(record, Vec::new(), None)]));
+        let (_, body) = request(&get("GET", "/tenants/Tenant-Control-A"), token, &control).await;
+        assert_eq!(body["running"], false);
+        assert_eq!(body["last_run"]["logs_saved"], 42);
+
+        // Run requests wake the daemon loop
+        let (status, _) = request(&get("POST", "/tenants/TENANT-CONTROL-B/run"), token, &control).await;
+        assert_eq!(status, "HTTP/1.1 202 Accepted");
+        let next_run = tokio::time::Instant::now() + std::time::Duration::from_secs(60);
+        let requested = tokio::time::timeout(std::time::Duration::from_secs(1), control.wait_for_requests(next_run, Utc::now()))
+            .await.unwrap();
+        assert_eq!(requested, vec!["tenant-control-b".to_string()]);
+
+        request(&get("POST", "/tenants/tenant-control-b/pause"), token, &control).await;
+        assert!(pause::is_tenant_paused("tenant-control-b"));
+        assert_eq!(request(&get("POST", "/tenants/tenant-control-b/run"), token, &control).await.0, "HTTP/1.1 409 Conflict");
+        request(&get("POST", "/tenants/tenant-control-b/resume"), token, &control).await;
+        assert!(!pause::is_tenant_paused("tenant-control-b"));
+
+        // Without --config there is nothing to reload
+        let (status, body) = request(&get("POST", "/reload"), token, &control).await;
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+        assert!(body["error"].as_str().unwrap().contains("--config"));
+        assert!(control.take_reloaded().is_none());
+    }
+}
@@ -1,39 +1,90 @@
 use futures::channel::mpsc::{Sender, Receiver};
-use std::collections::HashMap;
-use std::fs::{self, OpenOptions};
-use std::io::{BufWriter, Write};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex as StdMutex};
-use reqwest::header::HeaderMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::api_connection::{Resubscriber, SharedToken};
+use crate::throttle::Throttle;
+use crate::graph::GraphSource;
+use crate::page_cursors::PageCursors;
+use crate::state::StateManager;
+use crate::known_logs::KnownLogs;
+use crate::lag::IngestionLag;
+use crate::log_filter::LogFilters;
+use crate::transform::LogTransform;
+use chrono::{DateTime, Utc};
 use serde_derive::Deserialize;
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use log::{info, warn};
 use serde_json::Value;
+use tokio_util::sync::CancellationToken;
 use crate::config::ContentTypesSubConfig;
+use crate::file_rotation::{open_shared, RotationPolicy, SharedFile};
+use crate::routing::Router;
 
 /// List of JSON responses (used to represent content blobs)
 pub type ArbitraryJson = HashMap<String, Value>;
 pub type JsonList = Vec<ArbitraryJson>;
 
 
+/// A log buffered for one or more outputs. Outputs that send a log unchanged share it; an
+/// output that redacts, renames or flattens it changes its own copy.
+pub type SharedLog = Arc<ArbitraryJson>;
+pub type SharedLogList = Vec<SharedLog>;
+
+/// Size of a log as JSON, counted without serializing it into memory.
+pub fn json_size(log: &ArbitraryJson) -> usize {
+    struct Counter(usize);
+    impl std::io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let mut counter = Counter(0);
+    match serde_json::to_writer(&mut counter, log) {
+        Ok(()) => counter.0,
+        Err(_) => 0,
+    }
+}
+
+
 #[derive(Default, Clone, Debug)]
 pub struct Caches {
-    pub general: JsonList,
-    pub aad: JsonList,
-    pub exchange: JsonList,
-    pub sharepoint: JsonList,
-    pub dlp: JsonList,
+    pub general: SharedLogList,
+    pub aad: SharedLogList,
+    pub exchange: SharedLogList,
+    pub sharepoint: SharedLogList,
+    pub dlp: SharedLogList,
+    /// Logs of other content types, e.g. from Microsoft Graph, by content type
+    pub other: BTreeMap<String, SharedLogList>,
     pub size: usize,
+    /// Full once the logs take this many bytes as JSON, however few they are
+    pub max_bytes: Option<usize>,
+    /// Bytes of the logs as JSON when they were inserted
+    pub bytes: usize,
 }
 impl Caches {
 
-    pub fn full(&self) -> bool {
-        let size = self.general.len()
+    pub fn len(&self) -> usize {
+        self.general.len()
             + self.aad.len()
             + self.exchange.len()
             + self.sharepoint.len()
-            + self.dlp.len();
-        size >= self.size
+            + self.dlp.len()
+            + self.other.values().map(|logs| logs.len()).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn full(&self) -> bool {
+        self.len() >= self.size || self.max_bytes.is_some_and(|max_bytes| self.bytes >= max_bytes)
     }
 
     pub fn new(size: usize) -> Self {
@@ -41,35 +92,47 @@ impl Caches {
         cache.size = size;
         cache
     }
-    pub fn insert(&mut self, log: ArbitraryJson, content_type: &String) {
+
+    pub fn with_max_bytes(mut self, max_bytes: Option<usize>) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+    #[allow(clippy::ptr_arg)]
+    pub fn insert(&mut self, log: impl Into<SharedLog>, content_type: &String) {
+        let log = log.into();
+        self.bytes += json_size(&log);
         match content_type.as_str() {
             "Audit.General" => self.general.push(log),
             "Audit.AzureActiveDirectory" => self.aad.push(log),
             "Audit.Exchange" => self.exchange.push(log),
             "Audit.SharePoint" => self.sharepoint.push(log),
             "DLP.All" => self.dlp.push(log),
-            _ => warn!("Unknown content type cached: {}", content_type),
+            _ => self.other.entry(content_type.clone()).or_default().push(log),
         }
     }
 
-    pub fn get_all_types(&self) -> [(String, &JsonList); 5] {
-        [
+    pub fn get_all_types(&self) -> Vec<(String, &SharedLogList)> {
+        let mut all = vec![
             ("Audit.General".to_string(), &self.general),
             ("Audit.AzureActiveDirectory".to_string(), &self.aad),
             ("Audit.Exchange".to_string(), &self.exchange),
             ("Audit.SharePoint".to_string(), &self.sharepoint),
             ("DLP.All".to_string(), &self.dlp)
-        ]
+        ];
+        all.extend(self.other.iter().map(|(content_type, logs)| (content_type.clone(), logs)));
+        all
     }
 
-    pub fn get_all(&mut self) -> [&mut JsonList; 5] {
-        [
+    pub fn get_all(&mut self) -> Vec<&mut SharedLogList> {
+        let mut all = vec![
             &mut self.general,
             &mut self.aad,
             &mut self.exchange,
             &mut self.sharepoint,
             &mut self.dlp
-        ]
+        ];
+        all.extend(self.other.values_mut());
+        all
     }
 }
 
@@ -79,6 +142,8 @@ impl Caches {
 #[derive(Deserialize, Debug)]
 pub struct AuthResult {
     pub access_token: String,
+    /// Seconds, a string in v1 token responses
+    pub expires_in: Option<Value>,
 }
 
 
@@ -93,6 +158,23 @@ pub struct ContentToRetrieve {
     pub url: String
 }
 
+/// Result of a retrieved content blob or Microsoft Graph page, sent from a download task to the
+/// collector. `logs` is only filled when interfaces other than the file writer are configured,
+/// so file-only deployments keep passing just a count.
+pub struct ContentResult {
+    pub count: usize,
+    pub logs: JsonList,
+    pub content_type: String,
+    /// The retrieved blob, None for Graph pages (see graph.rs)
+    pub content: Option<ContentToRetrieve>,
+    /// Latest CreationTime of the logs
+    pub latest: Option<DateTime<Utc>>,
+    /// Logs that could not be written to the file output, see FileWriter::write_logs
+    pub write_failures: usize,
+    /// IDs the logs added to the known logs, forgotten if they are not delivered
+    pub known_log_ids: Vec<String>,
+}
+
 /// Messages for status channel between main threads and the blob/content retrieving threads.
 /// Mainly used to keep track of which content still needs retrieving and which is finished, which
 /// is necessary for knowing when to terminate.
@@ -107,29 +189,75 @@ pub enum StatusMessage {
 /// Used by thread getting content blobs
 pub struct GetBlobConfig {
     pub client: reqwest::Client,
-    pub headers: HeaderMap,
+    pub token: SharedToken,
+    pub throttle: Throttle,
     pub status_tx: Sender<StatusMessage>,
     pub blobs_tx: Sender<(String, String)>,
     pub blob_error_tx: Sender<(String, String)>,
     pub content_tx: Sender<ContentToRetrieve>,
+    /// Progress of the listings, see page_cursors.rs
+    pub cursors: Arc<PageCursors>,
+    pub resubscriber: Resubscriber,
     pub threads: usize,
-    pub duplicate: usize
+    pub duplicate: usize,
+    pub queues: Arc<QueueDepths>,
+    /// Cancelled when the run stops, aborting the listings in flight
+    pub cancel: CancellationToken,
 }
 
 
 /// Used by thread getting content.
-/// MEMORY FIX: result_tx now carries (usize, ContentToRetrieve) — a log count, not
-/// a multi-MB response body String. Processing happens inline in the download task.
+/// MEMORY FIX: result_tx carries a ContentResult — a log count, plus the parsed logs only
+/// when forward_logs is set — not a multi-MB response body String. Processing happens
+/// inline in the download task.
 pub struct GetContentConfig {
     pub client: reqwest::Client,
-    pub headers: HeaderMap,
-    pub result_tx: Sender<(usize, ContentToRetrieve)>,
+    pub token: SharedToken,
+    pub throttle: Throttle,
+    pub result_tx: Sender<ContentResult>,
     pub content_error_tx: Sender<ContentToRetrieve>,
     pub status_tx: Sender<StatusMessage>,
     pub threads: usize,
     pub max_response_size: Option<usize>,
     pub file_writer: Arc<FileWriter>,
-    pub filters: HashMap<String, ArbitraryJson>,
+    pub filters: LogFilters,
+    pub transform: Arc<LogTransform>,
+    /// Decides which logs the file output receives
+    pub router: Arc<Router>,
+    pub forward_logs: bool,
+    /// Set with skipKnownLogs
+    pub known_logs: Option<Arc<KnownLogs>>,
+    pub queues: Arc<QueueDepths>,
+    /// Found blobs the oldest is chosen from, see content_queue.rs
+    pub queued: usize,
+    /// Cancelled when the run stops, aborting the downloads in flight
+    pub cancel: CancellationToken,
+}
+
+
+/// Used by the task retrieving logs from Microsoft Graph, see graph.rs
+pub struct GetGraphConfig {
+    pub client: reqwest::Client,
+    pub token: SharedToken,
+    pub throttle: Throttle,
+    pub result_tx: Sender<ContentResult>,
+    pub status_tx: Sender<StatusMessage>,
+    pub file_writer: Arc<FileWriter>,
+    pub filters: LogFilters,
+    pub transform: Arc<LogTransform>,
+    pub router: Arc<Router>,
+    pub forward_logs: bool,
+    pub sources: Vec<&'static GraphSource>,
+    pub tenant_id: String,
+    pub state_manager: Arc<StateManager>,
+    pub known_logs: Option<Arc<KnownLogs>>,
+    /// Start of the first run of a source
+    pub default_start: DateTime<Utc>,
+    /// Logs newer than this are left for the next run
+    pub delay: chrono::Duration,
+    pub queues: Arc<QueueDepths>,
+    /// Cancelled when the run stops, aborting the page in flight
+    pub cancel: CancellationToken,
 }
 
 
@@ -144,8 +272,15 @@ pub struct MessageLoopConfig {
     pub content_tx: Sender<ContentToRetrieve>,
     pub content_error_rx: Receiver<ContentToRetrieve>,
     pub urls: Vec<(String, String)>,
+    /// Microsoft Graph sources, each finishing like a content type
+    pub graph_sources: usize,
+    /// Blobs announced by webhook notifications
+    pub notified: Vec<ContentToRetrieve>,
     pub content_types: ContentTypesSubConfig,
     pub retries: usize,
+    pub cursors: Arc<PageCursors>,
+    /// Cancelled when the collector is stopped, e.g. at the global timeout
+    pub cancel: CancellationToken,
 }
 
 
@@ -165,6 +300,49 @@ pub struct RunState {
     pub awaiting_content_blobs: usize,
     pub stats: RunStatistics,
     pub rate_limited: bool,
+    /// Logs retrieved so far, after filtering
+    pub logs_saved: usize,
+    /// Items waiting in the channels of the run
+    pub queues: Arc<QueueDepths>,
+    /// Bytes of the logs buffered by all outputs
+    pub buffered_bytes: usize,
+}
+
+
+/// Items waiting in a channel, counted by its senders and its receiver, with the most that
+/// waited at once.
+#[derive(Default, Debug)]
+pub struct QueueDepth {
+    depth: AtomicUsize,
+    max: AtomicUsize,
+}
+
+impl QueueDepth {
+
+    pub fn sent(&self) {
+        let depth = self.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        self.max.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    pub fn received(&self) {
+        let _ = self.depth.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |depth| Some(depth.saturating_sub(1)));
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    pub fn max(&self) -> usize {
+        self.max.load(Ordering::Relaxed)
+    }
+}
+
+/// Depths of the channels that hold the most memory: found blobs waiting to be downloaded and
+/// downloaded logs waiting for the outputs.
+#[derive(Default, Debug)]
+pub struct QueueDepths {
+    pub content: QueueDepth,
+    pub results: QueueDepth,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -187,75 +365,165 @@ pub struct CliArgs {
     #[arg(long, help = "(DEPRECATED: Use config file) Secret key of app registration used to retrieve logs")]
     pub secret_key: Option<String>,
 
-    #[arg(short, long, default_value = "12345678-1234-1234-1234-123456789123", help = "Publisher ID, set to tenant-id if left empty.")]
-    pub publisher_id: String,
+    #[arg(short, long, help = "(DEPRECATED: Use config file) Publisher ID of tenants without a publisher_id, default their tenant ID.")]
+    pub publisher_id: Option<String>,
 
-    #[arg(long, help = "Path to mandatory config file.")]
-    pub config: String,
+    #[arg(long, help = "Path to the config file, needed for everything but generate-config.")]
+    pub config: Option<String>,
 
     #[arg(short, long, default_value = "", help = "Shared key for Azure Log Analytics Workspace.")]
     pub oms_key: String,
 
-    #[arg(short, long, required = false, help = "Interactive interface for (load) testing.")]
+    #[arg(short, long, required = false, help = "Collect every tenant in a terminal interface with their live progress and logs.")]
     pub interactive: bool,
+
+    #[arg(long, help = "Validate the config, resolve its secrets and check the working directory, then exit.")]
+    pub check_config: bool,
+
+    #[arg(long, help = "Only collect this tenant ID, can be repeated (default: all tenants).")]
+    pub tenant: Vec<String>,
+
+    #[arg(long, help = "Do not collect this tenant ID, can be repeated.")]
+    pub skip_tenant: Vec<String>,
+
+    #[arg(long, help = "Only collect this content type (e.g. DLP.All or Graph.SignIns), can be repeated (default: the configured ones).")]
+    pub content_type: Vec<String>,
+
+    #[arg(long, help = "Directory for state files and known blobs, overrides workingDir.")]
+    pub working_dir: Option<String>,
+
+    #[arg(long, help = "Run as a daemon with this interval (e.g. 5m), overrides interval.")]
+    pub interval: Option<String>,
+
+    #[arg(long, help = "Hours to collect without state, overrides collect.hoursToCollect.")]
+    pub hours_to_collect: Option<i64>,
+
+    #[arg(long, help = "Write logs only to this file instead of the configured outputs.")]
+    pub output_file: Option<String>,
+
+    #[arg(long, help = "error, warn, info, debug or trace, overrides log.level and log.debug.")]
+    pub log_level: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Management tasks run instead of a collection.
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Show, start or stop the audit feed subscriptions of the configured tenants.
+    Subscriptions {
+        #[arg(value_enum)]
+        action: SubscriptionAction,
+
+        #[arg(long, help = "Only manage the subscriptions of this tenant ID (default: all configured tenants).")]
+        tenant: Option<String>,
+
+        #[arg(long, help = "Content type to start or stop, can be repeated (default: the configured subscriptions).")]
+        content_type: Vec<String>,
+    },
+    /// Show or change the saved state (last_log_time) of tenants and subscriptions.
+    State {
+        #[command(subcommand)]
+        action: StateAction,
+    },
+    /// Write a commented sample config.
+    GenerateConfig {
+        #[arg(long, help = "Only the options a first deployment needs (default: all options).")]
+        minimal: bool,
+
+        #[arg(long, help = "File to write the sample to, must not exist yet (default: stdout).")]
+        output: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum StateAction {
+    /// Print the state of the configured tenants and subscriptions.
+    Show {
+        #[arg(long, help = "Only show the state of this tenant ID (default: all configured tenants).")]
+        tenant: Option<String>,
+    },
+    /// Delete the state of a tenant, so its next run is a first run.
+    Reset {
+        #[arg(long, help = "Tenant ID to reset.")]
+        tenant: String,
+
+        #[arg(long, help = "Only reset this subscription (default: all configured subscriptions).")]
+        subscription: Option<String>,
+    },
+    /// Set the last_log_time the next run starts from.
+    Set {
+        #[arg(long, help = "UTC time, e.g. 2024-01-31T12:00:00Z.")]
+        last_log_time: String,
+
+        #[arg(long, help = "Only set the state of this tenant ID (default: all configured tenants).")]
+        tenant: Option<String>,
+
+        #[arg(long, help = "Only set the state of this subscription (default: all configured subscriptions).")]
+        subscription: Option<String>,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum SubscriptionAction {
+    List,
+    Start,
+    Stop,
 }
 
 
 /// Thread-safe JSONL file writer that download tasks use to write logs directly to disk.
-/// Eliminates in-memory buffering by writing each log entry as it's parsed.
+/// Eliminates in-memory buffering by writing the logs of each blob as soon as it's parsed.
 ///
-/// Each content type has its own Mutex<BufWriter<File>> so concurrent download tasks
+/// Each content type has its own Mutex<RotatingFile> so concurrent download tasks
 /// writing to DIFFERENT content types don't contend. Same-type writes serialize on the
-/// Mutex (correct, since file appends must be ordered).
+/// Mutex (correct, since file appends must be ordered). Files are shared per path across
+/// tenants, and rolled over according to the RotationPolicy.
 pub struct FileWriter {
-    writers: HashMap<String, StdMutex<BufWriter<std::fs::File>>>,
-    unified_writer: Option<StdMutex<BufWriter<std::fs::File>>>,
+    writers: HashMap<String, SharedFile>,
+    unified_writer: Option<SharedFile>,
     separate: bool,
+    /// Logs that could not be written, retried when the run ends, see FileInterface
+    failed: StdMutex<Caches>,
+    /// Logs that could not be written and were not kept, because `failed` was full
+    overflow: AtomicUsize,
+    /// Logs written
+    written: AtomicUsize,
+    /// Lag of the logs written by this collector
+    pub lag: IngestionLag,
 }
 
+/// Most logs kept for retrying when writing to the file output fails, e.g. on a full disk
+const MAX_FAILED_WRITES: usize = 100_000;
+
 impl FileWriter {
     /// Create a FileWriter with separate files per content type.
-    pub fn new_separated(paths: HashMap<String, String>) -> Self {
+    pub fn new_separated(paths: HashMap<String, String>, policy: &RotationPolicy) -> std::io::Result<Self> {
         let mut writers = HashMap::new();
         for (content_type, path) in &paths {
-            // Ensure parent directory exists
-            if let Some(parent) = Path::new(path).parent() {
-                if !parent.as_os_str().is_empty() {
-                    let _ = fs::create_dir_all(parent);
-                }
-            }
-            let file = OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(path)
-                .unwrap_or_else(|e| panic!("Cannot open output file '{}': {}", path, e));
-            writers.insert(
-                content_type.clone(),
-                StdMutex::new(BufWriter::with_capacity(64 * 1024, file)),
-            );
+            writers.insert(content_type.clone(), Self::open(path, policy)?);
             info!("FileWriter: opened {} for {}", path, content_type);
         }
-        FileWriter { writers, unified_writer: None, separate: true }
+        Ok(FileWriter { writers, separate: true, ..Self::new_noop() })
     }
 
     /// Create a FileWriter with a single unified output file.
-    pub fn new_unified(path: &str) -> Self {
+    pub fn new_unified(path: &str, policy: &RotationPolicy) -> std::io::Result<Self> {
+        let file = Self::open(path, policy)?;
+        info!("FileWriter: opened {} (unified)", path);
+        Ok(FileWriter { unified_writer: Some(file), ..Self::new_noop() })
+    }
+
+    /// Open an output file, creating its directory.
+    fn open(path: &str, policy: &RotationPolicy) -> std::io::Result<SharedFile> {
         if let Some(parent) = Path::new(path).parent() {
             if !parent.as_os_str().is_empty() {
                 let _ = fs::create_dir_all(parent);
             }
         }
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(path)
-            .unwrap_or_else(|e| panic!("Cannot open output file '{}': {}", path, e));
-        info!("FileWriter: opened {} (unified)", path);
-        FileWriter {
-            writers: HashMap::new(),
-            unified_writer: Some(StdMutex::new(BufWriter::with_capacity(64 * 1024, file))),
-            separate: false,
-        }
+        open_shared(path, policy)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("Cannot open output file '{}': {}", path, e)))
     }
 
     /// Create an empty/no-op FileWriter (when no file output is configured).
@@ -264,37 +532,97 @@ impl FileWriter {
             writers: HashMap::new(),
             unified_writer: None,
             separate: false,
+            failed: StdMutex::new(Caches::new(MAX_FAILED_WRITES)),
+            overflow: AtomicUsize::new(0),
+            written: AtomicUsize::new(0),
+            lag: IngestionLag::default(),
         }
     }
 
-    /// Write a single JSONL line for a given content type.
-    pub fn write_log(&self, content_type: &str, json_line: &str) -> std::io::Result<()> {
-        if self.separate {
-            if let Some(mutex) = self.writers.get(content_type) {
-                let mut writer = mutex.lock().unwrap();
-                writer.write_all(json_line.as_bytes())?;
-                writer.write_all(b"\n")?;
+    /// Write a batch of JSONL lines of a content type, with their CreationTime to record their
+    /// lag. If the batch cannot be written, none of it is, and its lines are kept to be retried,
+    /// see take_failed.
+    pub fn write_logs(&self, content_type: &str, lines: &[(String, Option<DateTime<Utc>>)])
+        -> std::io::Result<()> {
+        let result = self.write_lines(content_type, lines);
+        if result.is_err() {
+            let mut failed = self.failed.lock().unwrap_or_else(|e| e.into_inner());
+            let mut overflow = 0;
+            for (json_line, _) in lines {
+                if failed.full() {
+                    overflow += 1;
+                } else if let Ok(log) = serde_json::from_str::<ArbitraryJson>(json_line) {
+                    failed.insert(log, &content_type.to_string());
+                }
+            }
+            if overflow > 0 && self.overflow.fetch_add(overflow, Ordering::Relaxed) == 0 {
+                warn!("FileWriter: {} logs are waiting to be retried, dropping further logs that cannot be written",
+                      MAX_FAILED_WRITES);
+            }
+        }
+        result
+    }
+
+    /// Write a batch of JSONL lines of a content type, without keeping them when that fails.
+    pub fn write_lines(&self, content_type: &str, lines: &[(String, Option<DateTime<Utc>>)])
+        -> std::io::Result<()> {
+        if lines.is_empty() {
+            return Ok(())
+        }
+        if let Some(mutex) = self.writer(content_type) {
+            let batch: Vec<&str> = lines.iter().map(|(json_line, _)| json_line.as_str()).collect();
+            mutex.lock().unwrap_or_else(|e| e.into_inner()).write_lines(&batch)?;
+            self.written.fetch_add(lines.len(), Ordering::Relaxed);
+            for creation_time in lines.iter().filter_map(|(_, creation_time)| *creation_time) {
+                self.lag.record(content_type, creation_time);
             }
-        } else if let Some(ref mutex) = self.unified_writer {
-            let mut writer = mutex.lock().unwrap();
-            writer.write_all(json_line.as_bytes())?;
-            writer.write_all(b"\n")?;
         }
         Ok(())
     }
 
-    /// Flush all buffered writers. Call at end of each collection run.
+    fn writer(&self, content_type: &str) -> Option<&SharedFile> {
+        match self.separate {
+            true => self.writers.get(content_type),
+            false => self.unified_writer.as_ref(),
+        }
+    }
+
+    /// Whether logs of a content type are written to a file.
+    pub fn writes(&self, content_type: &str) -> bool {
+        self.writer(content_type).is_some()
+    }
+
+    /// Logs that could not be written since the last call.
+    pub fn take_failed(&self) -> Caches {
+        let mut failed = self.failed.lock().unwrap_or_else(|e| e.into_inner());
+        std::mem::replace(&mut *failed, Caches::new(MAX_FAILED_WRITES))
+    }
+
+    /// Logs that could not be written nor kept to be retried since the last call.
+    pub fn take_overflow(&self) -> usize {
+        self.overflow.swap(0, Ordering::Relaxed)
+    }
+
+    /// Logs written since the last call.
+    pub fn take_written(&self) -> usize {
+        self.written.swap(0, Ordering::Relaxed)
+    }
+
+    /// Rotate files that are due. Call at end of each collection run.
     pub fn flush_all(&self) {
-        for (_, mutex) in &self.writers {
-            if let Ok(mut w) = mutex.lock() {
-                let _ = w.flush();
-            }
+        if let Err(e) = self.flush() {
+            warn!("FileWriter: flush failed: {}", e);
         }
-        if let Some(ref mutex) = self.unified_writer {
-            if let Ok(mut w) = mutex.lock() {
-                let _ = w.flush();
-            }
+    }
+
+    /// Rotate files that are due, returning the first error.
+    pub fn flush(&self) -> std::io::Result<()> {
+        let mut result = Ok(());
+        for mutex in self.writers.values().chain(self.unified_writer.iter()) {
+            let flushed = mutex.lock().unwrap_or_else(|e| e.into_inner()).flush();
+            result = result.and(flushed);
         }
+        result
     }
 
     /// Build output file paths for separate-by-content-type mode.
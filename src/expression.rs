@@ -0,0 +1,487 @@
+// A small expression language for `expressions`, to filter logs and compute fields beyond what
+// collect.filter can say, e.g.
+//
+//   RecordType in [15, 9] && !(lower(UserId) endsWith "@contoso.com")
+//   Operation + " by " + UserId
+//
+// Fields are named as in the log, dot separated for nested fields; a missing field is null and a
+// list in a path gives the list of what its items have (`ModifiedProperties.Name`). Values are
+// strings in double or single quotes, numbers, true, false, null and lists in brackets.
+// Operators, weakest binding first:
+//   ||  &&
+//   ==  !=  <  <=  >  >=  in  contains  startsWith  endsWith  matches (a regex string)
+//   +  -  (+ also joins text)
+//   *  /  %
+//   !  - (negation)
+// and the functions lower(x), upper(x) and len(x). A filter keeps a log when its expression is
+// true, a non-zero number or a non-empty string or list.
+
+use std::cmp::Ordering;
+use regex::Regex;
+use serde_json::{Map, Value};
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Text(String),
+    Number(f64),
+    Word(String),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: [&str; 19] = [
+    "&&", "||", "==", "!=", "<=", ">=", "!", "<", ">", "(", ")", "[", "]", ",", "+", "-", "*", "/", "%",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operator {
+    Equal, NotEqual, Less, LessEqual, Greater, GreaterEqual,
+    In, Contains, StartsWith, EndsWith,
+    Add, Subtract, Multiply, Divide, Remainder,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Function {
+    Lower,
+    Upper,
+    Len,
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Literal(Value),
+    Field(Vec<String>),
+    List(Vec<Expr>),
+    Not(Box<Expr>),
+    Negate(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Binary(Operator, Box<Expr>, Box<Expr>),
+    Matches(Box<Expr>, Regex),
+    Call(Function, Box<Expr>),
+}
+
+/// A parsed expression.
+#[derive(Clone, Debug)]
+pub struct Expression {
+    expr: Expr,
+}
+
+impl Expression {
+
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens, position: 0 };
+        let expr = parser.or()?;
+        match parser.tokens.get(parser.position) {
+            None => Ok(Expression { expr }),
+            Some(token) => Err(format!("unexpected {}", describe(token))),
+        }
+    }
+
+    pub fn evaluate(&self, log: &Map<String, Value>) -> Value {
+        evaluate(&self.expr, &|k| log.get(k))
+    }
+
+    /// Whether the expression is true for the log.
+    pub fn holds(&self, log: &Map<String, Value>) -> bool {
+        self.holds_for(&|k| log.get(k))
+    }
+
+    /// Whether the expression is true for a log whose top level fields `field` looks up.
+    pub fn holds_for<'a>(&self, field: &dyn Fn(&str) -> Option<&'a Value>) -> bool {
+        truthy(&evaluate(&self.expr, field))
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Text(text) => format!("\"{}\"", text),
+        Token::Number(number) => number.to_string(),
+        Token::Word(word) => format!("'{}'", word),
+        Token::Symbol(symbol) => format!("'{}'", symbol),
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' || c == '\'' {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    None => return Err(format!("unterminated string at position {}", start)),
+                    Some((_, quote)) if quote == c => break,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, 'n')) => text.push('\n'),
+                        Some((_, 't')) => text.push('\t'),
+                        Some((_, escaped)) => text.push(escaped),
+                        None => return Err(format!("unterminated string at position {}", start)),
+                    },
+                    Some((_, other)) => text.push(other),
+                }
+            }
+            tokens.push(Token::Text(text));
+        } else if c.is_ascii_digit() {
+            let mut number = String::new();
+            while let Some(&(_, digit)) = chars.peek().filter(|(_, d)| d.is_ascii_digit() || *d == '.') {
+                number.push(digit);
+                chars.next();
+            }
+            tokens.push(Token::Number(number.parse().map_err(|_| format!("invalid number '{}'", number))?));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut word = String::new();
+            while let Some(&(_, letter)) = chars.peek().filter(|(_, l)| l.is_alphanumeric() || *l == '_' || *l == '.') {
+                word.push(letter);
+                chars.next();
+            }
+            tokens.push(Token::Word(word));
+        } else {
+            let rest = &source[start..];
+            let symbol = SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol))
+                .ok_or_else(|| format!("unexpected '{}' at position {}", c, start))?;
+            for _ in 0..symbol.len() {
+                chars.next();
+            }
+            tokens.push(Token::Symbol(symbol));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn take_symbol(&mut self, symbol: &'static str) -> bool {
+        let found = self.peek() == Some(&Token::Symbol(symbol));
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect_symbol(&mut self, symbol: &'static str) -> Result<(), String> {
+        match self.take_symbol(symbol) {
+            true => Ok(()),
+            false => Err(match self.peek() {
+                Some(token) => format!("expected '{}' but found {}", symbol, describe(token)),
+                None => format!("expected '{}' at the end", symbol),
+            }),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut left = self.and()?;
+        while self.take_symbol("||") {
+            left = Expr::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut left = self.comparison()?;
+        while self.take_symbol("&&") {
+            left = Expr::And(Box::new(left), Box::new(self.comparison()?));
+        }
+        Ok(left)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let left = self.sum()?;
+        let operator = match self.peek() {
+            Some(Token::Symbol("==")) => Operator::Equal,
+            Some(Token::Symbol("!=")) => Operator::NotEqual,
+            Some(Token::Symbol("<")) => Operator::Less,
+            Some(Token::Symbol("<=")) => Operator::LessEqual,
+            Some(Token::Symbol(">")) => Operator::Greater,
+            Some(Token::Symbol(">=")) => Operator::GreaterEqual,
+            Some(Token::Word(word)) => match word.as_str() {
+                "in" => Operator::In,
+                "contains" => Operator::Contains,
+                "startsWith" => Operator::StartsWith,
+                "endsWith" => Operator::EndsWith,
+                "matches" => {
+                    self.position += 1;
+                    return match self.sum()? {
+                        Expr::Literal(Value::String(pattern)) => Regex::new(&pattern)
+                            .map(|regex| Expr::Matches(Box::new(left), regex))
+                            .map_err(|e| format!("invalid pattern: {}", e)),
+                        _ => Err("matches needs a string".to_string()),
+                    }
+                },
+                _ => return Ok(left),
+            },
+            _ => return Ok(left),
+        };
+        self.position += 1;
+        Ok(Expr::Binary(operator, Box::new(left), Box::new(self.sum()?)))
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut left = self.product()?;
+        loop {
+            let operator = match self.peek() {
+                Some(Token::Symbol("+")) => Operator::Add,
+                Some(Token::Symbol("-")) => Operator::Subtract,
+                _ => return Ok(left),
+            };
+            self.position += 1;
+            left = Expr::Binary(operator, Box::new(left), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+        loop {
+            let operator = match self.peek() {
+                Some(Token::Symbol("*")) => Operator::Multiply,
+                Some(Token::Symbol("/")) => Operator::Divide,
+                Some(Token::Symbol("%")) => Operator::Remainder,
+                _ => return Ok(left),
+            };
+            self.position += 1;
+            left = Expr::Binary(operator, Box::new(left), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.take_symbol("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)))
+        }
+        if self.take_symbol("-") {
+            return Ok(Expr::Negate(Box::new(self.unary()?)))
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let token = self.peek().cloned().ok_or_else(|| "unexpected end".to_string())?;
+        self.position += 1;
+        match token {
+            Token::Text(text) => Ok(Expr::Literal(Value::String(text))),
+            Token::Number(number) => Ok(Expr::Literal(number_value(number))),
+            Token::Symbol("(") => {
+                let expr = self.or()?;
+                self.expect_symbol(")")?;
+                Ok(expr)
+            },
+            Token::Symbol("[") => {
+                let mut items = Vec::new();
+                if !self.take_symbol("]") {
+                    loop {
+                        items.push(self.or()?);
+                        if self.take_symbol("]") {
+                            break
+                        }
+                        self.expect_symbol(",")?;
+                    }
+                }
+                Ok(Expr::List(items))
+            },
+            Token::Word(word) => match word.as_str() {
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                "null" => Ok(Expr::Literal(Value::Null)),
+                _ if self.peek() == Some(&Token::Symbol("(")) => {
+                    let function = match word.as_str() {
+                        "lower" => Function::Lower,
+                        "upper" => Function::Upper,
+                        "len" => Function::Len,
+                        _ => return Err(format!("unknown function '{}'", word)),
+                    };
+                    self.position += 1;
+                    let argument = self.or()?;
+                    self.expect_symbol(")")?;
+                    Ok(Expr::Call(function, Box::new(argument)))
+                },
+                _ if word.split('.').any(str::is_empty) => Err(format!("invalid field '{}'", word)),
+                _ => Ok(Expr::Field(word.split('.').map(String::from).collect())),
+            },
+            token => Err(format!("unexpected {}", describe(&token))),
+        }
+    }
+}
+
+fn number_value(number: f64) -> Value {
+    if number.fract() == 0.0 && number.abs() < i64::MAX as f64 {
+        Value::from(number as i64)
+    } else {
+        serde_json::Number::from_f64(number).map(Value::Number).unwrap_or(Value::Null)
+    }
+}
+
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(number) => number.as_f64() != Some(0.0),
+        Value::String(text) => !text.is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        Value::Object(object) => !object.is_empty(),
+    }
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+fn equal(a: &Value, b: &Value) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+fn field(value: &Value, path: &[String]) -> Value {
+    match (value, path.split_first()) {
+        (value, None) => value.clone(),
+        (Value::Object(object), Some((key, below))) => object.get(key).map_or(Value::Null, |nested| field(nested, below)),
+        (Value::Array(items), Some(_)) => Value::Array(items.iter()
+            .map(|item| field(item, path))
+            .filter(|value| !value.is_null())
+            .collect()),
+        _ => Value::Null,
+    }
+}
+
+fn evaluate<'a>(expr: &Expr, log: &dyn Fn(&str) -> Option<&'a Value>) -> Value {
+    match expr {
+        Expr::Literal(value) => value.clone(),
+        Expr::Field(path) => log(&path[0]).map_or(Value::Null, |value| field(value, &path[1..])),
+        Expr::List(items) => Value::Array(items.iter().map(|item| evaluate(item, log)).collect()),
+        Expr::Not(expr) => Value::Bool(!truthy(&evaluate(expr, log))),
+        Expr::Negate(expr) => evaluate(expr, log).as_f64().map_or(Value::Null, |number| number_value(-number)),
+        Expr::And(a, b) => Value::Bool(truthy(&evaluate(a, log)) && truthy(&evaluate(b, log))),
+        Expr::Or(a, b) => Value::Bool(truthy(&evaluate(a, log)) || truthy(&evaluate(b, log))),
+        Expr::Matches(expr, regex) => Value::Bool(match evaluate(expr, log) {
+            Value::Null => false,
+            value => regex.is_match(&text(&value)),
+        }),
+        Expr::Call(function, argument) => {
+            let argument = evaluate(argument, log);
+            match function {
+                Function::Lower => Value::String(text(&argument).to_lowercase()),
+                Function::Upper => Value::String(text(&argument).to_uppercase()),
+                Function::Len => Value::from(match &argument {
+                    Value::Array(items) => items.len(),
+                    Value::Object(object) => object.len(),
+                    other => text(other).chars().count(),
+                }),
+            }
+        },
+        Expr::Binary(operator, a, b) => binary(*operator, evaluate(a, log), evaluate(b, log)),
+    }
+}
+
+fn binary(operator: Operator, a: Value, b: Value) -> Value {
+    let ordering = || compare(&a, &b);
+    let bool = |holds: bool| Value::Bool(holds);
+    match operator {
+        Operator::Equal => bool(equal(&a, &b)),
+        Operator::NotEqual => bool(!equal(&a, &b)),
+        Operator::Less => bool(ordering() == Some(Ordering::Less)),
+        Operator::LessEqual => bool(matches!(ordering(), Some(Ordering::Less | Ordering::Equal))),
+        Operator::Greater => bool(ordering() == Some(Ordering::Greater)),
+        Operator::GreaterEqual => bool(matches!(ordering(), Some(Ordering::Greater | Ordering::Equal))),
+        Operator::In => bool(contains(&b, &a)),
+        Operator::Contains => bool(contains(&a, &b)),
+        Operator::StartsWith => bool(a.as_str().zip(b.as_str()).is_some_and(|(a, b)| a.starts_with(b))),
+        Operator::EndsWith => bool(a.as_str().zip(b.as_str()).is_some_and(|(a, b)| a.ends_with(b))),
+        Operator::Add => match (a.as_f64(), b.as_f64()) {
+            (Some(x), Some(y)) if a.is_number() && b.is_number() => number_value(x + y),
+            _ => Value::String(text(&a) + &text(&b)),
+        },
+        Operator::Subtract | Operator::Multiply | Operator::Divide | Operator::Remainder => {
+            let (Some(x), Some(y)) = (a.as_f64(), b.as_f64()) else {
+                return Value::Null
+            };
+            match operator {
+                Operator::Subtract => number_value(x - y),
+                Operator::Multiply => number_value(x * y),
+                _ if y == 0.0 => Value::Null,
+                Operator::Divide => number_value(x / y),
+                _ => number_value(x % y),
+            }
+        },
+    }
+}
+
+/// A list has an item equal to the value, or a text has the value as part.
+fn contains(container: &Value, value: &Value) -> bool {
+    match container {
+        Value::Array(items) => items.iter().any(|item| equal(item, value)),
+        Value::String(text) => value.as_str().is_some_and(|part| text.contains(part)),
+        _ => false,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_expression() {
+        let log = json!({
+            "RecordType": 15, "UserId": "Alice@Contoso.com", "Operation": "UserLoggedIn", "ClientIP": "203.0.113.5",
+            "ModifiedProperties": [{"Name": "Role", "NewValue": "Admin"}, {"Name": "Title"}],
+            "AppAccessContext": {"ClientAppName": "Teams"}, "Size": 2048,
+        });
+        let log = log.as_object().unwrap();
+        let evaluate = |source: &str| Expression::parse(source).unwrap().evaluate(log);
+        let holds = |source: &str| Expression::parse(source).unwrap().holds(log);
+
+        assert!(holds("RecordType in [15, 9] && !(lower(UserId) endsWith \"@fabrikam.com\")"));
+        assert!(!holds("RecordType in [15] && !(lower(UserId) endsWith '@contoso.com')"));
+        assert!(holds("RecordType == 15.0 && RecordType >= 10 && RecordType < 16 && Size / 1024 == 2"));
+        assert!(holds("AppAccessContext.ClientAppName == 'Teams' || false"));
+        assert!(holds("'Role' in ModifiedProperties.Name && ModifiedProperties.NewValue contains 'Admin'"));
+        assert!(holds("ClientIP matches '^203\\\\.0\\\\.113\\\\.' && ClientIP startsWith '203.' && ClientIP contains '113'"));
+        assert!(holds("Missing == null && !Missing && Missing != 'x'"));
+        assert!(holds("Operation > 'A' && len(ModifiedProperties) == 2 && len(Operation) == 12"));
+        assert!(!holds("Missing matches '.*' || UserId < 5"));
+        assert!(holds("1 + 2 * 3 == 7 && -(2 - 3) == 1 && 7 % 4 == 3 && (1 + 2) * 3 == 9"));
+
+        assert_eq!(evaluate("Operation + ' by ' + upper(UserId)"), json!("UserLoggedIn by ALICE@CONTOSO.COM"));
+        assert_eq!(evaluate("Size / 1024 / 8"), json!(0.25));
+        assert_eq!(evaluate("Size / 0"), Value::Null);
+        assert_eq!(evaluate("[RecordType, 'a\\'b', null]"), json!([15, "a'b", null]));
+
+        for (source, problem) in [("RecordType ==", "unexpected end"),
+                                  ("(RecordType == 15", "expected ')' at the end"),
+                                  ("RecordType = 15", "unexpected '=' at position 11"),
+                                  ("UserId matches '('", "invalid pattern"),
+                                  ("UserId matches Operation", "matches needs a string"),
+                                  ("trim(UserId)", "unknown function 'trim'"),
+                                  ("'open", "unterminated string at position 0"),
+                                  ("RecordType 15", "unexpected 15"),
+                                  ("a..b", "invalid field 'a..b'")] {
+            let error = Expression::parse(source).unwrap_err();
+            assert!(error.starts_with(problem), "{}: {}", source, error);
+        }
+    }
+}
@@ -0,0 +1,306 @@
+// Size/time based rotation of the JSONL output files, with optional gzip of rotated files and
+// pruning past a retention count.
+//
+// AuditGeneral.json is rolled to AuditGeneral-<period start>.json(.gz), where the period start
+// is aligned to rotate_interval (e.g. AuditGeneral-20240101T00.json.gz for hourly rotation).
+// Tenants are collected concurrently and write to the same files, so every path is opened once
+// per process and shared; otherwise a tenant could keep appending to a file another tenant
+// just rotated away.
+//
+// Lines are written a batch at a time, unbuffered. A batch that fails, e.g. on a full disk, is
+// cut off the file again, so the file never holds a partial line and the lines it does hold
+// were all written successfully.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex, OnceLock, Weak};
+use std::time::SystemTime;
+use chrono::{DateTime, TimeZone, Utc};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use log::{error, info, warn};
+use crate::config::{Config, FileOutputSubConfig};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RotationPolicy {
+    pub compress: bool,
+    pub rotate_size: Option<u64>,
+    /// Seconds
+    pub rotate_interval: Option<i64>,
+    /// Number of rotated files to keep per output file
+    pub retention: Option<usize>,
+}
+
+impl RotationPolicy {
+    pub fn from_config(config: &FileOutputSubConfig) -> Self {
+        let policy = RotationPolicy {
+            compress: config.compress.unwrap_or(false),
+            rotate_size: config.rotate_size.as_deref().map(|s| Config::parse_size(s) as u64),
+            rotate_interval: config.rotate_interval.as_deref().map(|s| Config::parse_interval(s) as i64),
+            retention: config.retention,
+        };
+        if !policy.rotates() && (policy.compress || policy.retention.is_some()) {
+            warn!("File output 'compress' and 'retention' only apply to rotated files, \
+                   set 'rotate_size' and/or 'rotate_interval'");
+        }
+        policy
+    }
+
+    fn rotates(&self) -> bool {
+        self.rotate_size.is_some() || self.rotate_interval.is_some()
+    }
+
+    /// Start of the rotation period containing `time`.
+    fn period_start(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        match self.rotate_interval {
+            Some(interval) if interval > 0 => {
+                let start = time.timestamp() - time.timestamp().rem_euclid(interval);
+                Utc.timestamp_opt(start, 0).single().unwrap_or(time)
+            },
+            _ => time,
+        }
+    }
+}
+
+pub type SharedFile = Arc<StdMutex<RotatingFile>>;
+
+/// Open `path` for appending, or return the handle already open in this process.
+pub fn open_shared(path: &str, policy: &RotationPolicy) -> io::Result<SharedFile> {
+    static OPEN_FILES: OnceLock<StdMutex<HashMap<PathBuf, Weak<StdMutex<RotatingFile>>>>> = OnceLock::new();
+
+    let mut open_files = OPEN_FILES.get_or_init(|| StdMutex::new(HashMap::new())).lock().unwrap();
+    open_files.retain(|_, file| file.strong_count() > 0);
+    let key = PathBuf::from(path);
+    if let Some(file) = open_files.get(&key).and_then(|f| f.upgrade()) {
+        return Ok(file);
+    }
+    let file = Arc::new(StdMutex::new(RotatingFile::open(&key, policy.clone())?));
+    open_files.insert(key, Arc::downgrade(&file));
+    Ok(file)
+}
+
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    /// Bytes written to the current file
+    size: u64,
+    period_start: DateTime<Utc>,
+    policy: RotationPolicy,
+}
+
+impl RotatingFile {
+
+    pub fn open(path: &Path, policy: RotationPolicy) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        // An existing file belongs to the period it was started in, so a restart after a
+        // long pause still rotates it under the right name.
+        let started = if metadata.len() > 0 {
+            metadata.created().or_else(|_| metadata.modified()).unwrap_or_else(|_| SystemTime::now())
+        } else {
+            SystemTime::now()
+        };
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file,
+            size: metadata.len(),
+            period_start: policy.period_start(DateTime::<Utc>::from(started)),
+            policy,
+        })
+    }
+
+    /// Write a batch of JSONL lines, rotating first if the current file is due. If that fails,
+    /// none of the lines are kept.
+    pub fn write_lines(&mut self, lines: &[&str]) -> io::Result<()> {
+        self.rotate_if_due(Utc::now())?;
+        let mut batch = Vec::with_capacity(lines.iter().map(|line| line.len() + 1).sum());
+        for line in lines {
+            batch.extend_from_slice(line.as_bytes());
+            batch.push(b'\n');
+        }
+        if let Err(e) = self.file.write_all(&batch) {
+            // Cut off what part of the batch was written
+            if let Err(truncate_error) = self.file.set_len(self.size) {
+                error!("Could not remove partly written lines from {}: {}", self.path.display(), truncate_error);
+            }
+            return Err(e);
+        }
+        self.size += batch.len() as u64;
+        Ok(())
+    }
+
+    /// Rotate if the current file is due.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.rotate_if_due(Utc::now())
+    }
+
+    fn is_due(&self, now: DateTime<Utc>) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let size_due = self.policy.rotate_size.map(|max| self.size >= max).unwrap_or(false);
+        let time_due = self.policy.rotate_interval
+            .map(|interval| now.timestamp() >= self.period_start.timestamp() + interval)
+            .unwrap_or(false);
+        size_due || time_due
+    }
+
+    fn rotate_if_due(&mut self, now: DateTime<Utc>) -> io::Result<()> {
+        if self.is_due(now) {
+            self.rotate(now)?;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self, now: DateTime<Utc>) -> io::Result<()> {
+        let rotated = rotated_path(&self.path, self.period_start);
+        fs::rename(&self.path, &rotated)?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.period_start = self.policy.period_start(now);
+        info!("Rotated {} to {}", self.path.display(), rotated.display());
+
+        let (path, compress, retention) = (self.path.clone(), self.policy.compress, self.policy.retention);
+        if compress {
+            // Compressing a large file takes a while, don't hold up the download tasks
+            std::thread::spawn(move || {
+                if let Err(e) = compress_file(&rotated) {
+                    error!("Could not compress rotated file {}: {}", rotated.display(), e);
+                }
+                if let Some(keep) = retention {
+                    prune_rotated(&path, keep);
+                }
+            });
+        } else if let Some(keep) = retention {
+            prune_rotated(&path, keep);
+        }
+        Ok(())
+    }
+}
+
+
+/// Split "dir/AuditGeneral.json" into ("dir", "AuditGeneral", ".json").
+fn split_path(path: &Path) -> (PathBuf, String, String) {
+    let dir = path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+    let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let extension = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+    (dir, stem, extension)
+}
+
+/// First free "<stem>-<period>[-n]<ext>" name, checking compressed names as well.
+fn rotated_path(path: &Path, period_start: DateTime<Utc>) -> PathBuf {
+    let (dir, stem, extension) = split_path(path);
+    let base = format!("{}-{}", stem, period_start.format("%Y%m%dT%H"));
+    let mut n = 0;
+    loop {
+        let name = if n == 0 { format!("{}{}", base, extension) } else { format!("{}-{}{}", base, n, extension) };
+        let candidate = dir.join(&name);
+        if !candidate.exists() && !dir.join(format!("{}.gz", name)).exists() {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+/// Gzip a file to "<file>.gz" and remove the original.
+fn compress_file(path: &Path) -> io::Result<()> {
+    let target = PathBuf::from(format!("{}.gz", path.display()));
+    let partial = PathBuf::from(format!("{}.gz.partial", path.display()));
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut encoder = GzEncoder::new(BufWriter::new(File::create(&partial)?), Compression::default());
+    io::copy(&mut reader, &mut encoder)?;
+    encoder.finish()?.flush()?;
+    fs::rename(&partial, &target)?;
+    fs::remove_file(path)
+}
+
+/// Delete the oldest rotated files of `path` so at most `keep` remain.
+fn prune_rotated(path: &Path, keep: usize) {
+    let (dir, stem, extension) = split_path(path);
+    let search_dir = if dir.as_os_str().is_empty() { PathBuf::from(".") } else { dir };
+    let prefix = format!("{}-", stem);
+    let entries = match fs::read_dir(&search_dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Could not list {} to prune rotated files: {}", search_dir.display(), e);
+            return
+        }
+    };
+    let mut rotated: Vec<(SystemTime, PathBuf)> = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| {
+            let name = e.file_name().to_string_lossy().to_string();
+            name.starts_with(&prefix)
+                && (name.ends_with(&extension) || name.ends_with(&format!("{}.gz", extension)))
+        })
+        .filter_map(|e| Some((e.metadata().ok()?.modified().ok()?, e.path())))
+        .collect();
+    if rotated.len() <= keep {
+        return
+    }
+    rotated.sort();
+    for (_, old) in rotated.iter().take(rotated.len() - keep) {
+        match fs::remove_file(old) {
+            Ok(()) => info!("Removed rotated file {} (retention {})", old.display(), keep),
+            Err(e) => warn!("Could not remove rotated file {}: {}", old.display(), e),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use flate2::read::GzDecoder;
+
+    #[test]
+    fn test_period_start() {
+        let policy = RotationPolicy { rotate_interval: Some(3600), ..Default::default() };
+        let time = Utc.with_ymd_and_hms(2024, 1, 1, 10, 42, 5).unwrap();
+        assert_eq!(policy.period_start(time), Utc.with_ymd_and_hms(2024, 1, 1, 10, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn test_rotated_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("AuditGeneral.json");
+        let period = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(rotated_path(&path, period), dir.path().join("AuditGeneral-20240101T00.json"));
+        File::create(dir.path().join("AuditGeneral-20240101T00.json.gz")).unwrap();
+        assert_eq!(rotated_path(&path, period), dir.path().join("AuditGeneral-20240101T00-1.json"));
+    }
+
+    #[test]
+    fn test_size_rotation_and_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("DLPAll.json");
+        let policy = RotationPolicy { rotate_size: Some(10), retention: Some(2), ..Default::default() };
+        let mut file = RotatingFile::open(&path, policy).unwrap();
+        for i in 0..7 {
+            file.write_lines(&[&format!("{{\"n\":{}}}", i)]).unwrap();
+            file.flush().unwrap();
+        }
+        let names: Vec<String> = fs::read_dir(dir.path()).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        // The active file plus the two newest rotated files
+        assert_eq!(names.len(), 3);
+        assert!(names.contains(&"DLPAll.json".to_string()));
+    }
+
+    #[test]
+    fn test_compress_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a-20240101T00.json");
+        fs::write(&path, "{\"a\":1}\n").unwrap();
+        compress_file(&path).unwrap();
+        assert!(!path.exists());
+        let mut content = String::new();
+        GzDecoder::new(File::open(dir.path().join("a-20240101T00.json.gz")).unwrap())
+            .read_to_string(&mut content).unwrap();
+        assert_eq!(content, "{\"a\":1}\n");
+    }
+}
@@ -0,0 +1,210 @@
+// Microsoft Sentinel ASIM normalization, so the built-in ASIM analytic rules and workbooks work
+// on O365 logs without custom parsers. Sign-ins (UserLoggedIn, UserLoginFailed and Azure AD STS
+// logons) become Authentication events, everything else AuditEvent. The original log is kept
+// in AdditionalFields.
+//
+// | O365 field     | AuditEvent           | Authentication       |
+// |----------------|----------------------|----------------------|
+// | CreationTime   | EventStartTime/End   | EventStartTime/End   |
+// | Operation      | Operation            | EventOriginalType    |
+// | Id             | EventOriginalUid     | EventOriginalUid     |
+// | ResultStatus   | EventResult          | EventResult          |
+// | UserId         | ActorUsername        | TargetUsername       |
+// | UserKey        | ActorUserId          | TargetUserId         |
+// | ClientIP       | SrcIpAddr            | SrcIpAddr            |
+// | UserAgent      | HttpUserAgent        | HttpUserAgent        |
+// | Workload       | TargetAppName        | TargetAppName        |
+// | ObjectId       | Object               |                      |
+// | LogonError     |                      | EventResultDetails   |
+
+use serde_json::Value;
+use crate::data_structures::ArbitraryJson;
+use crate::formatters::{strip_port, value_to_string, PRODUCT, VENDOR};
+
+/// AzureActiveDirectoryStsLogon
+const STS_LOGON_RECORD_TYPE: i64 = 15;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AsimSchema {
+    AuditEvent,
+    Authentication,
+}
+
+impl AsimSchema {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AsimSchema::AuditEvent => "AuditEvent",
+            AsimSchema::Authentication => "Authentication",
+        }
+    }
+
+    fn version(&self) -> &'static str {
+        match self {
+            AsimSchema::AuditEvent => "0.1",
+            AsimSchema::Authentication => "0.1.3",
+        }
+    }
+}
+
+/// Reshape a log into the ASIM schema it belongs to.
+pub fn normalize(log: &ArbitraryJson) -> (AsimSchema, ArbitraryJson) {
+
+    let field = |name: &str| log.get(name).map(value_to_string).filter(|v| !v.is_empty());
+    let operation = field("Operation").unwrap_or_default();
+    let schema = if operation == "UserLoggedIn" || operation == "UserLoginFailed"
+        || log.get("RecordType").and_then(|t| t.as_i64()) == Some(STS_LOGON_RECORD_TYPE) {
+        AsimSchema::Authentication
+    } else {
+        AsimSchema::AuditEvent
+    };
+
+    let mut record = ArbitraryJson::new();
+    let mut set = |key: &str, value: Option<String>| {
+        if let Some(value) = value {
+            record.insert(key.to_string(), Value::String(value));
+        }
+    };
+    let time = field("CreationTime").map(|t| if t.ends_with('Z') { t } else { format!("{}Z", t) });
+    set("TimeGenerated", time.clone());
+    set("EventStartTime", time.clone());
+    set("EventEndTime", time);
+    set("EventVendor", Some(VENDOR.to_string()));
+    set("EventProduct", Some(PRODUCT.to_string()));
+    set("EventSchema", Some(schema.name().to_string()));
+    set("EventSchemaVersion", Some(schema.version().to_string()));
+    set("EventOriginalType", Some(operation.clone()));
+    set("EventOriginalUid", field("Id"));
+    set("EventOriginalResultDetails", field("ResultStatus"));
+    set("SrcIpAddr", field("ClientIP").or_else(|| field("ActorIpAddress")).map(|ip| strip_port(&ip)));
+    set("HttpUserAgent", field("UserAgent").or_else(|| extended_property(log, "UserAgent")));
+    set("TargetAppName", field("Workload"));
+
+    let username_type = field("UserId").map(|u| if u.contains('@') { "UPN" } else { "Simple" }.to_string());
+    match schema {
+        AsimSchema::Authentication => {
+            let logoff = operation.contains("Logoff") || operation.contains("LoggedOut");
+            set("EventType", Some(if logoff { "Logoff" } else { "Logon" }.to_string()));
+            let result = if operation == "UserLoginFailed" { "Failure" } else { event_result(field("ResultStatus")) };
+            set("EventResult", Some(result.to_string()));
+            set("EventResultDetails", field("LogonError"));
+            set("TargetUsername", field("UserId"));
+            set("TargetUsernameType", username_type);
+            set("TargetUserId", field("UserKey"));
+            set("TargetAppId", field("ApplicationId"));
+        },
+        AsimSchema::AuditEvent => {
+            set("EventType", Some(audit_event_type(&operation).to_string()));
+            set("EventResult", Some(event_result(field("ResultStatus")).to_string()));
+            set("Operation", Some(operation));
+            set("Object", field("ObjectId"));
+            set("ActorUsername", field("UserId"));
+            set("ActorUsernameType", username_type);
+            set("ActorUserId", field("UserKey"));
+        },
+    }
+    record.insert("EventCount".to_string(), Value::from(1));
+    record.insert("AdditionalFields".to_string(), Value::Object(log.clone().into_iter().collect()));
+    (schema, record)
+}
+
+/// ASIM EventResult from an O365 ResultStatus.
+fn event_result(status: Option<String>) -> &'static str {
+    match status.map(|s| s.to_lowercase()).as_deref() {
+        Some("succeeded") | Some("success") | Some("true") => "Success",
+        Some("partiallysucceeded") => "Partial",
+        Some("failed") | Some("failure") | Some("false") => "Failure",
+        _ => "NA",
+    }
+}
+
+/// ASIM AuditEvent EventType guessed from the O365 operation name.
+fn audit_event_type(operation: &str) -> &'static str {
+    let matches = |words: &[&str]| words.iter().any(|w| operation.contains(w));
+    if matches(&["Enable"]) {
+        "Enable"
+    } else if matches(&["Disable"]) {
+        "Disable"
+    } else if matches(&["Delete", "Remove", "Purge"]) {
+        "Delete"
+    } else if matches(&["Add", "Create", "New", "Upload"]) {
+        "Create"
+    } else if matches(&["Set", "Update", "Modif", "Change", "Rename", "Move", "Restore"]) {
+        "Set"
+    } else if matches(&["Access", "View", "Read", "Download", "Get", "Search", "Preview", "Sync"]) {
+        "Read"
+    } else if matches(&["Run", "Execute", "Invoke", "Send"]) {
+        "Execute"
+    } else {
+        "Other"
+    }
+}
+
+/// Value of a {"Name": .., "Value": ..} entry in ExtendedProperties.
+fn extended_property(log: &ArbitraryJson, name: &str) -> Option<String> {
+    log.get("ExtendedProperties")?.as_array()?.iter()
+        .find(|p| p.get("Name").and_then(|n| n.as_str()) == Some(name))
+        .and_then(|p| p.get("Value"))
+        .map(value_to_string)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn log(value: Value) -> ArbitraryJson {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_audit_event() {
+        let (schema, record) = normalize(&log(json!({
+            "CreationTime": "2024-01-01T10:00:00",
+            "Id": "abc",
+            "Operation": "FileDeleted",
+            "ResultStatus": "Succeeded",
+            "UserId": "alice@contoso.com",
+            "ClientIP": "10.0.0.1:5000",
+            "Workload": "SharePoint",
+            "ObjectId": "https://contoso.sharepoint.com/doc.docx",
+        })));
+        assert_eq!(schema, AsimSchema::AuditEvent);
+        assert_eq!(record["EventType"], "Delete");
+        assert_eq!(record["EventResult"], "Success");
+        assert_eq!(record["EventStartTime"], "2024-01-01T10:00:00Z");
+        assert_eq!(record["ActorUsername"], "alice@contoso.com");
+        assert_eq!(record["ActorUsernameType"], "UPN");
+        assert_eq!(record["SrcIpAddr"], "10.0.0.1");
+        assert_eq!(record["Object"], "https://contoso.sharepoint.com/doc.docx");
+        assert_eq!(record["AdditionalFields"]["Id"], "abc");
+    }
+
+    #[test]
+    fn test_authentication() {
+        let (schema, record) = normalize(&log(json!({
+            "CreationTime": "2024-01-01T10:00:00",
+            "Operation": "UserLoginFailed",
+            "RecordType": 15,
+            "UserId": "bob@contoso.com",
+            "LogonError": "InvalidUserNameOrPassword",
+            "ExtendedProperties": [{"Name": "UserAgent", "Value": "Mozilla/5.0"}],
+        })));
+        assert_eq!(schema, AsimSchema::Authentication);
+        assert_eq!(record["EventType"], "Logon");
+        assert_eq!(record["EventResult"], "Failure");
+        assert_eq!(record["EventResultDetails"], "InvalidUserNameOrPassword");
+        assert_eq!(record["TargetUsername"], "bob@contoso.com");
+        assert_eq!(record["HttpUserAgent"], "Mozilla/5.0");
+        assert_eq!(record["EventSchemaVersion"], "0.1.3");
+    }
+
+    #[test]
+    fn test_audit_event_type() {
+        assert_eq!(audit_event_type("Set-Mailbox"), "Set");
+        assert_eq!(audit_event_type("FileAccessed"), "Read");
+        assert_eq!(audit_event_type("Add member to group."), "Create");
+        assert_eq!(audit_event_type("Disable Strong Authentication."), "Disable");
+        assert_eq!(audit_event_type("MipLabel"), "Other");
+    }
+}
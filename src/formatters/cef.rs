@@ -0,0 +1,155 @@
+// ArcSight CEF rendering for Graylog / syslog receivers that only parse CEF.
+//
+// Header: CEF:0|Microsoft|Office 365|<version>|<Operation>|<Operation>|<severity>|
+// Severity is 7 when ResultStatus reports a failure and 5 otherwise. Well known O365 fields
+// are mapped to CEF extension keys, all other top level fields are carried under their own
+// name (non alphanumeric characters removed), with nested values serialized as JSON.
+//
+// | O365 field     | CEF extension                    |
+// |----------------|----------------------------------|
+// | CreationTime   | rt (epoch milliseconds)          |
+// | UserId         | suser                            |
+// | ClientIP       | src                              |
+// | RecordType     | cat                              |
+// | Operation      | act                              |
+// | Id             | externalId                       |
+// | ResultStatus   | outcome                          |
+// | UserAgent      | requestClientApplication         |
+// | Workload       | cs1 (cs1Label=Workload)          |
+// | ObjectId       | cs2 (cs2Label=ObjectId)          |
+// | OrganizationId | cs3 (cs3Label=OrganizationId)    |
+
+use chrono::NaiveDateTime;
+use serde_json::Value;
+use crate::data_structures::ArbitraryJson;
+use crate::formatters::{strip_port, value_to_string, PRODUCT, VENDOR};
+
+const MAPPED_FIELDS: [(&str, &str); 8] = [
+    ("CreationTime", "rt"),
+    ("UserId", "suser"),
+    ("ClientIP", "src"),
+    ("RecordType", "cat"),
+    ("Operation", "act"),
+    ("Id", "externalId"),
+    ("ResultStatus", "outcome"),
+    ("UserAgent", "requestClientApplication"),
+];
+
+const CUSTOM_STRING_FIELDS: [(&str, &str); 3] = [
+    ("Workload", "cs1"),
+    ("ObjectId", "cs2"),
+    ("OrganizationId", "cs3"),
+];
+
+/// Render a log as a single CEF line (without line terminator).
+pub fn format_cef(log: &ArbitraryJson, product_version: &str) -> String {
+
+    let operation = log.get("Operation").map(value_to_string).unwrap_or_else(|| "Unknown".to_string());
+    let failed = log.get("ResultStatus")
+        .and_then(|s| s.as_str())
+        .map(|s| s.eq_ignore_ascii_case("failed") || s.eq_ignore_ascii_case("failure"))
+        .unwrap_or(false);
+    let mut line = format!("CEF:0|{}|{}|{}|{}|{}|{}|",
+                           VENDOR, PRODUCT, escape_header(product_version),
+                           escape_header(&operation), escape_header(&operation),
+                           if failed { 7 } else { 5 });
+
+    let mut extensions: Vec<(String, String)> = Vec::new();
+    for (field, key) in MAPPED_FIELDS.iter() {
+        if let Some(value) = log.get(*field) {
+            let value = match *field {
+                "CreationTime" => epoch_millis(&value_to_string(value)).unwrap_or_else(|| value_to_string(value)),
+                "ClientIP" => strip_port(&value_to_string(value)),
+                _ => value_to_string(value),
+            };
+            extensions.push((key.to_string(), value));
+        }
+    }
+    for (field, key) in CUSTOM_STRING_FIELDS.iter() {
+        if let Some(value) = log.get(*field) {
+            extensions.push((key.to_string(), value_to_string(value)));
+            extensions.push((format!("{}Label", key), field.to_string()));
+        }
+    }
+
+    let mut rest: Vec<(&String, &Value)> = log.iter()
+        .filter(|(k, _)| !MAPPED_FIELDS.iter().chain(CUSTOM_STRING_FIELDS.iter()).any(|(field, _)| field == k))
+        .collect();
+    rest.sort_by(|a, b| a.0.cmp(b.0));
+    for (key, value) in rest {
+        let key: String = key.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+        if !key.is_empty() {
+            extensions.push((key, value_to_string(value)));
+        }
+    }
+
+    let body = extensions.iter()
+        .map(|(k, v)| format!("{}={}", k, escape_extension(v)))
+        .collect::<Vec<String>>()
+        .join(" ");
+    line.push_str(&body);
+    line
+}
+
+/// CreationTime ("2024-01-01T10:00:00", optionally with fraction or Z) as epoch milliseconds.
+fn epoch_millis(time: &str) -> Option<String> {
+    NaiveDateTime::parse_from_str(time.trim_end_matches('Z'), "%Y-%m-%dT%H:%M:%S%.f")
+        .ok()
+        .map(|t| t.and_utc().timestamp_millis().to_string())
+}
+
+fn escape_header(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('|', "\\|").replace(['\r', '\n'], " ")
+}
+
+fn escape_extension(value: &str) -> String {
+    value.replace('\\', "\\\\")
+        .replace('=', "\\=")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_format_cef() {
+        let log: ArbitraryJson = serde_json::from_value(json!({
+            "CreationTime": "2024-01-01T10:00:00",
+            "Operation": "UserLoginFailed",
+            "UserId": "user@contoso.com",
+            "ClientIP": "10.0.0.1:50123",
+            "RecordType": 15,
+            "ResultStatus": "Failed",
+            "Workload": "AzureActiveDirectory",
+            "Extended_Properties": [{"Name": "a=b"}],
+        })).unwrap();
+        let line = format_cef(&log, "1.0");
+        assert_eq!(line, "CEF:0|Microsoft|Office 365|1.0|UserLoginFailed|UserLoginFailed|7|\
+                          rt=1704103200000 suser=user@contoso.com src=10.0.0.1 cat=15 act=UserLoginFailed \
+                          outcome=Failed cs1=AzureActiveDirectory cs1Label=Workload \
+                          ExtendedProperties=[{\"Name\":\"a\\=b\"}]");
+    }
+
+    #[test]
+    fn test_escaping() {
+        let log: ArbitraryJson = serde_json::from_value(json!({
+            "Operation": "a|b",
+            "Comment": "x\\y\nz",
+        })).unwrap();
+        let line = format_cef(&log, "1.0");
+        assert!(line.starts_with("CEF:0|Microsoft|Office 365|1.0|a\\|b|a\\|b|5|"));
+        assert!(line.ends_with("Comment=x\\\\y\\nz"));
+    }
+
+    #[test]
+    fn test_epoch_millis() {
+        assert_eq!(epoch_millis("1970-01-01T00:00:01").unwrap(), "1000");
+        assert_eq!(epoch_millis("1970-01-01T00:00:01.5Z").unwrap(), "1500");
+        assert!(epoch_millis("not a time").is_none());
+    }
+}
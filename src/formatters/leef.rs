@@ -0,0 +1,116 @@
+// LEEF 2.0 (IBM Log Event Extended Format) rendering for QRadar.
+//
+// Header: LEEF:2.0|Microsoft|Office 365|<version>|<Operation>|x09|
+// Attributes are tab separated key=value pairs. Well known O365 fields are mapped to the LEEF
+// predefined attributes, all other top level fields are carried under their own name, with
+// nested objects and arrays serialized as JSON.
+//
+// | O365 field   | LEEF attribute |
+// |--------------|----------------|
+// | CreationTime | devTime        |
+// | UserId       | usrName        |
+// | ClientIP     | src            |
+// | RecordType   | cat            |
+// | Operation    | header EventID |
+
+use serde_json::Value;
+use crate::data_structures::ArbitraryJson;
+use crate::formatters::{strip_port, value_to_string, PRODUCT, VENDOR};
+
+const DELIMITER: char = '\t';
+const DEV_TIME_FORMAT: &str = "yyyy-MM-dd'T'HH:mm:ss";
+
+const MAPPED_FIELDS: [(&str, &str); 4] = [
+    ("CreationTime", "devTime"),
+    ("UserId", "usrName"),
+    ("ClientIP", "src"),
+    ("RecordType", "cat"),
+];
+
+/// Render a log as a single LEEF 2.0 line (without line terminator).
+pub fn format_leef(log: &ArbitraryJson, product_version: &str) -> String {
+
+    let event_id = log.get("Operation").map(value_to_string).unwrap_or_else(|| "Unknown".to_string());
+    let mut line = format!("LEEF:2.0|{}|{}|{}|{}|x09|",
+                           VENDOR, PRODUCT, escape_header(product_version), escape_header(&event_id));
+
+    let mut attributes: Vec<(String, String)> = Vec::new();
+    for (field, attribute) in MAPPED_FIELDS.iter() {
+        if let Some(value) = log.get(*field) {
+            let value = value_to_string(value);
+            let value = if *field == "ClientIP" { strip_port(&value) } else { value };
+            attributes.push((attribute.to_string(), value));
+        }
+    }
+    if log.contains_key("CreationTime") {
+        attributes.push(("devTimeFormat".to_string(), DEV_TIME_FORMAT.to_string()));
+    }
+
+    let mut extensions: Vec<(&String, &Value)> = log.iter()
+        .filter(|(k, _)| !MAPPED_FIELDS.iter().any(|(field, _)| field == k))
+        .collect();
+    extensions.sort_by(|a, b| a.0.cmp(b.0));
+    for (key, value) in extensions {
+        attributes.push((key.replace(['=', DELIMITER, ' '], "_"), value_to_string(value)));
+    }
+
+    let body = attributes.iter()
+        .map(|(k, v)| format!("{}={}", k, escape_value(v)))
+        .collect::<Vec<String>>()
+        .join(&DELIMITER.to_string());
+    line.push_str(&body);
+    line
+}
+
+fn escape_header(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('|', "\\|").replace(['\r', '\n'], " ")
+}
+
+fn escape_value(value: &str) -> String {
+    value.replace([DELIMITER, '\r', '\n'], " ")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_format_leef() {
+        let log: ArbitraryJson = serde_json::from_value(json!({
+            "CreationTime": "2024-01-01T10:00:00",
+            "Operation": "FileAccessed",
+            "UserId": "user@contoso.com",
+            "ClientIP": "10.0.0.1:50123",
+            "RecordType": 6,
+            "Workload": "SharePoint",
+            "Site": {"Id": "abc"},
+        })).unwrap();
+        let line = format_leef(&log, "1.0");
+        assert!(line.starts_with("LEEF:2.0|Microsoft|Office 365|1.0|FileAccessed|x09|"));
+        let attributes: Vec<&str> = line.split("x09|").nth(1).unwrap().split('\t').collect();
+        assert_eq!(attributes, vec![
+            "devTime=2024-01-01T10:00:00",
+            "usrName=user@contoso.com",
+            "src=10.0.0.1",
+            "cat=6",
+            "devTimeFormat=yyyy-MM-dd'T'HH:mm:ss",
+            "Operation=FileAccessed",
+            "Site={\"Id\":\"abc\"}",
+            "Workload=SharePoint",
+        ]);
+    }
+
+    #[test]
+    fn test_escaping() {
+        let log: ArbitraryJson = serde_json::from_value(json!({
+            "Operation": "a|b",
+            "Comment": "line1\nline2\tend",
+        })).unwrap();
+        let line = format_leef(&log, "1.0");
+        assert!(line.starts_with("LEEF:2.0|Microsoft|Office 365|1.0|a\\|b|x09|"));
+        assert!(line.contains("Comment=line1 line2 end"));
+        assert!(!line.contains('\n'));
+    }
+}
@@ -0,0 +1,67 @@
+use std::net::{IpAddr, SocketAddr};
+use serde_derive::Deserialize;
+use serde_json::Value;
+use crate::data_structures::ArbitraryJson;
+
+pub(crate) mod asim;
+pub(crate) mod cef;
+pub(crate) mod leef;
+
+pub const VENDOR: &str = "Microsoft";
+pub const PRODUCT: &str = "Office 365";
+pub const DEFAULT_PRODUCT_VERSION: &str = "1.0";
+
+/// Wire format of a log for outputs that can send more than plain JSON.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Json,
+    Cef,
+    Leef,
+}
+
+impl LogFormat {
+    /// Render a log in this format as a single line (without line terminator).
+    pub fn format(&self, log: &ArbitraryJson, product_version: &str) -> String {
+        match self {
+            LogFormat::Json => serde_json::to_string(log).unwrap_or_default(),
+            LogFormat::Cef => cef::format_cef(log, product_version),
+            LogFormat::Leef => leef::format_leef(log, product_version),
+        }
+    }
+}
+
+pub(crate) fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+/// O365 reports ClientIP with a port at times ("1.2.3.4:50000", "[::1]:443"); SIEMs expect a
+/// bare address.
+pub(crate) fn strip_port(address: &str) -> String {
+    if address.parse::<IpAddr>().is_ok() {
+        return address.to_string();
+    }
+    match address.parse::<SocketAddr>() {
+        Ok(socket) => socket.ip().to_string(),
+        Err(_) => address.to_string(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_port() {
+        assert_eq!(strip_port("10.0.0.1"), "10.0.0.1");
+        assert_eq!(strip_port("10.0.0.1:50123"), "10.0.0.1");
+        assert_eq!(strip_port("[2001:db8::1]:443"), "2001:db8::1");
+        assert_eq!(strip_port("2001:db8::1"), "2001:db8::1");
+        assert_eq!(strip_port(""), "");
+    }
+}
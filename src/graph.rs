@@ -0,0 +1,205 @@
+// Collection of Entra ID logs and Defender/Purview security alerts from Microsoft Graph, next to
+// the Office Management Activity API.
+// Graph has no content blobs: every source is a list of logs, filtered on their timestamp and
+// paged with @odata.nextLink. A run retrieves the window from the end of the previous one (kept
+// by the state manager under the source's content type) up to shortly before now, as logs take a
+// few minutes to show up in Graph. Pages are processed like content blobs, so they go through the
+// same filters, file output, routing and interfaces.
+
+use chrono::{DateTime, Utc};
+use anyhow::{anyhow, Result};
+use futures::SinkExt;
+use log::{error, info};
+use serde_derive::Deserialize;
+use serde_json::Value;
+use crate::api_connection::{get_with_backoff, process_logs, until_stopped, LogProcessing};
+use crate::config::Config;
+use crate::data_structures::{ContentResult, GetGraphConfig, StatusMessage};
+use crate::state::TenantSubscriptionState;
+use crate::throttle;
+
+/// Logs per page, the maximum the audit log sources accept
+const PAGE_SIZE: usize = 999;
+const PAGE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+/// Default time logs are given to become available in Graph
+pub const DEFAULT_DELAY: &str = "5m";
+
+pub struct GraphSource {
+    /// Name in the `graph.sources` config
+    pub name: &'static str,
+    /// Content type of the logs, used for OriginFeed, state, file names and routing
+    pub content_type: &'static str,
+    path: &'static str,
+    /// Timestamp the window of a run is selected on
+    time_field: &'static str,
+}
+
+pub const SOURCES: [GraphSource; 3] = [
+    GraphSource {
+        name: "directoryAudits",
+        content_type: "Graph.DirectoryAudits",
+        path: "auditLogs/directoryAudits",
+        time_field: "activityDateTime",
+    },
+    GraphSource {
+        name: "signIns",
+        content_type: "Graph.SignIns",
+        path: "auditLogs/signIns",
+        time_field: "createdDateTime",
+    },
+    // Alerts are selected on their last update, so an alert is collected again whenever e.g. its
+    // status or evidence changes
+    GraphSource {
+        name: "alerts",
+        content_type: "Graph.SecurityAlerts",
+        path: "security/alerts_v2",
+        time_field: "lastUpdateDateTime",
+    },
+];
+
+#[derive(Deserialize)]
+struct GraphPage {
+    value: Vec<Value>,
+    #[serde(rename = "@odata.nextLink")]
+    next_link: Option<String>,
+}
+
+/// The Graph sources to collect, none without a `graph` section.
+pub fn sources(config: &Config) -> Result<Vec<&'static GraphSource>> {
+    let Some(graph) = &config.graph else {
+        return Ok(Vec::new())
+    };
+    graph.sources.iter()
+        .map(|name| SOURCES.iter().find(|source| source.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| anyhow!("Unknown graph source '{}', must be one of: {}", name,
+                                   SOURCES.iter().map(|s| s.name).collect::<Vec<_>>().join(", "))))
+        .collect()
+}
+
+/// Content types of the configured Graph sources, ignoring unknown ones.
+pub fn content_types(config: &Config) -> Vec<String> {
+    config.graph.iter()
+        .flat_map(|graph| graph.sources.iter())
+        .filter_map(|name| SOURCES.iter().find(|source| source.name.eq_ignore_ascii_case(name)))
+        .map(|source| source.content_type.to_string())
+        .collect()
+}
+
+/// Retrieve the logs of all configured sources. Every source reports FinishedContentBlobs when
+/// done, whether it succeeded or not.
+pub async fn get_graph_logs_async(config: GetGraphConfig) {
+    let sources = config.sources.clone();
+    futures::future::join_all(sources.into_iter().map(|source| {
+        let config = &config;
+        async move {
+            let mut status_tx = config.status_tx.clone();
+            if let Err(e) = get_source_logs(config, source).await {
+                error!("Could not retrieve {} from Microsoft Graph for tenant {}: {}",
+                       source.name, config.tenant_id, e);
+            }
+            status_tx.send(StatusMessage::FinishedContentBlobs).await.unwrap_or_else(
+                |e| panic!("Could not send status update, channel closed?: {}", e));
+        }
+    })).await;
+}
+
+/// Retrieve all pages of a source for the window since the last run. The state is only moved
+/// forward once every page was retrieved.
+async fn get_source_logs(config: &GetGraphConfig, source: &'static GraphSource) -> Result<()> {
+
+    let state_manager = &config.state_manager;
+    let start = state_manager.load_state(&config.tenant_id, source.content_type)
+        .map(|state| state.last_log_time)
+        .unwrap_or(config.default_start);
+    let end = Utc::now() - config.delay;
+    if end <= start {
+        return Ok(())
+    }
+
+    let mut status_tx = config.status_tx.clone();
+    let mut result_tx = config.result_tx.clone();
+    let mut next = Some(first_page_url(config.token.resource_endpoint(), source, start, end)?);
+    let mut total = 0;
+    while let Some(url) = next {
+        let request = get_with_backoff(&config.client, &url, PAGE_TIMEOUT, &config.token, &config.throttle,
+                                       throttle::GRAPH, &mut status_tx);
+        let resp = until_stopped(&config.cancel, request).await
+            .ok_or_else(|| anyhow!("run stopped before all pages were retrieved"))??;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            return Err(anyhow!("{} {}", status, resp.text().await.unwrap_or_default()))
+        }
+        let page: GraphPage = resp.json().await?;
+        next = page.next_link;
+        let processed = process_logs(page.value, source.content_type, &LogProcessing {
+            file_writer: &config.file_writer,
+            filters: &config.filters,
+            transform: &config.transform,
+            router: &config.router,
+            forward_logs: config.forward_logs,
+            known_logs: config.known_logs.as_deref(),
+        });
+        total += processed.count;
+        let result = ContentResult {
+            count: processed.count,
+            logs: processed.logs,
+            content_type: source.content_type.to_string(),
+            content: None,
+            latest: None,
+            write_failures: processed.write_failures,
+            known_log_ids: processed.known_log_ids,
+        };
+        result_tx.send(result).await?;
+        config.queues.results.sent();
+    }
+
+    let now = Utc::now();
+    let state = TenantSubscriptionState { last_log_time: end, last_run: now, first_run: false };
+    state_manager.save_state(&config.tenant_id, source.content_type, &state).map_err(|e| anyhow!(e))?;
+    info!("Retrieved {} logs from Microsoft Graph {} for tenant {}", total, source.name, config.tenant_id);
+    Ok(())
+}
+
+/// URL of the first page of logs in [start, end).
+fn first_page_url(graph_endpoint: &str, source: &GraphSource, start: DateTime<Utc>, end: DateTime<Utc>)
+    -> Result<String> {
+    let format = "%Y-%m-%dT%H:%M:%SZ";
+    let filter = format!("{field} ge {} and {field} lt {}", start.format(format), end.format(format),
+                         field = source.time_field);
+    let url = reqwest::Url::parse_with_params(
+        &format!("{}/v1.0/{}", graph_endpoint, source.path),
+        &[("$filter", filter), ("$top", PAGE_SIZE.to_string())])?;
+    Ok(url.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sources() {
+        let mut config: Config = serde_yaml::from_str(
+            "{output: {}, graph: {sources: [signIns, DirectoryAudits, alerts]}}").unwrap();
+        let sources = sources(&config).unwrap();
+        assert_eq!(sources.iter().map(|s| s.content_type).collect::<Vec<_>>(),
+                   vec!["Graph.SignIns", "Graph.DirectoryAudits", "Graph.SecurityAlerts"]);
+        config.graph.as_mut().unwrap().sources.push("riskyUsers".to_string());
+        assert!(super::sources(&config).is_err());
+        assert_eq!(content_types(&config).len(), 3);
+    }
+
+    #[test]
+    fn test_first_page_url() {
+        let start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        let end = DateTime::parse_from_rfc3339("2024-01-01T01:00:00Z").unwrap().with_timezone(&Utc);
+        let url = first_page_url("https://graph.microsoft.com", &SOURCES[1], start, end).unwrap();
+        assert_eq!(url, "https://graph.microsoft.com/v1.0/auditLogs/signIns?%24filter=createdDateTime+ge+\
+                         2024-01-01T00%3A00%3A00Z+and+createdDateTime+lt+2024-01-01T01%3A00%3A00Z&%24top=999");
+
+        let page: GraphPage = serde_json::from_str(
+            r#"{"value": [{"id": "1"}], "@odata.nextLink": "https://graph.microsoft.com/next"}"#).unwrap();
+        assert_eq!(page.value.len(), 1);
+        assert_eq!(page.next_link.as_deref(), Some("https://graph.microsoft.com/next"));
+    }
+}
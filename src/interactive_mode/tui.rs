@@ -21,6 +21,7 @@ use tokio::{
 use tokio_util::sync::CancellationToken;
 
 #[derive(Clone, Debug)]
+#[allow(dead_code)]
 pub enum Event {
     Init,
     Error,
@@ -37,43 +38,14 @@ pub enum Event {
 #[derive(Copy, Clone)]
 pub enum Action {
     Tick,
-    GoToCommand,
-    GoToSubscriptions,
-    GoToLogs,
-    GoToResults,
-    HandleUp,
-    HandleDown,
-    HandleLeft,
-    HandleRight,
-    HandleEnter,
-    EnableSubscriptionGeneral,
-    DisableSubscriptionGeneral,
-    EnableSubscriptionAad,
-    DisableSubscriptionAad,
-    EnableSubscriptionSharePoint,
-    DisableSubscriptionSharePoint,
-    EnableSubscriptionExchange,
-    DisableSubscriptionExchange,
-    EnableSubscriptionDlp,
-    DisableSubscriptionDlp,
-    DisconnectApi,
-    ConnectApi,
+    Render,
+    SelectPrevious,
+    SelectNext,
     ScrollPageUp,
     ScrollPageDown,
-    UpdateFoundBlobs(usize),
-    UpdateAwaitingBlobs(usize),
-    UpdateSuccessfulBlobs(usize),
-    UpdateErrorBlobs(usize),
-    UpdateRetryBlobs(usize),
-    LogsRetrieved(usize),
-    LogsRetrievedSpeed((f64, f64)),
-    RunProgress(u16),
-    RunStarted,
-    RunEnded,
-    RateLimited,
-    NotRateLimited,
+    StartRun,
+    ToggleAllLogs,
     Quit,
-    Render,
     None,
 }
 
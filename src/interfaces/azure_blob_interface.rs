@@ -0,0 +1,158 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::info;
+use crate::aad_auth::AadTokenProvider;
+use crate::aws_sigv4::encode_path;
+use crate::tls;
+use crate::config::Config;
+use crate::data_structures::Caches;
+use crate::interfaces::file_interface::gzip;
+use crate::interfaces::interface::Interface;
+use crate::interfaces::object_upload::jsonl_objects;
+
+const STORAGE_SCOPE: &str = "https://storage.azure.com/.default";
+const STORAGE_API_VERSION: &str = "2021-08-06";
+
+enum BlobAuth {
+    Sas(String),
+    Aad(AadTokenProvider),
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Partitioning {
+    Hourly,
+    Daily,
+}
+
+/// Interface that archives logs as NDJSON block blobs (optionally gzipped) in an Azure Storage
+/// container, partitioned by tenant, content type and day or hour:
+/// <prefix>/<tenant>/<content type>/YYYY/MM/DD[/HH]/<timestamp>-<uuid>.json[.gz]
+pub struct AzureBlobInterface {
+    client: reqwest::Client,
+    container_url: String,
+    auth: BlobAuth,
+    partitioning: Partitioning,
+    prefix: String,
+    compress: bool,
+    tenant_id: String,
+}
+
+impl AzureBlobInterface {
+
+    pub fn new(config: Config, tenant_id: String) -> Result<Self> {
+
+        let blob_config = config.output.azure_blob.as_ref()
+            .ok_or_else(|| anyhow!("No azure_blob output configured"))?;
+
+        let auth = if let Some(sas) = &blob_config.sas_token {
+            BlobAuth::Sas(sas.trim_start_matches('?').to_string())
+        } else if let Some(aad) = &blob_config.aad {
+            BlobAuth::Aad(AadTokenProvider::new(aad, STORAGE_SCOPE, tls::http_client(config.tls.as_ref())?)?)
+        } else {
+            return Err(anyhow!("azure_blob output requires either 'sas_token' or 'aad'"));
+        };
+
+        let partitioning = match blob_config.partitioning.as_deref().unwrap_or("daily") {
+            "daily" => Partitioning::Daily,
+            "hourly" => Partitioning::Hourly,
+            other => return Err(anyhow!("Invalid azure_blob partitioning '{}', must be 'hourly' or 'daily'", other)),
+        };
+
+        let endpoint = blob_config.endpoint.clone()
+            .unwrap_or_else(|| format!("https://{}.blob.core.windows.net", blob_config.account));
+        let container_url = format!("{}/{}", endpoint.trim_end_matches('/'), blob_config.container);
+
+        info!("Azure Blob interface writing to {}", container_url);
+        Ok(AzureBlobInterface {
+            client: tls::http_client(config.tls.as_ref())?,
+            container_url,
+            auth,
+            partitioning,
+            prefix: blob_config.prefix.clone().unwrap_or_default(),
+            compress: blob_config.compress.unwrap_or(true),
+            tenant_id,
+        })
+    }
+
+    async fn upload(&mut self, content_type: &str, data: &[u8]) -> Result<()> {
+
+        let body = if self.compress { gzip(data)? } else { data.to_vec() };
+        let extension = if self.compress { "json.gz" } else { "json" };
+        let name = blob_name(&self.prefix, &self.tenant_id, content_type, self.partitioning,
+                             extension, Utc::now());
+        let url = format!("{}/{}", self.container_url, encode_path(&name));
+        let (url, bearer) = match &mut self.auth {
+            BlobAuth::Sas(sas) => (format!("{}?{}", url, sas), None),
+            BlobAuth::Aad(provider) => (url, Some(provider.get_token().await?)),
+        };
+
+        let mut builder = self.client.put(&url);
+        if let Some(token) = bearer {
+            builder = builder.header("Authorization", format!("Bearer {}", token));
+        }
+        let content_type_header = if self.compress { "application/gzip" } else { "application/x-ndjson" };
+        let response = builder
+            .header("x-ms-blob-type", "BlockBlob")
+            .header("x-ms-version", STORAGE_API_VERSION)
+            .header("x-ms-date", Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+            .header("Content-Type", content_type_header)
+            .body(body)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            return Err(anyhow!("Azure Storage returned {} for {}: {}", status, name, text));
+        }
+        info!("Uploaded {} bytes of {} logs to blob {}", data.len(), content_type, name);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Interface for AzureBlobInterface {
+
+    /// Upload the logs of each content type of the batch as one object. The batching of the
+    /// output (flush_size and flush_interval by default) decides how large objects get.
+    async fn send_logs(&mut self, logs: Caches) -> Result<()> {
+        for (content_type, data) in jsonl_objects(&logs) {
+            self.upload(&content_type, &data).await
+                .map_err(|e| anyhow!("Error uploading {} logs to Azure Blob Storage: {}", content_type, e))?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Build the partitioned blob name for an upload.
+fn blob_name(prefix: &str, tenant_id: &str, content_type: &str, partitioning: Partitioning,
+             extension: &str, now: DateTime<Utc>) -> String {
+    let partition = match partitioning {
+        Partitioning::Daily => now.format("%Y/%m/%d").to_string(),
+        Partitioning::Hourly => now.format("%Y/%m/%d/%H").to_string(),
+    };
+    let file = format!("{}-{}.{}", now.format("%Y%m%dT%H%M%S"), uuid::Uuid::new_v4(), extension);
+    [prefix.trim_matches('/'), tenant_id, content_type, &partition, &file].iter()
+        .filter(|p| !p.is_empty())
+        .copied()
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blob_name() {
+        let now = DateTime::parse_from_rfc3339("2024-03-05T07:08:09Z").unwrap().with_timezone(&Utc);
+        let name = blob_name("", "tenant-1", "Audit.Exchange", Partitioning::Daily, "json.gz", now);
+        assert!(name.starts_with("tenant-1/Audit.Exchange/2024/03/05/20240305T070809-"));
+        assert!(name.ends_with(".json.gz"));
+
+        let name = blob_name("/o365/", "tenant-1", "DLP.All", Partitioning::Hourly, "json", now);
+        assert!(name.starts_with("o365/tenant-1/DLP.All/2024/03/05/07/20240305T070809-"));
+    }
+}
@@ -1,3 +1,6 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
@@ -5,24 +8,66 @@ use chrono::Utc;
 use futures::{stream, StreamExt};
 use hmac::{Hmac, Mac};
 use log::{error, info, warn};
+use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
 use sha2::Sha256;
+use tokio::time::sleep;
+use crate::tls;
 use crate::config::Config;
 use crate::data_structures::Caches;
+use crate::formatters::asim::{self, AsimSchema};
 use crate::interfaces::interface::Interface;
 
+const RESOURCE: &str = "/api/logs";
+
+const DEFAULT_TIME_GENERATED_FIELD: &str = "CreationTime";
+const ASIM_TIME_GENERATED_FIELD: &str = "EventStartTime";
+
 pub struct OmsInterface {
     config: Config,
-    key: String
+    client: reqwest::Client,
+    key: String,
+    log_types: HashMap<String, String>,
+    time_generated_field: String,
+    asim: bool,
+    stats: OmsStats,
 }
 
 impl OmsInterface {
 
-    pub fn new(config: Config, key: String) -> Self {
+    pub fn new(config: Config, key: String) -> Result<Self> {
 
-        OmsInterface {
+        let oms_config = config.output.oms.as_ref().unwrap();
+        let mut log_types = HashMap::new();
+        for (content_type, log_type) in oms_config.log_types.iter() {
+            let log_type = log_type.strip_suffix("_CL").unwrap_or(log_type);
+            if !valid_log_type(log_type) {
+                warn!("Invalid OMS log type '{}' for {}: use letters, digits and '_' only, at most \
+                       100 characters. Using the default log type.", log_type, content_type);
+                continue
+            }
+            log_types.insert(content_type.clone(), log_type.to_string());
+        }
+        let asim = oms_config.asim.unwrap_or(false);
+        let time_generated_field = oms_config.time_generated_field.clone()
+            .unwrap_or_else(|| if asim { ASIM_TIME_GENERATED_FIELD } else { DEFAULT_TIME_GENERATED_FIELD }.to_string());
+        Ok(OmsInterface {
+            client: tls::http_client(config.tls.as_ref())?,
             config,
             key,
-        }
+            log_types,
+            time_generated_field,
+            asim,
+            stats: OmsStats::default(),
+        })
+    }
+
+    fn log_type(&self, content_type: &str) -> String {
+        self.log_types.get(content_type).cloned().unwrap_or_else(|| content_type.replace('.', "_"))
+    }
+
+    fn asim_log_type(&self, schema: AsimSchema) -> String {
+        self.log_types.get(schema.name()).cloned().unwrap_or_else(|| format!("O365_ASim{}", schema.name()))
     }
 }
 
@@ -50,106 +95,216 @@ impl OmsInterface {
     }
 }
 
-/// MEMORY FIX: Process logs in chunks to avoid creating all tasks upfront.
-/// Previously, all logs were collected into a Vec before streaming, causing
-/// memory spikes when flushing large caches (500k logs = ~500MB in task allocations).
-const OMS_CHUNK_SIZE: usize = 1000;
+/// The Data Collector API rejects posts over 30 MB, stay well below that.
+const MAX_CHUNK_BYTES: usize = 25 * 1024 * 1024;
+/// Chunks posted at the same time.
+const CONCURRENCY: usize = 4;
+/// Times a throttled (429/503) chunk is resent, waiting for Retry-After in between.
+const MAX_THROTTLE_RETRIES: usize = 5;
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(10);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// Chunk counts since the last flush, logged at the end of each run.
+#[derive(Default, Debug)]
+struct OmsStats {
+    chunks_sent: usize,
+    chunks_failed: usize,
+    logs_sent: usize,
+    logs_failed: usize,
+}
+
+impl OmsInterface {
+
+    /// Post one chunk (a JSON array of logs), waiting and resending while throttled.
+    async fn post_chunk(&self, client: &reqwest::Client, uri: &str, log_type: &str, body: String) -> Result<()> {
+        for attempt in 0..=MAX_THROTTLE_RETRIES {
+            let rfc1123date = Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+            let signature = self.build_signature(rfc1123date.clone(), body.len(), "POST".to_string(),
+                                                 "application/json".to_string(), RESOURCE.to_string());
+            let response = client
+                .post(uri)
+                .header("content-type", "application/json")
+                .header("Authorization", signature)
+                .header("Log-Type", log_type)
+                .header("time-generated-field", &self.time_generated_field)
+                .header("x-ms-date", rfc1123date)
+                .body(body.clone())
+                .send()
+                .await?;
+
+            let status = response.status();
+            if status.is_success() {
+                return Ok(())
+            }
+            let throttled = status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE;
+            if throttled && attempt < MAX_THROTTLE_RETRIES {
+                let wait = retry_after(response.headers());
+                warn!("OMS returned {}, retrying chunk in {}s", status, wait.as_secs());
+                sleep(wait).await;
+                continue
+            }
+            let text = response.text().await.unwrap_or_default();
+            return Err(anyhow!("OMS returned {}: {}", status, text))
+        }
+        Err(anyhow!("OMS still throttling after {} retries", MAX_THROTTLE_RETRIES))
+    }
+}
 
 #[async_trait]
 impl Interface for OmsInterface {
 
-    async fn send_logs(&mut self, logs: Caches) {
-        let client = reqwest::Client::new();
-
-        let resource = "/api/logs";
+    async fn send_logs(&mut self, logs: Caches) -> Result<()> {
+        let client = self.client.clone();
         let uri = format!("https://{}.ods.opinsights.azure.com{}?api-version=2016-04-01",
-                          self.config.output.oms.as_ref().unwrap().workspace_id, resource);
+                          self.config.output.oms.as_ref().unwrap().workspace_id, RESOURCE);
 
-        info!("Sending logs to OMS interface (chunked streaming).");
-
-        // Process logs in chunks to avoid memory spikes
+        let mut bodies: HashMap<String, Vec<String>> = HashMap::new();
         for (content_type, content_logs) in logs.get_all_types() {
-            if content_logs.is_empty() {
-                continue;
+            for log in content_logs.iter() {
+                let (table_name, body) = if self.asim {
+                    let (schema, record) = asim::normalize(log);
+                    (self.asim_log_type(schema), serde_json::to_string(&record))
+                } else {
+                    (self.log_type(&content_type), serde_json::to_string(log))
+                };
+                match body {
+                    Ok(body) => bodies.entry(table_name).or_default().push(body),
+                    Err(e) => warn!("Failed to serialize log: {}", e),
+                }
+            }
+        }
+        let mut chunks = Vec::new();
+        for (table_name, table_bodies) in bodies {
+            for (body, count) in split_chunks(table_bodies.into_iter()) {
+                chunks.push((table_name.clone(), body, count));
             }
+        }
+        info!("Sending {} logs to OMS in {} chunk(s)", logs.len(), chunks.len());
 
-            info!("Sending {} {} logs to OMS in chunks of {}", content_logs.len(), content_type, OMS_CHUNK_SIZE);
-
-            // Process in chunks
-            for chunk in content_logs.chunks(OMS_CHUNK_SIZE) {
-                let mut chunk_requests = Vec::with_capacity(chunk.len());
-
-                for log in chunk.iter() {
-                    let table_name = content_type.replace('.', "_");
-                    let body = match serde_json::to_string(log) {
-                        Ok(b) => b,
-                        Err(e) => {
-                            warn!("Failed to serialize log: {}", e);
-                            continue;
-                        }
-                    };
-                    let content_length = body.len();
-
-                    let time_value = if let Some(i) = log.get("CreationTime") {
-                        i.as_str().unwrap_or_default().to_string()
-                    } else {
-                        warn!("Expected CreationTime field, skipping log");
-                        continue;
-                    };
-
-                    chunk_requests.push((body, table_name, time_value, content_length));
-                }
+        let this = &*self;
+        let results: Vec<(usize, Result<()>)> = stream::iter(chunks)
+            .map(|(table_name, body, count)| {
+                let client = client.clone();
+                let uri = uri.clone();
+                async move { (count, this.post_chunk(&client, &uri, &table_name, body).await) }
+            })
+            .buffer_unordered(CONCURRENCY)
+            .collect()
+            .await;
 
-                // Stream this chunk with bounded concurrency
-                let calls = stream::iter(chunk_requests)
-                    .map(|(body, table_name, time_value, content_length)| {
-                        let client = client.clone();
-                        let uri = uri.clone();
-                        let method = "POST".to_string();
-                        let content_type_header = "application/json".to_string();
-                        let rfc1123date = Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
-                        let signature = self.build_signature(
-                            rfc1123date.clone(),
-                            content_length,
-                            method,
-                            content_type_header,
-                            resource.to_string()
-                        );
-
-                        async move {
-                            let result = client
-                                .post(&uri)
-                                .header("content-type", "application/json")
-                                .header("content-length", content_length)
-                                .header("Authorization", signature)
-                                .header("Log-Type", table_name)
-                                .header("x-ms-date", rfc1123date)
-                                .header("time-generated-field", time_value)
-                                .body(body)
-                                .send()
-                                .await;
-
-                            match result {
-                                Ok(response) => {
-                                    if !response.status().is_success() {
-                                        match response.text().await {
-                                            Ok(text) => error!("Error response after sending log to OMS: {}", text),
-                                            Err(e) => error!("Error response after sending log to OMS, could not parse: {}", e),
-                                        }
-                                    }
-                                },
-                                Err(e) => {
-                                    error!("Error sending log to OMS: {}", e);
-                                }
-                            }
-                        }
-                    })
-                    .buffer_unordered(10);
-
-                calls.for_each(|_| async {}).await;
+        let mut failed_logs = 0;
+        let mut last_error = None;
+        for (count, result) in results {
+            match result {
+                Ok(()) => {
+                    self.stats.chunks_sent += 1;
+                    self.stats.logs_sent += count;
+                },
+                Err(e) => {
+                    error!("Error sending chunk of {} logs to OMS: {}", count, e);
+                    self.stats.chunks_failed += 1;
+                    self.stats.logs_failed += count;
+                    failed_logs += count;
+                    last_error = Some(e);
+                }
             }
         }
+        if let Some(e) = last_error {
+            return Err(anyhow!("{} logs could not be sent to OMS: {}", failed_logs, e));
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        let stats = std::mem::take(&mut self.stats);
+        if stats.chunks_sent + stats.chunks_failed > 0 {
+            info!("OMS: sent {} chunk(s) with {} logs, {} chunk(s) with {} logs failed",
+                  stats.chunks_sent, stats.logs_sent, stats.chunks_failed, stats.logs_failed);
+        }
+        Ok(())
+    }
+}
+
+
+/// Log types may contain letters, digits and underscores, up to 100 characters.
+fn valid_log_type(log_type: &str) -> bool {
+    !log_type.is_empty() && log_type.len() <= 100
+        && log_type.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Join serialized logs into JSON array bodies of at most MAX_CHUNK_BYTES, with the number of
+/// logs in each. Logs too large for a chunk on their own are dropped with a warning.
+fn split_chunks(bodies: impl Iterator<Item = String>) -> Vec<(String, usize)> {
+    let mut chunks = Vec::new();
+    let mut chunk = String::from("[");
+    let mut count = 0;
+    for body in bodies {
+        if body.len() + 2 > MAX_CHUNK_BYTES {
+            warn!("Dropping log of {} bytes, exceeds the OMS post limit", body.len());
+            continue
+        }
+        if count > 0 && chunk.len() + body.len() + 2 > MAX_CHUNK_BYTES {
+            chunk.push(']');
+            chunks.push((std::mem::replace(&mut chunk, String::from("[")), count));
+            count = 0;
+        }
+        if count > 0 {
+            chunk.push(',');
+        }
+        chunk.push_str(&body);
+        count += 1;
+    }
+    if count > 0 {
+        chunk.push(']');
+        chunks.push((chunk, count));
+    }
+    chunks
+}
+
+/// Wait time from a Retry-After header in seconds, capped to MAX_RETRY_AFTER.
+fn retry_after(headers: &HeaderMap) -> Duration {
+    headers.get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_RETRY_AFTER)
+        .min(MAX_RETRY_AFTER)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_split_chunks() {
+        let bodies = vec!["{\"a\":1}".to_string(), "{\"b\":2}".to_string()];
+        assert_eq!(split_chunks(bodies.into_iter()), vec![("[{\"a\":1},{\"b\":2}]".to_string(), 2)]);
+
+        let large = "x".repeat(10 * 1024 * 1024);
+        let chunks = split_chunks(vec![large.clone(); 5].into_iter());
+        assert_eq!(chunks.iter().map(|(_, n)| *n).collect::<Vec<_>>(), vec![2, 2, 1]);
+        assert!(chunks.iter().all(|(body, _)| body.len() <= MAX_CHUNK_BYTES));
+
+        assert!(split_chunks(vec!["x".repeat(MAX_CHUNK_BYTES)].into_iter()).is_empty());
+    }
+
+    #[test]
+    fn test_valid_log_type() {
+        assert!(valid_log_type("O365Exchange"));
+        assert!(valid_log_type("DLP_All"));
+        assert!(!valid_log_type("O365.Exchange"));
+        assert!(!valid_log_type(""));
+    }
 
-        info!("Finished sending logs to OMS");
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), DEFAULT_RETRY_AFTER);
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("30"));
+        assert_eq!(retry_after(&headers), Duration::from_secs(30));
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("3600"));
+        assert_eq!(retry_after(&headers), MAX_RETRY_AFTER);
     }
 }
@@ -0,0 +1,247 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde_json::json;
+use sha2::Sha256;
+use crate::aad_auth::AadTokenProvider;
+use crate::tls;
+use crate::config::Config;
+use crate::data_structures::Caches;
+use crate::interfaces::interface::Interface;
+
+/// Event Hubs rejects batches over 1 MB; keep some headroom for the batch envelope.
+const MAX_BATCH_BYTES: usize = 1000 * 1024;
+
+/// Lifetime of generated SAS tokens.
+const SAS_TOKEN_TTL_SECS: u64 = 3600;
+
+const EVENT_HUBS_SCOPE: &str = "https://eventhubs.azure.net/.default";
+
+enum EventHubAuth {
+    SharedAccessKey { key_name: String, key: String },
+    Aad(AadTokenProvider),
+}
+
+/// Interface that sends logs to an Azure Event Hub using the REST batch send API. Every event
+/// carries the tenant ID as partition key, so all logs of a tenant land in the same partition.
+pub struct EventHubInterface {
+    client: reqwest::Client,
+    resource_uri: String,
+    auth: EventHubAuth,
+    partition_key: String,
+}
+
+impl EventHubInterface {
+
+    pub fn new(config: Config, tenant_id: String) -> Result<Self> {
+
+        let hub_config = config.output.event_hub.as_ref()
+            .ok_or_else(|| anyhow!("No event_hub output configured"))?;
+        let connection_string = hub_config.get_connection_string().map_err(|e| anyhow!(e))?;
+
+        let (namespace, entity_path, auth) = if let Some(connection_string) = connection_string {
+            let parsed = ConnectionString::parse(&connection_string)?;
+            (parsed.namespace,
+             parsed.entity_path,
+             EventHubAuth::SharedAccessKey { key_name: parsed.key_name, key: parsed.key })
+        } else if let Some(aad) = &hub_config.aad {
+            let namespace = hub_config.namespace.clone()
+                .ok_or_else(|| anyhow!("event_hub output with aad auth requires 'namespace'"))?;
+            (namespace, None, EventHubAuth::Aad(AadTokenProvider::new(aad, EVENT_HUBS_SCOPE, tls::http_client(config.tls.as_ref())?)?))
+        } else {
+            return Err(anyhow!("event_hub output requires either 'connection_string' or 'aad'"));
+        };
+
+        let event_hub = hub_config.event_hub.clone()
+            .or(entity_path)
+            .ok_or_else(|| anyhow!("event_hub output requires 'event_hub' or an EntityPath in the connection string"))?;
+
+        let resource_uri = format!("https://{}/{}", namespace.trim_end_matches('/'), event_hub);
+        info!("Event Hub interface sending to {}", resource_uri);
+        Ok(EventHubInterface {
+            client: tls::http_client(config.tls.as_ref())?,
+            resource_uri,
+            auth,
+            partition_key: tenant_id,
+        })
+    }
+
+    async fn get_authorization(&mut self) -> Result<String> {
+        match &mut self.auth {
+            EventHubAuth::SharedAccessKey { key_name, key } => {
+                let expiry = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() + SAS_TOKEN_TTL_SECS;
+                Ok(build_sas_token(&self.resource_uri, key_name, key, expiry))
+            },
+            EventHubAuth::Aad(provider) => {
+                Ok(format!("Bearer {}", provider.get_token().await?))
+            }
+        }
+    }
+
+    async fn send_batch(&mut self, batch: &[String]) -> Result<()> {
+
+        let body = format!("[{}]", batch.join(","));
+        let authorization = self.get_authorization().await?;
+        let url = format!("{}/messages?timeout=60&api-version=2014-01", self.resource_uri);
+        let response = self.client
+            .post(url)
+            .header("Authorization", authorization)
+            .header("Content-Type", "application/vnd.microsoft.servicebus.json")
+            .body(body)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            return Err(anyhow!("Event Hub returned {}: {}", status, text));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Interface for EventHubInterface {
+
+    async fn send_logs(&mut self, logs: Caches) -> Result<()> {
+
+        let mut batches: Vec<Vec<String>> = Vec::new();
+        let mut batch: Vec<String> = Vec::new();
+        let mut batch_size = 0;
+
+        for (_, content_logs) in logs.get_all_types() {
+            for log in content_logs.iter() {
+                let event = match serde_json::to_string(log) {
+                    Ok(body) => build_event(body, &self.partition_key),
+                    Err(e) => {
+                        warn!("Could not serialize a log in Event Hub interface: {}", e);
+                        continue
+                    }
+                };
+                if event.len() + 2 > MAX_BATCH_BYTES {
+                    warn!("Dropping log of {} bytes, exceeds the Event Hub batch limit", event.len());
+                    continue
+                }
+                if batch_size + event.len() + 1 > MAX_BATCH_BYTES {
+                    batches.push(std::mem::take(&mut batch));
+                    batch_size = 0;
+                }
+                batch_size += event.len() + 1;
+                batch.push(event);
+            }
+        }
+        if !batch.is_empty() {
+            batches.push(batch);
+        }
+
+        info!("Sending {} batch(es) to Event Hub.", batches.len());
+        for batch in batches {
+            self.send_batch(&batch).await
+                .map_err(|e| anyhow!("Error sending batch of {} logs to Event Hub: {}", batch.len(), e))?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Fields of an Event Hubs SAS connection string we need.
+#[derive(Debug)]
+struct ConnectionString {
+    namespace: String,
+    key_name: String,
+    key: String,
+    entity_path: Option<String>,
+}
+
+impl ConnectionString {
+    fn parse(s: &str) -> Result<Self> {
+        let mut namespace = None;
+        let mut key_name = None;
+        let mut key = None;
+        let mut entity_path = None;
+        for part in s.split(';').filter(|p| !p.trim().is_empty()) {
+            let (k, v) = part.split_once('=')
+                .ok_or_else(|| anyhow!("Invalid Event Hub connection string segment: {}", part))?;
+            match k.trim() {
+                "Endpoint" => namespace = Some(v.trim()
+                    .trim_start_matches("sb://")
+                    .trim_end_matches('/')
+                    .to_string()),
+                "SharedAccessKeyName" => key_name = Some(v.trim().to_string()),
+                "SharedAccessKey" => key = Some(v.trim().to_string()),
+                "EntityPath" => entity_path = Some(v.trim().to_string()),
+                _ => (),
+            }
+        }
+        Ok(ConnectionString {
+            namespace: namespace.ok_or_else(|| anyhow!("Connection string is missing Endpoint"))?,
+            key_name: key_name.ok_or_else(|| anyhow!("Connection string is missing SharedAccessKeyName"))?,
+            key: key.ok_or_else(|| anyhow!("Connection string is missing SharedAccessKey"))?,
+            entity_path,
+        })
+    }
+}
+
+
+/// Wrap a serialized log in the Event Hubs batch envelope.
+fn build_event(body: String, partition_key: &str) -> String {
+    json!({
+        "Body": body,
+        "BrokerProperties": {"PartitionKey": partition_key},
+    }).to_string()
+}
+
+
+/// Build a Service Bus SAS token for the given resource URI.
+fn build_sas_token(resource_uri: &str, key_name: &str, key: &str, expiry: u64) -> String {
+    let encoded_uri = utf8_percent_encode(&resource_uri.to_lowercase(), NON_ALPHANUMERIC).to_string();
+    let string_to_sign = format!("{}\n{}", encoded_uri, expiry);
+    type HmacSha = Hmac<Sha256>;
+    let mut mac = HmacSha::new_from_slice(key.as_bytes()).unwrap();
+    mac.update(string_to_sign.as_bytes());
+    let signature = BASE64_STANDARD.encode(mac.finalize().into_bytes());
+    format!("SharedAccessSignature sr={}&sig={}&se={}&skn={}",
+            encoded_uri,
+            utf8_percent_encode(&signature, NON_ALPHANUMERIC),
+            expiry,
+            key_name)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn test_parse_connection_string() {
+        let parsed = ConnectionString::parse(
+            "Endpoint=sb://myns.servicebus.windows.net/;SharedAccessKeyName=send;\
+             SharedAccessKey=abc=;EntityPath=o365").unwrap();
+        assert_eq!(parsed.namespace, "myns.servicebus.windows.net");
+        assert_eq!(parsed.key_name, "send");
+        assert_eq!(parsed.key, "abc=");
+        assert_eq!(parsed.entity_path.as_deref(), Some("o365"));
+
+        assert!(ConnectionString::parse("Endpoint=sb://myns.servicebus.windows.net/").is_err());
+    }
+
+    #[test]
+    fn test_build_event() {
+        let event: Value = serde_json::from_str(
+            &build_event("{\"Id\":\"1\"}".to_string(), "tenant-1")).unwrap();
+        assert_eq!(event["Body"], "{\"Id\":\"1\"}");
+        assert_eq!(event["BrokerProperties"]["PartitionKey"], "tenant-1");
+    }
+
+    #[test]
+    fn test_build_sas_token() {
+        let token = build_sas_token("https://myns.servicebus.windows.net/o365", "send", "key", 1700000000);
+        assert!(token.starts_with("SharedAccessSignature sr=https%3A%2F%2Fmyns%2Eservicebus%2Ewindows%2Enet%2Fo365&sig="));
+        assert!(token.ends_with("&se=1700000000&skn=send"));
+    }
+}
@@ -0,0 +1,153 @@
+use std::process::Stdio;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::{info, warn};
+use tokio::io::AsyncWriteExt;
+use tokio::process::{Child, ChildStdin, Command};
+use tokio::time::timeout;
+use crate::config::Config;
+use crate::data_structures::Caches;
+use crate::interfaces::interface::Interface;
+
+/// How long the subprocess gets to finish after its stdin is closed at the end of a run.
+const EXIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Process {
+    child: Child,
+    stdin: ChildStdin,
+}
+
+/// Interface that streams logs as JSONL to the stdin of a configured subprocess, as a generic
+/// extension point for destinations without a native output. Writes wait for the pipe, so a
+/// slow subprocess backpressures the collector. The subprocess is restarted when it exits (the
+/// batch is retried by the collector), and its stdin is closed at the end of every run so it
+/// can flush and exit.
+pub struct ExecInterface {
+    command: String,
+    args: Vec<String>,
+    process: Option<Process>,
+}
+
+impl ExecInterface {
+
+    pub fn new(config: Config) -> Result<Self> {
+
+        let exec_config = config.output.exec.as_ref()
+            .ok_or_else(|| anyhow!("No exec output configured"))?;
+        info!("Exec interface streaming logs to '{}'", exec_config.command);
+        Ok(ExecInterface {
+            command: exec_config.command.clone(),
+            args: exec_config.args.clone(),
+            process: None,
+        })
+    }
+
+    fn spawn(&self) -> Result<Process> {
+        // stdout is discarded so a chatty subprocess cannot mix into the stdout output
+        let mut child = Command::new(&self.command)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| anyhow!("Could not start '{}': {}", self.command, e))?;
+        let stdin = child.stdin.take().ok_or_else(|| anyhow!("No stdin for '{}'", self.command))?;
+        info!("Started exec output '{}' (pid {:?})", self.command, child.id());
+        Ok(Process { child, stdin })
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        if let Some(process) = &mut self.process {
+            if let Ok(Some(status)) = process.child.try_wait() {
+                warn!("Exec output '{}' exited with {}, restarting", self.command, status);
+                self.process = None;
+            }
+        }
+        if self.process.is_none() {
+            self.process = Some(self.spawn()?);
+        }
+        let process = self.process.as_mut().unwrap();
+        let result = async {
+            process.stdin.write_all(data).await?;
+            process.stdin.flush().await
+        }.await;
+        if let Err(e) = result {
+            self.process = None;
+            return Err(anyhow!("Could not write to '{}': {}", self.command, e));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Interface for ExecInterface {
+
+    async fn send_logs(&mut self, logs: Caches) -> Result<()> {
+
+        let mut data = Vec::new();
+        for (_, content_logs) in logs.get_all_types() {
+            for log in content_logs.iter() {
+                match serde_json::to_vec(log) {
+                    Ok(line) => {
+                        data.extend_from_slice(&line);
+                        data.push(b'\n');
+                    },
+                    Err(e) => warn!("Could not serialize a log in exec interface: {}", e),
+                }
+            }
+        }
+        if data.is_empty() {
+            return Ok(())
+        }
+        // A failed write drops the process, the retry of the batch starts a new one
+        self.write(&data).await
+    }
+
+    /// Close stdin and wait for the program to exit. A program that fails or does not exit
+    /// may not have handled the logs it was sent.
+    async fn flush(&mut self) -> Result<()> {
+        let Some(Process { mut child, stdin }) = self.process.take() else {
+            return Ok(())
+        };
+        drop(stdin);
+        match timeout(EXIT_TIMEOUT, child.wait()).await {
+            Ok(Ok(status)) if status.success() => Ok(()),
+            Ok(Ok(status)) => Err(anyhow!("Exec output '{}' exited with {}", self.command, status)),
+            Ok(Err(e)) => Err(anyhow!("Could not wait for exec output '{}': {}", self.command, e)),
+            Err(_) => {
+                let _ = child.kill().await;
+                Err(anyhow!("Exec output '{}' did not exit within {}s of closing stdin, killed it",
+                            self.command, EXIT_TIMEOUT.as_secs()))
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use crate::data_structures::ArbitraryJson;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_streams_jsonl_to_stdin() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.jsonl");
+        let mut interface = ExecInterface {
+            command: "sh".to_string(),
+            args: vec!["-c".to_string(), format!("cat > {}", output.display())],
+            process: None,
+        };
+        let mut log = ArbitraryJson::new();
+        log.insert("Operation".to_string(), Value::String("FileAccessed".to_string()));
+        let mut logs = Caches::default();
+        logs.insert(log, &"Audit.SharePoint".to_string());
+        interface.send_logs(logs).await.unwrap();
+        interface.flush().await.unwrap();
+        assert_eq!(std::fs::read_to_string(output).unwrap(), "{\"Operation\":\"FileAccessed\"}\n");
+    }
+}
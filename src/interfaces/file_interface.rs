@@ -1,157 +1,97 @@
-use std::collections::HashMap;
-use std::path::Path;
-use std::fs::OpenOptions;
 use std::io::Write;
+use std::sync::Arc;
+use anyhow::Context;
 use async_trait::async_trait;
-use chrono::Utc;
-use crate::config::Config;
-use crate::data_structures::{ArbitraryJson, Caches};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use crate::data_structures::{Caches, FileWriter};
 use crate::interfaces::interface::Interface;
+use crate::lag;
 
-/// Interface that sends found logs to JSON file(s) - one JSON object per line (JSONL format)
+/// Interface writing batches to the file output. The file output is written by the download
+/// tasks themselves (see FileWriter); logs it could not write, e.g. on a full disk, are sent
+/// through this interface when the run ends, so they are retried and spooled like the logs of
+/// any other output.
 pub struct FileInterface {
-    config: Config,
-    paths: HashMap<String, String>,
-    postfix: String,
+    writer: Arc<FileWriter>,
 }
 
 impl FileInterface {
-    pub fn new(config: Config) -> Self {
-
-        let postfix = Utc::now().format("%Y%m%d%H%M%S").to_string();
-        let mut interface = FileInterface {
-            config,
-            paths: HashMap::new(),
-            postfix: postfix.clone()
-        };
-        if interface.separate_by_content_type() {
-            interface.create_content_type_paths();
-        }
-        interface
-    }
-
-    /// Based on the desired CSV path, create a path for each content type. Used
-    /// when SeparateByContentType is true.
-    fn create_content_type_paths(&mut self) {
-        let path = Path::new(&self.config.output.file
-            .as_ref()
-            .unwrap()
-            .path);
-        let dir = path.parent();
-        let stem = path
-            .file_stem().unwrap()
-            .to_str().unwrap()
-            .to_string();
-
-        // Get subscriptions from config (new format) or fallback to legacy content_types
-        let content_strings = if !self.config.subscriptions.is_empty() {
-            self.config.subscriptions.clone()
-        } else {
-            self.config.collect.as_ref()
-                .map(|c| c.content_types.get_content_type_strings())
-                .unwrap_or_else(Vec::new)
-        };
-        for content_type in content_strings {
-            // Simple filename without timestamp - user handles rotation
-            let filename = format!("{}.json",
-                               content_type.as_str().replace('.', ""));
-            let file = if let Some(parent) = dir {
-                let parent_str = parent.to_str().unwrap();
-                if parent_str.is_empty() {
-                    filename
-                } else {
-                    format!("{}/{}", parent_str, filename)
-                }
-            } else {
-                filename
-            };
-            self.paths.insert(content_type, file);
-        }
-    }
-
-    /// Convenience method to get config property.
-    fn separate_by_content_type(&self) -> bool {
-        self.config.output.file.as_ref().unwrap().separate_by_content_type.unwrap_or(false)
+    pub fn new(writer: Arc<FileWriter>) -> Self {
+        FileInterface { writer }
     }
+}
 
-    /// Save the logs of all content types in a single JSON file (JSONL format - one JSON per line)
-    fn send_logs_unified(&self, mut cache: Caches) {
-        let all_logs = cache.get_all();
-        let path = &self.config.output.file.as_ref().unwrap().path;
-
-        let mut file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(path)
-            .unwrap_or_else(|e| panic!("Error in file interface: Could not write to path '{}': {}", path, e));
-
-        for logs in all_logs.iter() {
+#[async_trait]
+impl Interface for FileInterface {
+    async fn send_logs(&mut self, logs: Caches) -> anyhow::Result<()> {
+        for (content_type, logs) in logs.get_all_types() {
+            let mut lines = Vec::with_capacity(logs.len());
             for log in logs.iter() {
-                let json_str = serde_json::to_string(log).unwrap();
-                writeln!(file, "{}", json_str).unwrap();
+                lines.push((serde_json::to_string(log)?, lag::creation_time(log)));
             }
+            self.writer.write_lines(&content_type, &lines)
+                .with_context(|| format!("Could not write {} logs to file", content_type))?;
         }
-        file.flush().unwrap();
+        self.writer.flush().context("Could not rotate file output")
     }
+}
 
-    /// Save the logs of each content type to a separate JSON file (JSONL format)
-    fn send_logs_separated(&self, mut cache: Caches) {
-        for (content_type, logs) in cache.get_all_types() {
-            if logs.is_empty() {
-                continue
-            }
-            let path = self.paths.get(&content_type).unwrap();
-            let mut file = OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(path)
-                .unwrap_or_else(|e| panic!("Error in file interface: Could not write to path '{}': {}", path, e));
 
-            for log in logs {
-                let json_str = serde_json::to_string(log).unwrap();
-                writeln!(file, "{}", json_str).unwrap();
-            }
-            file.flush().unwrap();
-        }
-    }
+/// Gzip-compress a buffer of JSONL data.
+pub fn gzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(data.len() / 4), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
 }
 
-#[async_trait]
-impl Interface for FileInterface {
-    async fn send_logs(&mut self, logs: Caches) {
-        if !self.separate_by_content_type() {
-            self.send_logs_unified(logs);
-        } else {
-            self.send_logs_separated(logs);
-        }
-    }
-}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_rotation::RotationPolicy;
 
-/// Get all column names in a heterogeneous collection of logs.
-pub fn get_all_columns(logs: &[ArbitraryJson]) -> Vec<String> {
+    #[test]
+    fn test_open_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        let path = file.join("out.json");
+        assert!(FileWriter::new_unified(path.to_str().unwrap(), &RotationPolicy::default()).is_err());
+    }
 
-    let mut columns: Vec<String> = Vec::new();
-    for log in logs.iter() {
-        for k in log.keys() {
-            if !columns.contains(k) {
-                columns.push(k.to_string());
-            }
-        }
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_failed_writes() {
+        // Writes to /dev/full fail as on a full disk
+        let writer = Arc::new(FileWriter::new_unified("/dev/full", &RotationPolicy::default()).unwrap());
+        let line = serde_json::json!({"Id": "1", "Data": "x".repeat(100_000)}).to_string();
+        assert!(writer.write_logs("Audit.General", &[(line.clone(), None)]).is_err());
+        let failed = writer.take_failed();
+        assert_eq!(failed.len(), 1);
+        assert!(writer.take_failed().is_empty());
+        assert!(FileInterface::new(writer).send_logs(failed.clone()).await.is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.json");
+        let writer = Arc::new(FileWriter::new_unified(path.to_str().unwrap(), &RotationPolicy::default()).unwrap());
+        FileInterface::new(writer.clone()).send_logs(failed).await.unwrap();
+        let written: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written, serde_json::from_str::<serde_json::Value>(&line).unwrap());
+        assert_eq!(writer.take_written(), 1);
     }
-    columns
-}
 
-/// Due to heterogeneous logs not all logs have all columns. Fill missing columns of
-/// a log with an empty string.
-pub fn fill_log(log: &ArbitraryJson, columns: &Vec<String>) -> Vec<String> {
-    let mut new_log= Vec::new();
-    for col in columns {
-        if !log.contains_key(col) {
-            new_log.push("".to_string());
-        } else {
-            new_log.push(log.get(col).unwrap().to_string())
-        }
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_failed_writes_overflow() {
+        let writer = FileWriter::new_unified("/dev/full", &RotationPolicy::default()).unwrap();
+        let lines: Vec<(String, Option<chrono::DateTime<chrono::Utc>>)> = (0..100_010)
+            .map(|i| (format!("{{\"Id\":{}}}", i), None))
+            .collect();
+        assert!(writer.write_logs("Audit.General", &lines).is_err());
+        assert_eq!(writer.take_failed().len(), 100_000);
+        assert_eq!(writer.take_overflow(), 10);
+        assert_eq!(writer.take_overflow(), 0);
+        assert_eq!(writer.take_written(), 0);
     }
-    new_log
-}
\ No newline at end of file
+}
@@ -0,0 +1,224 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use chrono::Utc;
+use log::{info, warn};
+use serde_json::{json, Value};
+use crate::aws_sigv4::{self, AwsCredentials, SigningRequest};
+use crate::tls;
+use crate::config::Config;
+use crate::data_structures::Caches;
+use crate::interfaces::interface::Interface;
+
+/// PutRecordBatch limits: 500 records and 4 MiB per call, 1000 KiB per record.
+const MAX_BATCH_RECORDS: usize = 500;
+const MAX_BATCH_BYTES: usize = 4 * 1024 * 1024;
+const MAX_RECORD_BYTES: usize = 1000 * 1024;
+
+/// Records rejected by Firehose (e.g. throttling) are resent this many times.
+const MAX_RETRIES: usize = 3;
+
+const TARGET: &str = "Firehose_20150804.PutRecordBatch";
+const CONTENT_TYPE: &str = "application/x-amz-json-1.1";
+
+/// Interface that writes logs as newline terminated JSON records to a Kinesis Data Firehose
+/// delivery stream using PutRecordBatch, signed with AWS SigV4.
+pub struct FirehoseInterface {
+    client: reqwest::Client,
+    credentials: AwsCredentials,
+    region: String,
+    url: String,
+    host: String,
+    delivery_stream: String,
+}
+
+impl FirehoseInterface {
+
+    pub fn new(config: Config) -> Result<Self> {
+
+        let firehose_config = config.output.firehose.as_ref()
+            .ok_or_else(|| anyhow!("No firehose output configured"))?;
+        let credentials = AwsCredentials::resolve(firehose_config.access_key_id.as_ref(),
+                                                  firehose_config.secret_access_key.as_ref(),
+                                                  firehose_config.session_token.as_ref())?;
+        let url = firehose_config.endpoint.clone()
+            .unwrap_or_else(|| format!("https://firehose.{}.amazonaws.com", firehose_config.region));
+        let parsed = reqwest::Url::parse(&url)
+            .map_err(|e| anyhow!("Invalid firehose endpoint '{}': {}", url, e))?;
+        let host = match (parsed.host_str(), parsed.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            _ => return Err(anyhow!("firehose endpoint has no host")),
+        };
+
+        info!("Firehose interface sending to delivery stream {} at {}",
+              firehose_config.delivery_stream, url);
+        Ok(FirehoseInterface {
+            client: tls::http_client(config.tls.as_ref())?,
+            credentials,
+            region: firehose_config.region.clone(),
+            url: format!("{}/", url.trim_end_matches('/')),
+            host,
+            delivery_stream: firehose_config.delivery_stream.clone(),
+        })
+    }
+
+    /// Send one batch, returning the records Firehose rejected so they can be retried.
+    async fn put_record_batch(&self, batch: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+
+        let records: Vec<Value> = batch.iter()
+            .map(|data| json!({"Data": BASE64_STANDARD.encode(data)}))
+            .collect();
+        let body = serde_json::to_vec(&json!({
+            "DeliveryStreamName": self.delivery_stream,
+            "Records": records,
+        }))?;
+
+        let request = SigningRequest {
+            method: "POST",
+            host: &self.host,
+            path: "/",
+            query: "",
+            headers: &[("content-type", CONTENT_TYPE), ("x-amz-target", TARGET)],
+            payload: &body,
+        };
+        let signed = aws_sigv4::sign(&self.credentials, &self.region, "firehose", &request, Utc::now());
+
+        let mut builder = self.client
+            .post(&self.url)
+            .header("content-type", CONTENT_TYPE)
+            .header("x-amz-target", TARGET);
+        for (k, v) in signed {
+            builder = builder.header(k, v);
+        }
+        let response = builder.body(body).send().await?;
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        if !status.is_success() {
+            return Err(anyhow!("Firehose returned {}: {}", status, text));
+        }
+        let response: Value = serde_json::from_str(&text)?;
+        Ok(failed_records(batch, &response))
+    }
+}
+
+#[async_trait]
+impl Interface for FirehoseInterface {
+
+    async fn send_logs(&mut self, logs: Caches) -> Result<()> {
+
+        let mut records = Vec::new();
+        for (_, content_logs) in logs.get_all_types() {
+            for log in content_logs.iter() {
+                match serde_json::to_vec(log) {
+                    Ok(mut record) => {
+                        record.push(b'\n');
+                        records.push(record);
+                    },
+                    Err(e) => warn!("Could not serialize a log in Firehose interface: {}", e),
+                }
+            }
+        }
+        if records.is_empty() {
+            return Ok(())
+        }
+
+        let total = records.len();
+        let mut pending = records;
+        for attempt in 0..=MAX_RETRIES {
+            if pending.is_empty() {
+                break
+            }
+            if attempt > 0 {
+                warn!("Retrying {} records rejected by Firehose (attempt {})", pending.len(), attempt);
+            }
+            let mut rejected = Vec::new();
+            for batch in split_batches(pending) {
+                let count = batch.len();
+                let failed = self.put_record_batch(batch).await
+                    .map_err(|e| anyhow!("Error sending {} records to Firehose: {}", count, e))?;
+                rejected.extend(failed);
+            }
+            pending = rejected;
+        }
+        if !pending.is_empty() {
+            return Err(anyhow!("{} of {} records still rejected by Firehose after {} retries",
+                               pending.len(), total, MAX_RETRIES));
+        }
+        info!("Sent {} records to Firehose delivery stream {}", total, self.delivery_stream);
+        Ok(())
+    }
+}
+
+
+/// Group records into batches within the PutRecordBatch record count and size limits.
+/// Records larger than the per record limit are dropped with a warning.
+fn split_batches(records: Vec<Vec<u8>>) -> Vec<Vec<Vec<u8>>> {
+    let mut batches = Vec::new();
+    let mut batch: Vec<Vec<u8>> = Vec::new();
+    let mut batch_size = 0;
+    for record in records {
+        if record.len() > MAX_RECORD_BYTES {
+            warn!("Dropping log of {} bytes, exceeds the Firehose record limit", record.len());
+            continue
+        }
+        if batch.len() == MAX_BATCH_RECORDS || batch_size + record.len() > MAX_BATCH_BYTES {
+            batches.push(std::mem::take(&mut batch));
+            batch_size = 0;
+        }
+        batch_size += record.len();
+        batch.push(record);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+
+/// Pick the records that have an ErrorCode in the PutRecordBatch response.
+fn failed_records(batch: Vec<Vec<u8>>, response: &Value) -> Vec<Vec<u8>> {
+    if response["FailedPutCount"].as_u64().unwrap_or(0) == 0 {
+        return Vec::new();
+    }
+    let results = response["RequestResponses"].as_array().cloned().unwrap_or_default();
+    batch.into_iter()
+        .zip(results.iter())
+        .filter(|(_, result)| result.get("ErrorCode").map(|c| !c.is_null()).unwrap_or(false))
+        .map(|(record, _)| record)
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_batches() {
+        let records = vec![vec![b'a'; 10]; 1001];
+        let batches = split_batches(records);
+        assert_eq!(batches.iter().map(|b| b.len()).collect::<Vec<_>>(), vec![500, 500, 1]);
+
+        let records = vec![vec![b'a'; 900 * 1024]; 5];
+        assert_eq!(split_batches(records).len(), 2);
+
+        assert!(split_batches(vec![vec![b'a'; MAX_RECORD_BYTES + 1]]).is_empty());
+    }
+
+    #[test]
+    fn test_failed_records() {
+        let batch = vec![b"1".to_vec(), b"2".to_vec(), b"3".to_vec()];
+        let response = json!({
+            "FailedPutCount": 1,
+            "RequestResponses": [
+                {"RecordId": "a"},
+                {"ErrorCode": "ServiceUnavailableException", "ErrorMessage": "Slow down."},
+                {"RecordId": "c"}
+            ]
+        });
+        assert_eq!(failed_records(batch.clone(), &response), vec![b"2".to_vec()]);
+        assert!(failed_records(batch, &json!({"FailedPutCount": 0})).is_empty());
+    }
+}
@@ -1,64 +1,350 @@
-use std::time::SystemTime;
-use chrono::{DateTime, NaiveDateTime, Utc};
-use core::time;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Cursor, ErrorKind};
+use std::time::Duration;
+use chrono::{NaiveDateTime, Utc};
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use poston::{Client, Settings, WorkerPool};
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use log::{info, warn};
+use serde::de::{self, DeserializeOwned, Deserializer, Visitor};
+use serde_derive::Deserialize;
+use sha2::{Digest, Sha512};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+use crate::aws_sigv4::hex;
+use crate::tls;
 use crate::config::Config;
 use crate::data_structures::{ArbitraryJson, Caches};
 use crate::interfaces::interface::Interface;
+use crate::interfaces::tcp_sender::{self, Connection, TlsSettings};
 
+/// Entries per forward mode message.
+const MAX_ENTRIES_PER_MESSAGE: usize = 1000;
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_HOSTNAME: &str = "office365-log-collector";
+
+/// Credentials for the forward protocol handshake (in_forward <security> section).
+struct Auth {
+    shared_key: String,
+    hostname: String,
+    username: String,
+    password: String,
+}
+
+/// Interface that sends logs to Fluentd/td-agent with the forward protocol, in forward mode
+/// messages tagged with the tenant name. Optionally over TLS, with the shared key handshake,
+/// and waiting for the server to acknowledge every chunk.
 pub struct FluentdInterface {
-    config: Config,
-    pool: WorkerPool
+    tag: String,
+    address: String,
+    port: u16,
+    tls: Option<TlsSettings>,
+    auth: Option<Auth>,
+    require_ack: bool,
+    connection: Option<Box<dyn Connection>>,
+    read_buffer: Vec<u8>,
 }
+
 impl FluentdInterface {
-    pub fn new(config: Config) -> Self {
-
-        let pool = {
-            let addr = format!("{}:{}",
-                               config.output.fluentd.as_ref().unwrap().address,
-                               config.output.fluentd.as_ref().unwrap().port
-            );
-            let settings = Settings {
-                flush_period: time::Duration::from_millis(10),
-                max_flush_entries: 1000,
-                connection_retry_timeout: time::Duration::from_secs(60),
-                write_timeout: time::Duration::from_secs(30),
-                read_timeout: time::Duration::from_secs(30),
-                ..Default::default()
-            };
-            WorkerPool::with_settings(&addr, &settings).expect("Couldn't create the worker pool.")
+
+    pub fn new(config: Config) -> Result<Self> {
+
+        let fluentd_config = config.output.fluentd.as_ref()
+            .ok_or_else(|| anyhow!("No fluentd output configured"))?;
+        if fluentd_config.shared_key.is_none() && fluentd_config.username.is_some() {
+            return Err(anyhow!("fluentd output 'username' requires 'shared_key'"));
+        }
+        let auth = fluentd_config.shared_key.as_ref().map(|shared_key| Auth {
+            shared_key: shared_key.clone(),
+            hostname: fluentd_config.hostname.clone()
+                .or_else(|| std::env::var("HOSTNAME").ok())
+                .unwrap_or_else(|| DEFAULT_HOSTNAME.to_string()),
+            username: fluentd_config.username.clone().unwrap_or_default(),
+            password: fluentd_config.password.clone().unwrap_or_default(),
+        });
+        Ok(FluentdInterface {
+            tag: fluentd_config.tenant_name.clone(),
+            address: fluentd_config.address.clone(),
+            port: fluentd_config.port,
+            tls: tcp_sender::tls_settings(&fluentd_config.address,
+                                          tls::merge(fluentd_config.tls.as_ref(), config.tls.as_ref()).as_ref())?,
+            auth,
+            require_ack: fluentd_config.require_ack.unwrap_or(false),
+            connection: None,
+            read_buffer: Vec::new(),
+        })
+    }
+
+    async fn connect(&mut self) -> Result<()> {
+        self.read_buffer.clear();
+        self.connection = Some(tcp_sender::connect(&self.address, self.port, self.tls.as_ref()).await?);
+        if self.auth.is_some() {
+            self.handshake().await?;
+        }
+        Ok(())
+    }
+
+    /// Shared key (and optionally user) authentication: HELO from the server, PING from us,
+    /// PONG with the result and the server's proof that it knows the shared key.
+    async fn handshake(&mut self) -> Result<()> {
+        let (kind, helo): (String, Helo) = self.read_message().await?;
+        if kind != "HELO" {
+            return Err(anyhow!("Expected HELO from Fluentd, got {}", kind));
+        }
+        let auth = self.auth.as_ref().unwrap();
+        let salt = hex(uuid::Uuid::new_v4().as_bytes());
+        let nonce = helo.nonce.0;
+        let user_digest = match (&helo.auth, auth.username.is_empty()) {
+            (Some(auth_salt), false) if !auth_salt.0.is_empty() =>
+                sha512_hex(&[&auth_salt.0, auth.username.as_bytes(), auth.password.as_bytes()]),
+            _ => String::new(),
         };
-        FluentdInterface {
-            config,
-            pool,
+        let ping = rmp_serde::to_vec(&(
+            "PING",
+            &auth.hostname,
+            &salt,
+            sha512_hex(&[salt.as_bytes(), auth.hostname.as_bytes(), &nonce, auth.shared_key.as_bytes()]),
+            &auth.username,
+            user_digest,
+        ))?;
+        let shared_key = auth.shared_key.clone();
+        self.write(&ping).await?;
+
+        let (kind, authenticated, reason, server_hostname, digest): (String, bool, String, String, String) =
+            self.read_message().await?;
+        if kind != "PONG" {
+            return Err(anyhow!("Expected PONG from Fluentd, got {}", kind));
+        }
+        if !authenticated {
+            return Err(anyhow!("Fluentd rejected authentication: {}", reason));
+        }
+        if digest != sha512_hex(&[salt.as_bytes(), server_hostname.as_bytes(), &nonce, shared_key.as_bytes()]) {
+            return Err(anyhow!("Fluentd server {} failed shared key verification", server_hostname));
         }
+        info!("Authenticated to Fluentd server {}", server_hostname);
+        Ok(())
     }
 
-    fn get_tenant_name(&self) -> String {
-        self.config.output.fluentd.as_ref().unwrap().tenant_name.clone()
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        let connection = self.connection.as_mut().ok_or_else(|| anyhow!("Not connected"))?;
+        connection.write_all(data).await?;
+        connection.flush().await?;
+        Ok(())
+    }
+
+    /// Read one msgpack message from the connection.
+    async fn read_message<T: DeserializeOwned>(&mut self) -> Result<T> {
+        loop {
+            let mut cursor = Cursor::new(&self.read_buffer[..]);
+            match rmp_serde::from_read::<_, T>(&mut cursor) {
+                Ok(message) => {
+                    let used = cursor.position() as usize;
+                    self.read_buffer.drain(..used);
+                    return Ok(message)
+                },
+                Err(e) if !is_incomplete(&e) => return Err(anyhow!("Invalid message from Fluentd: {}", e)),
+                Err(_) => (),
+            }
+            let connection = self.connection.as_mut().ok_or_else(|| anyhow!("Not connected"))?;
+            let mut buffer = [0u8; 4096];
+            let n = timeout(READ_TIMEOUT, connection.read(&mut buffer)).await
+                .map_err(|_| anyhow!("Timed out waiting for Fluentd"))??;
+            if n == 0 {
+                return Err(anyhow!("Fluentd closed the connection"));
+            }
+            self.read_buffer.extend_from_slice(&buffer[..n]);
+        }
+    }
+
+    async fn send_message(&mut self, entries: &[(u64, &ArbitraryJson)]) -> Result<()> {
+        let chunk = BASE64_STANDARD.encode(uuid::Uuid::new_v4().as_bytes());
+        let options = ForwardOptions { size: entries.len(), chunk: self.require_ack.then_some(chunk.as_str()) };
+        let message = rmp_serde::to_vec(&(&self.tag, entries, options))?;
+        if self.connection.is_none() {
+            self.connect().await?;
+        }
+        self.write(&message).await?;
+        if self.require_ack {
+            let response: HashMap<String, String> = self.read_message().await?;
+            if response.get("ack") != Some(&chunk) {
+                return Err(anyhow!("Fluentd acknowledged {:?} instead of chunk {}", response.get("ack"), chunk));
+            }
+        }
+        Ok(())
     }
 }
 
 #[async_trait]
 impl Interface for FluentdInterface {
-    async fn send_logs(&mut self, mut logs: Caches) {
 
-        let all_logs = logs.get_all();
-        for logs in all_logs {
-            for log in logs {
-                let timestamp = get_timestamp(log);
-                self.pool.send(self.get_tenant_name(), log, timestamp).unwrap();
+    async fn send_logs(&mut self, logs: Caches) -> Result<()> {
+
+        for (_, content_logs) in logs.get_all_types() {
+            let entries: Vec<(u64, &ArbitraryJson)> = content_logs.iter()
+                .map(|log| (get_timestamp(log), &**log))
+                .collect();
+            for message in entries.chunks(MAX_ENTRIES_PER_MESSAGE) {
+                if let Err(e) = self.send_message(message).await {
+                    // Start over on a fresh connection when the batch is retried
+                    self.connection = None;
+                    return Err(anyhow!("Could not send logs to Fluentd: {}", e));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        if let Some(mut connection) = self.connection.take() {
+            let _ = connection.shutdown().await;
+        }
+        Ok(())
+    }
+}
+
+
+/// Options of a forward mode message. The chunk id asks the server for an ack.
+struct ForwardOptions<'a> {
+    size: usize,
+    chunk: Option<&'a str>,
+}
+
+impl serde::Serialize for ForwardOptions<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+        let mut map = serializer.serialize_map(Some(if self.chunk.is_some() { 2 } else { 1 }))?;
+        map.serialize_entry("size", &self.size)?;
+        if let Some(chunk) = self.chunk {
+            map.serialize_entry("chunk", chunk)?;
+        }
+        map.end()
+    }
+}
+
+#[derive(Deserialize)]
+struct Helo {
+    nonce: Bytes,
+    #[serde(default)]
+    auth: Option<Bytes>,
+}
+
+/// Nonce and salt of the HELO message, which Fluentd sends as either bin or str.
+struct Bytes(Vec<u8>);
+
+impl<'de> serde::Deserialize<'de> for Bytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BytesVisitor;
+        impl Visitor<'_> for BytesVisitor {
+            type Value = Bytes;
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("bytes or string")
+            }
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Bytes, E> {
+                Ok(Bytes(v.to_vec()))
+            }
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Bytes, E> {
+                Ok(Bytes(v.as_bytes().to_vec()))
             }
         }
+        deserializer.deserialize_any(BytesVisitor)
+    }
+}
+
+fn sha512_hex(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update(part);
     }
+    hex(&hasher.finalize())
+}
+
+/// Whether decoding failed only because the rest of the message was not received yet.
+fn is_incomplete(error: &rmp_serde::decode::Error) -> bool {
+    match error {
+        rmp_serde::decode::Error::InvalidMarkerRead(e) | rmp_serde::decode::Error::InvalidDataRead(e) =>
+            e.kind() == ErrorKind::UnexpectedEof,
+        _ => false,
+    }
+}
+
+/// Event time in seconds from CreationTime, or now if the log has none.
+fn get_timestamp(log: &ArbitraryJson) -> u64 {
+    log.get("CreationTime")
+        .and_then(|t| t.as_str())
+        .and_then(|t| NaiveDateTime::parse_from_str(t, "%Y-%m-%dT%H:%M:%S").ok())
+        .map(|t| t.and_utc().timestamp())
+        .unwrap_or_else(|| {
+            warn!("Log without valid CreationTime sent to Fluentd with the current time");
+            Utc::now().timestamp()
+        }) as u64
 }
 
-fn get_timestamp(log: &ArbitraryJson) -> SystemTime {
 
-    let time_string = log.get("CreationTime").unwrap().as_str().unwrap();
-    let time = NaiveDateTime::parse_from_str(
-        time_string, "%Y-%m-%dT%H:%M:%S").unwrap();
-    let time_utc = DateTime::<Utc>::from_naive_utc_and_offset(time, Utc);
-    SystemTime::from(time_utc)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use tokio::net::TcpListener;
+
+    fn test_interface(port: u16, auth: Option<Auth>, require_ack: bool) -> FluentdInterface {
+        FluentdInterface {
+            tag: "tenant".to_string(),
+            address: "127.0.0.1".to_string(),
+            port,
+            tls: None,
+            auth,
+            require_ack,
+            connection: None,
+            read_buffer: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_get_timestamp() {
+        let mut log = ArbitraryJson::new();
+        log.insert("CreationTime".to_string(), Value::String("2024-01-01T00:00:10".to_string()));
+        assert_eq!(get_timestamp(&log), 1704067210);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_and_ack() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut server = test_interface(0, None, false);
+            server.connection = Some(Box::new(socket));
+            let nonce = b"0123456789abcdef".to_vec();
+            let helo = rmp_serde::to_vec(&("HELO", HashMap::from([("nonce", String::from_utf8(nonce.clone()).unwrap())])))
+                .unwrap();
+            server.write(&helo).await.unwrap();
+
+            let (_, hostname, salt, digest, _, _): (String, String, String, String, String, String) =
+                server.read_message().await.unwrap();
+            assert_eq!(digest, sha512_hex(&[salt.as_bytes(), hostname.as_bytes(), &nonce, b"secret"]));
+            let pong = sha512_hex(&[salt.as_bytes(), b"fluentd", &nonce, b"secret"]);
+            server.write(&rmp_serde::to_vec(&("PONG", true, "", "fluentd", pong)).unwrap()).await.unwrap();
+
+            let (tag, entries, options): (String, Vec<(u64, ArbitraryJson)>, HashMap<String, Value>) =
+                server.read_message().await.unwrap();
+            assert_eq!(tag, "tenant");
+            assert_eq!(entries.len(), 1);
+            let ack = HashMap::from([("ack", options["chunk"].as_str().unwrap().to_string())]);
+            server.write(&rmp_serde::to_vec(&ack).unwrap()).await.unwrap();
+        });
+
+        let auth = Auth {
+            shared_key: "secret".to_string(),
+            hostname: "collector".to_string(),
+            username: String::new(),
+            password: String::new(),
+        };
+        let mut interface = test_interface(port, Some(auth), true);
+        let mut logs = Caches::new(10);
+        logs.insert(ArbitraryJson::new(), &"Audit.General".to_string());
+        interface.send_logs(logs).await.unwrap();
+        server.await.unwrap();
+    }
 }
@@ -1,61 +1,139 @@
 use std::io::{ErrorKind, Write};
-use std::net::{TcpStream, ToSocketAddrs};
-use std::time::Duration;
+use std::sync::Arc;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use chrono::{DateTime, NaiveDateTime, Utc};
+use flate2::Compression;
+use flate2::write::GzEncoder;
 use log::{warn};
 use serde_json::Value;
-use crate::config::Config;
+use tokio::net::{lookup_host, UdpSocket};
+use crate::tls;
+use crate::config::{Config, Framing, GraylogProtocol, TlsSubConfig};
 use crate::data_structures::{ArbitraryJson, Caches};
+use crate::formatters::{LogFormat, DEFAULT_PRODUCT_VERSION};
 use crate::interfaces::interface::Interface;
+use crate::interfaces::tcp_sender::TcpSender;
+
+/// GELF UDP chunk header: magic bytes, 8 byte message id, sequence number and count.
+const GELF_CHUNK_MAGIC: [u8; 2] = [0x1e, 0x0f];
+const GELF_CHUNK_HEADER_BYTES: usize = 12;
+/// Graylog discards messages of more than 128 chunks.
+const GELF_MAX_CHUNKS: usize = 128;
+/// Fits a typical WAN MTU, Graylog recommends up to 8154 on a LAN.
+const DEFAULT_MAX_CHUNK_SIZE: usize = 1420;
+
+enum Transport {
+    Tcp(TcpSender),
+    Udp(UdpSocket),
+}
 
 pub struct GraylogInterface {
     address: String,
     port: u16,
+    format: LogFormat,
+    transport: Option<Transport>,
+    protocol: GraylogProtocol,
+    tls: Option<TlsSubConfig>,
+    delimiter: u8,
+    compress: bool,
+    max_chunk_size: usize,
 }
 
 impl GraylogInterface {
 
-    pub fn new(config: Config) -> Self {
+    pub fn new(config: Config) -> Result<Self> {
 
-        let address = config.output.graylog.as_ref().unwrap().address.clone();
-        let port = config.output.graylog.as_ref().unwrap().port;
-        let interface = GraylogInterface {
-            address,
-            port
-        };
-
-        // Test socket, if we cannot connect there's no point in running
-        let _ = interface.get_socket();
-        interface
+        let graylog_config = config.output.graylog.as_ref()
+            .ok_or_else(|| anyhow!("No graylog output configured"))?;
+        let format = graylog_config.format.unwrap_or(LogFormat::Json);
+        let protocol = graylog_config.protocol.unwrap_or(GraylogProtocol::Tcp);
+        if protocol == GraylogProtocol::Udp && graylog_config.tls.is_some() {
+            return Err(anyhow!("graylog output 'tls' requires protocol tcp"));
+        }
+        let framing = graylog_config.framing.unwrap_or(
+            if format == LogFormat::Json { Framing::Null } else { Framing::Newline });
+        let max_chunk_size = graylog_config.max_chunk_size.unwrap_or(DEFAULT_MAX_CHUNK_SIZE);
+        if max_chunk_size <= GELF_CHUNK_HEADER_BYTES {
+            return Err(anyhow!("graylog output 'max_chunk_size' must be more than {} bytes", GELF_CHUNK_HEADER_BYTES));
+        }
+        Ok(GraylogInterface {
+            address: graylog_config.address.clone(),
+            port: graylog_config.port,
+            format,
+            transport: None,
+            protocol,
+            tls: tls::merge(graylog_config.tls.as_ref(), config.tls.as_ref()),
+            delimiter: if framing == Framing::Null { b'\0' } else { b'\n' },
+            compress: graylog_config.compress.unwrap_or(true),
+            max_chunk_size,
+        })
     }
 }
 
 impl GraylogInterface {
-    fn get_socket(&self) -> TcpStream {
-
-        let ip_addr = (self.address.clone(), self.port)
-            .to_socket_addrs()
-            .expect("Unable to resolve the IP address")
-            .next()
-            .expect("DNS resolution returned no IP addresses");
-        TcpStream::connect_timeout(&ip_addr, Duration::from_secs(10)).unwrap_or_else(
-            |e| panic!("Could not connect to Graylog interface on: {}:{} with: {}",
-                       self.address, self.port, e)
-        )
+
+    async fn get_transport(&mut self) -> Result<&mut Transport> {
+        if self.transport.is_none() {
+            let transport = match self.protocol {
+                GraylogProtocol::Tcp => Transport::Tcp(TcpSender::new(&self.address, self.port, self.tls.as_ref())?),
+                GraylogProtocol::Udp => {
+                    let remote = lookup_host((self.address.as_str(), self.port)).await
+                        .map_err(|e| anyhow!("Unable to resolve the IP address of {}: {}", self.address, e))?
+                        .next()
+                        .ok_or_else(|| anyhow!("DNS resolution of {} returned no IP addresses", self.address))?;
+                    let local = if remote.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+                    let socket = UdpSocket::bind(local).await?;
+                    socket.connect(remote).await?;
+                    Transport::Udp(socket)
+                }
+            };
+            self.transport = Some(transport);
+        }
+        Ok(self.transport.as_mut().unwrap())
+    }
+
+    async fn send_message(&mut self, mut message: Vec<u8>) -> Result<()> {
+        let (delimiter, compress, max_chunk_size) = (self.delimiter, self.compress, self.max_chunk_size);
+        match self.get_transport().await? {
+            Transport::Tcp(sender) => {
+                message.push(delimiter);
+                sender.send(&message).await
+            },
+            Transport::Udp(socket) => {
+                let payload = if compress { gzip(&message)? } else { message };
+                let message_id: [u8; 8] = uuid::Uuid::new_v4().as_bytes()[..8].try_into()?;
+                let size = payload.len();
+                let Some(datagrams) = gelf_chunks(payload, message_id, max_chunk_size) else {
+                    warn!("Dropping log of {} bytes, it needs more than {} GELF chunks", size, GELF_MAX_CHUNKS);
+                    return Ok(())
+                };
+                for datagram in datagrams {
+                    socket.send(&datagram).await?;
+                }
+                Ok(())
+            }
+        }
     }
 }
 
 #[async_trait]
 impl Interface for GraylogInterface {
 
-    async fn send_logs(&mut self, mut logs: Caches) {
+    async fn send_logs(&mut self, mut logs: Caches) -> Result<()> {
 
         let mut all_logs = logs.get_all();
         for logs in all_logs.iter_mut() {
             for log in logs.iter_mut() {
 
-                match add_timestamp_field(log) {
+                if self.format != LogFormat::Json {
+                    let line = self.format.format(log, DEFAULT_PRODUCT_VERSION);
+                    self.send_message(line.into_bytes()).await
+                        .map_err(|e| anyhow!("Could not send log to Graylog interface: {}", e))?;
+                    continue
+                }
+
+                match add_timestamp_field(Arc::make_mut(log)) {
                     Ok(()) => (),
                     Err(e) => {
                         warn!("Could parse timestamp for log in Graylog interface: {}", e);
@@ -63,22 +141,57 @@ impl Interface for GraylogInterface {
                     }
                 }
 
-                match serde_json::to_string(log) {
+                match serde_json::to_vec(log) {
                     Ok(json) => {
-                        let mut socket = self.get_socket();
-                        socket.write_all(&json.as_bytes()).unwrap_or_else(
-                            |e| warn!("Could not send log to Graylog interface: {}", e));
-                        socket.flush().unwrap_or_else(
-                            |e| warn!("Could not send log to Graylog interface: {}", e));
+                        self.send_message(json).await
+                            .map_err(|e| anyhow!("Could not send log to Graylog interface: {}", e))?;
                     }
                     Err(e) => warn!("Could not serialize a log in Graylog interface: {}.", e)
                 }
             }
         }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        if let Some(Transport::Tcp(sender)) = self.transport.as_mut() {
+            sender.close().await;
+        }
+        self.transport = None;
+        Ok(())
     }
 }
 
 
+/// Split a (compressed) GELF message into UDP datagrams. Messages that fit in one datagram are
+/// sent as is, larger ones as GELF chunks. None if the message needs too many chunks.
+fn gelf_chunks(payload: Vec<u8>, message_id: [u8; 8], max_chunk_size: usize) -> Option<Vec<Vec<u8>>> {
+    if payload.len() <= max_chunk_size {
+        return Some(vec![payload]);
+    }
+    let data_size = max_chunk_size - GELF_CHUNK_HEADER_BYTES;
+    let count = payload.len().div_ceil(data_size);
+    if count > GELF_MAX_CHUNKS {
+        return None;
+    }
+    Some(payload.chunks(data_size).enumerate().map(|(i, data)| {
+        let mut chunk = Vec::with_capacity(GELF_CHUNK_HEADER_BYTES + data.len());
+        chunk.extend_from_slice(&GELF_CHUNK_MAGIC);
+        chunk.extend_from_slice(&message_id);
+        chunk.push(i as u8);
+        chunk.push(count as u8);
+        chunk.extend_from_slice(data);
+        chunk
+    }).collect())
+}
+
+fn gzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+
 pub fn add_timestamp_field(log: &mut ArbitraryJson) -> Result<(), std::io::Error> {
 
     let time_value = if let Some(i) = log.get("CreationTime") {
@@ -110,3 +223,43 @@ pub fn add_timestamp_field(log: &mut ArbitraryJson) -> Result<(), std::io::Error
     log.insert("timestamp".to_string(), Value::String(time_stamp));
     Ok(())
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gelf_chunks() {
+        let id = [1, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(gelf_chunks(vec![7; 100], id, 1420).unwrap(), vec![vec![7; 100]]);
+
+        let chunks = gelf_chunks(vec![7; 2500], id, 1012).unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(&chunks[0][..12], &[0x1e, 0x0f, 1, 2, 3, 4, 5, 6, 7, 8, 0, 3]);
+        assert_eq!(&chunks[2][10..12], &[2, 3]);
+        assert!(chunks.iter().all(|c| c.len() <= 1012));
+        assert_eq!(chunks.iter().map(|c| c.len() - 12).sum::<usize>(), 2500);
+
+        assert!(gelf_chunks(vec![7; 129 * 1000], id, 1012).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_udp_round_trip() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut config: Config = serde_yaml::from_str(&format!(
+            "output:\n  graylog:\n    address: 127.0.0.1\n    port: {}\n    protocol: udp\n    compress: false\n",
+            receiver.local_addr().unwrap().port())).unwrap();
+        config.output.graylog.as_mut().unwrap().max_chunk_size = Some(100);
+        let mut interface = GraylogInterface::new(config).unwrap();
+        interface.send_message(vec![b'a'; 250]).await.unwrap();
+
+        let mut data = Vec::new();
+        let mut buffer = [0u8; 200];
+        for _ in 0..3 {
+            let n = receiver.recv(&mut buffer).await.unwrap();
+            data.extend_from_slice(&buffer[12..n]);
+        }
+        assert_eq!(data, vec![b'a'; 250]);
+    }
+}
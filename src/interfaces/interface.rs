@@ -1,7 +1,16 @@
+use anyhow::Result;
 use async_trait::async_trait;
 use crate::data_structures::Caches;
 
 #[async_trait]
 pub trait Interface {
-    async fn send_logs(&mut self, logs: Caches);
-}
\ No newline at end of file
+    /// Send a batch of logs. An error means (part of) the batch was not delivered; the caller
+    /// retries the whole batch, so outputs may see duplicates after a partial failure.
+    async fn send_logs(&mut self, logs: Caches) -> Result<()>;
+
+    /// Send anything the interface buffered itself. Called at the end of each run; an error
+    /// means logs of batches it accepted were not delivered.
+    async fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
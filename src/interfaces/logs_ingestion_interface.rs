@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::{info, warn};
+use serde_json::Value;
+use crate::aad_auth::AadTokenProvider;
+use crate::tls;
+use crate::config::Config;
+use crate::data_structures::{ArbitraryJson, Caches};
+use crate::formatters::asim;
+use crate::interfaces::interface::Interface;
+
+/// The Logs Ingestion API accepts at most 1 MB per call.
+const MAX_BODY_BYTES: usize = 1000 * 1024;
+
+const MONITOR_SCOPE: &str = "https://monitor.azure.com/.default";
+const API_VERSION: &str = "2023-01-01";
+
+/// Interface that sends logs to Log Analytics / Sentinel custom tables through the Azure Monitor
+/// Logs Ingestion API, using a data collection endpoint (DCE) and rule (DCR). Each log gets a
+/// TimeGenerated column derived from its CreationTime.
+pub struct LogsIngestionInterface {
+    client: reqwest::Client,
+    endpoint: String,
+    dcr_immutable_id: String,
+    stream_name: String,
+    streams: HashMap<String, String>,
+    token_provider: AadTokenProvider,
+    asim: bool,
+}
+
+impl LogsIngestionInterface {
+
+    pub fn new(config: Config) -> Result<Self> {
+
+        let ingestion_config = config.output.logs_ingestion.as_ref()
+            .ok_or_else(|| anyhow!("No logs_ingestion output configured"))?;
+        info!("Logs Ingestion interface sending to DCR {} on {}",
+              ingestion_config.dcr_immutable_id, ingestion_config.endpoint);
+        Ok(LogsIngestionInterface {
+            client: tls::http_client(config.tls.as_ref())?,
+            endpoint: ingestion_config.endpoint.trim_end_matches('/').to_string(),
+            dcr_immutable_id: ingestion_config.dcr_immutable_id.clone(),
+            stream_name: ingestion_config.stream_name.clone(),
+            streams: ingestion_config.streams.clone(),
+            token_provider: AadTokenProvider::new(&ingestion_config.aad, MONITOR_SCOPE, tls::http_client(config.tls.as_ref())?)?,
+            asim: ingestion_config.asim.unwrap_or(false),
+        })
+    }
+
+    async fn send_batch(&mut self, stream: &str, batch: &[String]) -> Result<()> {
+
+        let url = format!("{}/dataCollectionRules/{}/streams/{}?api-version={}",
+                          self.endpoint, self.dcr_immutable_id, stream, API_VERSION);
+        let token = self.token_provider.get_token().await?;
+        let response = self.client
+            .post(url)
+            .header("Authorization", format!("Bearer {}", token))
+            .header("Content-Type", "application/json")
+            .body(format!("[{}]", batch.join(",")))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            return Err(anyhow!("Logs Ingestion API returned {}: {}", status, text));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Interface for LogsIngestionInterface {
+
+    async fn send_logs(&mut self, logs: Caches) -> Result<()> {
+
+        // With asim logs are streamed by ASIM schema, otherwise by content type
+        let mut records: HashMap<String, Vec<String>> = HashMap::new();
+        for (content_type, content_logs) in logs.get_all_types() {
+            for log in content_logs.iter() {
+                let (key, record) = if self.asim {
+                    let (schema, record) = asim::normalize(log);
+                    (schema.name().to_string(), record)
+                } else {
+                    (content_type.clone(), with_time_generated(log))
+                };
+                match serde_json::to_string(&record) {
+                    Ok(record) => records.entry(key).or_default().push(record),
+                    Err(e) => warn!("Could not serialize a log in Logs Ingestion interface: {}", e),
+                }
+            }
+        }
+
+        for (key, key_records) in records {
+            let stream = self.streams.get(&key)
+                .cloned()
+                .unwrap_or_else(|| self.stream_name.clone());
+            let count = key_records.len();
+            let batches = split_batches(key_records, MAX_BODY_BYTES);
+            info!("Sending {} {} logs to stream {} in {} call(s).", count, key, stream, batches.len());
+            for batch in batches {
+                self.send_batch(&stream, &batch).await.map_err(|e| anyhow!(
+                    "Error sending {} {} logs to Logs Ingestion API: {}", batch.len(), key, e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// Copy of the log with a TimeGenerated column (from CreationTime) if it has none yet.
+fn with_time_generated(log: &ArbitraryJson) -> ArbitraryJson {
+    let mut record = log.clone();
+    if !record.contains_key("TimeGenerated") {
+        if let Some(Value::String(time)) = log.get("CreationTime") {
+            let time = if time.ends_with('Z') { time.clone() } else { format!("{}Z", time) };
+            record.insert("TimeGenerated".to_string(), Value::String(time));
+        }
+    }
+    record
+}
+
+
+/// Group serialized records into JSON array bodies of at most `max_bytes`. Records that do
+/// not fit in a body on their own are dropped with a warning.
+fn split_batches(records: Vec<String>, max_bytes: usize) -> Vec<Vec<String>> {
+    let mut batches = Vec::new();
+    let mut batch: Vec<String> = Vec::new();
+    let mut batch_size = 2;
+    for record in records {
+        if record.len() + 2 > max_bytes {
+            warn!("Dropping log of {} bytes, exceeds the {} byte request limit", record.len(), max_bytes);
+            continue
+        }
+        if batch_size + record.len() + 1 > max_bytes {
+            batches.push(std::mem::take(&mut batch));
+            batch_size = 2;
+        }
+        batch_size += record.len() + 1;
+        batch.push(record);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_time_generated() {
+        let mut log = ArbitraryJson::new();
+        log.insert("CreationTime".to_string(), Value::String("2024-01-01T10:00:00".to_string()));
+        assert_eq!(with_time_generated(&log)["TimeGenerated"], "2024-01-01T10:00:00Z");
+    }
+
+    #[test]
+    fn test_split_batches() {
+        let records = vec!["a".repeat(40), "b".repeat(40), "c".repeat(40), "d".repeat(200)];
+        let batches = split_batches(records, 100);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].len(), 2);
+        assert_eq!(batches[1].len(), 1);
+    }
+}
@@ -2,5 +2,20 @@ pub(crate) mod file_interface;
 pub(crate) mod fluentd_interface;
 pub(crate) mod graylog_interface;
 pub(crate) mod azure_oms_interface;
+pub(crate) mod logs_ingestion_interface;
+pub(crate) mod event_hub_interface;
+pub(crate) mod s3_interface;
+pub(crate) mod azure_blob_interface;
+pub(crate) mod object_upload;
+pub(crate) mod firehose_interface;
+pub(crate) mod qradar_interface;
+pub(crate) mod tcp_sender;
+pub(crate) mod stdout_interface;
+pub(crate) mod exec_interface;
+pub(crate) mod retry;
+pub(crate) mod spool;
+pub(crate) mod output;
+pub(crate) mod oversized;
+#[cfg(unix)]
+pub(crate) mod wazuh_interface;
 pub mod interface;
-pub mod interactive_interface;
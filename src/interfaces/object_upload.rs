@@ -0,0 +1,46 @@
+use log::warn;
+use crate::data_structures::Caches;
+
+/// The logs of a batch as one JSONL object per content type, for interfaces that upload logs as
+/// objects (S3, Azure Blob). How large objects get is up to the batching of the output, see
+/// Config::get_batching.
+pub fn jsonl_objects(logs: &Caches) -> Vec<(String, Vec<u8>)> {
+    let mut objects = Vec::new();
+    for (content_type, content_logs) in logs.get_all_types() {
+        if content_logs.is_empty() {
+            continue
+        }
+        let mut data = Vec::new();
+        for log in content_logs.iter() {
+            match serde_json::to_vec(log) {
+                Ok(line) => {
+                    data.extend_from_slice(&line);
+                    data.push(b'\n');
+                },
+                Err(e) => warn!("Could not serialize a log for object upload: {}", e),
+            }
+        }
+        objects.push((content_type, data));
+    }
+    objects
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_jsonl_objects() {
+        let mut logs = Caches::new(10);
+        for (content_type, id) in [("Audit.General", "1"), ("DLP.All", "2"), ("Audit.General", "3")] {
+            logs.insert(Arc::new(serde_json::from_str(&format!(r#"{{"Id":"{}"}}"#, id)).unwrap()), &content_type.to_string());
+        }
+        let objects = jsonl_objects(&logs);
+        assert_eq!(objects, [
+            ("Audit.General".to_string(), b"{\"Id\":\"1\"}\n{\"Id\":\"3\"}\n".to_vec()),
+            ("DLP.All".to_string(), b"{\"Id\":\"2\"}\n".to_vec()),
+        ]);
+    }
+}
@@ -0,0 +1,367 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use log::{error, warn};
+use crate::config::Config;
+use crate::data_structures::{Caches, SharedLog};
+use crate::interfaces::interface::Interface;
+use crate::interfaces::oversized::OversizedPolicy;
+use crate::interfaces::retry::{send_with_retry, RetryPolicy};
+use crate::interfaces::spool::Spool;
+use crate::lag::{self, IngestionLag};
+use crate::redact::Redaction;
+use crate::transform::{self, Renames};
+use crate::run_summary::OutputSummary;
+use tracing::Instrument;
+
+/// An interface with its delivery settings and the logs buffered for it. Every output has its
+/// own batch size and flush interval, so a slow destination can get big batches while a real
+/// time one gets frequent small ones.
+pub struct Output {
+    pub name: &'static str,
+    interface: Box<dyn Interface + Send>,
+    retry: RetryPolicy,
+    redaction: Redaction,
+    renames: Renames,
+    flatten: bool,
+    oversized: Option<OversizedPolicy>,
+    spool: Option<Spool>,
+    buffer: Caches,
+    /// Content blobs with logs in the buffer
+    holding: Vec<String>,
+    /// Content blobs of buffers the interface accepted, settled once it flushed them
+    unflushed: Vec<String>,
+    /// Content blobs of sent buffers, with whether their logs were delivered or spooled
+    settled: Vec<(String, bool)>,
+    flush_interval: Option<Duration>,
+    last_flush: Instant,
+    /// What happened to the logs of this run
+    pub summary: OutputSummary,
+    /// Lag of the logs delivered this run
+    pub lag: IngestionLag,
+}
+
+impl Output {
+
+    pub fn new(name: &'static str, interface: Box<dyn Interface + Send>, config: &Config,
+               tenant_id: &str, default_batch_size: usize, default_batch_bytes: usize) -> Result<Self> {
+        let (batch_size, flush_interval, batch_bytes) = config.get_batching(name);
+        Ok(Output {
+            name,
+            interface,
+            retry: RetryPolicy::for_output(&config.retry, name),
+            redaction: Redaction::for_output(&config.redact, name).map_err(|e| anyhow!(e))?,
+            renames: Renames::for_output(config, name).map_err(|e| anyhow!(e))?,
+            flatten: config.get_flatten(name),
+            oversized: OversizedPolicy::for_output(config, name),
+            spool: Spool::from_config(config, name, tenant_id)?,
+            buffer: Caches::new(batch_size.unwrap_or(default_batch_size))
+                .with_max_bytes(Some(batch_bytes.unwrap_or(default_batch_bytes))),
+            holding: Vec::new(),
+            unflushed: Vec::new(),
+            settled: Vec::new(),
+            flush_interval: flush_interval.map(Duration::from_secs),
+            last_flush: Instant::now(),
+            summary: OutputSummary { name: name.to_string(), ..Default::default() },
+            lag: IngestionLag::default(),
+        })
+    }
+
+    /// Redact, rename, flatten, limit the size of and buffer a log, sending the buffer once it
+    /// holds batch_size logs or batch_bytes of them. The log is only copied if this output changes it and other
+    /// outputs share it.
+    pub async fn add(&mut self, mut log: SharedLog, content_type: &String) {
+        if self.redaction.applies_to(content_type) || !self.renames.is_empty() || self.flatten {
+            let log = Arc::make_mut(&mut log);
+            self.redaction.apply(content_type, log.iter_mut());
+            self.renames.apply(log);
+            if self.flatten {
+                transform::flatten(log);
+            }
+        }
+        if let Some(oversized) = &self.oversized {
+            let (is_oversized, kept) = oversized.apply(self.name, log);
+            if is_oversized {
+                self.summary.logs_oversized += 1;
+            }
+            match kept {
+                Some(kept) => log = kept,
+                None => return,
+            }
+        }
+        self.buffer.insert(log, content_type);
+        if self.buffer.full() {
+            self.send_buffer().await;
+        }
+    }
+
+    /// Send the buffer if the flush interval passed since the last send.
+    pub async fn send_if_due(&mut self) {
+        if let Some(interval) = self.flush_interval {
+            if self.last_flush.elapsed() >= interval && !self.buffer.is_empty() {
+                self.send_buffer().await;
+            }
+        }
+    }
+
+    pub async fn send_buffer(&mut self) {
+        self.last_flush = Instant::now();
+        let held = std::mem::take(&mut self.holding);
+        if self.buffer.is_empty() {
+            // Their logs were all left out, see OversizedPolicy
+            self.settled.extend(held.into_iter().map(|content_id| (content_id, true)));
+            return
+        }
+        let empty = Caches::new(self.buffer.size).with_max_bytes(self.buffer.max_bytes);
+        let logs = std::mem::replace(&mut self.buffer, empty);
+        match self.deliver(logs).await {
+            Delivery::Sent => self.unflushed.extend(held),
+            Delivery::Spooled => self.settled.extend(held.into_iter().map(|content_id| (content_id, true))),
+            Delivery::Dropped => self.settled.extend(held.into_iter().map(|content_id| (content_id, false))),
+        }
+    }
+
+    /// Hold a content blob until the buffer the next logs are added to is sent, see
+    /// take_settled. False if that buffer holds it already.
+    pub fn hold(&mut self, content_id: &str) -> bool {
+        if self.holding.last().is_some_and(|held| held == content_id) {
+            return false
+        }
+        self.holding.push(content_id.to_string());
+        true
+    }
+
+    /// Content blobs held by the buffers sent and flushed or spooled since the last call, with
+    /// whether their logs were delivered or spooled.
+    pub fn take_settled(&mut self) -> Vec<(String, bool)> {
+        std::mem::take(&mut self.settled)
+    }
+
+    /// Bytes of the logs waiting in the buffer.
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffer.bytes
+    }
+
+    /// Send spooled batches, if the destination recovered.
+    pub async fn replay_spool(&mut self) {
+        if let Some(spool) = &self.spool {
+            spool.replay(self.name, self.interface.as_mut()).await;
+        }
+    }
+
+    /// Send the buffer and let the interface send whatever it buffered itself. Content blobs
+    /// of the batches it accepted are settled as delivered only if that succeeds.
+    pub async fn flush(&mut self) {
+        self.send_buffer().await;
+        let flushed = match self.interface.flush().await {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Could not flush {}: {}", self.name, e);
+                false
+            },
+        };
+        let unflushed = std::mem::take(&mut self.unflushed);
+        self.settled.extend(unflushed.into_iter().map(|content_id| (content_id, flushed)));
+    }
+
+    /// Send a batch with retries. Batches that cannot be delivered are spooled if the output
+    /// has a spool, and dropped otherwise. While older batches are spooled new ones queue
+    /// behind them, so the destination receives logs in order.
+    async fn deliver(&mut self, logs: Caches) -> Delivery {
+        let span = tracing::info_span!("send", output = self.name, logs = logs.len(), error = tracing::field::Empty);
+        self.deliver_batch(logs).instrument(span).await
+    }
+
+    async fn deliver_batch(&mut self, logs: Caches) -> Delivery {
+        let (name, interface) = (self.name, self.interface.as_mut());
+        let undelivered = match &self.spool {
+            Some(spool) if !spool.replay(name, interface).await => logs,
+            _ => {
+                let count = logs.len();
+                let creation_times = creation_times(&logs);
+                match send_with_retry(name, interface, logs, &self.retry).await {
+                    Ok(()) => {
+                        self.summary.logs_sent += count;
+                        for (content_type, times) in creation_times {
+                            for creation_time in times {
+                                self.lag.record(&content_type, creation_time);
+                            }
+                        }
+                        return Delivery::Sent
+                    },
+                    Err(logs) => logs,
+                }
+            },
+        };
+        tracing::Span::current().record("error", "not delivered");
+        match &self.spool {
+            Some(spool) => match spool.store(&undelivered) {
+                Ok(()) => {
+                    warn!("Spooled {} logs for {}", undelivered.len(), name);
+                    self.summary.logs_spooled += undelivered.len();
+                    Delivery::Spooled
+                },
+                Err(e) => {
+                    error!("Could not spool {} logs for {}, dropping them: {}", undelivered.len(), name, e);
+                    self.summary.logs_dropped += undelivered.len();
+                    Delivery::Dropped
+                },
+            },
+            None => {
+                error!("Dropping {} logs that could not be sent to {}", undelivered.len(), name);
+                self.summary.logs_dropped += undelivered.len();
+                Delivery::Dropped
+            },
+        }
+    }
+}
+
+/// What became of a batch, see Output::deliver.
+enum Delivery {
+    /// The interface accepted it, but may still buffer it until it is flushed
+    Sent,
+    /// Stored in the spool, to be sent by a later run
+    Spooled,
+    Dropped,
+}
+
+/// CreationTime of the logs of a batch by content type, so their lag can be recorded once the
+/// batch is delivered.
+fn creation_times(logs: &Caches) -> Vec<(String, Vec<DateTime<Utc>>)> {
+    logs.get_all_types().into_iter()
+        .map(|(content_type, logs)| (content_type, logs.iter()
+            .filter_map(|log| lag::creation_time(log))
+            .collect()))
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use async_trait::async_trait;
+
+    struct CountingInterface {
+        batches: Arc<Mutex<Vec<usize>>>,
+    }
+
+    #[async_trait]
+    impl Interface for CountingInterface {
+        async fn send_logs(&mut self, logs: Caches) -> Result<()> {
+            self.batches.lock().unwrap().push(logs.len());
+            Ok(())
+        }
+    }
+
+    struct FailingInterface;
+
+    #[async_trait]
+    impl Interface for FailingInterface {
+        async fn send_logs(&mut self, _logs: Caches) -> Result<()> {
+            Err(anyhow!("unavailable"))
+        }
+    }
+
+    /// Accepts batches, but cannot deliver them when flushed
+    struct UnflushedInterface {
+        sent: usize,
+    }
+
+    #[async_trait]
+    impl Interface for UnflushedInterface {
+        async fn send_logs(&mut self, logs: Caches) -> Result<()> {
+            self.sent += logs.len();
+            Ok(())
+        }
+
+        async fn flush(&mut self) -> Result<()> {
+            Err(anyhow!("lost {} logs", self.sent))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_settled() {
+        let config: Config = serde_yaml::from_str("{output: {}, retry: {default: {max_retries: 0}}}").unwrap();
+        let general = "Audit.General".to_string();
+
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let interface = Box::new(CountingInterface { batches: batches.clone() });
+        let mut output = Output::new("fluentd", interface, &config, "tenant", 2, usize::MAX).unwrap();
+        assert!(output.hold("a"));
+        output.add(SharedLog::default(), &general).await;
+        assert!(!output.hold("a"));
+        assert!(output.take_settled().is_empty());
+        // The second log of "a" fills the buffer, the third goes into the next one. Sent
+        // buffers are settled once the interface is flushed.
+        output.add(SharedLog::default(), &general).await;
+        assert_eq!(*batches.lock().unwrap(), vec![2]);
+        assert!(output.take_settled().is_empty());
+        assert!(output.hold("a"));
+        output.add(SharedLog::default(), &general).await;
+        assert!(output.hold("b"));
+        output.flush().await;
+        assert_eq!(output.take_settled(), [("a".to_string(), true), ("a".to_string(), true), ("b".to_string(), true)]);
+
+        let mut output = Output::new("graylog", Box::new(FailingInterface), &config, "tenant", 100, usize::MAX).unwrap();
+        output.hold("c");
+        output.add(SharedLog::default(), &general).await;
+        output.flush().await;
+        assert_eq!(output.take_settled(), [("c".to_string(), false)]);
+
+        let interface = Box::new(UnflushedInterface { sent: 0 });
+        let mut output = Output::new("exec", interface, &config, "tenant", 100, usize::MAX).unwrap();
+        output.hold("d");
+        output.add(SharedLog::default(), &general).await;
+        output.flush().await;
+        assert_eq!(output.take_settled(), [("d".to_string(), false)]);
+        assert_eq!(output.summary.logs_sent, 1);
+    }
+
+    #[tokio::test]
+    async fn test_batch_size_and_flush_interval() {
+        let config: Config = serde_yaml::from_str(r#"
+            output: {}
+            batching:
+              default:
+                batch_size: 2
+              graylog:
+                flush_interval: 0s
+              logs_ingestion:
+                batch_bytes: 30
+        "#).unwrap();
+        let general = "Audit.General".to_string();
+
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let interface = Box::new(CountingInterface { batches: batches.clone() });
+        let mut output = Output::new("fluentd", interface, &config, "tenant", 100, usize::MAX).unwrap();
+        for _ in 0..5 {
+            output.add(SharedLog::default(), &general).await;
+        }
+        output.send_if_due().await;
+        assert_eq!(*batches.lock().unwrap(), vec![2, 2]);
+        output.flush().await;
+        assert_eq!(*batches.lock().unwrap(), vec![2, 2, 1]);
+        assert_eq!(output.summary.logs_sent, 5);
+
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let interface = Box::new(CountingInterface { batches: batches.clone() });
+        let mut output = Output::new("graylog", interface, &config, "tenant", 100, usize::MAX).unwrap();
+        output.add(SharedLog::default(), &general).await;
+        output.send_if_due().await;
+        assert_eq!(*batches.lock().unwrap(), vec![1]);
+
+        // Two logs of 17 bytes fill 30 bytes
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let interface = Box::new(CountingInterface { batches: batches.clone() });
+        let mut output = Output::new("logs_ingestion", interface, &config, "tenant", 100, usize::MAX).unwrap();
+        let log: SharedLog = Arc::new(serde_json::from_str(r#"{"Id":"12345678"}"#).unwrap());
+        output.add(log.clone(), &general).await;
+        assert_eq!(output.buffered_bytes(), 17);
+        output.add(log.clone(), &general).await;
+        output.add(log, &general).await;
+        assert_eq!(*batches.lock().unwrap(), vec![2]);
+        assert_eq!(output.buffered_bytes(), 17);
+    }
+}
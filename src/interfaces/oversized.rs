@@ -0,0 +1,168 @@
+// Handling of logs too big for a destination, such as large SharePoint or AppAccess logs over
+// the GELF UDP or Log Analytics limits. Configured per output name, falling back to "default":
+//
+// oversized:
+//   graylog:
+//     max_size: 8K
+//     action: truncate            # or dead_letter, or pass
+//     drop_fields: [AppAccessContext, ModifiedProperties]
+//
+// truncate removes drop_fields in order until the log fits, then its largest other fields, and
+// lists what it removed in TruncatedFields. dead_letter writes the log to a JSON lines file
+// instead of sending it, pass sends it anyway. Either way the log is counted.
+
+use std::io::Write;
+use std::sync::Arc;
+use log::warn;
+use serde_json::Value;
+use crate::config::{Config, OversizedAction};
+use crate::data_structures::{json_size, ArbitraryJson, SharedLog};
+
+/// Fields truncate keeps as long as others can go.
+const KEPT_FIELDS: [&str; 6] = ["Id", "id", "CreationTime", "Operation", "RecordType", "OriginFeed"];
+const DEFAULT_DEAD_LETTER: &str = "dead_letter.json";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OversizedPolicy {
+    max_size: usize,
+    action: OversizedAction,
+    drop_fields: Vec<String>,
+    dead_letter: String,
+}
+
+impl OversizedPolicy {
+
+    /// Policy of `output`, None without a max_size for it or the "default" entry.
+    pub fn for_output(config: &Config, output: &str) -> Option<Self> {
+        let default = config.oversized.get("default").cloned().unwrap_or_default();
+        let specific = config.oversized.get(output).cloned().unwrap_or_default();
+        let max_size = specific.max_size.or(default.max_size)?;
+        Some(OversizedPolicy {
+            max_size: Config::parse_size(&max_size),
+            action: specific.action.or(default.action).unwrap_or(OversizedAction::Truncate),
+            drop_fields: if specific.drop_fields.is_empty() { default.drop_fields } else { specific.drop_fields },
+            dead_letter: specific.dead_letter.or(default.dead_letter)
+                .unwrap_or_else(|| format!("{}/{}_{}", config.get_working_dir().trim_end_matches('/'), output, DEFAULT_DEAD_LETTER)),
+        })
+    }
+
+    /// Apply the policy to a log over max_size. Returns whether the log is oversized, and the
+    /// log if it should still be sent.
+    pub fn apply(&self, output: &str, mut log: SharedLog) -> (bool, Option<SharedLog>) {
+        if size(&log) <= self.max_size {
+            return (false, Some(log))
+        }
+        match self.action {
+            OversizedAction::Pass => (true, Some(log)),
+            OversizedAction::DeadLetter => {
+                if let Err(e) = self.write_dead_letter(&log) {
+                    warn!("Could not write oversized log for {} to {}, dropping it: {}", output, self.dead_letter, e);
+                }
+                (true, None)
+            },
+            OversizedAction::Truncate => {
+                truncate(Arc::make_mut(&mut log), self.max_size, &self.drop_fields);
+                (true, Some(log))
+            },
+        }
+    }
+
+    fn write_dead_letter(&self, log: &ArbitraryJson) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(log)?;
+        line.push(b'\n');
+        std::fs::OpenOptions::new().create(true).append(true).open(&self.dead_letter)?.write_all(&line)
+    }
+}
+
+fn size(log: &ArbitraryJson) -> usize {
+    json_size(log)
+}
+
+/// Remove drop_fields, then the largest other fields but KEPT_FIELDS, until the log fits.
+fn truncate(log: &mut ArbitraryJson, max_size: usize, drop_fields: &[String]) {
+    let mut removed = Vec::new();
+    for field in drop_fields {
+        if size(log) + truncated_size(&removed) <= max_size {
+            break
+        }
+        if log.remove(field).is_some() {
+            removed.push(field.clone());
+        }
+    }
+    let mut by_size: Vec<(String, usize)> = log.iter()
+        .filter(|(field, _)| !KEPT_FIELDS.contains(&field.as_str()))
+        .map(|(field, value)| (field.clone(), serde_json::to_vec(value).map(|v| v.len()).unwrap_or(0)))
+        .collect();
+    by_size.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    for (field, _) in by_size {
+        if size(log) + truncated_size(&removed) <= max_size {
+            break
+        }
+        log.remove(&field);
+        removed.push(field);
+    }
+    if !removed.is_empty() {
+        log.insert("TruncatedFields".to_string(), Value::Array(removed.into_iter().map(Value::String).collect()));
+    }
+}
+
+/// Size the TruncatedFields field will add.
+fn truncated_size(removed: &[String]) -> usize {
+    match removed.is_empty() {
+        true => r#","TruncatedFields":[]"#.len(),
+        false => r#","TruncatedFields":[]"#.len() + removed.iter().map(|field| field.len() + 3).sum::<usize>(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn log(value: Value) -> SharedLog {
+        Arc::new(serde_json::from_value(value).unwrap())
+    }
+
+    #[test]
+    fn test_oversized() {
+        let dir = tempfile::tempdir().unwrap();
+        let config: Config = serde_yaml::from_str(&format!(r#"
+output: {{}}
+workingDir: "{}"
+oversized:
+  default:
+    max_size: 200
+    drop_fields: [AppAccessContext, ModifiedProperties]
+  graylog:
+    action: dead_letter
+  stdout:
+    action: pass
+"#, dir.path().display())).unwrap();
+        let big = log(json!({
+            "Id": "1", "Operation": "FileAccessed", "UserId": "alice@contoso.com",
+            "AppAccessContext": {"ClientAppName": "x".repeat(100)},
+            "ModifiedProperties": [{"Name": "a"}], "SiteUrl": "y".repeat(150), "Small": 1,
+        }));
+        let small = log(json!({"Id": "2"}));
+
+        let truncate = OversizedPolicy::for_output(&config, "logs_ingestion").unwrap();
+        assert_eq!(truncate.apply("logs_ingestion", small.clone()), (false, Some(small.clone())));
+        let (oversized, truncated) = truncate.apply("logs_ingestion", big.clone());
+        let truncated = truncated.unwrap();
+        assert!(oversized && size(&truncated) <= 200);
+        assert_eq!(truncated["TruncatedFields"], json!(["AppAccessContext", "ModifiedProperties", "SiteUrl"]));
+        assert_eq!(truncated["UserId"], "alice@contoso.com");
+
+        assert_eq!(OversizedPolicy::for_output(&config, "stdout").unwrap().apply("stdout", big.clone()),
+                   (true, Some(big.clone())));
+
+        let dead_letter = OversizedPolicy::for_output(&config, "graylog").unwrap();
+        assert_eq!(dead_letter.apply("graylog", big.clone()), (true, None));
+        let written = std::fs::read_to_string(dir.path().join("graylog_dead_letter.json")).unwrap();
+        assert_eq!(serde_json::from_str::<ArbitraryJson>(written.trim()).unwrap(), *big);
+
+        let unset: Config = serde_yaml::from_str("output: {}").unwrap();
+        assert!(OversizedPolicy::for_output(&unset, "graylog").is_none());
+    }
+}
@@ -0,0 +1,63 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::info;
+use crate::tls;
+use crate::config::Config;
+use crate::data_structures::Caches;
+use crate::formatters::{LogFormat, DEFAULT_PRODUCT_VERSION};
+use crate::interfaces::interface::Interface;
+use crate::interfaces::tcp_sender::TcpSender;
+
+/// Interface that sends logs to IBM QRadar as newline delimited LEEF 2.0 (or CEF) events over
+/// a persistent TCP (or TLS) connection.
+pub struct QRadarInterface {
+    sender: TcpSender,
+    format: LogFormat,
+    product_version: String,
+}
+
+impl QRadarInterface {
+
+    pub fn new(config: Config) -> Result<Self> {
+
+        let qradar_config = config.output.qradar.as_ref()
+            .ok_or_else(|| anyhow!("No qradar output configured"))?;
+        let format = qradar_config.format.unwrap_or(LogFormat::Leef);
+        if format == LogFormat::Json {
+            return Err(anyhow!("qradar output supports format 'leef' or 'cef'"));
+        }
+        info!("QRadar interface sending {:?} to {}:{}", format, qradar_config.address, qradar_config.port);
+        Ok(QRadarInterface {
+            sender: TcpSender::new(&qradar_config.address, qradar_config.port, tls::merge(qradar_config.tls.as_ref(), config.tls.as_ref()).as_ref())?,
+            format,
+            product_version: qradar_config.product_version.clone()
+                .unwrap_or_else(|| DEFAULT_PRODUCT_VERSION.to_string()),
+        })
+    }
+}
+
+#[async_trait]
+impl Interface for QRadarInterface {
+
+    async fn send_logs(&mut self, logs: Caches) -> Result<()> {
+
+        for (content_type, content_logs) in logs.get_all_types() {
+            if content_logs.is_empty() {
+                continue
+            }
+            let mut data = Vec::new();
+            for log in content_logs.iter() {
+                data.extend_from_slice(self.format.format(log, &self.product_version).as_bytes());
+                data.push(b'\n');
+            }
+            self.sender.send(&data).await.map_err(|e| anyhow!(
+                "Error sending {} {} logs to QRadar: {}", content_logs.len(), content_type, e))?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.sender.close().await;
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+// Retries of failed interface batches with exponential backoff. Limits are configured per
+// output name, falling back to the "default" entry and then to the built-in defaults:
+//
+// retry:
+//   default:
+//     max_retries: 3
+//   graylog:
+//     max_retries: 10
+//     initial_backoff: 5s
+//     max_backoff: 5m
+
+use std::collections::HashMap;
+use std::time::Duration;
+use log::{error, warn};
+use tokio::time::sleep;
+use crate::config::{Config, RetrySubConfig};
+use crate::data_structures::Caches;
+use crate::interfaces::interface::Interface;
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: DEFAULT_MAX_RETRIES,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+        }
+    }
+}
+
+impl RetryPolicy {
+
+    /// Policy for `output`, settings missing for it are taken from the "default" entry.
+    pub fn for_output(config: &HashMap<String, RetrySubConfig>, output: &str) -> Self {
+        let default = config.get("default").cloned().unwrap_or_default();
+        let specific = config.get(output).cloned().unwrap_or_default();
+        let duration = |value: Option<String>, fallback: Duration| value
+            .map(|s| Duration::from_secs(Config::parse_interval(&s)))
+            .unwrap_or(fallback);
+        RetryPolicy {
+            max_retries: specific.max_retries.or(default.max_retries).unwrap_or(DEFAULT_MAX_RETRIES),
+            initial_backoff: duration(specific.initial_backoff.or(default.initial_backoff), DEFAULT_INITIAL_BACKOFF),
+            max_backoff: duration(specific.max_backoff.or(default.max_backoff), DEFAULT_MAX_BACKOFF),
+        }
+    }
+
+    /// Delay before retry number `attempt` (starting at 1), doubling up to max_backoff.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+/// Send `logs` to `interface`, retrying the whole batch on failure. A batch that still fails
+/// after the last retry is handed back, so the caller can spool or drop it.
+pub async fn send_with_retry(name: &str, interface: &mut (dyn Interface + Send), logs: Caches,
+                             policy: &RetryPolicy) -> Result<(), Caches> {
+    let count = logs.len();
+    let mut attempt = 0;
+    loop {
+        match interface.send_logs(logs.clone()).await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < policy.max_retries => {
+                attempt += 1;
+                let delay = policy.backoff(attempt);
+                warn!("Sending {} logs to {} failed: {}. Retry {}/{} in {}s",
+                      count, name, e, attempt, policy.max_retries, delay.as_secs_f32());
+                sleep(delay).await;
+            },
+            Err(e) => {
+                error!("Sending {} logs to {} failed after {} retries: {}",
+                       count, name, policy.max_retries, e);
+                return Err(logs)
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Result};
+    use async_trait::async_trait;
+    use crate::data_structures::ArbitraryJson;
+
+    struct FlakyInterface {
+        failures: usize,
+        calls: usize,
+    }
+
+    #[async_trait]
+    impl Interface for FlakyInterface {
+        async fn send_logs(&mut self, _logs: Caches) -> Result<()> {
+            self.calls += 1;
+            if self.calls <= self.failures { Err(anyhow!("unavailable")) } else { Ok(()) }
+        }
+    }
+
+    fn logs() -> Caches {
+        let mut logs = Caches::new(10);
+        logs.insert(ArbitraryJson::new(), &"Audit.General".to_string());
+        logs
+    }
+
+    #[test]
+    fn test_for_output() {
+        let config: HashMap<String, RetrySubConfig> = serde_yaml::from_str(r#"
+            default:
+              max_retries: 5
+              max_backoff: 2m
+            graylog:
+              max_retries: 10
+              initial_backoff: 5s
+        "#).unwrap();
+        let graylog = RetryPolicy::for_output(&config, "graylog");
+        assert_eq!(graylog, RetryPolicy {
+            max_retries: 10,
+            initial_backoff: Duration::from_secs(5),
+            max_backoff: Duration::from_secs(120),
+        });
+        assert_eq!(RetryPolicy::for_output(&config, "fluentd").max_retries, 5);
+        assert_eq!(RetryPolicy::for_output(&HashMap::new(), "fluentd"), RetryPolicy::default());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default();
+        let delays: Vec<u64> = (1..=8).map(|a| policy.backoff(a).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(policy.backoff(100), Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn test_send_with_retry() {
+        let policy = RetryPolicy { initial_backoff: Duration::ZERO, ..Default::default() };
+
+        let mut interface = FlakyInterface { failures: 2, calls: 0 };
+        assert!(send_with_retry("test", &mut interface, logs(), &policy).await.is_ok());
+        assert_eq!(interface.calls, 3);
+
+        let mut interface = FlakyInterface { failures: 10, calls: 0 };
+        let undelivered = send_with_retry("test", &mut interface, logs(), &policy).await.unwrap_err();
+        assert_eq!(undelivered.len(), 1);
+        assert_eq!(interface.calls, 4);
+    }
+}
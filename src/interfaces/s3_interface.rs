@@ -0,0 +1,154 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::info;
+use reqwest::Url;
+use crate::aws_sigv4::{self, AwsCredentials, SigningRequest};
+use crate::tls;
+use crate::config::Config;
+use crate::data_structures::Caches;
+use crate::interfaces::file_interface::gzip;
+use crate::interfaces::interface::Interface;
+use crate::interfaces::object_upload::jsonl_objects;
+
+const DEFAULT_KEY_TEMPLATE: &str = "{tenant}/{content_type}/{date}/{uuid}.json.gz";
+
+/// Interface that uploads the logs of every batch as (gzipped) JSONL objects, one per content
+/// type, to S3 or S3-compatible storage. Batches are sized by flush_size and flush_interval
+/// unless `batching` sets otherwise, see Config::get_batching.
+pub struct S3Interface {
+    client: reqwest::Client,
+    credentials: AwsCredentials,
+    region: String,
+    base_url: Url,
+    path_style: bool,
+    bucket: String,
+    key_template: String,
+    compress: bool,
+    tenant_id: String,
+}
+
+impl S3Interface {
+
+    pub fn new(config: Config, tenant_id: String) -> Result<Self> {
+
+        let s3_config = config.output.s3.as_ref()
+            .ok_or_else(|| anyhow!("No s3 output configured"))?;
+        let credentials = AwsCredentials::resolve(s3_config.access_key_id.as_ref(),
+                                                  s3_config.secret_access_key.as_ref(),
+                                                  s3_config.session_token.as_ref())?;
+        let endpoint = s3_config.endpoint.clone()
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", s3_config.region));
+        let base_url = Url::parse(&endpoint)
+            .map_err(|e| anyhow!("Invalid s3 endpoint '{}': {}", endpoint, e))?;
+        let path_style = s3_config.path_style.unwrap_or(s3_config.endpoint.is_some());
+
+        info!("S3 interface writing to bucket {} at {}", s3_config.bucket, endpoint);
+        Ok(S3Interface {
+            client: tls::http_client(config.tls.as_ref())?,
+            credentials,
+            region: s3_config.region.clone(),
+            base_url,
+            path_style,
+            bucket: s3_config.bucket.clone(),
+            key_template: s3_config.key_template.clone()
+                .unwrap_or_else(|| DEFAULT_KEY_TEMPLATE.to_string()),
+            compress: s3_config.compress.unwrap_or(true),
+            tenant_id,
+        })
+    }
+
+    fn object_url(&self, key: &str) -> Result<Url> {
+        let mut url = self.base_url.clone();
+        if self.path_style {
+            url.set_path(&format!("/{}/{}", self.bucket, key));
+        } else {
+            let host = url.host_str().ok_or_else(|| anyhow!("s3 endpoint has no host"))?;
+            let host = format!("{}.{}", self.bucket, host);
+            url.set_host(Some(&host))?;
+            url.set_path(&format!("/{}", key));
+        }
+        Ok(url)
+    }
+
+    async fn upload(&self, content_type: &str, data: &[u8]) -> Result<()> {
+
+        let body = if self.compress { gzip(data)? } else { data.to_vec() };
+        let key = render_key(&self.key_template, &self.tenant_id, content_type, Utc::now());
+        let url = self.object_url(&key)?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            _ => return Err(anyhow!("s3 endpoint has no host")),
+        };
+        let content_type_header = if self.compress { "application/gzip" } else { "application/x-ndjson" };
+        let request = SigningRequest {
+            method: "PUT",
+            host: &host,
+            path: url.path(),
+            query: "",
+            headers: &[("content-type", content_type_header)],
+            payload: &body,
+        };
+        let signed = aws_sigv4::sign(&self.credentials, &self.region, "s3", &request, Utc::now());
+
+        let mut builder = self.client
+            .put(url.clone())
+            .header("content-type", content_type_header);
+        for (k, v) in signed {
+            builder = builder.header(k, v);
+        }
+        let response = builder.body(body).send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            return Err(anyhow!("S3 returned {} for {}: {}", status, key, text));
+        }
+        info!("Uploaded {} bytes of {} logs to s3://{}/{}", data.len(), content_type, self.bucket, key);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Interface for S3Interface {
+
+    /// Upload the logs of each content type of the batch as one object. The batching of the
+    /// output (flush_size and flush_interval by default) decides how large objects get.
+    async fn send_logs(&mut self, logs: Caches) -> Result<()> {
+        for (content_type, data) in jsonl_objects(&logs) {
+            self.upload(&content_type, &data).await
+                .map_err(|e| anyhow!("Error uploading {} logs to S3: {}", content_type, e))?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Fill in the placeholders of an object key template.
+fn render_key(template: &str, tenant_id: &str, content_type: &str, now: DateTime<Utc>) -> String {
+    template
+        .replace("{tenant}", tenant_id)
+        .replace("{content_type}", content_type)
+        .replace("{date}", &now.format("%Y-%m-%d").to_string())
+        .replace("{hour}", &now.format("%H").to_string())
+        .replace("{timestamp}", &now.format("%Y%m%dT%H%M%S").to_string())
+        .replace("{uuid}", &uuid::Uuid::new_v4().to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_key() {
+        let now = DateTime::parse_from_rfc3339("2024-03-05T07:08:09Z").unwrap().with_timezone(&Utc);
+        let key = render_key(DEFAULT_KEY_TEMPLATE, "tenant-1", "Audit.General", now);
+        assert!(key.starts_with("tenant-1/Audit.General/2024-03-05/"));
+        assert!(key.ends_with(".json.gz"));
+        assert_eq!(key.len(), "tenant-1/Audit.General/2024-03-05/".len() + 36 + ".json.gz".len());
+
+        let key = render_key("logs/{hour}/{timestamp}.json", "t", "DLP.All", now);
+        assert_eq!(key, "logs/07/20240305T070809.json");
+    }
+}
@@ -0,0 +1,211 @@
+// Disk spool for batches an output could not accept after its retries, e.g. during a SIEM
+// maintenance window. Batches are written to <directory>/<output>/<tenant id>/ as JSONL files
+// and replayed oldest first before new logs are sent to that output, so ordering is kept. When
+// the spool grows past max_size the oldest batches are dropped.
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use log::{error, info, warn};
+use serde_json::json;
+use crate::config::Config;
+use crate::data_structures::{ArbitraryJson, Caches};
+use crate::interfaces::interface::Interface;
+
+const DEFAULT_MAX_SIZE: &str = "1G";
+const EXTENSION: &str = "jsonl";
+
+pub struct Spool {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+impl Spool {
+
+    /// Spool of `output` for `tenant_id`, if spooling is configured.
+    pub fn from_config(config: &Config, output: &str, tenant_id: &str) -> Result<Option<Self>> {
+        let Some(spool_config) = config.spool.as_ref() else {
+            return Ok(None)
+        };
+        let root = spool_config.directory.clone()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| Path::new(&config.get_working_dir()).join("spool"));
+        let max_size = Config::parse_size(spool_config.max_size.as_deref().unwrap_or(DEFAULT_MAX_SIZE));
+        Spool::open(root.join(output).join(tenant_id), max_size as u64).map(Some)
+    }
+
+    pub fn open(dir: PathBuf, max_size: u64) -> Result<Self> {
+        fs::create_dir_all(&dir)
+            .map_err(|e| anyhow!("Could not create spool directory {}: {}", dir.display(), e))?;
+        let spool = Spool { dir, max_size };
+        let pending = spool.files().len();
+        if pending > 0 {
+            info!("{} spooled batch(es) waiting in {}", pending, spool.dir.display());
+        }
+        Ok(spool)
+    }
+
+    /// Spooled batch files, oldest first.
+    fn files(&self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| p.extension().map(|e| e == EXTENSION).unwrap_or(false))
+                .collect(),
+            Err(e) => {
+                warn!("Could not list spool directory {}: {}", self.dir.display(), e);
+                Vec::new()
+            }
+        };
+        // Names start with the spool time, so they sort chronologically
+        files.sort();
+        files
+    }
+
+    /// Persist a batch, then drop the oldest batches if the spool is over its max size.
+    pub fn store(&self, logs: &Caches) -> Result<()> {
+        let name = format!("{}-{}", Utc::now().format("%Y%m%dT%H%M%S%.6f"), uuid::Uuid::new_v4().simple());
+        let path = self.dir.join(format!("{}.{}", name, EXTENSION));
+        let partial = self.dir.join(format!("{}.partial", name));
+
+        let mut writer = BufWriter::new(File::create(&partial)?);
+        for (content_type, content_logs) in logs.get_all_types() {
+            for log in content_logs.iter() {
+                serde_json::to_writer(&mut writer, &json!({"content_type": content_type, "log": log}))?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&partial, &path)?;
+        self.enforce_max_size();
+        Ok(())
+    }
+
+    fn enforce_max_size(&self) {
+        let mut files: Vec<(PathBuf, u64)> = self.files().into_iter()
+            .filter_map(|p| fs::metadata(&p).ok().map(|m| (p, m.len())))
+            .collect();
+        let mut total: u64 = files.iter().map(|(_, size)| size).sum();
+        // Always keep the newest batch, even if it alone is over the limit
+        while total > self.max_size && files.len() > 1 {
+            let (oldest, size) = files.remove(0);
+            error!("Spool {} is over its max size, dropping oldest batch {}", self.dir.display(), oldest.display());
+            if let Err(e) = fs::remove_file(&oldest) {
+                error!("Could not remove spooled batch {}: {}", oldest.display(), e);
+            }
+            total -= size;
+        }
+    }
+
+    fn load(path: &Path) -> Result<Caches> {
+        let mut logs = Caches::default();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue
+            }
+            let mut entry: ArbitraryJson = serde_json::from_str(&line)?;
+            let content_type = entry.remove("content_type")
+                .and_then(|v| v.as_str().map(|s| s.to_string()))
+                .ok_or_else(|| anyhow!("spooled log without content_type"))?;
+            let log: ArbitraryJson = match entry.remove("log") {
+                Some(serde_json::Value::Object(log)) => log.into_iter().collect(),
+                _ => return Err(anyhow!("spooled log without log object")),
+            };
+            logs.insert(log, &content_type);
+        }
+        logs.size = logs.len();
+        Ok(logs)
+    }
+
+    /// Send spooled batches to `interface`, oldest first, until one fails. Returns whether the
+    /// spool is empty afterwards.
+    pub async fn replay(&self, name: &str, interface: &mut (dyn Interface + Send)) -> bool {
+        for path in self.files() {
+            let logs = match Spool::load(&path) {
+                Ok(logs) => logs,
+                Err(e) => {
+                    // Keep it for inspection, but out of the way of the replay
+                    error!("Could not read spooled batch {}, setting it aside: {}", path.display(), e);
+                    let _ = fs::rename(&path, path.with_extension("corrupt"));
+                    continue
+                }
+            };
+            if let Err(e) = interface.send_logs(logs.clone()).await {
+                warn!("{} is still unavailable, keeping spooled batches: {}", name, e);
+                return false
+            }
+            info!("Replayed {} spooled logs to {}", logs.len(), name);
+            if let Err(e) = fs::remove_file(&path) {
+                error!("Could not remove replayed batch {}, it may be sent again: {}", path.display(), e);
+            }
+        }
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use serde_json::Value;
+
+    struct TestInterface {
+        available: bool,
+        received: Vec<Caches>,
+    }
+
+    #[async_trait]
+    impl Interface for TestInterface {
+        async fn send_logs(&mut self, logs: Caches) -> Result<()> {
+            if !self.available {
+                return Err(anyhow!("unavailable"))
+            }
+            self.received.push(logs);
+            Ok(())
+        }
+    }
+
+    fn batch(operation: &str) -> Caches {
+        let mut log = ArbitraryJson::new();
+        log.insert("Operation".to_string(), Value::String(operation.to_string()));
+        let mut logs = Caches::new(10);
+        logs.insert(log, &"Audit.SharePoint".to_string());
+        logs
+    }
+
+    #[tokio::test]
+    async fn test_store_and_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = Spool::open(dir.path().join("graylog"), 1024 * 1024).unwrap();
+        spool.store(&batch("FileAccessed")).unwrap();
+        spool.store(&batch("FileDeleted")).unwrap();
+
+        let mut interface = TestInterface { available: false, received: Vec::new() };
+        assert!(!spool.replay("graylog", &mut interface).await);
+        assert_eq!(spool.files().len(), 2);
+
+        interface.available = true;
+        assert!(spool.replay("graylog", &mut interface).await);
+        assert!(spool.files().is_empty());
+        let operations: Vec<&Value> = interface.received.iter().map(|b| &b.sharepoint[0]["Operation"]).collect();
+        assert_eq!(operations, vec!["FileAccessed", "FileDeleted"]);
+    }
+
+    #[test]
+    fn test_max_size_drops_oldest() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = Spool::open(dir.path().to_path_buf(), 100).unwrap();
+        for _ in 0..5 {
+            spool.store(&batch("FileAccessed")).unwrap();
+        }
+        let remaining = spool.files();
+        let size: u64 = remaining.iter().map(|p| fs::metadata(p).unwrap().len()).sum();
+        assert!(!remaining.is_empty());
+        assert!(size <= 100);
+    }
+}
@@ -0,0 +1,40 @@
+use std::io::{BufWriter, Write};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::warn;
+use crate::data_structures::Caches;
+use crate::interfaces::interface::Interface;
+
+/// Interface that writes each log as one JSON line to stdout, for running in containers where
+/// the platform log driver ships stdout. Diagnostics go to stderr (or the log file), so stdout
+/// only carries logs.
+pub struct StdoutInterface {}
+
+impl StdoutInterface {
+    pub fn new() -> Self {
+        StdoutInterface {}
+    }
+}
+
+#[async_trait]
+impl Interface for StdoutInterface {
+
+    async fn send_logs(&mut self, logs: Caches) -> Result<()> {
+
+        let stdout = std::io::stdout();
+        let mut writer = BufWriter::new(stdout.lock());
+        for (_, content_logs) in logs.get_all_types() {
+            for log in content_logs.iter() {
+                match serde_json::to_writer(&mut writer, log) {
+                    Ok(()) => {
+                        writer.write_all(b"\n")
+                            .map_err(|e| anyhow!("Could not write log to stdout: {}", e))?;
+                    },
+                    Err(e) if e.is_io() => return Err(anyhow!("Could not write log to stdout: {}", e)),
+                    Err(e) => warn!("Could not serialize a log for stdout: {}", e),
+                }
+            }
+        }
+        writer.flush().map_err(|e| anyhow!("Could not flush stdout: {}", e))
+    }
+}
@@ -0,0 +1,101 @@
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+use crate::config::TlsSubConfig;
+use crate::tls::client_config;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) trait Connection: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> Connection for T {}
+
+pub(crate) type TlsSettings = (TlsConnector, ServerName<'static>);
+
+/// TLS connector and the name to verify the server against, if TLS is configured.
+pub(crate) fn tls_settings(address: &str, tls: Option<&TlsSubConfig>) -> Result<Option<TlsSettings>> {
+    let Some(tls_config) = tls else {
+        return Ok(None)
+    };
+    let name = tls_config.server_name.clone().unwrap_or_else(|| address.to_string());
+    let server_name = ServerName::try_from(name.clone())
+        .map_err(|e| anyhow!("Invalid TLS server name '{}': {}", name, e))?;
+    Ok(Some((TlsConnector::from(Arc::new(client_config(tls_config)?)), server_name)))
+}
+
+/// Open a TCP connection, wrapped in TLS if `tls` is given.
+pub(crate) async fn connect(address: &str, port: u16, tls: Option<&TlsSettings>) -> Result<Box<dyn Connection>> {
+
+    let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect((address, port)))
+        .await
+        .map_err(|_| anyhow!("Timed out connecting to {}:{}", address, port))??;
+    stream.set_nodelay(true)?;
+    let connection: Box<dyn Connection> = match tls {
+        Some((connector, server_name)) => {
+            Box::new(connector.connect(server_name.clone(), stream).await?)
+        },
+        None => Box::new(stream),
+    };
+    info!("Connected to {}:{}{}", address, port, if tls.is_some() { " (TLS)" } else { "" });
+    Ok(connection)
+}
+
+/// Persistent TCP connection, optionally wrapped in TLS, for interfaces that stream logs to a
+/// socket (QRadar, syslog style receivers). Connects lazily and reconnects once when a write
+/// fails, e.g. because the receiver closed an idle connection.
+pub struct TcpSender {
+    address: String,
+    port: u16,
+    tls: Option<TlsSettings>,
+    connection: Option<Box<dyn Connection>>,
+}
+
+impl TcpSender {
+
+    pub fn new(address: &str, port: u16, tls: Option<&TlsSubConfig>) -> Result<Self> {
+
+        Ok(TcpSender {
+            address: address.to_string(),
+            port,
+            tls: tls_settings(address, tls)?,
+            connection: None,
+        })
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        if self.connection.is_none() {
+            self.connection = Some(connect(&self.address, self.port, self.tls.as_ref()).await?);
+        }
+        let connection = self.connection.as_mut().unwrap();
+        let result = async {
+            connection.write_all(data).await?;
+            connection.flush().await
+        }.await;
+        if result.is_err() {
+            self.connection = None;
+        }
+        Ok(result?)
+    }
+
+    /// Write data to the connection, reconnecting and retrying once on failure.
+    pub async fn send(&mut self, data: &[u8]) -> Result<()> {
+        if let Err(e) = self.write(data).await {
+            warn!("Write to {}:{} failed ({}), reconnecting", self.address, self.port, e);
+            self.write(data).await?;
+        }
+        Ok(())
+    }
+
+    /// Close the connection so the receiver sees a clean end of stream.
+    pub async fn close(&mut self) {
+        if let Some(mut connection) = self.connection.take() {
+            let _ = connection.shutdown().await;
+        }
+    }
+}
+
@@ -0,0 +1,141 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::{info, warn};
+use serde_json::json;
+use tokio::net::UnixDatagram;
+use crate::config::Config;
+use crate::data_structures::{ArbitraryJson, Caches};
+use crate::interfaces::interface::Interface;
+
+const DEFAULT_SOCKET_PATH: &str = "/var/ossec/queue/sockets/queue";
+const DEFAULT_LOCATION: &str = "office365";
+
+/// Wazuh truncates queue messages at OS_MAXSTR (64 KiB).
+const MAX_MESSAGE_BYTES: usize = 65536;
+
+/// Interface that writes logs to the local Wazuh agent/manager queue socket (a Unix datagram
+/// socket) as "1:<location>:<json>" messages, the framing used by Wazuh's own modules. By
+/// default each log is wrapped as {"integration":"office365","office365":{...}} so the
+/// built-in Office 365 decoders and rules apply.
+pub struct WazuhInterface {
+    socket_path: String,
+    location: String,
+    wrap: bool,
+    socket: Option<UnixDatagram>,
+}
+
+impl WazuhInterface {
+
+    pub fn new(config: Config) -> Result<Self> {
+
+        let wazuh_config = config.output.wazuh.as_ref()
+            .ok_or_else(|| anyhow!("No wazuh output configured"))?;
+        let socket_path = wazuh_config.socket_path.clone()
+            .unwrap_or_else(|| DEFAULT_SOCKET_PATH.to_string());
+        info!("Wazuh interface writing to {}", socket_path);
+        Ok(WazuhInterface {
+            socket_path,
+            location: wazuh_config.location.clone().unwrap_or_else(|| DEFAULT_LOCATION.to_string()),
+            wrap: wazuh_config.wrap.unwrap_or(true),
+            socket: None,
+        })
+    }
+
+    fn connect(&self) -> Result<UnixDatagram> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(&self.socket_path)
+            .map_err(|e| anyhow!("Could not connect to Wazuh socket {}: {}", self.socket_path, e))?;
+        Ok(socket)
+    }
+
+    /// Send one message, reconnecting once if the socket went away (e.g. Wazuh restarted).
+    async fn send_message(&mut self, message: &[u8]) -> Result<()> {
+        for attempt in 0..2 {
+            if self.socket.is_none() {
+                self.socket = Some(self.connect()?);
+            }
+            match self.socket.as_ref().unwrap().send(message).await {
+                Ok(_) => return Ok(()),
+                Err(e) if attempt == 0 => {
+                    warn!("Write to Wazuh socket failed ({}), reconnecting", e);
+                    self.socket = None;
+                },
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Interface for WazuhInterface {
+
+    async fn send_logs(&mut self, logs: Caches) -> Result<()> {
+
+        let mut sent = 0;
+        for (_, content_logs) in logs.get_all_types() {
+            for log in content_logs.iter() {
+                let message = match format_message(&self.location, log, self.wrap) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        warn!("Could not serialize a log for Wazuh: {}", e);
+                        continue
+                    }
+                };
+                if message.len() > MAX_MESSAGE_BYTES {
+                    warn!("Dropping log of {} bytes, exceeds the Wazuh message limit", message.len());
+                    continue
+                }
+                self.send_message(message.as_bytes()).await
+                    .map_err(|e| anyhow!("Error sending logs to Wazuh after {} logs: {}", sent, e))?;
+                sent += 1;
+            }
+        }
+        info!("Sent {} logs to Wazuh", sent);
+        Ok(())
+    }
+}
+
+
+fn format_message(location: &str, log: &ArbitraryJson, wrap: bool) -> serde_json::Result<String> {
+    let body = if wrap {
+        serde_json::to_string(&json!({"integration": "office365", "office365": log}))?
+    } else {
+        serde_json::to_string(log)?
+    };
+    Ok(format!("1:{}:{}", location, body))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn test_format_message() {
+        let mut log = ArbitraryJson::new();
+        log.insert("Operation".to_string(), Value::String("FileAccessed".to_string()));
+        assert_eq!(format_message("office365", &log, true).unwrap(),
+                   "1:office365:{\"integration\":\"office365\",\"office365\":{\"Operation\":\"FileAccessed\"}}");
+        assert_eq!(format_message("o365", &log, false).unwrap(),
+                   "1:o365:{\"Operation\":\"FileAccessed\"}");
+    }
+
+    #[tokio::test]
+    async fn test_send_message() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queue");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        let mut interface = WazuhInterface {
+            socket_path: path.to_string_lossy().to_string(),
+            location: DEFAULT_LOCATION.to_string(),
+            wrap: true,
+            socket: None,
+        };
+        interface.send_message(b"1:office365:{}").await.unwrap();
+        let mut buffer = [0u8; 64];
+        let n = receiver.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"1:office365:{}");
+    }
+}
@@ -6,16 +6,17 @@
 //! - TTL (Time-To-Live) based expiration using blob expiration times
 //! - Periodic cleanup of expired entries during runtime
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{BufReader, BufRead, LineWriter, Write};
+use std::io::{BufReader, BufRead};
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use chrono::{DateTime, NaiveDateTime, Utc};
 use log::{debug, info, warn};
 use lru::LruCache;
 use std::num::NonZeroUsize;
 use tokio::sync::RwLock;
+use crate::state::write_atomic;
 
 /// Maximum number of blob IDs to keep in memory.
 /// Office365 content blobs expire after 24 hours, and at typical ingestion rates
@@ -179,20 +180,18 @@ impl KnownBlobsCache {
         cache
     }
 
-    /// Save cache to file
-    pub fn save_to_file(&mut self, path: &Path) -> std::io::Result<()> {
+    /// Save cache to file, replacing it atomically so a crash mid-write keeps the previous one
+    pub fn save_to_file(&mut self, path: &Path, fsync: bool) -> std::io::Result<()> {
         // Clean up expired before saving
         self.cleanup_expired();
 
-        let file = File::create(path)?;
-        let mut writer = LineWriter::new(file);
-
-        for (id, expiration) in self.cache.iter() {
-            let expiration_str = expiration.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
-            writeln!(writer, "{},{}", id, expiration_str)?;
-        }
-
-        writer.flush()?;
+        write_atomic(path, fsync, |writer| {
+            for (id, expiration) in self.cache.iter() {
+                let expiration_str = expiration.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+                writeln!(writer, "{},{}", id, expiration_str)?;
+            }
+            Ok(())
+        })?;
         info!("Saved {} known blobs to file", self.cache.len());
         Ok(())
     }
@@ -233,18 +232,22 @@ impl Default for KnownBlobsCache {
 /// Thread-safe wrapper for shared access to KnownBlobsCache
 pub struct SharedKnownBlobsCache {
     inner: Arc<RwLock<KnownBlobsCache>>,
+    /// Blob IDs claimed for retrieval this run, known only once retrieved
+    claimed: Arc<Mutex<HashSet<String>>>,
 }
 
 impl SharedKnownBlobsCache {
     pub fn new() -> Self {
         SharedKnownBlobsCache {
             inner: Arc::new(RwLock::new(KnownBlobsCache::new())),
+            claimed: Arc::default(),
         }
     }
 
     pub fn from_cache(cache: KnownBlobsCache) -> Self {
         SharedKnownBlobsCache {
             inner: Arc::new(RwLock::new(cache)),
+            claimed: Arc::default(),
         }
     }
 
@@ -253,6 +256,12 @@ impl SharedKnownBlobsCache {
         cache.contains(blob_id)
     }
 
+    /// Claim a blob for retrieval, false if it is known or was already claimed, so blobs
+    /// listed or notified more than once in a run are retrieved once.
+    pub async fn claim(&self, blob_id: &str) -> bool {
+        !self.contains(blob_id).await && self.claimed.lock().unwrap().insert(blob_id.to_string())
+    }
+
     pub async fn insert(&self, blob_id: String, expiration: &str) {
         let mut cache = self.inner.write().await;
         cache.insert(blob_id, expiration);
@@ -268,9 +277,9 @@ impl SharedKnownBlobsCache {
         cache.cleanup_expired();
     }
 
-    pub async fn save_to_file(&self, path: &Path) -> std::io::Result<()> {
+    pub async fn save_to_file(&self, path: &Path, fsync: bool) -> std::io::Result<()> {
         let mut cache = self.inner.write().await;
-        cache.save_to_file(path)
+        cache.save_to_file(path, fsync)
     }
 
     pub async fn to_hashmap(&self) -> HashMap<String, String> {
@@ -281,6 +290,7 @@ impl SharedKnownBlobsCache {
     pub fn clone_arc(&self) -> Self {
         SharedKnownBlobsCache {
             inner: Arc::clone(&self.inner),
+            claimed: Arc::clone(&self.claimed),
         }
     }
 }
@@ -364,6 +374,17 @@ mod tests {
         assert!(cache.contains("blob-4"));
     }
 
+    #[tokio::test]
+    async fn test_claim() {
+        let known_blobs = SharedKnownBlobsCache::new();
+        known_blobs.insert("known".to_string(), "2999-01-01T00:00:00.000Z").await;
+
+        let listing = known_blobs.clone();
+        assert!(!listing.claim("known").await);
+        assert!(listing.claim("new").await);
+        assert!(!known_blobs.claim("new").await); // Listed again in the same run
+    }
+
     #[test]
     fn test_parse_expiration() {
         assert!(parse_expiration("2030-01-01T00:00:00.000Z").is_some());
@@ -0,0 +1,99 @@
+// IDs of the logs collected recently, for `skipKnownLogs`. The same audit record can be part of
+// several content blobs, e.g. when collection windows overlap (lookback_overlap), so skipping
+// known blobs alone still lets duplicates through. Logs are skipped on their Id (Graph: id).
+//
+// The IDs are kept in memory per tenant for the lifetime of the process, so in daemon mode they
+// are shared by consecutive runs. The oldest IDs are forgotten once max_known_logs is reached.
+// IDs are remembered when the logs are processed, before the outputs deliver them; the collector
+// forgets those of content blobs that were not delivered, so the logs pass again when the blob is
+// retrieved again.
+
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex as StdMutex, OnceLock};
+use lru::LruCache;
+use serde_json::{Map, Value};
+use crate::config::Config;
+
+pub const DEFAULT_MAX_KNOWN_LOGS: usize = 500_000;
+
+pub struct KnownLogs {
+    ids: StdMutex<LruCache<String, ()>>,
+}
+
+impl KnownLogs {
+
+    pub fn new(max_ids: usize) -> Self {
+        let capacity = NonZeroUsize::new(max_ids).unwrap_or(NonZeroUsize::MIN);
+        KnownLogs { ids: StdMutex::new(LruCache::new(capacity)) }
+    }
+
+    /// The known logs of a tenant, shared by all runs of this process.
+    pub fn for_tenant(tenant_id: &str, max_ids: usize) -> Arc<KnownLogs> {
+        static KNOWN_LOGS: OnceLock<StdMutex<HashMap<String, Arc<KnownLogs>>>> = OnceLock::new();
+
+        KNOWN_LOGS.get_or_init(|| StdMutex::new(HashMap::new())).lock().unwrap()
+            .entry(tenant_id.to_string())
+            .or_insert_with(|| Arc::new(KnownLogs::new(max_ids)))
+            .clone()
+    }
+
+    /// The known logs of a tenant if skipKnownLogs is set.
+    pub fn for_config(config: &Config, tenant_id: &str) -> Option<Arc<KnownLogs>> {
+        config.collect.as_ref()
+            .filter(|c| c.skip_known_logs.unwrap_or(false))
+            .map(|c| KnownLogs::for_tenant(tenant_id, c.max_known_logs.unwrap_or(DEFAULT_MAX_KNOWN_LOGS)))
+    }
+
+    /// Remember the ID of a log, false if it was known already. Logs without an ID are never
+    /// known.
+    pub fn first_seen(&self, log: &Map<String, Value>) -> bool {
+        let Some(id) = log_id(log) else {
+            return true
+        };
+        self.ids.lock().unwrap().put(id.to_string(), ()).is_none()
+    }
+
+    /// Forget the IDs of logs that were not delivered.
+    pub fn forget(&self, ids: &[String]) {
+        let mut known = self.ids.lock().unwrap();
+        for id in ids {
+            known.pop(id);
+        }
+    }
+}
+
+/// The ID logs are known by.
+pub fn log_id(log: &Map<String, Value>) -> Option<&str> {
+    log.get("Id").or_else(|| log.get("id")).and_then(|id| id.as_str())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn log(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn test_first_seen() {
+        let known_logs = KnownLogs::new(2);
+        assert!(known_logs.first_seen(&log(json!({"Id": "a"}))));
+        assert!(!known_logs.first_seen(&log(json!({"Id": "a", "Operation": "FileAccessed"}))));
+        assert!(known_logs.first_seen(&log(json!({"id": "b"}))));
+        assert!(known_logs.first_seen(&log(json!({"Operation": "FileAccessed"}))));
+        assert!(known_logs.first_seen(&log(json!({"Operation": "FileAccessed"}))));
+        // "a" is the oldest and forgotten when "c" comes in
+        assert!(known_logs.first_seen(&log(json!({"Id": "c"}))));
+        assert!(known_logs.first_seen(&log(json!({"Id": "a"}))));
+
+        known_logs.forget(&["a".to_string(), "x".to_string()]);
+        assert!(known_logs.first_seen(&log(json!({"Id": "a"}))));
+        assert!(!known_logs.first_seen(&log(json!({"Id": "c"}))));
+
+        assert!(Arc::ptr_eq(&KnownLogs::for_tenant("t", 10), &KnownLogs::for_tenant("t", 10)));
+    }
+}
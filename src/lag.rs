@@ -0,0 +1,104 @@
+// Ingestion lag: the time between a log's CreationTime and its delivery to an output, i.e. the
+// file output writing it or an interface accepting the batch it is in. Recorded per content type
+// for every run, and reported in the collector log and the run summary. Logs without a
+// CreationTime (e.g. from Microsoft Graph) are not recorded.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex as StdMutex;
+use chrono::{DateTime, Utc};
+use serde_derive::Serialize;
+use crate::data_structures::ArbitraryJson;
+use crate::state::parse_api_time;
+
+/// Lags of the logs delivered during a run, by content type.
+#[derive(Default)]
+pub struct IngestionLag {
+    /// Seconds per log
+    lags: StdMutex<HashMap<String, Vec<u32>>>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LagSummary {
+    pub logs: usize,
+    pub avg_seconds: u64,
+    pub p50_seconds: u32,
+    pub p95_seconds: u32,
+    pub p99_seconds: u32,
+    pub max_seconds: u32,
+}
+
+impl IngestionLag {
+
+    /// Record a log created at creation_time as delivered now.
+    pub fn record(&self, content_type: &str, creation_time: DateTime<Utc>) {
+        self.record_at(content_type, creation_time, Utc::now());
+    }
+
+    fn record_at(&self, content_type: &str, creation_time: DateTime<Utc>, delivered: DateTime<Utc>) {
+        // Clocks may disagree by a little, a log is never delivered before it was created
+        let lag = (delivered - creation_time).num_seconds().clamp(0, u32::MAX as i64) as u32;
+        let mut lags = self.lags.lock().unwrap();
+        match lags.get_mut(content_type) {
+            Some(content_lags) => content_lags.push(lag),
+            None => {
+                lags.insert(content_type.to_string(), vec![lag]);
+            },
+        }
+    }
+
+    /// Lag statistics per content type of the logs recorded so far.
+    pub fn summary(&self) -> BTreeMap<String, LagSummary> {
+        self.lags.lock().unwrap().iter_mut()
+            .filter(|(_, lags)| !lags.is_empty())
+            .map(|(content_type, lags)| (content_type.clone(), summarize(lags)))
+            .collect()
+    }
+}
+
+/// CreationTime of a log, if it has one.
+pub fn creation_time(log: &ArbitraryJson) -> Option<DateTime<Utc>> {
+    log.get("CreationTime").and_then(|t| t.as_str()).and_then(parse_api_time)
+}
+
+fn summarize(lags: &mut [u32]) -> LagSummary {
+    lags.sort_unstable();
+    // Nearest rank
+    let percentile = |p: usize| lags[((lags.len() * p).div_ceil(100)).max(1) - 1];
+    LagSummary {
+        logs: lags.len(),
+        avg_seconds: lags.iter().map(|lag| *lag as u64).sum::<u64>() / lags.len() as u64,
+        p50_seconds: percentile(50),
+        p95_seconds: percentile(95),
+        p99_seconds: percentile(99),
+        max_seconds: lags[lags.len() - 1],
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_summary() {
+        let lag = IngestionLag::default();
+        let delivered = Utc::now();
+        for seconds in 1..=100 {
+            lag.record_at("Audit.Exchange", delivered - chrono::Duration::try_seconds(seconds).unwrap(), delivered);
+        }
+        // Ahead of the collector's clock
+        lag.record_at("DLP.All", delivered + chrono::Duration::try_seconds(5).unwrap(), delivered);
+        let summary = lag.summary();
+        assert_eq!(summary["Audit.Exchange"], LagSummary {
+            logs: 100, avg_seconds: 50, p50_seconds: 50, p95_seconds: 95, p99_seconds: 99, max_seconds: 100 });
+        assert_eq!((summary["DLP.All"].logs, summary["DLP.All"].max_seconds), (1, 0));
+    }
+
+    #[test]
+    fn test_creation_time() {
+        let log: ArbitraryJson = serde_json::from_value(json!({"CreationTime": "2024-01-01T00:00:00"})).unwrap();
+        assert_eq!(creation_time(&log).map(|t| t.to_rfc3339()).as_deref(), Some("2024-01-01T00:00:00+00:00"));
+        assert_eq!(creation_time(&ArbitraryJson::new()), None);
+    }
+}
@@ -0,0 +1,345 @@
+// Filters of collect.filter, collect.record_types and collect.operations, by content type. A log
+// is kept when every filtered field it has matches; fields it does not have are not filtered on. A filter value is a JSON value the field
+// must equal, a list of values the field must be one of, or operators:
+//   UserId: {regex: "^svc-.*@contoso\\.com$"}   anywhere in the text unless anchored
+//   UserId: {glob: "svc-*@contoso.com"}         the whole text, * is any text and ? one character
+//   ObjectId: {contains: "/sites/finance/"}     part of the text
+//   RecordType: {gte: 6, lt: 10}                numbers, also gt and lte; all operators must hold
+//   Operation: {not: [FileAccessed, FilePreviewed]}   anything but what the value matches
+// Lists of values and `not` take operators too. Numbers and booleans are matched as their JSON
+// text, lists in a log match when any item does.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use regex::Regex;
+use serde_json::{Map, Value};
+use crate::config::Config;
+use crate::data_structures::ArbitraryJson;
+use crate::expression::Expression;
+use crate::recordtype_filter::RecordTypeFilter;
+use crate::sample::Sample;
+use crate::suppress::Suppress;
+
+/// Operators of a filter value, see parse
+const OPERATORS: [&str; 8] = ["regex", "glob", "contains", "gt", "gte", "lt", "lte", "not"];
+
+#[derive(Clone, Debug)]
+enum FieldFilter {
+    Equals(Value),
+    AnyOf(Vec<FieldFilter>),
+    AllOf(Vec<FieldFilter>),
+    Not(Box<FieldFilter>),
+    Matches(Regex),
+    Contains(String),
+    /// Holds when the field's number compares to the given one as the ordering says, e.g.
+    /// [Greater, Equal] for gte
+    Compare(f64, Vec<std::cmp::Ordering>),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LogFilter {
+    fields: Vec<(String, FieldFilter)>,
+    /// From collect.record_types
+    record_types: Option<RecordTypeFilter>,
+}
+
+impl LogFilter {
+
+    /// Compile the filter of a content type, failing on an invalid pattern or operator.
+    pub fn new(filter: &ArbitraryJson) -> Result<Self, String> {
+        let mut fields = Vec::new();
+        for (field, value) in filter {
+            fields.push((field.clone(), parse(value).map_err(|e| format!("{}: {}", field, e))?));
+        }
+        Ok(LogFilter { fields, record_types: None })
+    }
+
+    /// Compile the filters of every content type.
+    pub fn for_content_types(filters: &HashMap<String, ArbitraryJson>) -> Result<HashMap<String, LogFilter>, String> {
+        filters.iter()
+            .map(|(content_type, filter)| Ok((content_type.clone(), Self::new(filter)
+                .map_err(|e| format!("{}.{}", content_type, e))?)))
+            .collect()
+    }
+
+    /// Also filter on the RecordType of logs.
+    pub fn with_record_types(self, record_types: RecordTypeFilter) -> Self {
+        LogFilter { record_types: Some(record_types), ..self }
+    }
+
+    /// Also filter on the Operation of logs, with case-insensitive globs to include and exclude.
+    pub fn with_operations(mut self, include: Option<&[String]>, exclude: &[String]) -> Result<Self, String> {
+        let globs = |globs: &[String]| globs.iter()
+            .map(|glob| Regex::new(&format!("(?i){}", glob_to_regex(glob))).map(FieldFilter::Matches))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("operations: invalid pattern: {}", e));
+        if let Some(include) = include {
+            self.fields.push(("Operation".to_string(), FieldFilter::AnyOf(globs(include)?)));
+        }
+        if !exclude.is_empty() {
+            self.fields.push(("Operation".to_string(), FieldFilter::Not(Box::new(FieldFilter::AnyOf(globs(exclude)?)))));
+        }
+        Ok(self)
+    }
+
+    pub fn accepts(&self, log: &Map<String, Value>) -> bool {
+        self.accepts_fields(&|k| log.get(k))
+    }
+
+    /// Whether a log whose top level fields `field` looks up passes the filter.
+    pub fn accepts_fields<'a>(&self, field: &dyn Fn(&str) -> Option<&'a Value>) -> bool {
+        if let (Some(record_types), Some(record_type)) = (&self.record_types, field("RecordType").and_then(|r| r.as_i64())) {
+            if !record_types.should_include_log(record_type as i32) {
+                return false
+            }
+        }
+        self.fields.iter().all(|(name, filter)| match field(name) {
+            None => true,
+            Some(value) => matches(filter, value),
+        })
+    }
+}
+
+/// The filters and filter expressions of every content type and the suppress and sample rules of
+/// a run, shared by its download tasks, with the logs each dropped.
+#[derive(Clone, Debug, Default)]
+pub struct LogFilters {
+    by_content_type: HashMap<String, LogFilter>,
+    expressions: HashMap<String, Expression>,
+    suppress: Option<Arc<Suppress>>,
+    sample: Option<Arc<Sample>>,
+    /// Logs dropped by the filters and expressions, and counted with count_dropped, by rule
+    dropped: Arc<Mutex<BTreeMap<String, usize>>>,
+}
+
+impl LogFilters {
+
+    pub fn new(config: &Config) -> Result<Self, String> {
+        let by_content_type = match &config.collect {
+            Some(collect) => collect.get_log_filters()?,
+            None => HashMap::new(),
+        };
+        let suppress = match config.suppress.is_empty() {
+            true => None,
+            false => Some(Arc::new(Suppress::new(&config.suppress)?)),
+        };
+        let expressions = config.expressions.iter()
+            .filter_map(|(content_type, expressions)| expressions.filter.as_ref()
+                .map(|filter| Ok((content_type.clone(), Expression::parse(filter)
+                    .map_err(|e| format!("{} filter: {}", content_type, e))?))))
+            .collect::<Result<_, String>>()?;
+        let sample = match config.sample.is_empty() {
+            true => None,
+            false => Some(Arc::new(Sample::new(&config.sample)?)),
+        };
+        Ok(LogFilters { by_content_type, expressions, suppress, sample, dropped: Arc::default() })
+    }
+
+    /// Whether a log of content_type passes the filters and is neither suppressed nor sampled out.
+    pub fn accepts(&self, content_type: &str, log: &Map<String, Value>) -> bool {
+        if !self.by_content_type.get(content_type).is_none_or(|filter| filter.accepts(log)) {
+            self.count_dropped(&format!("collect.{}", content_type));
+            return false
+        }
+        if !self.expressions.get(content_type).is_none_or(|filter| filter.holds(log)) {
+            self.count_dropped(&format!("expressions.{}", content_type));
+            return false
+        }
+        !self.suppress.as_ref().is_some_and(|suppress| suppress.suppresses(content_type, log))
+            && !self.sample.as_ref().is_some_and(|sample| sample.samples_out(content_type, log))
+    }
+
+    /// Count a log dropped by `rule`, e.g. "known_logs".
+    pub fn count_dropped(&self, rule: &str) {
+        *self.dropped.lock().unwrap().entry(rule.to_string()).or_default() += 1;
+    }
+
+    /// Logs dropped so far by collect filters ("collect.<content type>"), expression filters
+    /// ("expressions.<content type>") and count_dropped, by rule.
+    pub fn filtered(&self) -> BTreeMap<String, usize> {
+        self.dropped.lock().unwrap().clone()
+    }
+
+    /// Logs suppressed so far by suppress rule name.
+    pub fn suppressed(&self) -> BTreeMap<String, usize> {
+        self.suppress.as_ref().map(|suppress| suppress.counts()).unwrap_or_default()
+    }
+
+    /// Logs sampled out so far by sample rule name.
+    pub fn sampled_out(&self) -> BTreeMap<String, usize> {
+        self.sample.as_ref().map(|sample| sample.counts()).unwrap_or_default()
+    }
+}
+
+/// A list is any of its values, an object with only operator keys all of its operators, any
+/// other value itself.
+fn parse(value: &Value) -> Result<FieldFilter, String> {
+    match value {
+        Value::Array(values) => Ok(FieldFilter::AnyOf(values.iter().map(parse).collect::<Result<_, _>>()?)),
+        Value::Object(object) if !object.is_empty() && object.keys().all(|key| OPERATORS.contains(&key.as_str())) => {
+            let mut filters = object.iter().map(|(operator, operand)| parse_operator(operator, operand))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(match filters.len() {
+                1 => filters.remove(0),
+                _ => FieldFilter::AllOf(filters),
+            })
+        },
+        value => Ok(FieldFilter::Equals(value.clone())),
+    }
+}
+
+fn parse_operator(operator: &str, operand: &Value) -> Result<FieldFilter, String> {
+    use std::cmp::Ordering::{Equal, Greater, Less};
+    let text = || operand.as_str().ok_or_else(|| format!("{} needs a string", operator));
+    let number = || operand.as_f64().ok_or_else(|| format!("{} needs a number", operator));
+    Ok(match operator {
+        "regex" => FieldFilter::Matches(Regex::new(text()?).map_err(|e| format!("invalid pattern: {}", e))?),
+        "glob" => FieldFilter::Matches(Regex::new(&glob_to_regex(text()?)).map_err(|e| format!("invalid pattern: {}", e))?),
+        "contains" => FieldFilter::Contains(text()?.to_string()),
+        "gt" => FieldFilter::Compare(number()?, vec![Greater]),
+        "gte" => FieldFilter::Compare(number()?, vec![Greater, Equal]),
+        "lt" => FieldFilter::Compare(number()?, vec![Less]),
+        "lte" => FieldFilter::Compare(number()?, vec![Less, Equal]),
+        _ => FieldFilter::Not(Box::new(parse(operand)?)),
+    })
+}
+
+fn matches(filter: &FieldFilter, value: &Value) -> bool {
+    match filter {
+        FieldFilter::Equals(expected) => value == expected,
+        FieldFilter::AnyOf(filters) => filters.iter().any(|filter| matches(filter, value)),
+        FieldFilter::AllOf(filters) => filters.iter().all(|filter| matches(filter, value)),
+        FieldFilter::Not(filter) => !matches(filter, value),
+        FieldFilter::Matches(regex) => text_matches(value, &|text| regex.is_match(text)),
+        FieldFilter::Contains(part) => text_matches(value, &|text| text.contains(part.as_str())),
+        FieldFilter::Compare(number, orderings) => {
+            let field = value.as_f64().or_else(|| value.as_str().and_then(|text| text.trim().parse().ok()));
+            field.and_then(|field| field.partial_cmp(number)).is_some_and(|ordering| orderings.contains(&ordering))
+        },
+    }
+}
+
+fn text_matches(value: &Value, test: &dyn Fn(&str) -> bool) -> bool {
+    match value {
+        Value::String(text) => test(text),
+        Value::Array(items) => items.iter().any(|item| text_matches(item, test)),
+        Value::Null | Value::Object(_) => false,
+        other => test(&other.to_string()),
+    }
+}
+
+/// Anchored regex of a glob, * is any text and ? one character.
+pub fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn log(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter: ArbitraryJson = serde_json::from_value(json!({
+            "UserId": {"glob": "svc-*@contoso.com"},
+            "Operation": {"regex": "^(Add|Remove)-"},
+            "RecordType": 1,
+            "ResultStatus": {"status": "Succeeded"},
+        })).unwrap();
+        let filter = LogFilter::new(&filter).unwrap();
+        assert!(filter.accepts(&log(json!({"UserId": "svc-backup@contoso.com", "Operation": "Add-MailboxPermission",
+                                            "RecordType": 1}))));
+        assert!(filter.accepts(&log(json!({"Operation": "Remove-Mailbox"}))));
+        assert!(!filter.accepts(&log(json!({"UserId": "svc-backup@contoso.com.evil"}))));
+        assert!(!filter.accepts(&log(json!({"Operation": "Set-Mailbox"}))));
+        assert!(!filter.accepts(&log(json!({"RecordType": 2}))));
+        // Objects with other keys than operators are compared as they are
+        assert!(filter.accepts(&log(json!({"ResultStatus": {"status": "Succeeded"}}))));
+
+        let filter: ArbitraryJson = serde_json::from_value(json!({
+            "Workload": {"regex": "Exchange|SharePoint"}, "RecordType": {"glob": "1?"}})).unwrap();
+        let filter = LogFilter::new(&filter).unwrap();
+        assert!(filter.accepts(&log(json!({"Workload": ["AzureActiveDirectory", "SharePoint"], "RecordType": 18}))));
+        assert!(!filter.accepts(&log(json!({"RecordType": 1}))));
+        assert!(!filter.accepts(&log(json!({"Workload": null}))));
+
+        let invalid: ArbitraryJson = serde_json::from_value(json!({"UserId": {"regex": "("}})).unwrap();
+        let filters = HashMap::from([("Audit.Exchange".to_string(), invalid)]);
+        assert!(LogFilter::for_content_types(&filters).unwrap_err().starts_with("Audit.Exchange.UserId: invalid pattern"));
+    }
+
+    #[test]
+    fn test_operators() {
+        let filter: ArbitraryJson = serde_json::from_value(json!({
+            "Operation": {"not": ["FileAccessed", {"glob": "FilePreview*"}]},
+            "RecordType": {"gte": 6, "lt": 10},
+            "Workload": ["Exchange", "SharePoint"],
+            "ObjectId": {"contains": "/sites/finance/"},
+        })).unwrap();
+        let filter = LogFilter::new(&filter).unwrap();
+        assert!(filter.accepts(&log(json!({"Operation": "FileDeleted", "RecordType": 6, "Workload": "SharePoint",
+                                            "ObjectId": "https://contoso.sharepoint.com/sites/finance/q1.xlsx"}))));
+        assert!(!filter.accepts(&log(json!({"Operation": "FileAccessed"}))));
+        assert!(!filter.accepts(&log(json!({"Operation": "FilePreviewed"}))));
+        assert!(filter.accepts(&log(json!({"RecordType": "9"}))));
+        assert!(!filter.accepts(&log(json!({"RecordType": 10}))));
+        assert!(!filter.accepts(&log(json!({"RecordType": 5}))));
+        assert!(!filter.accepts(&log(json!({"RecordType": "Exchange"}))));
+        assert!(!filter.accepts(&log(json!({"Workload": "AzureActiveDirectory"}))));
+        assert!(!filter.accepts(&log(json!({"ObjectId": "https://contoso.sharepoint.com/sites/hr/"}))));
+
+        let yaml = "{filter: {Audit.Exchange: {Operation: {not: Send}}}, record_types: {Audit.Exchange: {exclude: [6]}}}";
+        let collect: crate::config::CollectSubConfig = serde_yaml::from_str(yaml).unwrap();
+        let filter = &collect.get_log_filters().unwrap()["Audit.Exchange"];
+        assert!(filter.accepts(&log(json!({"Operation": "MailItemsAccessed", "RecordType": 2}))));
+        assert!(!filter.accepts(&log(json!({"Operation": "MailItemsAccessed", "RecordType": 6}))));
+        assert!(!filter.accepts(&log(json!({"Operation": "Send", "RecordType": 2}))));
+
+        let yaml = "{operations: {Audit.SharePoint: {include: ['File*', 'Sharing*'], exclude: [fileaccessed, FilePreview?d]}}}";
+        let collect: crate::config::CollectSubConfig = serde_yaml::from_str(yaml).unwrap();
+        let filter = &collect.get_log_filters().unwrap()["Audit.SharePoint"];
+        assert!(filter.accepts(&log(json!({"Operation": "FileDeleted"}))));
+        assert!(filter.accepts(&log(json!({"Operation": "SharingSet"}))));
+        assert!(!filter.accepts(&log(json!({"Operation": "FileAccessed"}))));
+        assert!(!filter.accepts(&log(json!({"Operation": "FilePreviewed"}))));
+        assert!(!filter.accepts(&log(json!({"Operation": "PageViewed"}))));
+
+        for (invalid, problem) in [(json!({"gt": "5"}), "gt needs a number"), (json!({"contains": 5}), "contains needs a string")] {
+            let filter: ArbitraryJson = serde_json::from_value(json!({"RecordType": invalid})).unwrap();
+            assert_eq!(LogFilter::new(&filter).unwrap_err(), format!("RecordType: {}", problem));
+        }
+    }
+
+    #[test]
+    fn test_filtered_counts() {
+        let config: Config = serde_yaml::from_str("
+output: {}
+collect: {filter: {Audit.Exchange: {Operation: {not: Send}}}}
+expressions: {Audit.SharePoint: {filter: 'Operation != \"FileAccessed\"'}}
+").unwrap();
+        let filters = LogFilters::new(&config).unwrap();
+        let shared = filters.clone();
+        assert!(!filters.accepts("Audit.Exchange", &log(json!({"Operation": "Send"}))));
+        assert!(filters.accepts("Audit.Exchange", &log(json!({"Operation": "Receive"}))));
+        assert!(!shared.accepts("Audit.SharePoint", &log(json!({"Operation": "FileAccessed"}))));
+        assert!(!shared.accepts("Audit.SharePoint", &log(json!({"Operation": "FileAccessed"}))));
+        shared.count_dropped("known_logs");
+        assert_eq!(filters.filtered(), BTreeMap::from([("collect.Audit.Exchange".to_string(), 1),
+                                                       ("expressions.Audit.SharePoint".to_string(), 2),
+                                                       ("known_logs".to_string(), 1)]));
+    }
+}
@@ -0,0 +1,210 @@
+// The collector log. Tenants are collected concurrently, so every line logged inside a tenant's
+// collector (its task in main, the blob, content, Graph and message loop tasks) is prefixed with
+// the tenant ID. The tenant is kept in a task local: tasks spawned for a tenant must be wrapped in
+// `inherit` to keep it. With `log.tenant_dir`, lines of a tenant go to <tenant_dir>/<tenant>.log
+// instead of the main log. In interactive mode the terminal belongs to the TUI: lines are sent to
+// it, and only written to log.path if that is a file.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex as StdMutex;
+use std::time::Instant;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use tokio::sync::mpsc::UnboundedSender;
+use crate::config::LogSubConfig;
+
+tokio::task_local! {
+    static TENANT: String;
+}
+
+/// Run a future with its log lines attributed to a tenant.
+pub async fn scope<F: Future>(tenant_id: String, future: F) -> F::Output {
+    TENANT.scope(tenant_id, future).await
+}
+
+/// Wrap a future to be spawned, so it logs for the tenant of the spawning task if any.
+pub fn inherit<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let tenant_id = current_tenant();
+    async move {
+        match tenant_id {
+            Some(tenant_id) => scope(tenant_id, future).await,
+            None => future.await,
+        }
+    }
+}
+
+/// Tenant of the current task, None outside a tenant's collector.
+pub fn current_tenant() -> Option<String> {
+    TENANT.try_with(|tenant_id| tenant_id.clone()).ok()
+}
+
+/// A line logged in interactive mode, with the tenant it was logged for.
+pub struct LogLine {
+    pub tenant_id: Option<String>,
+    pub level: Level,
+    pub line: String,
+}
+
+/// Log to log.path (stderr if empty) at log.level.
+pub fn init(config: Option<&LogSubConfig>) -> io::Result<()> {
+    install(config, Box::new(io::stderr()), None)
+}
+
+/// Send every line to the TUI, and write it to log.path unless that is empty.
+pub fn init_interactive(config: Option<&LogSubConfig>, tui: UnboundedSender<LogLine>) -> io::Result<()> {
+    install(config, Box::new(io::sink()), Some(tui))
+}
+
+fn install(config: Option<&LogSubConfig>, stderr: Box<dyn Write + Send>,
+           tui: Option<UnboundedSender<LogLine>>) -> io::Result<()> {
+    let default = LogSubConfig::default();
+    let config = config.unwrap_or(&default);
+    let main: Box<dyn Write + Send> = match config.path.is_empty() {
+        true => stderr,
+        false => Box::new(File::create(&config.path)?),
+    };
+    let logger = Logger {
+        start: Instant::now(),
+        sinks: StdMutex::new(Sinks::new(main, config.tenant_dir.as_ref().map(PathBuf::from))),
+        tui,
+    };
+    log::set_boxed_logger(Box::new(logger)).map_err(io::Error::other)?;
+    log::set_max_level(config.get_level().unwrap_or(LevelFilter::Info));
+    Ok(())
+}
+
+struct Logger {
+    start: Instant,
+    sinks: StdMutex<Sinks>,
+    tui: Option<UnboundedSender<LogLine>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let elapsed = self.start.elapsed();
+        let seconds = elapsed.as_secs();
+        let tenant_id = current_tenant();
+        let line = format!("[{:02}:{:02}:{:02}.{:03}] {:6} {}{}\n",
+                           seconds / 3600, (seconds / 60) % 60, seconds % 60, elapsed.subsec_millis(),
+                           record.level(),
+                           tenant_id.as_ref().map(|t| format!("[{}] ", t)).unwrap_or_default(),
+                           record.args());
+        if let Ok(mut sinks) = self.sinks.lock() {
+            sinks.write(tenant_id.as_deref(), &line);
+        }
+        if let Some(tui) = &self.tui {
+            let line = line.trim_end().to_string();
+            let _ = tui.send(LogLine { tenant_id, level: record.level(), line });
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut sinks) = self.sinks.lock() {
+            let _ = sinks.main.flush();
+        }
+    }
+}
+
+struct Sinks {
+    main: Box<dyn Write + Send>,
+    tenant_dir: Option<PathBuf>,
+    /// Tenant log files, opened once per process and appended to
+    tenants: HashMap<String, File>,
+}
+
+impl Sinks {
+
+    fn new(main: Box<dyn Write + Send>, tenant_dir: Option<PathBuf>) -> Self {
+        Sinks { main, tenant_dir, tenants: HashMap::new() }
+    }
+
+    /// Write a line to the log of its tenant, or to the main log if there is none or its file
+    /// cannot be written.
+    fn write(&mut self, tenant_id: Option<&str>, line: &str) {
+        if let (Some(tenant_id), Some(dir)) = (tenant_id, &self.tenant_dir) {
+            if !self.tenants.contains_key(tenant_id) {
+                let path = dir.join(format!("{}.log", file_name(tenant_id)));
+                let file = std::fs::create_dir_all(dir)
+                    .and_then(|_| OpenOptions::new().create(true).append(true).open(&path));
+                match file {
+                    Ok(file) => {
+                        self.tenants.insert(tenant_id.to_string(), file);
+                    },
+                    Err(e) => {
+                        let _ = writeln!(self.main, "Could not open tenant log {}: {}", path.display(), e);
+                    },
+                }
+            }
+            if let Some(file) = self.tenants.get_mut(tenant_id) {
+                if file.write_all(line.as_bytes()).is_ok() {
+                    return
+                }
+            }
+        }
+        let _ = self.main.write_all(line.as_bytes());
+    }
+}
+
+/// Tenant ID safe to use as a file name.
+fn file_name(tenant_id: &str) -> String {
+    tenant_id.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_scope() {
+        assert_eq!(current_tenant(), None);
+        let spawned = scope("t1".to_string(), async {
+            assert_eq!(current_tenant().as_deref(), Some("t1"));
+            tokio::spawn(inherit(async { current_tenant() })).await.unwrap()
+        }).await;
+        assert_eq!(spawned.as_deref(), Some("t1"));
+        assert_eq!(tokio::spawn(inherit(async { current_tenant() })).await.unwrap(), None);
+    }
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<StdMutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_sinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = Buffer::default();
+        let mut sinks = Sinks::new(Box::new(main.clone()), Some(dir.path().join("tenants")));
+        sinks.write(None, "main\n");
+        sinks.write(Some("contoso.onmicrosoft.com"), "first\n");
+        sinks.write(Some("contoso.onmicrosoft.com"), "second\n");
+        sinks.write(Some("../t2"), "other\n");
+        assert_eq!(String::from_utf8(main.0.lock().unwrap().clone()).unwrap(), "main\n");
+        let tenant_log = dir.path().join("tenants/contoso.onmicrosoft.com.log");
+        assert_eq!(std::fs::read_to_string(tenant_log).unwrap(), "first\nsecond\n");
+        assert_eq!(std::fs::read_to_string(dir.path().join("tenants/.._t2.log")).unwrap(), "other\n");
+
+        // Without tenant_dir every line goes to the main log
+        let mut sinks = Sinks::new(Box::new(main.clone()), None);
+        sinks.write(Some("t1"), "tenant\n");
+        assert!(String::from_utf8(main.0.lock().unwrap().clone()).unwrap().ends_with("main\ntenant\n"));
+    }
+}
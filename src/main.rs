@@ -1,25 +1,70 @@
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use clap::Parser;
 use chrono::{DateTime, Utc};
+use crate::alerts::Alerts;
+use crate::api_connection::LoginFailed;
 use crate::collector::Collector;
 use crate::config::{Config, MAX_LOOKBACK_HOURS};
+use crate::control::Control;
 use crate::state::StateManager;
-use log::{error, info, warn, LevelFilter};
-use tokio::sync::Mutex;
-use crate::data_structures::RunState;
-// Interactive mode is disabled - not updated for multi-tenant
-// use crate::interactive_mode::interactive;
+use log::{error, info, warn};
+use tokio::sync::{Mutex, Semaphore};
+use tracing::Instrument;
+use crate::data_structures::{RunState, RunStatistics};
+use crate::run_ledger::RunRecord;
+use crate::run_summary::{RunSummary, TenantSummary};
+use crate::tenant_source::TenantSource;
+use crate::webhook::WebhookQueue;
+use crate::interactive_mode::interactive;
 
 mod collector;
 mod api_connection;
 mod data_structures;
 mod config;
+mod content_queue;
 mod interfaces;
-// Interactive mode disabled
-// mod interactive_mode;
+mod interactive_mode;
 mod state;
+mod redis;
+mod sqlite;
 mod recordtype_filter;
 mod known_blobs_cache;
+mod known_logs;
+mod lag;
+mod log_filter;
+mod logging;
+mod page_cursors;
+mod pause;
+mod aad_auth;
+mod alerts;
+mod client_assertion;
+mod commands;
+mod control;
+mod aws_sigv4;
+mod expression;
+mod formatters;
+mod graph;
+mod file_rotation;
+mod plugin;
+mod routing;
+mod run_ledger;
+mod run_summary;
+mod redact;
+mod sample;
+mod sample_config;
+mod suppress;
+mod systemd;
+mod telemetry;
+mod tenant_source;
+mod throttle;
+mod tls;
+mod transform;
+mod webhook;
+
+/// Added to the longest a run or sleep can take before the systemd watchdog stops being pinged
+const WATCHDOG_GRACE: Duration = Duration::from_secs(10 * 60);
 
 // Use jemalloc as the global allocator. Unlike glibc malloc, jemalloc actively
 // returns freed pages to the OS, preventing the RSS ratchet effect where memory
@@ -44,14 +89,59 @@ pub static malloc_conf: &[u8] = b"dirty_decay_ms:0,muzzy_decay_ms:0\0";
 async fn main() {
 
     let args = data_structures::CliArgs::parse();
-    let config = Config::new(args.config.clone());
+    // The only command that needs no config
+    if let Some(data_structures::Command::GenerateConfig { minimal, output }) = &args.command {
+        if let Err(e) = commands::generate_config(*minimal, output.as_deref()) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.check_config {
+        if let Err(e) = commands::check_config(&args) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let config = match Config::load_for(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(command) = args.command.clone() {
+        init_non_interactive_logging(&config);
+        let config = TenantSource::default().sync(&config).await;
+        if let Err(e) = commands::run(command, args, config).await {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
     if args.interactive {
-        error!("Interactive mode is not supported in the multi-tenant version.");
-        error!("Interactive mode has not been updated for multi-tenant architecture and will fail.");
-        error!("Please use daemon mode instead: run without --interactive flag.");
-        error!("See KNOWN-ISSUES.md for details.");
-        std::process::exit(1);
+        // The terminal belongs to the interface, log lines are shown in it
+        let (log_tx, log_rx) = tokio::sync::mpsc::unbounded_channel();
+        if let Err(e) = logging::init_interactive(config.log.as_ref(), log_tx) {
+            eprintln!("Could not open log: {}", e);
+            std::process::exit(1);
+        }
+        if let Err(e) = config.get_state_backend() {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        let config = TenantSource::default().sync(&config).await;
+        if config.tenants.is_empty() {
+            eprintln!("No tenants configured. Please add at least one tenant to the config.");
+            std::process::exit(1);
+        }
+        if let Err(e) = interactive::run(args, config, log_rx).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
     } else {
         init_non_interactive_logging(&config);
 
@@ -60,32 +150,140 @@ async fn main() {
             info!("Office365 collector is disabled in config. Exiting.");
             return;
         }
+        if let Err(e) = config.get_state_backend() {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        if let Some(tracing_config) = &config.tracing {
+            if let Err(e) = telemetry::init(tracing_config, config.tls.as_ref()) {
+                error!("Could not start tracing: {}", e);
+                std::process::exit(1);
+            }
+        }
 
         // Daemon mode support
         let interval_seconds = config.get_interval_seconds();
         let daemon_mode = config.interval.is_some();
 
+        let webhook_queue = WebhookQueue::default();
+        webhook_queue.set_tenants(config.tenants.iter().map(|tenant| &tenant.tenant_id));
+        let mut tenant_source = TenantSource::default();
+        if let Some(webhook_config) = config.webhook.clone() {
+            if daemon_mode {
+                let queue = webhook_queue.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = webhook::serve(webhook_config, queue).await {
+                        error!("Webhook listener stopped: {}", e);
+                    }
+                });
+            } else {
+                warn!("Webhook notifications are only received in daemon mode (set 'interval')");
+            }
+        }
+
+        systemd::notify("READY=1");
         if daemon_mode {
+            let mut config = config;
+            let mut alerts = Alerts::load(&config);
+            let watchdog = systemd::Watchdog::start();
+            let control = Control::default();
+            if let Some(control_config) = config.control_api.clone() {
+                let (args, control) = (args.clone(), control.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = control::serve(control_config, args, control).await {
+                        error!("Control API stopped: {}", e);
+                    }
+                });
+            }
+            systemd::stop_on_sigterm();
+            pause::listen();
             info!("Starting Office365 collector in daemon mode with interval: {}s", interval_seconds);
             loop {
-                run_collection_for_all_tenants(args.clone(), config.clone()).await;
-
-                // Force jemalloc to return freed pages to the OS between cycles.
-                // Without this, jemalloc retains pages in dirty page lists, causing
-                // RSS to grow monotonically even when Rust has dropped all allocations.
-                #[cfg(not(target_env = "msvc"))]
-                log_jemalloc_stats();
-
-                info!("Sleeping for {} seconds until next collection...", interval_seconds);
-                tokio::time::sleep(tokio::time::Duration::from_secs(interval_seconds)).await;
+                if let Some(reloaded) = control.take_reloaded() {
+                    config = reloaded;
+                    alerts = Alerts::load(&config);
+                }
+                if pause::is_paused(&config) {
+                    if let Some(watchdog) = &watchdog {
+                        watchdog.expect_progress_within(None);
+                    }
+                    pause::wait_while_paused(&config).await;
+                }
+                // Tenants of tenant_source are synced every iteration
+                let run_config = tenant_source.sync(&config).await;
+                control.set_config(&run_config);
+                webhook_queue.set_tenants(run_config.tenants.iter().map(|tenant| &tenant.tenant_id));
+                daemon_run(&args, run_config.clone(), &webhook_queue, &control, &mut alerts, watchdog.as_ref()).await;
+
+                let interval = Duration::from_secs(config.get_interval_seconds());
+                let next_run = tokio::time::Instant::now() + interval;
+                let next_run_time = Utc::now() + chrono::Duration::from_std(interval).unwrap_or_default();
+                info!("Sleeping for {} seconds until next collection...", interval.as_secs());
+                loop {
+                    if let Some(watchdog) = &watchdog {
+                        watchdog.expect_progress_within(Some(next_run.saturating_duration_since(tokio::time::Instant::now()) + WATCHDOG_GRACE));
+                    }
+                    // Runs requested through the control API happen in between scheduled runs
+                    let requested = control.wait_for_requests(next_run, next_run_time).await;
+                    if requested.is_empty() {
+                        break
+                    }
+                    if pause::is_paused(&config) {
+                        warn!("Collection is paused, not running requested tenants {}", requested.join(", "));
+                        continue
+                    }
+                    let mut requested_config = run_config.clone();
+                    requested_config.tenants.retain(|tenant| requested.contains(&tenant.tenant_id));
+                    daemon_run(&args, requested_config, &webhook_queue, &control, &mut alerts, watchdog.as_ref()).await;
+                }
             }
         } else {
+            if pause::is_paused(&config) {
+                info!("Collection is paused, not collecting");
+                return;
+            }
             info!("Starting Office365 collector in single-run mode");
-            run_collection_for_all_tenants(args, config).await;
+            let config = tenant_source.sync(&config).await;
+            let exit_code = match run_collection_for_all_tenants(args, config.clone(), webhook_queue, &HashMap::new()).await {
+                Some(summary) => {
+                    Alerts::load(&config).run_ended(&config, &summary).await;
+                    print!("{}", summary.status_table());
+                    summary.exit_code()
+                },
+                None => run_summary::EXIT_FAILED,
+            };
+            telemetry::flush().await;
+            std::process::exit(exit_code);
         }
     }
 }
 
+/// One run of the daemon, of every tenant in config that is not paused.
+async fn daemon_run(args: &data_structures::CliArgs, mut config: Config, webhook_queue: &WebhookQueue,
+                    control: &Control, alerts: &mut Alerts, watchdog: Option<&systemd::Watchdog>) {
+    config.tenants.retain(|tenant| !pause::is_tenant_paused(&tenant.tenant_id));
+    if config.tenants.is_empty() {
+        info!("All tenants are paused, not collecting");
+        return
+    }
+    if let Some(watchdog) = watchdog {
+        let max_run = config.get_max_run_seconds().map(|seconds| Duration::from_secs(seconds) + WATCHDOG_GRACE);
+        watchdog.expect_progress_within(max_run);
+    }
+    let run_states = control.run_started(&config);
+    if let Some(summary) = run_collection_for_all_tenants(args.clone(), config.clone(), webhook_queue.clone(), &run_states).await {
+        control.run_ended(&summary);
+        alerts.run_ended(&config, &summary).await;
+    }
+    telemetry::flush().await;
+
+    // Force jemalloc to return freed pages to the OS between cycles.
+    // Without this, jemalloc retains pages in dirty page lists, causing
+    // RSS to grow monotonically even when Rust has dropped all allocations.
+    #[cfg(not(target_env = "msvc"))]
+    log_jemalloc_stats();
+}
+
 /// Log jemalloc memory stats between daemon cycles.
 /// Actual page purging is handled by dirty_decay_ms:0 / muzzy_decay_ms:0
 /// set via _rjem_malloc_conf at init time.
@@ -110,53 +308,96 @@ fn log_jemalloc_stats() {
     );
 }
 
-async fn run_collection_for_all_tenants(args: data_structures::CliArgs, config: Config) {
+/// Collect all tenants, returning the summary of the run. Progress of a tenant goes to its state
+/// in run_states if it has one, e.g. for the interactive mode to show.
+async fn run_collection_for_all_tenants(args: data_structures::CliArgs, config: Config,
+                                        webhook_queue: WebhookQueue,
+                                        run_states: &HashMap<String, Arc<Mutex<RunState>>>) -> Option<RunSummary> {
     if config.tenants.is_empty() {
         error!("No tenants configured. Please add at least one tenant to the config.");
-        return;
+        return None;
     }
 
     info!("Running collection for {} tenant(s)", config.tenants.len());
+    let run_started = Utc::now();
 
-    // Run collectors for all tenants concurrently
+    // Run collectors for all tenants concurrently, or at most max_concurrent_tenants at a time
     let mut handles = vec![];
+    let semaphore = config.max_concurrent_tenants.map(|max| Arc::new(Semaphore::new(max.max(1))));
 
-    for tenant in config.tenants.clone() {
+    for (index, tenant) in config.tenants.clone().into_iter().enumerate() {
+        let start_delay = config.get_start_delay(index, throttle::random_fraction().unwrap_or(0.0));
+        let semaphore = semaphore.clone();
         let args_clone = args.clone();
         let config_clone = config.clone();
         let tenant_clone = tenant.clone();
-
-        let handle = tokio::spawn(async move {
+        let notified = webhook_queue.take(&tenant.tenant_id);
+        // Queued again if the task fails
+        let taken = notified.clone();
+        let wrapped_state = run_states.get(&tenant.tenant_id).cloned().unwrap_or_default();
+        let webhook_queue = webhook_queue.clone();
+
+        let tenant_id = tenant.tenant_id.clone();
+        // Root span of the tenant's run, every span of its collector is below it
+        let span = tracing::info_span!("collect", tenant_id = %tenant_id, run_id = %uuid::Uuid::new_v4());
+        let handle = tokio::spawn(logging::scope(tenant_id, async move {
+            if !start_delay.is_zero() {
+                tokio::time::sleep(start_delay).await;
+            }
+            // Held until the collector is done
+            let _permit = match semaphore {
+                Some(semaphore) => Some(semaphore.acquire_owned().await.expect("semaphore is never closed")),
+                None => None,
+            };
             // Determine start time based on only_future_events and state
             let start_from = get_start_time_from_state(&config_clone, &tenant_clone.tenant_id);
 
-            let state = RunState::default();
-            let wrapped_state = Arc::new(Mutex::new(state));
             let runs = config_clone.get_needed_runs_from(start_from);
+            let (started, windows) = (Utc::now(), run_ledger::windows(&runs));
 
-            match Collector::new(args_clone, config_clone, tenant_clone.clone(), runs, wrapped_state.clone(), None).await {
+            match Collector::new(args_clone, config_clone, tenant_clone.clone(), runs, notified.clone(), wrapped_state.clone(), None).await {
                 Ok(mut collector) => {
                     info!("Started collector for tenant: {}", tenant_clone.tenant_id);
-                    collector.monitor().await;
+                    let summary = collector.monitor().await;
                     info!("Completed collection for tenant: {}", tenant_clone.tenant_id);
+                    summary
                 },
                 Err(e) => {
-                    error!("Could not start collector for tenant {}: {}", tenant_clone.tenant_id, e);
+                    error!("Could not start collector for tenant {}: {:#}", tenant_clone.tenant_id, e);
+                    webhook_queue.requeue(&tenant_clone.tenant_id, notified);
+                    let record = RunRecord::new(&tenant_clone.tenant_id, started, windows, &RunStatistics::default(), 0, false);
+                    let mut summary = TenantSummary::new(record, Vec::new(), Some(format!("{:#}", e)));
+                    summary.login_failed = e.is::<LoginFailed>();
+                    summary
                 }
             }
-        });
+        }.instrument(span)));
 
-        handles.push(handle);
+        handles.push((tenant.tenant_id, taken, handle));
     }
 
     // Wait for all tenant collectors to complete
-    for handle in handles {
-        if let Err(e) = handle.await {
-            error!("Tenant collector task failed: {}", e);
+    let mut summaries = Vec::new();
+    for (tenant_id, taken, handle) in handles {
+        match handle.await {
+            Ok(summary) => summaries.push(summary),
+            Err(e) => {
+                error!("Tenant collector task failed: {}", e);
+                webhook_queue.requeue(&tenant_id, taken);
+                let record = RunRecord::new(&tenant_id, run_started, Vec::new(), &RunStatistics::default(), 0, false);
+                summaries.push(TenantSummary::new(record, Vec::new(), Some(format!("Collector task failed: {}", e))));
+            }
         }
     }
 
     info!("All tenant collections completed");
+    let summary = RunSummary::new(run_started, summaries);
+    if let Some(path) = &config.run_summary {
+        if let Err(e) = summary.write(path) {
+            error!("Could not write run summary to {}: {}", path, e);
+        }
+    }
+    Some(summary)
 }
 
 fn get_start_time_from_state(config: &Config, tenant_id: &str) -> Option<DateTime<Utc>> {
@@ -164,12 +405,15 @@ fn get_start_time_from_state(config: &Config, tenant_id: &str) -> Option<DateTim
         return None;
     }
 
-    let working_dir = config.get_working_dir();
-    let state_manager = StateManager::new(&working_dir);
+    let state_manager = StateManager::for_config(config);
 
     let subscriptions = config.get_subscriptions();
-    if let Some(first_sub) = subscriptions.first() {
-        if let Some(state) = state_manager.load_state(tenant_id, first_sub) {
+    if !subscriptions.is_empty() {
+        // Subscriptions move forward with their own logs, start from the one furthest behind
+        let earliest = subscriptions.iter()
+            .filter_map(|subscription| state_manager.load_state(tenant_id, subscription))
+            .min_by_key(|state| state.last_log_time);
+        if let Some(state) = earliest {
             let now = Utc::now();
             let hours_since_last_run = (now - state.last_log_time).num_hours();
 
@@ -185,12 +429,15 @@ fn get_start_time_from_state(config: &Config, tenant_id: &str) -> Option<DateTim
                     state.last_log_time, tenant_id, hours_since_last_run);
             }
 
-            return Some(state.last_log_time);
+            // Back off so content that showed up after the previous run listed its window is
+            // listed again, known_blobs skips what was already retrieved
+            return Some(state.last_log_time - config.get_lookback_overlap());
         } else {
             let now = Utc::now();
-            let start_time = now - chrono::Duration::try_seconds(1).unwrap();
-            info!("First run for tenant {} with only_future_events=true: starting from {} (1 sec ago)",
-                tenant_id, start_time);
+            let lookback = config.get_initial_lookback();
+            let start_time = now - lookback;
+            info!("First run for tenant {} with only_future_events=true: starting from {} ({} sec ago)",
+                tenant_id, start_time, lookback.num_seconds());
 
             for subscription in &subscriptions {
                 let state = crate::state::TenantSubscriptionState {
@@ -213,17 +460,8 @@ fn get_start_time_from_state(config: &Config, tenant_id: &str) -> Option<DateTim
 }
 
 fn init_non_interactive_logging(config: &Config) {
-
-    let (path, level) = if let Some(log_config) = &config.log {
-        let level = if log_config.debug { LevelFilter::Debug } else { LevelFilter::Info };
-        (log_config.path.clone(), level)
-    } else {
-        ("".to_string(), LevelFilter::Info)
-    };
-
-    if !path.is_empty() {
-        simple_logging::log_to_file(path, level).unwrap();
-    } else {
-        simple_logging::log_to_stderr(level);
+    if let Err(e) = logging::init(config.log.as_ref()) {
+        eprintln!("Could not open log: {}", e);
+        std::process::exit(1);
     }
 }
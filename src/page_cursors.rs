@@ -0,0 +1,479 @@
+// Pagination cursors of content listings, persisted so a run that is stopped by the global
+// timeout (or crashes) continues listing where it left off instead of listing its windows from
+// the first page again.
+//
+// Every listing of a content type and time window is a chain of pages linked by NextPageUri.
+// The cursor of a chain is its first page that is not done: not listed yet, or with blobs found
+// on it that were not retrieved yet. Listing that page again finds those blobs again (known ones
+// are skipped) and continues the chain. The cursors of unfinished chains are kept in
+// office365-{tenant_id}-cursors.json in the working directory, or in the database of the sqlite
+// state backend. The next run lists them first and
+// leaves their time windows out of its own, since everything before a cursor was retrieved.
+//
+// The time windows of chains that finished are kept in the same file as soon as they finish, so a
+// run that is aborted halfway only leaves its unfinished windows for the next run. The state of a
+// content type does not move past its first unfinished window; completed windows behind the state
+// are dropped.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex as StdMutex;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use crate::config::{Config, StateBackend};
+use crate::sqlite::SqliteStore;
+use crate::state::{parse_api_time, write_atomic};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Cursor {
+    pub content_type: String,
+    pub url: String,
+}
+
+impl Cursor {
+    /// Time window of the listing the cursor is in.
+    pub fn window(&self) -> Option<Window> {
+        let (start, end) = window(&self.url)?;
+        Some(Window { content_type: self.content_type.clone(), start, end })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Window {
+    pub content_type: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+/// Content of the cursor file. Older versions only wrote the list of cursors.
+#[derive(Serialize, Deserialize, Default)]
+struct Progress {
+    cursors: Vec<Cursor>,
+    #[serde(default)]
+    completed: Vec<Window>,
+}
+
+/// Where the cursors of a tenant are kept
+enum Store {
+    File(PathBuf),
+    Sqlite { database: SqliteStore, tenant_id: String },
+}
+
+pub struct PageCursors {
+    store: Store,
+    resumed: Vec<Cursor>,
+    /// Windows completed by previous runs, as loaded
+    resumed_completed: Vec<Window>,
+    chains: StdMutex<Chains>,
+}
+
+#[derive(Default)]
+struct Chains {
+    /// By the URL of their first page
+    chains: HashMap<String, Chain>,
+    /// Chain of every page
+    pages: HashMap<String, String>,
+    /// Page every outstanding blob was found on, by content ID
+    blobs: HashMap<String, String>,
+    /// Windows of finished chains, this run's and those of previous runs that are still needed
+    completed: Vec<Window>,
+    /// Start of the windows left for later runs per content type, see defer
+    deferred: HashMap<String, DateTime<Utc>>,
+}
+
+struct Chain {
+    content_type: String,
+    pages: VecDeque<Page>,
+}
+
+struct Page {
+    url: String,
+    listed: bool,
+    outstanding: usize,
+}
+
+impl PageCursors {
+
+    /// Load the cursors the previous run of a tenant left, from the configured state backend.
+    pub fn for_config(config: &Config, tenant_id: &str) -> Self {
+        match config.get_state_backend() {
+            Ok(StateBackend::Sqlite(database)) => Self::from_store(Store::Sqlite { database, tenant_id: tenant_id.to_string() }),
+            _ => Self::load(&config.get_working_dir(), tenant_id),
+        }
+    }
+
+    /// Load the cursors the previous run of a tenant left in the working directory.
+    pub fn load(working_dir: &str, tenant_id: &str) -> Self {
+        Self::from_store(Store::File(Path::new(working_dir).join(format!("office365-{}-cursors.json", tenant_id))))
+    }
+
+    fn from_store(store: Store) -> Self {
+        let content = match &store {
+            Store::File(path) => fs::read_to_string(path).ok(),
+            Store::Sqlite { database, tenant_id } => database.load_cursors(tenant_id).unwrap_or_else(|e| {
+                error!("Failed to load cursors of tenant {}: {}", tenant_id, e);
+                None
+            }),
+        };
+        let progress = match content {
+            Some(content) => serde_json::from_str::<Progress>(&content)
+                .or_else(|e| serde_json::from_str::<Vec<Cursor>>(&content)
+                    .map(|cursors| Progress { cursors, completed: Vec::new() })
+                    .map_err(|_| e))
+                .unwrap_or_else(|e| {
+                    error!("Failed to parse cursors {}: {}", store.describe(), e);
+                    Progress::default()
+                }),
+            None => Progress::default(),
+        };
+        let chains = Chains { completed: progress.completed.clone(), ..Chains::default() };
+        PageCursors {
+            store,
+            resumed: progress.cursors,
+            resumed_completed: progress.completed,
+            chains: StdMutex::new(chains),
+        }
+    }
+
+    /// Cursors of listings the previous run did not finish.
+    pub fn resumed(&self) -> &[Cursor] {
+        &self.resumed
+    }
+
+    /// Windows previous runs completed that may not be behind the state yet.
+    pub fn completed(&self) -> &[Window] {
+        &self.resumed_completed
+    }
+
+    /// Start of the first window of a content type that is not finished yet.
+    pub fn unfinished_since(&self, content_type: &str) -> Option<DateTime<Utc>> {
+        let chains = self.chains.lock().unwrap();
+        chains.chains.iter()
+            .filter(|(_, chain)| chain.content_type == content_type)
+            .filter_map(|(chain_id, _)| window(chain_id).map(|(start, _)| start))
+            .chain(chains.deferred.get(content_type).copied())
+            .min()
+    }
+
+    /// The windows of a content type from `start` on are left for a later run.
+    pub fn defer(&self, content_type: &str, start: DateTime<Utc>) {
+        let mut chains = self.chains.lock().unwrap();
+        let deferred = chains.deferred.entry(content_type.to_string()).or_insert(start);
+        *deferred = (*deferred).min(start);
+    }
+
+    /// Forget the completed windows of a content type that end before `time`, the state has
+    /// moved past them.
+    pub fn prune_completed(&self, content_type: &str, time: DateTime<Utc>) {
+        self.chains.lock().unwrap().completed
+            .retain(|window| window.content_type != content_type || window.end > time);
+    }
+
+    /// A listing starts at this page.
+    pub fn start(&self, content_type: &str, url: &str) {
+        let mut chains = self.chains.lock().unwrap();
+        chains.pages.insert(url.to_string(), url.to_string());
+        chains.chains.insert(url.to_string(), Chain {
+            content_type: content_type.to_string(),
+            pages: VecDeque::from([Page { url: url.to_string(), listed: false, outstanding: 0 }]),
+        });
+    }
+
+    /// Listing `page` referred to the `next` one.
+    pub fn next_page(&self, page: &str, next: &str) {
+        let mut chains = self.chains.lock().unwrap();
+        let Some(chain_id) = chains.pages.get(page).cloned() else { return };
+        if chains.pages.contains_key(next) {
+            return
+        }
+        chains.pages.insert(next.to_string(), chain_id.clone());
+        if let Some(chain) = chains.chains.get_mut(&chain_id) {
+            chain.pages.push_back(Page { url: next.to_string(), listed: false, outstanding: 0 });
+        }
+    }
+
+    /// A blob to retrieve was found on `page`. Must be called before the blob is retrieved.
+    pub fn found(&self, page: &str, content_id: &str) {
+        let mut chains = self.chains.lock().unwrap();
+        if chains.blobs.contains_key(content_id) {
+            return
+        }
+        let Some(chain_id) = chains.pages.get(page).cloned() else { return };
+        chains.blobs.insert(content_id.to_string(), page.to_string());
+        if let Some(page) = chains.chains.get_mut(&chain_id)
+            .and_then(|chain| chain.pages.iter_mut().find(|p| p.url == page)) {
+            page.outstanding += 1;
+        }
+    }
+
+    /// All blobs on `page` were found.
+    pub fn listed(&self, page: &str) {
+        let mut chains = self.chains.lock().unwrap();
+        let Some(chain_id) = chains.pages.get(page).cloned() else { return };
+        if let Some(page) = chains.chains.get_mut(&chain_id)
+            .and_then(|chain| chain.pages.iter_mut().find(|p| p.url == page)) {
+            page.listed = true;
+        }
+        self.advance(&mut chains, &chain_id);
+    }
+
+    /// A blob was retrieved, or given up on.
+    pub fn retrieved(&self, content_id: &str) {
+        let mut chains = self.chains.lock().unwrap();
+        let Some(page) = chains.blobs.remove(content_id) else { return };
+        let Some(chain_id) = chains.pages.get(&page).cloned() else { return };
+        if let Some(page) = chains.chains.get_mut(&chain_id)
+            .and_then(|chain| chain.pages.iter_mut().find(|p| p.url == page)) {
+            page.outstanding = page.outstanding.saturating_sub(1);
+        }
+        self.advance(&mut chains, &chain_id);
+    }
+
+    /// Listing `page` was given up on, the rest of its listing is not resumed.
+    pub fn abandon(&self, page: &str) {
+        let mut chains = self.chains.lock().unwrap();
+        let Some(chain_id) = chains.pages.get(page).cloned() else { return };
+        if let Some(chain) = chains.chains.remove(&chain_id) {
+            for page in chain.pages {
+                chains.pages.remove(&page.url);
+            }
+        }
+        self.save_chains(&chains);
+    }
+
+    /// Persist the cursors of all unfinished listings.
+    pub fn save(&self) {
+        let chains = self.chains.lock().unwrap();
+        self.save_chains(&chains);
+    }
+
+    /// Drop the finished pages at the start of a chain, saving the cursors when it moved.
+    fn advance(&self, chains: &mut Chains, chain_id: &str) {
+        let Some(chain) = chains.chains.get_mut(chain_id) else { return };
+        let mut finished = Vec::new();
+        while chain.pages.front().is_some_and(|page| page.listed && page.outstanding == 0) {
+            finished.push(chain.pages.pop_front().unwrap().url);
+        }
+        if finished.is_empty() {
+            return
+        }
+        if chain.pages.is_empty() {
+            let content_type = chain.content_type.clone();
+            chains.chains.remove(chain_id);
+            if let Some((start, end)) = window(chain_id) {
+                chains.completed.push(Window { content_type, start, end });
+            }
+        }
+        for url in finished {
+            chains.pages.remove(&url);
+        }
+        self.save_chains(chains);
+    }
+
+    fn save_chains(&self, chains: &Chains) {
+        let cursors: Vec<Cursor> = chains.chains.values()
+            .filter_map(|chain| chain.pages.front().map(|page| Cursor {
+                content_type: chain.content_type.clone(),
+                url: page.url.clone(),
+            }))
+            .collect();
+        let progress = Progress { cursors, completed: chains.completed.clone() };
+        let content = match progress.cursors.is_empty() && progress.completed.is_empty() {
+            true => None,
+            false => Some(serde_json::to_string(&progress).unwrap_or_default()),
+        };
+        let result = match (&self.store, content) {
+            (Store::File(path), None) => match fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.to_string()),
+                _ => Ok(()),
+            },
+            (Store::File(path), Some(content)) => write_atomic(path, false, |writer| writer.write_all(content.as_bytes()))
+                .map_err(|e| e.to_string()),
+            (Store::Sqlite { database, tenant_id }, content) => database.save_cursors(tenant_id, content.as_deref()),
+        };
+        if let Err(e) = result {
+            warn!("Failed to save cursors {}: {}", self.store.describe(), e);
+        }
+    }
+}
+
+impl Store {
+    fn describe(&self) -> String {
+        match self {
+            Store::File(path) => format!("in {}", path.display()),
+            Store::Sqlite { database, tenant_id } => format!("of tenant {} in {}", tenant_id, database.path().display()),
+        }
+    }
+}
+
+/// Leave time windows (of resumed or completed listings) out of the runs (as
+/// "%Y-%m-%dT%H:%M:%SZ" start and end times per content type).
+pub fn trim_runs(mut runs: HashMap<String, Vec<(String, String)>>, cuts: &[Window])
+    -> HashMap<String, Vec<(String, String)>> {
+
+    for cut in cuts {
+        let Some(windows) = runs.get_mut(&cut.content_type) else { continue };
+        let mut trimmed = Vec::new();
+        for (start, end) in windows.drain(..) {
+            let (Some(run_start), Some(run_end)) = (parse_api_time(&start), parse_api_time(&end)) else {
+                trimmed.push((start, end));
+                continue
+            };
+            if run_start < cut.start {
+                trimmed.push((start.clone(), format_time(run_end.min(cut.start))));
+            }
+            if run_end > cut.end {
+                trimmed.push((format_time(run_start.max(cut.end)), end.clone()));
+            }
+        }
+        *windows = trimmed;
+    }
+    runs
+}
+
+/// Only keep the first `chunks` windows of every content type in the runs, deferring the rest to
+/// later runs (catchup_chunks_per_run).
+pub fn pace_runs(runs: &mut HashMap<String, Vec<(String, String)>>, chunks: usize, cursors: &PageCursors) {
+    for (content_type, windows) in runs.iter_mut() {
+        if windows.len() <= chunks {
+            continue
+        }
+        windows.sort_by_key(|(start, _)| parse_api_time(start));
+        let deferred = windows.split_off(chunks);
+        if let Some(start) = deferred.first().and_then(|(start, _)| parse_api_time(start)) {
+            info!("Catching up {}: listing {} of {} windows this run", content_type, chunks, chunks + deferred.len());
+            cursors.defer(content_type, start);
+        }
+    }
+}
+
+/// Start and end time of a content listing URL.
+fn window(url: &str) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let param = |name: &str| url.query_pairs()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .and_then(|(_, value)| parse_api_time(&value));
+    Some((param("startTime")?, param("endTime")?))
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(n: usize) -> String {
+        format!("https://manage.office.com/api/v1.0/t/activity/feed/subscriptions/content?contentType=Audit.Exchange\
+                 &startTime=2024-01-01T06:00:00&endTime=2024-01-01T12:00:00&nextPage={}", n)
+    }
+
+    #[test]
+    fn test_cursors() {
+        let dir = std::env::temp_dir().join(format!("o365-cursors-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let working_dir = dir.to_str().unwrap();
+        let cursors = PageCursors::load(working_dir, "t");
+        assert!(cursors.resumed().is_empty());
+
+        cursors.start("Audit.Exchange", &page(0));
+        cursors.next_page(&page(0), &page(1));
+        cursors.found(&page(0), "blob-a");
+        cursors.listed(&page(0));
+        cursors.next_page(&page(1), &page(2));
+        cursors.listed(&page(1));
+        // Page 0 still has a blob to retrieve
+        cursors.save();
+        assert_eq!(PageCursors::load(working_dir, "t").resumed()[0].url, page(0));
+
+        cursors.retrieved("blob-a");
+        assert_eq!(PageCursors::load(working_dir, "t").resumed(),
+                   &[Cursor { content_type: "Audit.Exchange".to_string(), url: page(2) }]);
+
+        cursors.abandon(&page(2));
+        assert!(PageCursors::load(working_dir, "t").resumed().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sqlite_cursors() {
+        let dir = tempfile::tempdir().unwrap();
+        let config: Config = serde_yaml::from_str(&format!("{{output: {{}}, state_backend: sqlite, workingDir: {}}}",
+                                                           dir.path().display())).unwrap();
+        let cursors = PageCursors::for_config(&config, "t");
+        cursors.start("Audit.Exchange", &page(0));
+        cursors.next_page(&page(0), &page(1));
+        cursors.listed(&page(0));
+        assert_eq!(PageCursors::for_config(&config, "t").resumed()[0].url, page(1));
+        assert!(PageCursors::for_config(&config, "other").resumed().is_empty());
+        // Nothing is written to the working directory besides the database
+        assert!(!dir.path().join("office365-t-cursors.json").exists());
+
+        cursors.abandon(&page(1));
+        assert!(PageCursors::for_config(&config, "t").resumed().is_empty());
+    }
+
+    #[test]
+    fn test_completed_windows() {
+        let dir = std::env::temp_dir().join(format!("o365-windows-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let working_dir = dir.to_str().unwrap();
+        let time = |s: &str| parse_api_time(s).unwrap();
+        let cursors = PageCursors::load(working_dir, "t");
+        let other = "https://manage.office.com/api/v1.0/t/activity/feed/subscriptions/content?contentType=\
+                     Audit.Exchange&startTime=2024-01-01T12:00:00&endTime=2024-01-01T18:00:00";
+        cursors.start("Audit.Exchange", &page(0));
+        cursors.start("Audit.Exchange", other);
+        assert_eq!(cursors.unfinished_since("Audit.Exchange"), Some(time("2024-01-01T06:00:00")));
+
+        cursors.listed(&page(0));
+        assert_eq!(cursors.unfinished_since("Audit.Exchange"), Some(time("2024-01-01T12:00:00")));
+        // The interrupted run leaves the finished window and the cursor of the other one
+        let loaded = PageCursors::load(working_dir, "t");
+        assert_eq!(loaded.completed(), &[Window {
+            content_type: "Audit.Exchange".to_string(),
+            start: time("2024-01-01T06:00:00"),
+            end: time("2024-01-01T12:00:00"),
+        }]);
+        assert_eq!(loaded.resumed()[0].url, other);
+
+        cursors.prune_completed("Audit.Exchange", time("2024-01-01T12:00:00"));
+        cursors.save();
+        assert!(PageCursors::load(working_dir, "t").completed().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pace_runs() {
+        let cursors = PageCursors::load(std::env::temp_dir().to_str().unwrap(), "pace-test");
+        let mut runs = HashMap::from([("Audit.Exchange".to_string(), vec![
+            ("2024-01-02T00:00:00Z".to_string(), "2024-01-02T10:00:00Z".to_string()),
+            ("2024-01-01T00:00:00Z".to_string(), "2024-01-02T00:00:00Z".to_string()),
+            ("2023-12-31T00:00:00Z".to_string(), "2024-01-01T00:00:00Z".to_string()),
+        ])]);
+        pace_runs(&mut runs, 2, &cursors);
+        assert_eq!(runs["Audit.Exchange"], vec![
+            ("2023-12-31T00:00:00Z".to_string(), "2024-01-01T00:00:00Z".to_string()),
+            ("2024-01-01T00:00:00Z".to_string(), "2024-01-02T00:00:00Z".to_string()),
+        ]);
+        assert_eq!(cursors.unfinished_since("Audit.Exchange"), parse_api_time("2024-01-02T00:00:00Z"));
+    }
+
+    #[test]
+    fn test_trim_runs() {
+        let runs = HashMap::from([("Audit.Exchange".to_string(), vec![
+            ("2024-01-01T00:00:00Z".to_string(), "2024-01-02T00:00:00Z".to_string()),
+            ("2024-01-02T00:00:00Z".to_string(), "2024-01-02T10:00:00Z".to_string()),
+        ])]);
+        let cuts = [Cursor { content_type: "Audit.Exchange".to_string(), url: page(3) }.window().unwrap()];
+        assert_eq!(trim_runs(runs, &cuts)["Audit.Exchange"], vec![
+            ("2024-01-01T00:00:00Z".to_string(), "2024-01-01T06:00:00Z".to_string()),
+            ("2024-01-01T12:00:00Z".to_string(), "2024-01-02T00:00:00Z".to_string()),
+            ("2024-01-02T00:00:00Z".to_string(), "2024-01-02T10:00:00Z".to_string()),
+        ]);
+    }
+}
@@ -0,0 +1,114 @@
+// Pausing collection, e.g. to quiesce it during a SIEM migration. SIGUSR1 or a `pause` file in the
+// working directory pauses, SIGUSR2 or removing the file resumes (the daemon is paused while
+// either says so). A run in progress stops requesting content, like at collect.globalTimeout,
+// and saves its state as usual: the next run continues from the logs it did not collect. The
+// daemon then idles until resumed, a single run does not start while paused. Single tenants are
+// paused through the control API (see control.rs); their runs stop the same way and the daemon
+// skips them until they are resumed.
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use log::{info, warn};
+use crate::config::Config;
+
+pub const PAUSE_FILE: &str = "pause";
+/// How often a paused daemon checks whether it was resumed
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+static SIGNALLED: AtomicBool = AtomicBool::new(false);
+/// Lowercase IDs of the paused tenants
+static PAUSED_TENANTS: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+/// Pause on SIGUSR1 and resume on SIGUSR2 until the process exits.
+pub fn listen() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        for (kind, paused) in [(SignalKind::user_defined1(), true), (SignalKind::user_defined2(), false)] {
+            let mut signals = match signal(kind) {
+                Ok(signals) => signals,
+                Err(e) => {
+                    warn!("Could not listen for pause signals: {}", e);
+                    return
+                },
+            };
+            tokio::spawn(async move {
+                while signals.recv().await.is_some() {
+                    info!("{} requested by signal", if paused { "Pause" } else { "Resume" });
+                    SIGNALLED.store(paused, Ordering::Relaxed);
+                }
+            });
+        }
+    }
+}
+
+/// Collection is paused by signal or by the pause file.
+pub fn is_paused(config: &Config) -> bool {
+    SIGNALLED.load(Ordering::Relaxed) || pause_file(config).exists()
+}
+
+/// Pause or resume collecting a single tenant. Returns whether that changed anything.
+pub fn set_tenant_paused(tenant_id: &str, paused: bool) -> bool {
+    let mut tenants = PAUSED_TENANTS.lock().unwrap();
+    let tenants = tenants.get_or_insert_with(HashSet::new);
+    match paused {
+        true => tenants.insert(tenant_id.to_lowercase()),
+        false => tenants.remove(&tenant_id.to_lowercase()),
+    }
+}
+
+/// The tenant was paused on its own, see set_tenant_paused.
+pub fn is_tenant_paused(tenant_id: &str) -> bool {
+    PAUSED_TENANTS.lock().unwrap().as_ref().is_some_and(|tenants| tenants.contains(&tenant_id.to_lowercase()))
+}
+
+/// Idle until collection is resumed, if it is paused.
+pub async fn wait_while_paused(config: &Config) {
+    if !is_paused(config) {
+        return
+    }
+    info!("Collection is paused, send SIGUSR2 or remove {} to resume", pause_file(config).display());
+    while is_paused(config) {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+    info!("Collection resumed");
+}
+
+fn pause_file(config: &Config) -> std::path::PathBuf {
+    Path::new(&config.get_working_dir()).join(PAUSE_FILE)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pause_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let yaml = format!("{{output: {{}}, workingDir: '{}'}}", dir.path().display());
+        let config: Config = serde_yaml::from_str(&yaml).unwrap();
+        assert!(!is_paused(&config));
+        std::fs::write(dir.path().join(PAUSE_FILE), "").unwrap();
+        assert!(is_paused(&config));
+
+        let config_clone = config.clone();
+        let waiting = tokio::spawn(async move { wait_while_paused(&config_clone).await });
+        std::fs::remove_file(dir.path().join(PAUSE_FILE)).unwrap();
+        tokio::time::timeout(CHECK_INTERVAL * 2, waiting).await.unwrap().unwrap();
+        assert!(!is_paused(&config));
+    }
+
+    #[test]
+    fn test_tenant_paused() {
+        assert!(set_tenant_paused("Tenant-Paused", true));
+        assert!(!set_tenant_paused("tenant-paused", true));
+        assert!(is_tenant_paused("TENANT-PAUSED"));
+        assert!(!is_tenant_paused("tenant-other"));
+        assert!(set_tenant_paused("tenant-paused", false));
+        assert!(!is_tenant_paused("tenant-paused"));
+    }
+}
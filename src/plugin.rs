@@ -0,0 +1,174 @@
+// A Lua script called for every log (`plugin`), for site specific processing the config cannot
+// express. The script defines
+//
+//   function process(log, content_type)
+//     if log.Operation == "FileAccessed" then return false end    -- drop the log
+//     log.Site = string.match(log.ObjectId or "", "sites/([^/]+)")  -- change it
+//     return log                                                 -- or return nothing to keep it as it was
+//   end
+//
+// Routing rules can match fields the script sets. Scripts only get the table, string, math and
+// utf8 libraries. Each call has a time budget, and the script a memory limit; a log the script
+// fails on is kept as it was and counted.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use mlua::{Function, HookTriggers, Lua, LuaOptions, LuaSerdeExt, StdLib, VmState};
+use serde_json::{Map, Value};
+use crate::config::PluginSubConfig;
+
+const DEFAULT_TIMEOUT_MS: u64 = 10;
+const DEFAULT_MAX_MEMORY_MB: usize = 64;
+
+/// What the script decided for a log.
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    Keep,
+    Drop,
+}
+
+pub struct Plugin {
+    lua: Lua,
+    process: Function,
+    content_types: Vec<String>,
+    timeout: Duration,
+    /// End of the budget of the running call
+    deadline: Arc<Mutex<Instant>>,
+    errors: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+impl std::fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Plugin").field("content_types", &self.content_types).finish()
+    }
+}
+
+impl Plugin {
+
+    pub fn new(config: &PluginSubConfig) -> Result<Self, String> {
+        let script = std::fs::read_to_string(&config.lua)
+            .map_err(|e| format!("could not read {}: {}", config.lua, e))?;
+        let lua = Lua::new_with(StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::UTF8, LuaOptions::default())
+            .map_err(|e| e.to_string())?;
+        lua.set_memory_limit(config.max_memory_mb.unwrap_or(DEFAULT_MAX_MEMORY_MB) * 1024 * 1024)
+            .map_err(|e| e.to_string())?;
+        lua.load(&script).set_name(config.lua.as_str()).exec()
+            .map_err(|e| format!("{}: {}", config.lua, e))?;
+        let process: Function = lua.globals().get("process")
+            .map_err(|_| format!("{} does not define a process function", config.lua))?;
+
+        let deadline = Arc::new(Mutex::new(Instant::now()));
+        let hook_deadline = deadline.clone();
+        lua.set_hook(HookTriggers::new().every_nth_instruction(1000), move |_, _| {
+            match Instant::now() > *hook_deadline.lock().unwrap() {
+                true => Err(mlua::Error::RuntimeError("time budget exceeded".to_string())),
+                false => Ok(VmState::Continue),
+            }
+        }).map_err(|e| e.to_string())?;
+
+        Ok(Plugin {
+            lua,
+            process,
+            content_types: config.content_types.clone(),
+            timeout: Duration::from_millis(config.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS)),
+            deadline,
+            errors: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        })
+    }
+
+    /// Call the script for a log, which it may change.
+    pub fn process(&self, content_type: &str, log: &mut Map<String, Value>) -> Decision {
+        if !self.content_types.is_empty() && !self.content_types.iter().any(|c| c == content_type) {
+            return Decision::Keep
+        }
+        *self.deadline.lock().unwrap() = Instant::now() + self.timeout;
+        let result = self.lua.to_value(&*log)
+            .and_then(|table| self.process.call::<mlua::Value>((table, content_type)))
+            .and_then(|result| match result {
+                mlua::Value::Nil | mlua::Value::Boolean(true) => Ok(None),
+                mlua::Value::Boolean(false) => Ok(Some(Decision::Drop)),
+                table @ mlua::Value::Table(_) => match self.lua.from_value::<Value>(table)? {
+                    Value::Object(changed) => {
+                        *log = changed;
+                        Ok(None)
+                    },
+                    _ => Err(mlua::Error::RuntimeError("process returned a list".to_string())),
+                },
+                other => Err(mlua::Error::RuntimeError(format!("process returned a {}", other.type_name()))),
+            });
+        match result {
+            Ok(Some(decision)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                decision
+            },
+            Ok(None) => Decision::Keep,
+            Err(e) => {
+                if self.errors.fetch_add(1, Ordering::Relaxed) == 0 {
+                    log::warn!("Plugin failed on a {} log, keeping it: {}", content_type, e);
+                }
+                Decision::Keep
+            },
+        }
+    }
+
+    /// Logs the script dropped and failed on so far.
+    pub fn counts(&self) -> (usize, usize) {
+        (self.dropped.load(Ordering::Relaxed), self.errors.load(Ordering::Relaxed))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::Write;
+
+    fn plugin(script: &str, extra: &str) -> Result<Plugin, String> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(script.as_bytes()).unwrap();
+        let config: PluginSubConfig = serde_yaml::from_str(
+            &format!("{{lua: '{}'{}}}", file.path().display(), extra)).unwrap();
+        Plugin::new(&config)
+    }
+
+    #[test]
+    fn test_plugin() {
+        let plugin = plugin(r#"
+function process(log, content_type)
+  if log.Operation == "FileAccessed" then return false end
+  if log.Operation == "Loop" then while true do end end
+  if log.Operation == "Fail" then error("failed") end
+  if log.Operation == "Keep" then return end
+  log.Site = string.match(log.ObjectId or "", "sites/([^/]+)")
+  log.Feed = content_type
+  log.Removed = nil
+  return log
+end
+"#, ", content_types: [Audit.SharePoint], timeout_ms: 50").unwrap();
+
+        let mut log = json!({"Operation": "FileModified", "ObjectId": "https://x/sites/finance/a.xlsx",
+                             "Removed": 1, "Labels": ["a"], "Empty": null}).as_object().unwrap().clone();
+        assert_eq!(plugin.process("Audit.SharePoint", &mut log), Decision::Keep);
+        assert_eq!(Value::Object(log), json!({"Operation": "FileModified", "ObjectId": "https://x/sites/finance/a.xlsx",
+                                              "Labels": ["a"], "Empty": null, "Site": "finance", "Feed": "Audit.SharePoint"}));
+
+        let log = |operation: &str| json!({"Operation": operation}).as_object().unwrap().clone();
+        assert_eq!(plugin.process("Audit.SharePoint", &mut log("FileAccessed")), Decision::Drop);
+        assert_eq!(plugin.process("Audit.Exchange", &mut log("FileAccessed")), Decision::Keep);
+        for operation in ["Loop", "Fail", "Keep"] {
+            let mut unchanged = log(operation);
+            assert_eq!(plugin.process("Audit.SharePoint", &mut unchanged), Decision::Keep);
+            assert_eq!(unchanged, log(operation));
+        }
+        assert_eq!(plugin.counts(), (1, 2));
+
+        assert!(self::plugin("x = 1", "").unwrap_err().ends_with("does not define a process function"));
+        assert!(self::plugin("function process(", "").is_err());
+        assert!(self::plugin("function process() return io.open('/etc/passwd') end", "").unwrap()
+            .process("Audit.General", &mut log("x")) == Decision::Keep);
+    }
+}
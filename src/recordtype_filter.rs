@@ -1,107 +1,166 @@
-// RecordType filtering for Office365 subscriptions
-// Ensures each subscription only returns its correct log types
+// RecordType filtering per subscription, configured with collect.record_types. Microsoft's API
+// does not always keep to the RecordTypes documented for a content type, so nothing is filtered
+// unless configured: a subscription can be limited to the RecordTypes it includes, and
+// RecordTypes it excludes are dropped. Logs without a RecordType are kept.
 
 use std::collections::HashSet;
+use crate::config::RecordTypesSubConfig;
 
 /// RecordType mappings based on Microsoft Office365 Management Activity API
 /// Reference: https://docs.microsoft.com/en-us/office/office-365-management-api/office-365-management-activity-api-schema
-pub struct RecordTypeFilter;
+#[derive(Clone, Debug, Default)]
+pub struct RecordTypeFilter {
+    /// Only these if set
+    include: Option<HashSet<i32>>,
+    exclude: HashSet<i32>,
+}
 
 impl RecordTypeFilter {
-    /// Get allowed RecordTypes for a given subscription
-    /// Returns None if no filtering should be applied (allow all)
-    pub fn get_allowed_recordtypes(subscription: &str) -> Option<HashSet<i32>> {
-        // DISABLED: Allow all RecordTypes for all subscriptions
-        // Microsoft's API behavior is inconsistent - just let everything through
-        None
-
-        /* ORIGINAL FILTERING (DISABLED):
-        match subscription {
-            // DLP.All - All DLP-related events
-            "DLP.All" => Some(vec![
-                11, // ComplianceDLPSharePoint (DLP evaluation on SharePoint/OneDrive)
-                13, // ComplianceDLPExchange (DLP evaluation on Exchange)
-                28, // DLPRuleMatch (actual DLP policy violations)
-            ].into_iter().collect()),
-
-            // Audit.Exchange - Exchange operations
-            "Audit.Exchange" => Some(vec![
-                1,  // ExchangeAdmin
-                2,  // ExchangeItem (mailbox operations)
-                3,  // ExchangeItemGroup
-                20, // ExchangeItemAggregated
-                50, // MailSubmission
-            ].into_iter().collect()),
-
-            // Audit.SharePoint - SharePoint and OneDrive operations
-            "Audit.SharePoint" => Some(vec![
-                4,  // SharePointFileOperation
-                6,  // SharePointFileOperation (legacy)
-                14, // SharePointSharingOperation
-                19, // SharePointListOperation
-            ].into_iter().collect()),
-
-            // Audit.AzureActiveDirectory - Azure AD operations
-            "Audit.AzureActiveDirectory" => Some(vec![
-                8,  // AzureActiveDirectory
-                15, // AzureActiveDirectoryStsLogon (user logins)
-            ].into_iter().collect()),
-
-            // Audit.General - Microsoft Teams and other workloads
-            "Audit.General" => Some(vec![
-                25, // MicrosoftTeams
-                30, // MicrosoftFlow
-                32, // Yammer
-                40, // Dynamics CRM
-                44, // PowerBI
-                62, // MicrosoftForms
-                63, // MicrosoftDefenderForEndpoint
-                64, // WorkplaceAnalytics
-                65, // PowerAppsApp
-                70, // MicrosoftGraphDataConnect
-            ].into_iter().collect()),
 
-            // Unknown subscription - allow all (no filtering)
-            _ => None,
+    pub fn new(config: &RecordTypesSubConfig) -> Self {
+        RecordTypeFilter {
+            include: config.include.as_ref().map(|include| include.iter().copied().collect()),
+            exclude: config.exclude.iter().copied().collect(),
         }
-        */
     }
 
     /// Check if a log should be included based on its RecordType
-    pub fn should_include_log(subscription: &str, record_type: i32) -> bool {
-        match Self::get_allowed_recordtypes(subscription) {
-            Some(allowed_types) => allowed_types.contains(&record_type),
-            None => true, // No filter defined, allow all
-        }
+    pub fn should_include_log(&self, record_type: i32) -> bool {
+        !self.exclude.contains(&record_type)
+            && self.include.as_ref().is_none_or(|include| include.contains(&record_type))
     }
 
-    /// Get human-readable description of RecordType
+    /// Name of a RecordType as in Microsoft's AuditLogRecordType list, "Unknown" for others.
     pub fn get_recordtype_description(record_type: i32) -> &'static str {
         match record_type {
             1 => "ExchangeAdmin",
             2 => "ExchangeItem",
             3 => "ExchangeItemGroup",
-            4 => "SharePointFileOperation",
+            4 => "SharePoint",
             6 => "SharePointFileOperation",
+            7 => "OneDrive",
             8 => "AzureActiveDirectory",
+            9 => "AzureActiveDirectoryAccountLogon",
+            10 => "DataCenterSecurityCmdlet",
             11 => "ComplianceDLPSharePoint",
             13 => "ComplianceDLPExchange",
             14 => "SharePointSharingOperation",
             15 => "AzureActiveDirectoryStsLogon",
-            19 => "SharePointListOperation",
-            20 => "ExchangeItemAggregated",
+            16 => "SkypeForBusinessPSTNUsage",
+            17 => "SkypeForBusinessUsersBlocked",
+            18 => "SecurityComplianceCenterEOPCmdlet",
+            19 => "ExchangeAggregatedOperation",
+            20 => "PowerBIAudit",
+            21 => "CRM",
+            22 => "Yammer",
+            23 => "SkypeForBusinessCmdlets",
+            24 => "Discovery",
             25 => "MicrosoftTeams",
-            28 => "DLPRuleMatch",
+            28 => "ThreatIntelligence",
+            29 => "MailSubmission",
             30 => "MicrosoftFlow",
-            32 => "Yammer",
-            40 => "DynamicsCRM",
-            44 => "PowerBI",
-            50 => "MailSubmission",
-            62 => "MicrosoftForms",
-            63 => "MicrosoftDefenderForEndpoint",
-            64 => "WorkplaceAnalytics",
-            65 => "PowerAppsApp",
-            70 => "MicrosoftGraphDataConnect",
+            31 => "AeD",
+            32 => "MicrosoftStream",
+            33 => "ComplianceDLPSharePointClassification",
+            34 => "ThreatFinder",
+            35 => "Project",
+            36 => "SharePointListOperation",
+            37 => "SharePointCommentOperation",
+            38 => "DataGovernance",
+            39 => "Kaizala",
+            40 => "SecurityComplianceAlerts",
+            41 => "ThreatIntelligenceUrl",
+            42 => "SecurityComplianceInsights",
+            43 => "MIPLabel",
+            44 => "WorkplaceAnalytics",
+            45 => "PowerAppsApp",
+            46 => "PowerAppsPlan",
+            47 => "ThreatIntelligenceAtpContent",
+            48 => "LabelContentExplorer",
+            49 => "TeamsHealthcare",
+            50 => "ExchangeItemAggregated",
+            51 => "HygieneEvent",
+            52 => "DataInsightsRestApiAudit",
+            53 => "InformationBarrierPolicyApplication",
+            54 => "SharePointListItemOperation",
+            55 => "SharePointContentTypeOperation",
+            56 => "SharePointFieldOperation",
+            57 => "MicrosoftTeamsAdmin",
+            58 => "HRSignal",
+            59 => "MicrosoftTeamsDevice",
+            60 => "MicrosoftTeamsAnalytics",
+            61 => "InformationWorkerProtection",
+            62 => "Campaign",
+            63 => "DLPEndpoint",
+            64 => "AirInvestigation",
+            65 => "Quarantine",
+            66 => "MicrosoftForms",
+            67 => "ApplicationAudit",
+            68 => "ComplianceSupervisionExchange",
+            69 => "CustomerKeyServiceEncryption",
+            70 => "OfficeNative",
+            71 => "MipAutoLabelSharePointItem",
+            72 => "MipAutoLabelSharePointPolicyLocation",
+            73 => "MicrosoftTeamsShifts",
+            75 => "MipAutoLabelExchangeItem",
+            76 => "CortanaBriefing",
+            78 => "WDATPAlerts",
+            82 => "SensitivityLabelPolicyMatch",
+            83 => "SensitivityLabelAction",
+            84 => "SensitivityLabeledFileAction",
+            85 => "AttackSim",
+            86 => "AirManualInvestigation",
+            87 => "SecurityComplianceRBAC",
+            88 => "UserTraining",
+            89 => "AirAdminActionInvestigation",
+            90 => "MSTIC",
+            91 => "PhysicalBadgingSignal",
+            93 => "AipDiscover",
+            94 => "AipSensitivityLabelAction",
+            95 => "AipProtectionAction",
+            96 => "AipFileDeleted",
+            97 => "AipHeartBeat",
+            98 => "MCASAlerts",
+            99 => "OnPremisesFileShareScannerDlp",
+            100 => "OnPremisesSharePointScannerDlp",
+            101 => "ExchangeSearch",
+            102 => "SharePointSearch",
+            103 => "PrivacyInsights",
+            105 => "MyAnalyticsSettings",
+            106 => "SecurityComplianceUserChange",
+            107 => "ComplianceDLPExchangeClassification",
+            109 => "MipExactDataMatch",
+            113 => "MS365DCustomDetection",
+            147 => "CoreReportingSettings",
+            148 => "ComplianceConnector",
+            154 => "OMEPortal",
+            174 => "DataShareOperation",
+            181 => "EduDataLake",
+            183 => "MicrosoftGraphDataConnectOperation",
+            184 => "PowerPagesSite",
+            186 => "PlannerPlan",
+            187 => "PlannerCopyPlan",
+            188 => "PlannerTask",
+            189 => "PlannerRoster",
+            190 => "PlannerPlanList",
+            191 => "PlannerTaskList",
+            192 => "PlannerTenantSettings",
+            193 => "ProjectForTheWebProject",
+            194 => "ProjectForTheWebTask",
+            195 => "ProjectForTheWebRoadmap",
+            196 => "ProjectForTheWebRoadmapItem",
+            197 => "ProjectForTheWebProjectSettings",
+            198 => "ProjectForTheWebRoadmapSettings",
+            216 => "VivaGoals",
+            217 => "MicrosoftGraphDataConnectConsent",
+            218 => "AttackSimAdmin",
+            230 => "TeamsUpdates",
+            231 => "PlannerRosterSensitivityLabel",
+            237 => "DefenderExpertsforXDRAdmin",
+            251 => "VfamCreatePolicy",
+            252 => "VfamUpdatePolicy",
+            253 => "VfamDeletePolicy",
+            261 => "CopilotInteraction",
             _ => "Unknown",
         }
     }
@@ -111,33 +170,52 @@ impl RecordTypeFilter {
 mod tests {
     use super::*;
 
+    fn filter(yaml: &str) -> RecordTypeFilter {
+        RecordTypeFilter::new(&serde_yaml::from_str(yaml).unwrap())
+    }
+
     #[test]
     fn test_dlp_all_filtering() {
-        // DLP.All should ONLY accept RecordType 28
-        assert!(RecordTypeFilter::should_include_log("DLP.All", 28));
-        assert!(!RecordTypeFilter::should_include_log("DLP.All", 6));
-        assert!(!RecordTypeFilter::should_include_log("DLP.All", 4));
+        // DLP.All limited to DLP rule matches
+        let dlp = filter("include: [28]");
+        assert!(dlp.should_include_log(28));
+        assert!(!dlp.should_include_log(6));
+        assert!(!dlp.should_include_log(4));
     }
 
     #[test]
     fn test_sharepoint_filtering() {
-        // SharePoint should accept file operations
-        assert!(RecordTypeFilter::should_include_log("Audit.SharePoint", 4));
-        assert!(RecordTypeFilter::should_include_log("Audit.SharePoint", 6));
-        assert!(RecordTypeFilter::should_include_log("Audit.SharePoint", 14));
+        // SharePoint limited to file, sharing and list operations
+        let sharepoint = filter("include: [4, 6, 14, 19]");
+        assert!(sharepoint.should_include_log(4));
+        assert!(sharepoint.should_include_log(6));
+        assert!(sharepoint.should_include_log(14));
 
         // But NOT Teams logs
-        assert!(!RecordTypeFilter::should_include_log("Audit.SharePoint", 25));
+        assert!(!sharepoint.should_include_log(25));
     }
 
     #[test]
     fn test_exchange_filtering() {
-        // Exchange should accept mail operations
-        assert!(RecordTypeFilter::should_include_log("Audit.Exchange", 1));
-        assert!(RecordTypeFilter::should_include_log("Audit.Exchange", 2));
-        assert!(RecordTypeFilter::should_include_log("Audit.Exchange", 50));
+        // Exchange without the SharePoint logs it sometimes returns
+        let exchange = filter("exclude: [4, 6]");
+        assert!(exchange.should_include_log(1));
+        assert!(exchange.should_include_log(2));
+        assert!(exchange.should_include_log(50));
+        assert!(!exchange.should_include_log(6));
+
+        let both = filter("{include: [1, 2], exclude: [2]}");
+        assert!(both.should_include_log(1));
+        assert!(!both.should_include_log(2));
+        assert!(filter("{}").should_include_log(6));
+    }
 
-        // But NOT SharePoint logs
-        assert!(!RecordTypeFilter::should_include_log("Audit.Exchange", 6));
+    #[test]
+    fn test_recordtype_description() {
+        assert_eq!(RecordTypeFilter::get_recordtype_description(1), "ExchangeAdmin");
+        assert_eq!(RecordTypeFilter::get_recordtype_description(6), "SharePointFileOperation");
+        assert_eq!(RecordTypeFilter::get_recordtype_description(15), "AzureActiveDirectoryStsLogon");
+        assert_eq!(RecordTypeFilter::get_recordtype_description(261), "CopilotInteraction");
+        assert_eq!(RecordTypeFilter::get_recordtype_description(5), "Unknown");
     }
 }
@@ -0,0 +1,175 @@
+// Redaction of personal data for selected outputs, configured as `redact` rules. A rule hashes,
+// truncates or replaces fields (UserId, ClientIP, file names) of some or all content types on
+// their way to its outputs, so e.g. a SIEM hosted elsewhere gets pseudonymized logs while the
+// archive file keeps the originals. Fields are dot separated paths like in projection.
+
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use crate::config::{RedactAction, RedactSubConfig};
+use crate::routing::OUTPUT_NAMES;
+
+const DEFAULT_REPLACEMENT: &str = "REDACTED";
+
+#[derive(Clone, Debug)]
+struct Rule {
+    fields: Vec<Vec<String>>,
+    content_types: Vec<String>,
+    action: RedactAction,
+    key: Option<String>,
+    length: usize,
+    replacement: String,
+}
+
+impl Rule {
+    fn redact(&self, value: &str) -> String {
+        match self.action {
+            RedactAction::Hash => match &self.key {
+                Some(key) => {
+                    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any size");
+                    mac.update(value.as_bytes());
+                    format!("{:x}", mac.finalize().into_bytes())
+                },
+                None => format!("{:x}", Sha256::digest(value.as_bytes())),
+            },
+            RedactAction::Truncate => value.chars().take(self.length).collect(),
+            RedactAction::Replace => self.replacement.clone(),
+        }
+    }
+}
+
+/// The redact rules applying to one output.
+#[derive(Clone, Debug, Default)]
+pub struct Redaction {
+    rules: Vec<Rule>,
+}
+
+impl Redaction {
+
+    pub fn for_output(config: &[RedactSubConfig], output: &str) -> Result<Self, String> {
+        let mut rules = Vec::new();
+        for (i, rule) in config.iter().enumerate() {
+            if rule.fields.is_empty() {
+                return Err(format!("rule {}: set the fields to redact", i))
+            }
+            if let Some(unknown) = rule.outputs.iter().find(|o| !OUTPUT_NAMES.contains(&o.as_str())) {
+                return Err(format!("rule {}: unknown output '{}', must be one of: {}", i, unknown, OUTPUT_NAMES.join(", ")))
+            }
+            if rule.action == RedactAction::Truncate && rule.length.is_none() {
+                return Err(format!("rule {}: truncate needs a length", i))
+            }
+            if let Some(field) = rule.fields.iter().find(|f| f.split('.').any(str::is_empty)) {
+                return Err(format!("rule {}: invalid field '{}'", i, field))
+            }
+            if !rule.outputs.is_empty() && !rule.outputs.iter().any(|o| o == output) {
+                continue
+            }
+            rules.push(Rule {
+                fields: rule.fields.iter().map(|f| f.split('.').map(String::from).collect()).collect(),
+                content_types: rule.content_types.clone(),
+                action: rule.action,
+                key: rule.key.clone(),
+                length: rule.length.unwrap_or_default(),
+                replacement: rule.replacement.clone().unwrap_or_else(|| DEFAULT_REPLACEMENT.to_string()),
+            });
+        }
+        Ok(Redaction { rules })
+    }
+
+    /// Whether any rule applies to logs of content_type.
+    pub fn applies_to(&self, content_type: &str) -> bool {
+        self.rules.iter().any(|rule| rule.content_types.is_empty() || rule.content_types.iter().any(|c| c == content_type))
+    }
+
+    /// Redact a log of content_type, given the iter_mut of its fields.
+    pub fn apply<'a>(&self, content_type: &str, fields: impl Iterator<Item = (&'a String, &'a mut Value)>) {
+        let rules: Vec<&Rule> = self.rules.iter()
+            .filter(|rule| rule.content_types.is_empty() || rule.content_types.iter().any(|c| c == content_type))
+            .collect();
+        if rules.is_empty() {
+            return
+        }
+        for (key, value) in fields {
+            for rule in &rules {
+                for path in rule.fields.iter().filter(|path| path[0] == *key) {
+                    redact_path(value, &path[1..], rule);
+                }
+            }
+        }
+    }
+}
+
+fn redact_path(value: &mut Value, path: &[String], rule: &Rule) {
+    match (value, path.split_first()) {
+        (Value::Array(items), _) => for item in items.iter_mut() {
+            redact_path(item, path, rule);
+        },
+        (Value::Object(object), Some((key, below))) => if let Some(nested) = object.get_mut(key) {
+            redact_path(nested, below, rule);
+        },
+        (Value::Null | Value::Object(_), _) | (_, Some(_)) => {},
+        (value, None) => {
+            let text = match &mut *value {
+                Value::String(text) => std::mem::take(text),
+                other => other.to_string(),
+            };
+            *value = Value::String(rule.redact(&text));
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn redacted(redaction: &Redaction, content_type: &str, mut log: Value) -> Value {
+        redaction.apply(content_type, log.as_object_mut().unwrap().iter_mut());
+        log
+    }
+
+    #[test]
+    fn test_redaction() {
+        let config: Vec<RedactSubConfig> = serde_yaml::from_str("
+- fields: [UserId, Actor.ID]
+  action: hash
+  outputs: [graylog]
+- fields: [ClientIP]
+  action: truncate
+  length: 7
+- fields: [SourceFileName, Modified.Name]
+  action: replace
+  content_types: [Audit.SharePoint]
+").unwrap();
+        let graylog = Redaction::for_output(&config, "graylog").unwrap();
+        let file = Redaction::for_output(&config, "file").unwrap();
+        let log = json!({
+            "UserId": "alice@contoso.com", "ClientIP": "203.0.113.45", "SourceFileName": "salaries.xlsx",
+            "Actor": [{"ID": "alice@contoso.com", "Type": 5}, {"ID": 42}], "Modified": {"Name": ["a", "b"]},
+        });
+        let alice = format!("{:x}", Sha256::digest("alice@contoso.com"));
+
+        assert_eq!(redacted(&graylog, "Audit.SharePoint", log.clone()), json!({
+            "UserId": alice, "ClientIP": "203.0.1", "SourceFileName": "REDACTED",
+            "Actor": [{"ID": alice, "Type": 5}, {"ID": format!("{:x}", Sha256::digest("42"))}],
+            "Modified": {"Name": ["REDACTED", "REDACTED"]},
+        }));
+        assert_eq!(redacted(&file, "Audit.Exchange", log.clone())["UserId"], log["UserId"]);
+        assert_eq!(redacted(&file, "Audit.Exchange", log.clone())["ClientIP"], "203.0.1");
+        assert!(file.applies_to("Audit.Exchange"));
+        assert!(!Redaction::for_output(&config[..1], "file").unwrap().applies_to("Audit.Exchange"));
+
+        let keyed: Vec<RedactSubConfig> = serde_yaml::from_str("[{fields: [UserId], action: hash, key: secret}]").unwrap();
+        let keyed = redacted(&Redaction::for_output(&keyed, "s3").unwrap(), "Audit.General", log.clone());
+        assert_ne!(keyed["UserId"], alice);
+        assert_eq!(keyed["UserId"].as_str().unwrap().len(), 64);
+
+        for (rule, problem) in [("{fields: [], action: hash}", "rule 0: set the fields to redact"),
+                                ("{fields: [UserId], action: truncate}", "rule 0: truncate needs a length"),
+                                ("{fields: [UserId], action: hash, outputs: [splunk]}", "rule 0: unknown output 'splunk'")] {
+            let config: Vec<RedactSubConfig> = serde_yaml::from_str(&format!("[{}]", rule)).unwrap();
+            assert!(Redaction::for_output(&config, "file").unwrap_err().starts_with(problem));
+        }
+    }
+}
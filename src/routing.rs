@@ -0,0 +1,166 @@
+// Routing of logs to outputs. Without routes every output receives every log. An output named
+// in one or more routes only receives the logs matching at least one of those routes, e.g.:
+//
+// routing:
+//   - outputs: [logs_ingestion]
+//     content_types: [DLP.All]
+//
+// sends only DLP logs to Sentinel while unrouted outputs (say the archive file) still get all.
+// Besides `match`ing field values, a route can take a `filter` like collect.filter and an
+// `expression`, to send e.g. only failed sign-ins to an expensive destination.
+
+use std::collections::{HashMap, HashSet};
+use log::warn;
+use serde_json::Value;
+use crate::config::RouteSubConfig;
+use crate::data_structures::ArbitraryJson;
+use crate::expression::Expression;
+use crate::log_filter::LogFilter;
+
+/// Output names as used in the `output` section of the config.
+pub const OUTPUT_NAMES: [&str; 13] = [
+    "file", "stdout", "graylog", "fluentd", "azureLogAnalytics", "logs_ingestion", "event_hub",
+    "s3", "azure_blob", "firehose", "qradar", "wazuh", "exec",
+];
+
+#[derive(Clone, Debug)]
+struct Route {
+    content_types: Vec<String>,
+    matches: ArbitraryJson,
+    filter: Option<LogFilter>,
+    expression: Option<Expression>,
+}
+
+impl Route {
+    fn accepts<'a>(&self, content_type: &str, field: &dyn Fn(&str) -> Option<&'a Value>) -> bool {
+        if !self.content_types.is_empty() && !self.content_types.iter().any(|c| c == content_type) {
+            return false;
+        }
+        self.matches.iter().all(|(k, v)| field(k) == Some(v))
+            && self.filter.as_ref().is_none_or(|filter| filter.accepts_fields(field))
+            && self.expression.as_ref().is_none_or(|expression| expression.holds_for(field))
+    }
+}
+
+/// Routes of one tenant, by output name.
+#[derive(Clone, Debug, Default)]
+pub struct Router {
+    routed_outputs: HashSet<String>,
+    routes: HashMap<String, Vec<Route>>,
+}
+
+impl Router {
+
+    pub fn new(config: &[RouteSubConfig], tenant_id: &str) -> Result<Self, String> {
+        let mut router = Router::default();
+        for (i, route_config) in config.iter().enumerate() {
+            let filter = match route_config.filter.is_empty() {
+                true => None,
+                false => Some(LogFilter::new(&route_config.filter).map_err(|e| format!("rule {} filter.{}", i, e))?),
+            };
+            let expression = route_config.expression.as_deref().map(Expression::parse).transpose()
+                .map_err(|e| format!("rule {} expression: {}", i, e))?;
+            for output in route_config.outputs.iter() {
+                if !OUTPUT_NAMES.contains(&output.as_str()) {
+                    warn!("Routing rule refers to unknown output '{}', known outputs are: {}",
+                          output, OUTPUT_NAMES.join(", "));
+                }
+                router.routed_outputs.insert(output.clone());
+                if !route_config.tenants.is_empty() && !route_config.tenants.iter().any(|t| t == tenant_id) {
+                    continue
+                }
+                router.routes.entry(output.clone()).or_default().push(Route {
+                    content_types: route_config.content_types.clone(),
+                    matches: route_config.matches.clone(),
+                    filter: filter.clone(),
+                    expression: expression.clone(),
+                });
+            }
+        }
+        Ok(router)
+    }
+
+    pub fn is_routed(&self, output: &str) -> bool {
+        self.routed_outputs.contains(output)
+    }
+
+    /// Whether a log of `content_type` should go to `output`. `field` looks up log fields.
+    pub fn accepts<'a>(&self, output: &str, content_type: &str,
+                       field: &dyn Fn(&str) -> Option<&'a Value>) -> bool {
+        if !self.is_routed(output) {
+            return true;
+        }
+        self.routes.get(output)
+            .map(|routes| routes.iter().any(|r| r.accepts(content_type, field)))
+            .unwrap_or(false)
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn test_router(tenant_id: &str) -> Router {
+        let config: Vec<RouteSubConfig> = serde_yaml::from_str(r#"
+            - outputs: [logs_ingestion]
+              content_types: [DLP.All]
+            - outputs: [logs_ingestion, graylog]
+              tenants: [tenant-a]
+              match:
+                Operation: FileDeleted
+        "#).unwrap();
+        Router::new(&config, tenant_id).unwrap()
+    }
+
+    #[test]
+    fn test_unrouted_output_gets_everything() {
+        let router = test_router("tenant-a");
+        assert!(router.accepts("file", "Audit.General", &|_| None));
+    }
+
+    #[test]
+    fn test_routes() {
+        let (deleted_value, accessed_value) = (json!("FileDeleted"), json!("FileAccessed"));
+        let deleted = |k: &str| if k == "Operation" { Some(&deleted_value) } else { None };
+        let accessed = |k: &str| if k == "Operation" { Some(&accessed_value) } else { None };
+
+        let router = test_router("tenant-a");
+        assert!(router.accepts("logs_ingestion", "DLP.All", &accessed));
+        assert!(router.accepts("logs_ingestion", "Audit.SharePoint", &deleted));
+        assert!(!router.accepts("logs_ingestion", "Audit.SharePoint", &accessed));
+        assert!(router.accepts("graylog", "Audit.SharePoint", &deleted));
+
+        // The tenant-a rule does not apply, graylog is routed so gets nothing
+        let router = test_router("tenant-b");
+        assert!(!router.accepts("logs_ingestion", "Audit.SharePoint", &deleted));
+        assert!(!router.accepts("graylog", "Audit.SharePoint", &deleted));
+    }
+
+    #[test]
+    fn test_route_filters() {
+        let config: Vec<RouteSubConfig> = serde_yaml::from_str(r#"
+            - outputs: [logs_ingestion]
+              content_types: [Audit.AzureActiveDirectory]
+              filter:
+                Operation: UserLoginFailed
+            - outputs: [logs_ingestion]
+              expression: "RecordType in [11, 13] && Severity != 'Low'"
+        "#).unwrap();
+        let router = Router::new(&config, "tenant-a").unwrap();
+        let log = json!({"Operation": "UserLoginFailed", "RecordType": 15, "Severity": "High"});
+        let field = |k: &str| log.get(k);
+        assert!(router.accepts("logs_ingestion", "Audit.AzureActiveDirectory", &field));
+        assert!(!router.accepts("logs_ingestion", "Audit.General", &field));
+        let log = json!({"Operation": "DlpRuleMatch", "RecordType": 11, "Severity": "High"});
+        assert!(router.accepts("logs_ingestion", "DLP.All", &|k| log.get(k)));
+        let log = json!({"Operation": "DlpRuleMatch", "RecordType": 11, "Severity": "Low"});
+        assert!(!router.accepts("logs_ingestion", "DLP.All", &|k| log.get(k)));
+        assert!(router.accepts("file", "DLP.All", &|k| log.get(k)));
+
+        let config: Vec<RouteSubConfig> = serde_yaml::from_str("[{outputs: [file], expression: 'RecordType =='}]").unwrap();
+        assert_eq!(Router::new(&config, "tenant-a").unwrap_err(), "rule 0 expression: unexpected end");
+    }
+}
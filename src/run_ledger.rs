@@ -0,0 +1,166 @@
+// Ledger of every run: one JSON line per tenant and run, with the time windows it covered and what
+// it retrieved, as evidence that collection is continuous. Written to run_ledger.jsonl in the
+// working directory unless another path is configured, and rotated like the file output.
+
+use std::collections::HashMap;
+use std::path::Path;
+use chrono::{DateTime, Utc};
+use serde_derive::Serialize;
+use serde_json::{json, Value};
+use crate::config::{Config, LedgerSubConfig};
+use crate::data_structures::RunStatistics;
+use crate::file_rotation::{open_shared, RotationPolicy};
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct RunRecord {
+    pub tenant_id: String,
+    pub started: DateTime<Utc>,
+    pub ended: DateTime<Utc>,
+    pub duration_seconds: i64,
+    /// Time window the run was started for per content type, resumed listings and catch-up pacing
+    /// (catchup_chunks_per_run) can list less
+    pub windows: Vec<RunWindow>,
+    pub blobs_found: usize,
+    pub blobs_successful: usize,
+    pub blobs_failed: usize,
+    pub blobs_retried: usize,
+    pub logs_saved: usize,
+    /// Logs dropped by filters, known_logs, suppress and sample rules and the plugin
+    pub logs_filtered: usize,
+    /// Stopped by the global timeout before everything was retrieved
+    pub timed_out: bool,
+    /// Stopped at max_blobs_per_run or max_logs_per_run, the rest is left for the next runs
+    pub limit_reached: bool,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct RunWindow {
+    pub content_type: String,
+    pub start: String,
+    pub end: String,
+}
+
+impl RunRecord {
+
+    pub fn new(tenant_id: &str, started: DateTime<Utc>, windows: Vec<RunWindow>, stats: &RunStatistics,
+               logs_saved: usize, timed_out: bool) -> Self {
+        let ended = Utc::now();
+        RunRecord {
+            tenant_id: tenant_id.to_string(),
+            started,
+            ended,
+            duration_seconds: (ended - started).num_seconds(),
+            windows,
+            blobs_found: stats.blobs_found,
+            blobs_successful: stats.blobs_successful,
+            blobs_failed: stats.blobs_error,
+            blobs_retried: stats.blobs_retried,
+            logs_saved,
+            logs_filtered: 0,
+            timed_out,
+            limit_reached: false,
+        }
+    }
+
+    /// Heartbeat log of the run for the outputs, with fields named like those of audit logs so
+    /// the SIEM can alert on tenants whose heartbeats stop or report failures.
+    pub fn heartbeat(&self) -> Value {
+        let time_format = "%Y-%m-%dT%H:%M:%S";
+        json!({
+            "Id": uuid::Uuid::new_v4().to_string(),
+            "CreationTime": self.ended.format(time_format).to_string(),
+            "Operation": "CollectorHeartbeat",
+            "OrganizationId": self.tenant_id,
+            "CollectorVersion": env!("CARGO_PKG_VERSION"),
+            "RunStarted": self.started.format(time_format).to_string(),
+            "DurationSeconds": self.duration_seconds,
+            "ContentTypes": self.windows.iter().map(|window| &window.content_type).collect::<Vec<_>>(),
+            "BlobsFound": self.blobs_found,
+            "BlobsSuccessful": self.blobs_successful,
+            "BlobsFailed": self.blobs_failed,
+            "BlobsRetried": self.blobs_retried,
+            "LogsSaved": self.logs_saved,
+            "LogsFiltered": self.logs_filtered,
+            "TimedOut": self.timed_out,
+            "LimitReached": self.limit_reached,
+        })
+    }
+}
+
+/// The span of the runs of every content type, from the start of the first to the end of the last.
+pub fn windows(runs: &HashMap<String, Vec<(String, String)>>) -> Vec<RunWindow> {
+    let mut windows: Vec<RunWindow> = runs.iter()
+        .filter_map(|(content_type, runs)| Some(RunWindow {
+            content_type: content_type.clone(),
+            start: runs.iter().map(|(start, _)| start).min()?.clone(),
+            end: runs.iter().map(|(_, end)| end).max()?.clone(),
+        }))
+        .collect();
+    windows.sort_by(|a, b| a.content_type.cmp(&b.content_type));
+    windows
+}
+
+/// Append a run to the ledger.
+pub fn append(ledger: &LedgerSubConfig, config: &Config, record: &RunRecord) -> std::io::Result<()> {
+    let path = ledger.path.clone().unwrap_or_else(|| {
+        Path::new(&config.get_working_dir()).join("run_ledger.jsonl").to_string_lossy().to_string()
+    });
+    let policy = RotationPolicy {
+        compress: ledger.compress.unwrap_or(false),
+        rotate_size: ledger.rotate_size.as_deref().map(|s| Config::parse_size(s) as u64),
+        rotate_interval: ledger.rotate_interval.as_deref().map(|s| Config::parse_interval(s) as i64),
+        retention: ledger.retention,
+    };
+    let file = open_shared(&path, &policy)?;
+    let mut file = file.lock().unwrap();
+    file.write_lines(&[&serde_json::to_string(record)?])?;
+    file.flush()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let config: Config = serde_yaml::from_str(&format!("{{workingDir: {}, output: {{}}, ledger: {{}}}}",
+                                                           dir.path().display())).unwrap();
+        let runs = HashMap::from([("Audit.Exchange".to_string(), vec![
+            ("2024-01-02T00:00:00Z".to_string(), "2024-01-02T10:00:00Z".to_string()),
+            ("2024-01-01T10:00:00Z".to_string(), "2024-01-02T00:00:00Z".to_string()),
+        ])]);
+        let stats = RunStatistics { blobs_found: 3, blobs_successful: 2, blobs_error: 1, blobs_retried: 1 };
+        let record = RunRecord::new("t1", Utc::now(), windows(&runs), &stats, 42, false);
+        append(config.ledger.as_ref().unwrap(), &config, &record).unwrap();
+        append(config.ledger.as_ref().unwrap(), &config, &record).unwrap();
+
+        let content = std::fs::read_to_string(dir.path().join("run_ledger.jsonl")).unwrap();
+        assert_eq!(content.lines().count(), 2);
+        let line: serde_json::Value = serde_json::from_str(content.lines().next().unwrap()).unwrap();
+        assert_eq!(line["tenant_id"], "t1");
+        assert_eq!(line["windows"][0]["start"], "2024-01-01T10:00:00Z");
+        assert_eq!(line["windows"][0]["end"], "2024-01-02T10:00:00Z");
+        assert_eq!((line["blobs_failed"].as_u64(), line["logs_saved"].as_u64()), (Some(1), Some(42)));
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let runs = HashMap::from([("DLP.All".to_string(), vec![
+            ("2024-01-01T00:00:00Z".to_string(), "2024-01-01T01:00:00Z".to_string()),
+        ])]);
+        let stats = RunStatistics { blobs_found: 3, blobs_successful: 2, blobs_error: 1, blobs_retried: 1 };
+        let mut record = RunRecord::new("t1", Utc::now(), windows(&runs), &stats, 42, true);
+        record.logs_filtered = 7;
+        let heartbeat = record.heartbeat();
+        assert_eq!((heartbeat["Operation"].as_str(), heartbeat["OrganizationId"].as_str()),
+                   (Some("CollectorHeartbeat"), Some("t1")));
+        assert_eq!(heartbeat["ContentTypes"], json!(["DLP.All"]));
+        assert_eq!((heartbeat["BlobsFailed"].as_u64(), heartbeat["LogsSaved"].as_u64()), (Some(1), Some(42)));
+        assert_eq!(heartbeat["LogsFiltered"], json!(7));
+        assert_eq!(heartbeat["TimedOut"], json!(true));
+        assert_eq!(heartbeat["LimitReached"], json!(false));
+        assert!(crate::state::parse_api_time(heartbeat["CreationTime"].as_str().unwrap()).is_some());
+    }
+}
@@ -0,0 +1,201 @@
+// Summary of the latest run as one JSON file, for schedulers and monitoring that need to know
+// what a run did without parsing the collector log. Written at the end of every run to the
+// `run_summary` path, replacing the summary of the previous run.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use chrono::{DateTime, Utc};
+use serde_derive::Serialize;
+use crate::lag::LagSummary;
+use crate::run_ledger::RunRecord;
+
+/// Exit codes of a single run, for cron wrappers and schedulers
+pub const EXIT_SUCCEEDED: i32 = 0;
+/// Every tenant failed, or the collector could not run at all (e.g. a config error)
+pub const EXIT_FAILED: i32 = 1;
+pub const EXIT_PARTIALLY_FAILED: i32 = 2;
+
+#[derive(Serialize, Debug)]
+pub struct RunSummary {
+    pub started: DateTime<Utc>,
+    pub ended: DateTime<Utc>,
+    pub duration_seconds: i64,
+    /// Every tenant succeeded
+    pub succeeded: bool,
+    pub tenants: Vec<TenantSummary>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TenantSummary {
+    #[serde(flatten)]
+    pub record: RunRecord,
+    pub outputs: Vec<OutputSummary>,
+    /// Lag of the delivered logs by output ("file" for the file output) and content type
+    pub lag: BTreeMap<String, BTreeMap<String, LagSummary>>,
+    /// Logs dropped by suppress rules, by rule name
+    pub suppressed: BTreeMap<String, usize>,
+    /// Logs dropped by sample rules, by rule name
+    pub sampled_out: BTreeMap<String, usize>,
+    /// Logs dropped by collect and expression filters by content type, by known_logs and by the plugin
+    pub filtered: BTreeMap<String, usize>,
+    /// Why the collector could not run, if it could not
+    pub error: Option<String>,
+    /// The collector could not start because its login failed
+    pub login_failed: bool,
+    /// Started, retrieved every blob, did not time out and delivered every log
+    pub succeeded: bool,
+}
+
+/// Logs given to an output during a run.
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct OutputSummary {
+    pub name: String,
+    pub logs_sent: usize,
+    pub logs_spooled: usize,
+    pub logs_dropped: usize,
+    /// Logs over the output's oversized max_size
+    pub logs_oversized: usize,
+}
+
+impl TenantSummary {
+
+    pub fn new(record: RunRecord, outputs: Vec<OutputSummary>, error: Option<String>) -> Self {
+        let succeeded = error.is_none()
+            && !record.timed_out
+            && record.blobs_failed == 0
+            && outputs.iter().all(|output| output.logs_spooled == 0 && output.logs_dropped == 0);
+        TenantSummary { record, outputs, lag: BTreeMap::new(), suppressed: BTreeMap::new(), sampled_out: BTreeMap::new(),
+                        filtered: BTreeMap::new(), error, login_failed: false, succeeded }
+    }
+
+    pub fn status(&self) -> &'static str {
+        if self.login_failed {
+            "login failed"
+        } else if self.error.is_some() {
+            "failed"
+        } else if self.record.timed_out {
+            "timed out"
+        } else if !self.succeeded {
+            "incomplete"
+        } else {
+            "ok"
+        }
+    }
+}
+
+impl RunSummary {
+
+    pub fn new(started: DateTime<Utc>, tenants: Vec<TenantSummary>) -> Self {
+        let ended = Utc::now();
+        RunSummary {
+            started,
+            ended,
+            duration_seconds: (ended - started).num_seconds(),
+            succeeded: tenants.iter().all(|tenant| tenant.succeeded),
+            tenants,
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self.tenants.iter().filter(|tenant| tenant.succeeded).count() {
+            succeeded if succeeded == self.tenants.len() => EXIT_SUCCEEDED,
+            0 => EXIT_FAILED,
+            _ => EXIT_PARTIALLY_FAILED,
+        }
+    }
+
+    /// One line per tenant with how its run went.
+    pub fn status_table(&self) -> String {
+        let mut rows = vec![["TENANT", "STATUS", "BLOBS", "FAILED", "LOGS", "SECONDS", "ERROR"].map(String::from)];
+        for tenant in &self.tenants {
+            let record = &tenant.record;
+            let undelivered: usize = tenant.outputs.iter().map(|output| output.logs_spooled + output.logs_dropped).sum();
+            let error = match (&tenant.error, undelivered) {
+                (Some(error), _) => error.clone(),
+                (None, 0) => String::new(),
+                (None, undelivered) => format!("{} logs not delivered", undelivered),
+            };
+            rows.push([
+                record.tenant_id.clone(),
+                tenant.status().to_string(),
+                format!("{}/{}", record.blobs_successful, record.blobs_found),
+                record.blobs_failed.to_string(),
+                record.logs_saved.to_string(),
+                record.duration_seconds.to_string(),
+                error,
+            ]);
+        }
+        let widths: Vec<usize> = (0..7).map(|column| rows.iter().map(|row| row[column].len()).max().unwrap_or(0)).collect();
+        rows.iter()
+            .map(|row| {
+                let line: Vec<String> = row.iter().zip(&widths).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect();
+                format!("{}\n", line.join("  ").trim_end())
+            })
+            .collect()
+    }
+
+    /// Replace the summary at path. Readers never see a partly written summary.
+    pub fn write(&self, path: &str) -> std::io::Result<()> {
+        if let Some(parent) = Path::new(path).parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let partial = format!("{}.partial", path);
+        let mut file = File::create(&partial)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.write_all(b"\n")?;
+        file.sync_all()?;
+        fs::rename(&partial, path)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_structures::RunStatistics;
+
+    #[test]
+    fn test_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("summaries/last_run.json").to_string_lossy().to_string();
+        let stats = RunStatistics { blobs_found: 3, blobs_successful: 3, blobs_error: 0, blobs_retried: 0 };
+        let output = OutputSummary { name: "graylog".to_string(), logs_sent: 42, ..Default::default() };
+        let ok = TenantSummary::new(RunRecord::new("t1", Utc::now(), Vec::new(), &stats, 42, false),
+                                    vec![output.clone()], None);
+        let failed = TenantSummary::new(RunRecord::new("t2", Utc::now(), Vec::new(), &RunStatistics::default(), 0, false),
+                                        Vec::new(), Some("Could not log in".to_string()));
+        assert!(ok.succeeded && !failed.succeeded);
+        let spooled = OutputSummary { logs_spooled: 1, ..output };
+        assert!(!TenantSummary::new(RunRecord::new("t1", Utc::now(), Vec::new(), &stats, 42, false),
+                                    vec![spooled], None).succeeded);
+
+        RunSummary::new(Utc::now(), vec![ok, failed]).write(&path).unwrap();
+        let summary: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(summary["succeeded"], false);
+        assert_eq!(summary["tenants"][0]["tenant_id"], "t1");
+        assert_eq!(summary["tenants"][0]["outputs"][0]["logs_sent"], 42);
+        assert_eq!(summary["tenants"][1]["error"], "Could not log in");
+        assert!(!Path::new(&format!("{}.partial", path)).exists());
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let stats = RunStatistics { blobs_found: 3, blobs_successful: 2, blobs_error: 1, blobs_retried: 0 };
+        let tenant = |tenant_id: &str, stats: &RunStatistics, error: Option<&str>| TenantSummary::new(
+            RunRecord::new(tenant_id, Utc::now(), Vec::new(), stats, 42, false), Vec::new(), error.map(String::from));
+        let ok = || tenant("t1", &RunStatistics::default(), None);
+        let failed = || tenant("t2", &RunStatistics::default(), Some("Could not log in"));
+        assert_eq!(RunSummary::new(Utc::now(), vec![ok(), ok()]).exit_code(), EXIT_SUCCEEDED);
+        assert_eq!(RunSummary::new(Utc::now(), vec![ok(), failed()]).exit_code(), EXIT_PARTIALLY_FAILED);
+        assert_eq!(RunSummary::new(Utc::now(), vec![failed(), tenant("t3", &stats, None)]).exit_code(), EXIT_FAILED);
+
+        let table = RunSummary::new(Utc::now(), vec![ok(), failed(), tenant("t3", &stats, None)]).status_table();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], "TENANT  STATUS      BLOBS  FAILED  LOGS  SECONDS  ERROR");
+        assert_eq!(lines[1], "t1      ok          0/0    0       42    0");
+        assert_eq!(lines[2], "t2      failed      0/0    0       42    0        Could not log in");
+        assert_eq!(lines[3], "t3      incomplete  2/3    1       42    0");
+    }
+}
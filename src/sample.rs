@@ -0,0 +1,125 @@
+// Sampling of high volume logs, configured as `sample` rules, e.g. keeping 5% of the
+// FileAccessed logs of SharePoint to control SIEM cost while their volume and trends stay
+// visible. Whether a log is kept depends only on its Id, so a log retrieved twice gets the same
+// decision, and every collector instance keeps the same logs. Logs without an Id are kept. The
+// logs each rule sampled out are counted for the log and run summary.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use regex::Regex;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use crate::config::SampleSubConfig;
+use crate::log_filter::glob_to_regex;
+
+#[derive(Debug)]
+struct Rule {
+    name: String,
+    content_types: Vec<String>,
+    operations: Vec<Regex>,
+    /// Logs kept per 10000
+    keep: u64,
+}
+
+/// The sample rules of a run, with the logs each sampled out.
+#[derive(Debug)]
+pub struct Sample {
+    rules: Vec<Rule>,
+    counts: Vec<AtomicUsize>,
+}
+
+impl Sample {
+
+    pub fn new(config: &[SampleSubConfig]) -> Result<Self, String> {
+        let mut rules = Vec::new();
+        for rule in config {
+            if !(0.0..=100.0).contains(&rule.percent) {
+                return Err(format!("{}: percent must be from 0 to 100", rule.name))
+            }
+            let operations = rule.operations.iter()
+                .map(|operation| Regex::new(&format!("(?i){}", glob_to_regex(operation))))
+                .collect::<Result<_, _>>()
+                .map_err(|e| format!("{}: invalid operation pattern: {}", rule.name, e))?;
+            rules.push(Rule {
+                name: rule.name.clone(),
+                content_types: rule.content_types.clone(),
+                operations,
+                keep: (rule.percent * 100.0).round() as u64,
+            });
+        }
+        let counts = rules.iter().map(|_| AtomicUsize::new(0)).collect();
+        Ok(Sample { rules, counts })
+    }
+
+    /// Whether the first rule matching the log samples it out, counting it if so.
+    pub fn samples_out(&self, content_type: &str, log: &Map<String, Value>) -> bool {
+        let Some(id) = log.get("Id").or_else(|| log.get("id")).and_then(|id| id.as_str()) else {
+            return false
+        };
+        let operation = log.get("Operation").and_then(|operation| operation.as_str());
+        let rule = self.rules.iter().zip(&self.counts).find(|(rule, _)| {
+            (rule.content_types.is_empty() || rule.content_types.iter().any(|c| c == content_type))
+                && (rule.operations.is_empty() || operation.is_some_and(|o| rule.operations.iter().any(|r| r.is_match(o))))
+        });
+        match rule {
+            Some((rule, count)) if bucket(id) >= rule.keep => {
+                count.fetch_add(1, Ordering::Relaxed);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Logs sampled out so far by rule name, only rules that sampled out any.
+    pub fn counts(&self) -> BTreeMap<String, usize> {
+        self.rules.iter().zip(&self.counts)
+            .map(|(rule, count)| (rule.name.clone(), count.load(Ordering::Relaxed)))
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+}
+
+/// 0 to 9999 from the Id, the same on every platform and version.
+fn bucket(id: &str) -> u64 {
+    let digest = Sha256::digest(id.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().expect("SHA-256 has 32 bytes")) % 10000
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_sample() {
+        let config: Vec<SampleSubConfig> = serde_yaml::from_str("
+- name: file-accessed
+  content_types: [Audit.SharePoint]
+  operations: [FileAccessed, filepreview*]
+  percent: 5
+- name: nothing
+  content_types: [Audit.Exchange]
+  percent: 0
+").unwrap();
+        let sample = Sample::new(&config).unwrap();
+        let log = |id: usize, operation: &str| json!({"Id": format!("id-{}", id), "Operation": operation})
+            .as_object().unwrap().clone();
+
+        let kept = (0..10000).filter(|i| !sample.samples_out("Audit.SharePoint", &log(*i, "FileAccessed"))).count();
+        assert!((400..600).contains(&kept), "kept {}", kept);
+        // The same logs every time
+        assert!((0..10000).all(|i| sample.samples_out("Audit.SharePoint", &log(i, "FilePreviewed"))
+            == sample.samples_out("Audit.SharePoint", &log(i, "FileAccessed"))));
+        assert!(!sample.samples_out("Audit.SharePoint", &log(1, "FileModified")));
+        assert!(sample.samples_out("Audit.Exchange", &log(1, "Send")));
+        assert!(!sample.samples_out("Audit.Exchange", &json!({"Operation": "Send"}).as_object().unwrap().clone()));
+
+        let counts = sample.counts();
+        assert_eq!(counts["file-accessed"], 3 * (10000 - kept));
+        assert_eq!(counts["nothing"], 1);
+
+        let config: Vec<SampleSubConfig> = serde_yaml::from_str("[{name: a, percent: 101}]").unwrap();
+        assert_eq!(Sample::new(&config).unwrap_err(), "a: percent must be from 0 to 100");
+    }
+}
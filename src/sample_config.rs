@@ -0,0 +1,320 @@
+// Sample config written by the `generate-config` command. The minimal sample holds what a first
+// deployment needs; the full one adds every other option, with the ones that change behaviour or
+// connect somewhere else commented out. See docs/CONFIGURATION.md for the details of each.
+
+enum Kind {
+    /// In both samples
+    Minimal,
+    /// Only in the full sample
+    Full,
+    /// Only in the full sample, commented out
+    Example,
+}
+
+struct Section {
+    kind: Kind,
+    /// Comment lines above the section, without '#'
+    comment: &'static str,
+    yaml: &'static str,
+}
+
+const SECTIONS: &[Section] = &[
+    Section { kind: Kind::Minimal, comment: "\
+Office 365 audit log collector configuration, see docs/CONFIGURATION.md for all options.
+Durations take an s, m, h or d suffix, sizes K, M or G.", yaml: "" },
+    Section { kind: Kind::Full, comment: "Set to false to exit immediately, e.g. during maintenance", yaml: "enabled: true
+" },
+    Section { kind: Kind::Minimal, comment: "Run as a daemon, collecting every interval. Without it the collector runs once.", yaml: "interval: \"5m\"
+" },
+    Section { kind: Kind::Example, comment: "\
+With many tenants: start each tenant this much later than the previous one, plus a random delay", yaml: "tenant_stagger: \"2s\"
+schedule_jitter: \"30s\"
+" },
+    Section { kind: Kind::Example, comment: "Collect at most this many tenants at the same time", yaml: "max_concurrent_tenants: 20
+" },
+    Section { kind: Kind::Minimal, comment: "\
+Start from now on the first run instead of collecting the last hoursToCollect hours, and
+continue from the saved state afterwards", yaml: "only_future_events: true
+" },
+    Section { kind: Kind::Full, comment: "\
+History the first run collects with only_future_events (default: none, max 167h)", yaml: "initial_lookback: \"1h\"
+" },
+    Section { kind: Kind::Full, comment: "\
+Kept between the latest collected CreationTime and the saved state, for logs that show up late", yaml: "state_safety_lag: \"5m\"
+" },
+    Section { kind: Kind::Example, comment: "\
+Start every run this long before the saved state (default: no overlap), see collect.skipKnownLogs", yaml: "lookback_overlap: \"15m\"
+" },
+    Section { kind: Kind::Example, comment: "\
+After downtime: catch up at most this far back, and list at most this many 24 hour windows per run", yaml: "max_catchup: \"48h\"
+catchup_chunks_per_run: 1
+" },
+    Section { kind: Kind::Minimal, comment: "Directory of the state files and known blobs", yaml: "workingDir: \"/var/lib/office365-collector\"
+" },
+    Section { kind: Kind::Full, comment: "Sync state files to disk when saving them", yaml: "state_fsync: true
+" },
+    Section { kind: Kind::Example, comment: "\
+Keep state and known blobs in Redis instead, to share them between collector instances, or with
+state_backend: \"sqlite\" in one SQLite database in the working directory", yaml: "state_backend: \"redis\"
+redis:
+  url: \"redis://:password@redis.example.com:6379/0\"
+  key_prefix: \"office365\"
+" },
+    Section { kind: Kind::Example, comment: "\
+Record every run with the time windows it covered (default path: run_ledger.jsonl in workingDir)", yaml: "ledger:
+  path: \"/var/lib/office365-collector/run_ledger.jsonl\"
+  rotate_interval: \"30d\"
+  retention: 12
+  compress: true
+" },
+    Section { kind: Kind::Example, comment: "\
+Send a heartbeat log (OriginFeed Collector.Heartbeat) with the run's statistics to the outputs
+for every tenant and run, to alert on tenants that stop producing data", yaml: "heartbeat: true
+" },
+    Section { kind: Kind::Example, comment: "JSON summary of the latest run, for schedulers and monitoring", yaml: "run_summary: \"/var/lib/office365-collector/last_run.json\"
+" },
+    Section { kind: Kind::Example, comment: "\
+Alert when a tenant fails, its login keeps failing or logs could not be delivered", yaml: "on_error:
+  url: \"https://hooks.slack.com/services/...\"
+  format: \"slack\"  # json, slack or teams
+  # exec: \"/usr/local/bin/page-oncall\"
+  # args: [\"{event}\", \"{tenant_id}\"]
+  # login_failures: 3
+" },
+    Section { kind: Kind::Minimal, comment: "Collector log, an empty path logs to stderr", yaml: "log:
+  path: \"\"
+  level: \"info\"  # error, warn, info, debug or trace
+  # tenant_dir: \"/var/log/office365/tenants\"  # a log file per tenant instead of path
+" },
+    Section { kind: Kind::Example, comment: "\
+Export traces of every run (token requests, listing, downloads, output sends) to an OTLP/HTTP
+receiver", yaml: "tracing:
+  endpoint: \"http://otel-collector:4318\"
+  service_name: \"office365-log-collector\"
+" },
+    Section { kind: Kind::Minimal, comment: "\
+Tenants to collect from. The app registration needs the ActivityFeed.Read and
+ActivityFeed.ReadDlp permissions of the Office 365 Management APIs.", yaml: "tenants:
+  - tenant_id: \"00000000-0000-0000-0000-000000000000\"
+    client_id: \"00000000-0000-0000-0000-000000000000\"
+    client_secret_path: \"/etc/office365-collector/client_secret\"  # or client_secret
+    api_type: \"commercial\"  # commercial, gcc, gcc-high, dod, china or an api_types entry
+" },
+    Section { kind: Kind::Example, comment: "\
+A tenant authenticating with a certificate, and its own PublisherIdentifier quota", yaml: "  - tenant_id: \"11111111-1111-1111-1111-111111111111\"
+    client_id: \"11111111-1111-1111-1111-111111111111\"
+    certificate_path: \"/etc/office365-collector/tenant2.pem\"
+    private_key_path: \"/etc/office365-collector/tenant2.key\"  # if not in certificate_path
+    publisher_id: \"11111111-1111-1111-1111-111111111111\"
+" },
+    Section { kind: Kind::Example, comment: "\
+Load more tenants from a CSV file (header row of tenant fields) or, with url and headers, from
+an HTTP endpoint returning JSON. Synced every run.", yaml: "tenant_source:
+  csv: \"/etc/office365-collector/tenants.csv\"
+" },
+    Section { kind: Kind::Example, comment: "Endpoints of clouds that are not built in, selected with api_type", yaml: "api_types:
+  sovereign:
+    login_endpoint: \"https://login.example.net\"
+    resource_endpoint: \"https://manage.example.net\"
+    graph_endpoint: \"https://graph.example.net\"
+" },
+    Section { kind: Kind::Minimal, comment: "Audit feeds to collect", yaml: "subscriptions:
+  - \"Audit.AzureActiveDirectory\"
+  - \"Audit.Exchange\"
+  - \"Audit.SharePoint\"
+  - \"Audit.General\"
+  - \"DLP.All\"
+" },
+    Section { kind: Kind::Example, comment: "\
+Also collect Entra ID audit and sign-in logs and security alerts from Microsoft Graph
+(needs AuditLog.Read.All and SecurityAlert.Read.All)", yaml: "graph:
+  sources: [directoryAudits, signIns, alerts]
+  delay: \"5m\"
+" },
+    Section { kind: Kind::Example, comment: "\
+Collection tuning. Only logs matching a filter's fields are kept; skipKnownLogs skips logs whose
+Id was written before.", yaml: "collect:
+  maxThreads: 10
+  retries: 3
+  globalTimeout: \"30m\"  # or minutes
+  hoursToCollect: 24
+  cacheSize: 500000
+  cacheBytes: 256M
+  max_requests_per_second: 30
+  skipKnownLogs: true
+  max_known_logs: 500000
+  filter:
+    Audit.Exchange:
+      Operation: \"MailItemsAccessed\"
+" },
+    Section { kind: Kind::Minimal, comment: "Where logs are sent, one or more outputs", yaml: "output:
+" },
+    Section { kind: Kind::Minimal, comment: "JSON lines files, one per content type with separateByContentType", yaml: "  file:
+    path: \"/var/log/office365/audit.json\"
+    separateByContentType: true
+" },
+    Section { kind: Kind::Full, comment: "Rotation of the files", yaml: "    rotate_size: \"100M\"
+    rotate_interval: \"1h\"
+    compress: true
+    retention: 48
+" },
+    Section { kind: Kind::Example, comment: "Each log as one JSON line on stdout", yaml: "  stdout: {}
+" },
+    Section { kind: Kind::Example, comment: "Fluentd forward protocol", yaml: "  fluentd:
+    tenantName: \"YourOrg\"
+    address: \"localhost\"
+    port: 24224
+    shared_key: \"secret\"
+    require_ack: true
+" },
+    Section { kind: Kind::Example, comment: "Graylog GELF over TCP or UDP, or CEF", yaml: "  graylog:
+    address: \"graylog.example.com\"
+    port: 12201
+    format: json  # json or cef
+    protocol: tcp  # tcp or udp
+" },
+    Section { kind: Kind::Example, comment: "\
+Azure Monitor Logs Ingestion API (data collection rules), for Sentinel and Log Analytics", yaml: "  logs_ingestion:
+    endpoint: \"https://my-dce-abcd.westeurope-1.ingest.monitor.azure.com\"
+    dcr_immutable_id: \"dcr-00000000000000000000000000000000\"
+    stream_name: \"Custom-Office365Audit_CL\"
+    asim: false
+    aad:
+      tenant_id: \"00000000-0000-0000-0000-000000000000\"
+      client_id: \"00000000-0000-0000-0000-000000000000\"
+      client_secret_path: \"/etc/office365-collector/monitor_secret\"
+" },
+    Section { kind: Kind::Example, comment: "\
+Azure Log Analytics HTTP Data Collector API (deprecated), the shared key is passed with --oms-key", yaml: "  azureLogAnalytics:
+    workspaceId: \"00000000-0000-0000-0000-000000000000\"
+" },
+    Section { kind: Kind::Example, comment: "Azure Event Hubs", yaml: "  event_hub:
+    connection_string_path: \"/etc/office365-collector/event_hub\"
+" },
+    Section { kind: Kind::Example, comment: "S3 or S3-compatible storage, credentials default to the AWS_* variables", yaml: "  s3:
+    bucket: \"office365-archive\"
+    region: \"eu-west-1\"
+    key_template: \"{tenant}/{content_type}/{date}/{uuid}.json.gz\"
+    flush_size: \"8M\"
+    flush_interval: \"5m\"
+" },
+    Section { kind: Kind::Example, comment: "Azure Blob Storage", yaml: "  azure_blob:
+    account: \"o365archive\"
+    container: \"audit-logs\"
+    sas_token: \"sv=2022-11-02&ss=b&srt=co&sp=cw&...\"
+    partitioning: \"daily\"
+    flush_size: \"8M\"
+    flush_interval: \"5m\"
+" },
+    Section { kind: Kind::Example, comment: "Kinesis Data Firehose", yaml: "  firehose:
+    delivery_stream: \"office365-audit\"
+    region: \"eu-west-1\"
+" },
+    Section { kind: Kind::Example, comment: "IBM QRadar, LEEF or CEF over TCP", yaml: "  qradar:
+    address: \"qradar.example.com\"
+    port: 514
+    format: leef
+" },
+    Section { kind: Kind::Example, comment: "Local Wazuh queue socket", yaml: "  wazuh:
+    socket_path: \"/var/ossec/queue/sockets/queue\"
+" },
+    Section { kind: Kind::Example, comment: "A program receiving JSON lines on stdin", yaml: "  exec:
+    command: \"/usr/local/bin/ship-logs\"
+    args: [\"--destination\", \"siem.example.com\"]
+" },
+    Section { kind: Kind::Example, comment: "\
+Send only matching logs to the outputs named in a rule, other outputs get everything", yaml: "routing:
+  - outputs: [graylog]
+    content_types: [DLP.All]
+    match:
+      Operation: \"DlpRuleMatch\"
+" },
+    Section { kind: Kind::Example, comment: "Batch size and flush interval of outputs, by output name or default", yaml: "batching:
+  default:
+    batch_size: 50000
+    flush_interval: \"10s\"
+" },
+    Section { kind: Kind::Example, comment: "Retries of batches an output did not accept", yaml: "retry:
+  default:
+    max_retries: 3
+    initial_backoff: \"1s\"
+    max_backoff: \"1m\"
+" },
+    Section { kind: Kind::Example, comment: "Keep batches that could not be delivered on disk and replay them later", yaml: "spool:
+  directory: \"/var/lib/office365-collector/spool\"
+  max_size: \"1G\"
+" },
+    Section { kind: Kind::Example, comment: "TLS of the Management API client and HTTPS outputs, e.g. the CA of a proxy", yaml: "tls:
+  ca_file: \"/etc/ssl/proxy-ca.pem\"
+  min_version: \"1.2\"
+" },
+    Section { kind: Kind::Example, comment: "\
+Content notifications from the API instead of only polling, daemon mode only", yaml: "webhook:
+  address: \"https://collector.example.com/o365\"
+  listen: \"0.0.0.0:8443\"
+  auth_id: \"random-secret\"
+" },
+    Section { kind: Kind::Example, comment: "\
+Local HTTP API to run, pause and resume tenants, query their status and reload the config", yaml: "control_api:
+  listen: \"127.0.0.1:8080\"
+  token: \"random-secret\"
+" },
+];
+
+/// The sample config, only the options a first deployment needs when minimal.
+pub fn render(minimal: bool) -> String {
+    render_sections(minimal, false)
+}
+
+fn render_sections(minimal: bool, uncomment: bool) -> String {
+    let mut sample = String::new();
+    for section in SECTIONS {
+        let commented = match section.kind {
+            Kind::Minimal => false,
+            Kind::Full if minimal => continue,
+            Kind::Full => false,
+            Kind::Example if minimal => continue,
+            Kind::Example => !uncomment,
+        };
+        let indent = &section.yaml[..section.yaml.len() - section.yaml.trim_start().len()];
+        if !section.comment.is_empty() {
+            if indent.is_empty() && !sample.is_empty() {
+                sample.push('\n');
+            }
+            for line in section.comment.lines() {
+                sample.push_str(&format!("{}# {}\n", indent, line));
+            }
+        }
+        for line in section.yaml.lines() {
+            if commented {
+                sample.push_str(&format!("{}# {}\n", indent, &line[indent.len().min(line.len())..]));
+            } else {
+                sample.push_str(line);
+                sample.push('\n');
+            }
+        }
+    }
+    sample
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_samples_are_valid() {
+        for (minimal, uncomment) in [(true, false), (false, false), (false, true)] {
+            let sample = render_sections(minimal, uncomment);
+            let config: Config = serde_yaml::from_str(&sample)
+                .unwrap_or_else(|e| panic!("{}\n{}", e, sample));
+            assert_eq!(config.validate(&sample), Vec::<String>::new());
+            assert_eq!(config.tenants.len(), if uncomment { 2 } else { 1 });
+        }
+        let full = render(false);
+        assert!(full.contains("\n  # graylog:\n  #   address: \"graylog.example.com\"\n"));
+        assert!(full.contains("\n    rotate_size: \"100M\"\n"));
+        assert!(!render(true).contains("graylog"));
+    }
+}
@@ -0,0 +1,186 @@
+// SQLite database of the `sqlite` state backend: the state of every tenant and subscription, the
+// known blobs and the listing cursors of all tenants in one file, instead of files per tenant and
+// subscription in the working directory. Every save is a transaction, so a crash while saving
+// leaves the previous version. A connection is opened per operation, as for redis; concurrent
+// tenants (and other processes) wait for each other's transactions up to BUSY_TIMEOUT.
+//
+// Tables:
+//   state(tenant_id, subscription, last_log_time, last_run, first_run)
+//   known_blobs(tenant_id, content_id, expiration)
+//   cursors(tenant_id, progress)  progress JSON as in the cursor files, see page_cursors.rs
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use rusqlite::{params, Connection, OptionalExtension};
+use crate::config::SqliteSubConfig;
+use crate::state::TenantSubscriptionState;
+
+const DEFAULT_FILE_NAME: &str = "office365-state.sqlite";
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+const SCHEMA: &str = "
+PRAGMA journal_mode = WAL;
+CREATE TABLE IF NOT EXISTS state (
+    tenant_id TEXT NOT NULL,
+    subscription TEXT NOT NULL,
+    last_log_time TEXT NOT NULL,
+    last_run TEXT NOT NULL,
+    first_run INTEGER NOT NULL,
+    PRIMARY KEY (tenant_id, subscription)
+);
+CREATE TABLE IF NOT EXISTS known_blobs (
+    tenant_id TEXT NOT NULL,
+    content_id TEXT NOT NULL,
+    expiration TEXT NOT NULL,
+    PRIMARY KEY (tenant_id, content_id)
+);
+CREATE TABLE IF NOT EXISTS cursors (
+    tenant_id TEXT PRIMARY KEY,
+    progress TEXT NOT NULL
+);";
+
+#[derive(Clone, Debug)]
+pub struct SqliteStore {
+    path: PathBuf,
+}
+
+impl SqliteStore {
+
+    /// Database at the configured path, by default in the working directory. It is created when
+    /// first used.
+    pub fn new(config: Option<&SqliteSubConfig>, working_dir: &str) -> Self {
+        let path = match config.and_then(|config| config.path.as_ref()) {
+            Some(path) => PathBuf::from(path),
+            None => Path::new(working_dir).join(DEFAULT_FILE_NAME),
+        };
+        SqliteStore { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn load_state(&self, tenant_id: &str, subscription: &str) -> Result<Option<TenantSubscriptionState>, String> {
+        self.connect()?.query_row(
+            "SELECT last_log_time, last_run, first_run FROM state WHERE tenant_id = ?1 AND subscription = ?2",
+            params![tenant_id, subscription],
+            |row| Ok(TenantSubscriptionState { last_log_time: row.get(0)?, last_run: row.get(1)?, first_run: row.get(2)? }),
+        ).optional().map_err(database_error)
+    }
+
+    pub fn save_state(&self, tenant_id: &str, subscription: &str, state: &TenantSubscriptionState) -> Result<(), String> {
+        self.connect()?.execute(
+            "INSERT OR REPLACE INTO state (tenant_id, subscription, last_log_time, last_run, first_run) \
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![tenant_id, subscription, state.last_log_time, state.last_run, state.first_run],
+        ).map(|_| ()).map_err(database_error)
+    }
+
+    /// Delete the state of a tenant+subscription, false if there was none.
+    pub fn delete_state(&self, tenant_id: &str, subscription: &str) -> Result<bool, String> {
+        self.connect()?.execute(
+            "DELETE FROM state WHERE tenant_id = ?1 AND subscription = ?2",
+            params![tenant_id, subscription],
+        ).map(|deleted| deleted > 0).map_err(database_error)
+    }
+
+    /// Known blobs of a tenant, content ID to expiration.
+    pub fn load_known_blobs(&self, tenant_id: &str) -> Result<HashMap<String, String>, String> {
+        let connection = self.connect()?;
+        let mut statement = connection.prepare("SELECT content_id, expiration FROM known_blobs WHERE tenant_id = ?1")
+            .map_err(database_error)?;
+        let rows = statement.query_map(params![tenant_id], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(database_error)?;
+        rows.collect::<Result<HashMap<String, String>, _>>().map_err(database_error)
+    }
+
+    /// Replace the known blobs of a tenant in one transaction.
+    pub fn replace_known_blobs(&self, tenant_id: &str, known_blobs: &HashMap<String, String>) -> Result<(), String> {
+        let mut connection = self.connect()?;
+        let transaction = connection.transaction().map_err(database_error)?;
+        transaction.execute("DELETE FROM known_blobs WHERE tenant_id = ?1", params![tenant_id])
+            .map_err(database_error)?;
+        {
+            let mut insert = transaction.prepare(
+                "INSERT INTO known_blobs (tenant_id, content_id, expiration) VALUES (?1, ?2, ?3)")
+                .map_err(database_error)?;
+            for (content_id, expiration) in known_blobs {
+                insert.execute(params![tenant_id, content_id, expiration]).map_err(database_error)?;
+            }
+        }
+        transaction.commit().map_err(database_error)
+    }
+
+    /// Listing progress of a tenant, see page_cursors.rs.
+    pub fn load_cursors(&self, tenant_id: &str) -> Result<Option<String>, String> {
+        self.connect()?.query_row(
+            "SELECT progress FROM cursors WHERE tenant_id = ?1", params![tenant_id], |row| row.get(0),
+        ).optional().map_err(database_error)
+    }
+
+    /// Save the listing progress of a tenant, deleting it when there is none.
+    pub fn save_cursors(&self, tenant_id: &str, progress: Option<&str>) -> Result<(), String> {
+        let connection = self.connect()?;
+        let result = match progress {
+            Some(progress) => connection.execute(
+                "INSERT OR REPLACE INTO cursors (tenant_id, progress) VALUES (?1, ?2)", params![tenant_id, progress]),
+            None => connection.execute("DELETE FROM cursors WHERE tenant_id = ?1", params![tenant_id]),
+        };
+        result.map(|_| ()).map_err(database_error)
+    }
+
+    fn connect(&self) -> Result<Connection, String> {
+        let connection = Connection::open(&self.path)
+            .map_err(|e| format!("Could not open state database {}: {}", self.path.display(), e))?;
+        connection.busy_timeout(BUSY_TIMEOUT).map_err(database_error)?;
+        connection.execute_batch(SCHEMA).map_err(database_error)?;
+        Ok(connection)
+    }
+}
+
+fn database_error(e: rusqlite::Error) -> String {
+    format!("State database error: {}", e)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn test_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SqliteStore::new(None, dir.path().to_str().unwrap());
+        assert_eq!(store.path(), dir.path().join("office365-state.sqlite"));
+
+        assert!(store.load_state("t", "Audit.Exchange").unwrap().is_none());
+        let state = TenantSubscriptionState {
+            last_log_time: Utc.with_ymd_and_hms(2024, 1, 31, 12, 0, 0).unwrap(),
+            last_run: Utc.with_ymd_and_hms(2024, 1, 31, 12, 5, 0).unwrap(),
+            first_run: false,
+        };
+        store.save_state("t", "Audit.Exchange", &state).unwrap();
+        let loaded = store.load_state("t", "Audit.Exchange").unwrap().unwrap();
+        assert_eq!((loaded.last_log_time, loaded.last_run, loaded.first_run),
+                   (state.last_log_time, state.last_run, state.first_run));
+        assert!(store.load_state("t", "Audit.General").unwrap().is_none());
+        assert!(store.delete_state("t", "Audit.Exchange").unwrap());
+        assert!(!store.delete_state("t", "Audit.Exchange").unwrap());
+
+        let known_blobs = HashMap::from([("a".to_string(), "2024-02-07T00:00:00.000Z".to_string()),
+                                         ("b".to_string(), "2024-02-08T00:00:00.000Z".to_string())]);
+        store.replace_known_blobs("t", &known_blobs).unwrap();
+        store.replace_known_blobs("other", &HashMap::from([("c".to_string(), String::new())])).unwrap();
+        assert_eq!(store.load_known_blobs("t").unwrap(), known_blobs);
+        store.replace_known_blobs("t", &HashMap::new()).unwrap();
+        assert!(store.load_known_blobs("t").unwrap().is_empty());
+        assert_eq!(store.load_known_blobs("other").unwrap().len(), 1);
+
+        store.save_cursors("t", Some("{\"cursors\":[]}")).unwrap();
+        assert_eq!(store.load_cursors("t").unwrap().as_deref(), Some("{\"cursors\":[]}"));
+        store.save_cursors("t", None).unwrap();
+        assert!(store.load_cursors("t").unwrap().is_none());
+    }
+}
@@ -2,10 +2,13 @@
 // Tracks last_log_time per tenant+subscription for precise resumption
 
 use std::fs;
-use std::path::PathBuf;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 use chrono::{DateTime, Utc};
 use serde_derive::{Deserialize, Serialize};
 use log::{debug, error, info};
+use crate::config::{Config, StateBackend};
+use crate::known_blobs_cache::{KnownBlobsCache, SharedKnownBlobsCache};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TenantSubscriptionState {
@@ -26,6 +29,10 @@ impl TenantSubscriptionState {
 
 pub struct StateManager {
     working_dir: PathBuf,
+    /// Flush state files to disk before they replace the previous ones
+    fsync: bool,
+    /// Where state and known blobs are kept, the files in working_dir by default
+    backend: StateBackend,
 }
 
 impl StateManager {
@@ -39,9 +46,24 @@ impl StateManager {
 
         Self {
             working_dir: dir,
+            fsync: false,
+            backend: StateBackend::File,
         }
     }
 
+    /// State manager of the configured working directory and state backend, which was checked
+    /// at startup.
+    pub fn for_config(config: &Config) -> Self {
+        let backend = config.get_state_backend().unwrap_or(StateBackend::File);
+        Self { backend, ..Self::new(&config.get_working_dir()).with_fsync(config.state_fsync.unwrap_or(false)) }
+    }
+
+    /// Sync state files to disk when saving them (`state_fsync`).
+    pub fn with_fsync(mut self, fsync: bool) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
     /// Get state file path for a specific tenant+subscription
     fn get_state_file_path(&self, tenant_id: &str, subscription: &str) -> PathBuf {
         let filename = format!("office365-{}-{}.json",
@@ -53,6 +75,28 @@ impl StateManager {
 
     /// Load state for a tenant+subscription
     pub fn load_state(&self, tenant_id: &str, subscription: &str) -> Option<TenantSubscriptionState> {
+        match &self.backend {
+            StateBackend::File => (),
+            StateBackend::Redis(redis) => {
+                let key = redis.state_key(tenant_id, subscription);
+                return match redis.get(&key) {
+                    Ok(Some(content)) => serde_json::from_str(&content)
+                        .map_err(|e| error!("Failed to parse state {}: {}", key, e))
+                        .ok(),
+                    Ok(None) => None,
+                    Err(e) => {
+                        error!("Failed to read state {}: {}", key, e);
+                        None
+                    }
+                }
+            },
+            StateBackend::Sqlite(sqlite) => {
+                return sqlite.load_state(tenant_id, subscription).unwrap_or_else(|e| {
+                    error!("Failed to read state of {}/{}: {}", tenant_id, subscription, e);
+                    None
+                })
+            },
+        }
         let path = self.get_state_file_path(tenant_id, subscription);
 
         if !path.exists() {
@@ -83,11 +127,23 @@ impl StateManager {
 
     /// Save state for a tenant+subscription
     pub fn save_state(&self, tenant_id: &str, subscription: &str, state: &TenantSubscriptionState) -> Result<(), String> {
+        match &self.backend {
+            StateBackend::File => (),
+            StateBackend::Redis(redis) => {
+                let content = serde_json::to_string(state).map_err(|e| format!("Failed to serialize state: {}", e))?;
+                return redis.set(&redis.state_key(tenant_id, subscription), &content)
+                    .map_err(|e| format!("Failed to write state: {}", e))
+            },
+            StateBackend::Sqlite(sqlite) => {
+                return sqlite.save_state(tenant_id, subscription, state)
+                    .map_err(|e| format!("Failed to write state: {}", e))
+            },
+        }
         let path = self.get_state_file_path(tenant_id, subscription);
 
         match serde_json::to_string_pretty(state) {
             Ok(content) => {
-                match fs::write(&path, content) {
+                match write_atomic(&path, self.fsync, |writer| writer.write_all(content.as_bytes())) {
                     Ok(_) => {
                         debug!("Saved state for {}/{}: last_log_time={}",
                             tenant_id, subscription, state.last_log_time);
@@ -106,6 +162,26 @@ impl StateManager {
         }
     }
 
+    /// Delete the state of a tenant+subscription, false if there was none
+    pub fn delete_state(&self, tenant_id: &str, subscription: &str) -> Result<bool, String> {
+        match &self.backend {
+            StateBackend::File => (),
+            StateBackend::Redis(redis) => {
+                return redis.del(&redis.state_key(tenant_id, subscription))
+                    .map_err(|e| format!("Failed to delete state: {}", e))
+            },
+            StateBackend::Sqlite(sqlite) => {
+                return sqlite.delete_state(tenant_id, subscription)
+                    .map_err(|e| format!("Failed to delete state: {}", e))
+            },
+        }
+        match fs::remove_file(self.get_state_file_path(tenant_id, subscription)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(format!("Failed to delete state: {}", e)),
+        }
+    }
+
     /// Initialize or update state for first run with only_future_events
     pub fn initialize_state(&self, tenant_id: &str, subscription: &str, only_future_events: bool) -> TenantSubscriptionState {
         // Try to load existing state
@@ -134,6 +210,51 @@ impl StateManager {
         state
     }
 
+    /// Known blobs file of a tenant
+    fn get_known_blobs_path(&self, tenant_id: &str) -> PathBuf {
+        self.working_dir.join(format!("known_blobs_{}", sanitize_filename(tenant_id)))
+    }
+
+    /// Load the blobs retrieved before for a tenant, without expired ones. Until the tenant has
+    /// its own file, the known_blobs file that all tenants shared before is used.
+    pub fn load_known_blobs(&self, tenant_id: &str) -> KnownBlobsCache {
+        let loaded = match &self.backend {
+            StateBackend::File => {
+                let path = self.get_known_blobs_path(tenant_id);
+                let legacy_path = self.working_dir.join("known_blobs");
+                if !path.exists() && legacy_path.exists() {
+                    info!("Loading known blobs of tenant {} from shared file {}", tenant_id, legacy_path.display());
+                    return KnownBlobsCache::load_from_file(&legacy_path)
+                }
+                return KnownBlobsCache::load_from_file(&path)
+            },
+            StateBackend::Redis(redis) => redis.hgetall(&redis.known_blobs_key(tenant_id)),
+            StateBackend::Sqlite(sqlite) => sqlite.load_known_blobs(tenant_id),
+        };
+        match loaded {
+            Ok(known_blobs) => KnownBlobsCache::from_hashmap(known_blobs),
+            Err(e) => {
+                error!("Failed to load known blobs: {}", e);
+                KnownBlobsCache::new()
+            }
+        }
+    }
+
+    pub async fn save_known_blobs(&self, tenant_id: &str, known_blobs: &SharedKnownBlobsCache) -> Result<(), String> {
+        match &self.backend {
+            StateBackend::File => known_blobs.save_to_file(&self.get_known_blobs_path(tenant_id), self.fsync).await
+                .map_err(|e| e.to_string()),
+            StateBackend::Redis(redis) => {
+                known_blobs.cleanup_expired().await;
+                redis.replace_hash(&redis.known_blobs_key(tenant_id), &known_blobs.to_hashmap().await)
+            },
+            StateBackend::Sqlite(sqlite) => {
+                known_blobs.cleanup_expired().await;
+                sqlite.replace_known_blobs(tenant_id, &known_blobs.to_hashmap().await)
+            },
+        }
+    }
+
     /// Check if this is the first run for a tenant+subscription
     pub fn is_first_run(&self, tenant_id: &str, subscription: &str) -> bool {
         self.load_state(tenant_id, subscription).is_none()
@@ -141,6 +262,60 @@ impl StateManager {
 }
 
 /// Sanitize filename to remove invalid characters
+/// Replace a file by writing a temporary file next to it and renaming it over the original, so a
+/// crash mid-write leaves either the old or the new content. With `fsync` the data (and on Unix
+/// the rename) is on disk before this returns.
+pub fn write_atomic<F>(path: &Path, fsync: bool, write: F) -> std::io::Result<()>
+    where F: FnOnce(&mut dyn Write) -> std::io::Result<()> {
+
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+    let result = (|| {
+        let mut writer = BufWriter::new(fs::File::create(&temp_path)?);
+        write(&mut writer)?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        if fsync {
+            file.sync_all()?;
+        }
+        fs::rename(&temp_path, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+        return result
+    }
+    #[cfg(unix)]
+    if fsync {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::File::open(dir)?.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+/// last_log_time to save after a run: the latest CreationTime collected minus the safety lag, so
+/// logs that show up late are still collected by the next run. Without collected logs, the end
+/// of the run minus the lag. Not past the start of the first window the run did not finish, and
+/// never back before the previous last_log_time.
+pub fn next_last_log_time(latest: Option<DateTime<Utc>>, previous: Option<DateTime<Utc>>,
+                          unfinished: Option<DateTime<Utc>>, lag: chrono::Duration, now: DateTime<Utc>)
+    -> DateTime<Utc> {
+    let next = latest.unwrap_or(now) - lag;
+    let next = unfinished.map_or(next, |unfinished| next.min(unfinished));
+    match previous {
+        Some(previous) if previous > next => previous,
+        _ => next,
+    }
+}
+
+/// Parse a timestamp of the Office Management API, e.g. a log's CreationTime or a listing's
+/// startTime. They are UTC, with or without fractional seconds and a trailing Z.
+pub fn parse_api_time(time: &str) -> Option<DateTime<Utc>> {
+    chrono::NaiveDateTime::parse_from_str(time.trim_end_matches('Z'), "%Y-%m-%dT%H:%M:%S%.f")
+        .ok()
+        .map(|time| time.and_utc())
+}
+
 fn sanitize_filename(s: &str) -> String {
     s.chars()
         .map(|c| match c {
@@ -155,6 +330,78 @@ mod tests {
     use super::*;
     use tempfile::tempdir;
 
+    #[tokio::test]
+    async fn test_known_blobs_per_tenant() {
+        let dir = tempdir().unwrap();
+        let manager = StateManager::new(dir.path().to_str().unwrap());
+        fs::write(dir.path().join("known_blobs"), "shared,2999-01-01T00:00:00.000Z\n").unwrap();
+        assert_eq!(manager.load_known_blobs("tenant-a").len(), 1);
+
+        let known_blobs = SharedKnownBlobsCache::from_cache(manager.load_known_blobs("tenant-b"));
+        known_blobs.insert("blob-b".to_string(), "2999-01-01T00:00:00.000Z").await;
+        manager.save_known_blobs("tenant-b", &known_blobs).await.unwrap();
+        assert!(dir.path().join("known_blobs_tenant-b").exists());
+        let mut loaded = manager.load_known_blobs("tenant-b");
+        assert!(loaded.contains("blob-b") && loaded.contains("shared"));
+        assert_eq!(manager.load_known_blobs("tenant-a").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_backend() {
+        let dir = tempdir().unwrap();
+        let config: Config = serde_yaml::from_str(&format!("{{output: {{}}, state_backend: sqlite, workingDir: {}}}",
+                                                           dir.path().display())).unwrap();
+        let manager = StateManager::for_config(&config);
+        let known_blobs = SharedKnownBlobsCache::from_cache(manager.load_known_blobs("tenant-a"));
+        known_blobs.insert("blob-a".to_string(), "2999-01-01T00:00:00.000Z").await;
+        known_blobs.insert("expired".to_string(), "2000-01-01T00:00:00.000Z").await;
+        manager.save_known_blobs("tenant-a", &known_blobs).await.unwrap();
+        let mut loaded = manager.load_known_blobs("tenant-a");
+        assert!(loaded.contains("blob-a") && !loaded.contains("expired"));
+
+        manager.save_state("tenant-a", "Audit.Exchange", &TenantSubscriptionState::new()).unwrap();
+        assert!(manager.load_state("tenant-a", "Audit.Exchange").unwrap().first_run);
+        assert!(manager.delete_state("tenant-a", "Audit.Exchange").unwrap());
+        // Only the database is written
+        let files: Vec<_> = fs::read_dir(dir.path()).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| !name.ends_with("-wal") && !name.ends_with("-shm"))
+            .collect();
+        assert_eq!(files, ["office365-state.sqlite"]);
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("known_blobs");
+        write_atomic(&path, true, |writer| writer.write_all(b"a,2024-01-01T00:00:00.000Z\n")).unwrap();
+        let failed = write_atomic(&path, false, |writer| {
+            writer.write_all(b"partial")?;
+            Err(std::io::Error::other("crash"))
+        });
+        assert!(failed.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a,2024-01-01T00:00:00.000Z\n");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_next_last_log_time() {
+        let time = |s: &str| parse_api_time(s).unwrap();
+        let lag = chrono::Duration::try_minutes(5).unwrap();
+        let now = time("2024-01-01T12:00:00Z");
+        assert_eq!(next_last_log_time(Some(time("2024-01-01T11:00:00")), None, None, lag, now),
+                   time("2024-01-01T10:55:00"));
+        assert_eq!(next_last_log_time(None, Some(time("2024-01-01T10:00:00")), None, lag, now),
+                   time("2024-01-01T11:55:00"));
+        assert_eq!(next_last_log_time(Some(time("2024-01-01T09:00:00")), Some(time("2024-01-01T10:00:00")), None,
+                                      lag, now),
+                   time("2024-01-01T10:00:00"));
+        assert_eq!(next_last_log_time(Some(time("2024-01-01T11:00:00")), Some(time("2024-01-01T06:00:00")),
+                                      Some(time("2024-01-01T08:00:00")), lag, now),
+                   time("2024-01-01T08:00:00"));
+        assert_eq!(time("2024-01-01T10:00:00.1234567Z").timestamp_subsec_millis(), 123);
+    }
+
     #[test]
     fn test_state_save_load() {
         let dir = tempdir().unwrap();
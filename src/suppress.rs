@@ -0,0 +1,168 @@
+// Suppression of the logs of known noisy users and addresses, e.g. backup service accounts and
+// vulnerability scanners, configured as `suppress` rules. A rule matches a log when every
+// condition it has holds: its user (UserId, or userPrincipalName of Graph logs) matches one of
+// `users`, and its client IP is in one of `ips`. Matching logs are dropped before they are
+// written or sent anywhere, and counted per rule for the log and run summary.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use regex::Regex;
+use serde_json::{Map, Value};
+use crate::config::SuppressSubConfig;
+use crate::log_filter::glob_to_regex;
+
+const USER_FIELDS: [&str; 2] = ["UserId", "userPrincipalName"];
+const IP_FIELDS: [&str; 4] = ["ClientIP", "ClientIPAddress", "ActorIpAddress", "ipAddress"];
+
+#[derive(Debug)]
+struct Rule {
+    name: String,
+    content_types: Vec<String>,
+    users: Vec<Regex>,
+    networks: Vec<(IpAddr, u8)>,
+}
+
+/// The suppress rules of a run, with the logs each suppressed.
+#[derive(Debug)]
+pub struct Suppress {
+    rules: Vec<Rule>,
+    counts: Vec<AtomicUsize>,
+}
+
+impl Suppress {
+
+    pub fn new(config: &[SuppressSubConfig]) -> Result<Self, String> {
+        let mut rules = Vec::new();
+        for rule in config {
+            if rule.users.is_empty() && rule.ips.is_empty() {
+                return Err(format!("{}: set users, ips or both", rule.name))
+            }
+            let users = rule.users.iter()
+                .map(|user| Regex::new(&format!("(?i){}", glob_to_regex(user))))
+                .collect::<Result<_, _>>()
+                .map_err(|e| format!("{}: invalid user pattern: {}", rule.name, e))?;
+            let networks = rule.ips.iter()
+                .map(|network| parse_network(network).map_err(|e| format!("{}: {}", rule.name, e)))
+                .collect::<Result<_, _>>()?;
+            rules.push(Rule { name: rule.name.clone(), content_types: rule.content_types.clone(), users, networks });
+        }
+        let counts = rules.iter().map(|_| AtomicUsize::new(0)).collect();
+        Ok(Suppress { rules, counts })
+    }
+
+    /// Whether a rule matches the log, counting it for the first rule that does.
+    pub fn suppresses(&self, content_type: &str, log: &Map<String, Value>) -> bool {
+        let user = USER_FIELDS.iter().find_map(|field| log.get(*field).and_then(|value| value.as_str()));
+        let ip = IP_FIELDS.iter().find_map(|field| log.get(*field).and_then(|value| value.as_str()).and_then(parse_ip));
+        for (rule, count) in self.rules.iter().zip(&self.counts) {
+            if !rule.content_types.is_empty() && !rule.content_types.iter().any(|c| c == content_type) {
+                continue
+            }
+            let user_matches = rule.users.is_empty() || user.is_some_and(|user| rule.users.iter().any(|r| r.is_match(user)));
+            let ip_matches = rule.networks.is_empty() || ip.is_some_and(|ip| rule.networks.iter().any(|n| contains(*n, ip)));
+            if user_matches && ip_matches {
+                count.fetch_add(1, Ordering::Relaxed);
+                return true
+            }
+        }
+        false
+    }
+
+    /// Logs suppressed so far by rule name, only rules that suppressed any.
+    pub fn counts(&self) -> BTreeMap<String, usize> {
+        self.rules.iter().zip(&self.counts)
+            .map(|(rule, count)| (rule.name.clone(), count.load(Ordering::Relaxed)))
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+}
+
+/// An address or a CIDR range such as 10.20.0.0/16.
+fn parse_network(network: &str) -> Result<(IpAddr, u8), String> {
+    let invalid = || format!("'{}' is not an IP address or CIDR range", network);
+    let (address, prefix) = network.trim().split_once('/').unwrap_or((network.trim(), ""));
+    let address: IpAddr = address.parse().map_err(|_| invalid())?;
+    let max = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        "" => max,
+        prefix => prefix.parse().ok().filter(|prefix| *prefix <= max).ok_or_else(invalid)?,
+    };
+    Ok((address, prefix))
+}
+
+/// Client IPs come as 1.2.3.4, 1.2.3.4:port, an IPv6 address or [IPv6]:port.
+fn parse_ip(ip: &str) -> Option<IpAddr> {
+    let ip = ip.trim();
+    if let Some(bracketed) = ip.strip_prefix('[') {
+        return bracketed.split(']').next()?.parse().ok()
+    }
+    ip.parse().ok().or_else(|| ip.rsplit_once(':').and_then(|(address, _)| address.parse::<std::net::Ipv4Addr>().ok()).map(IpAddr::V4))
+}
+
+fn contains((network, prefix): (IpAddr, u8), ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V6(v6) if network.is_ipv4() => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    };
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(network) & mask == u32::from(ip) & mask
+        },
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(network) & mask == u128::from(ip) & mask
+        },
+        _ => false,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn log(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn test_suppress() {
+        let config: Vec<SuppressSubConfig> = serde_yaml::from_str("
+- name: backup
+  users: ['svc-backup*@contoso.com']
+- name: scanners
+  ips: ['10.20.0.0/16', '2001:db8::/32', '192.0.2.7']
+- name: exchange-sync
+  users: [sync@contoso.com]
+  ips: ['198.51.100.0/24']
+  content_types: [Audit.Exchange]
+").unwrap();
+        let suppress = Suppress::new(&config).unwrap();
+        assert!(suppress.suppresses("Audit.SharePoint", &log(json!({"UserId": "SVC-Backup01@contoso.com"}))));
+        assert!(suppress.suppresses("Audit.SharePoint", &log(json!({"ClientIP": "10.20.3.4"}))));
+        assert!(suppress.suppresses("Audit.Exchange", &log(json!({"ClientIPAddress": "10.20.3.4:51234"}))));
+        assert!(suppress.suppresses("Audit.Exchange", &log(json!({"ClientIP": "[2001:db8::1]:443"}))));
+        assert!(suppress.suppresses("Audit.General", &log(json!({"ClientIP": "192.0.2.7"}))));
+        assert!(suppress.suppresses("Graph.SignIns", &log(json!({"ipAddress": "::ffff:10.20.0.1"}))));
+        assert!(!suppress.suppresses("Audit.General", &log(json!({"ClientIP": "192.0.2.8", "UserId": "alice@contoso.com"}))));
+        // All conditions of a rule must hold
+        assert!(suppress.suppresses("Audit.Exchange", &log(json!({"UserId": "sync@contoso.com", "ClientIP": "198.51.100.9"}))));
+        assert!(!suppress.suppresses("Audit.Exchange", &log(json!({"UserId": "sync@contoso.com", "ClientIP": "203.0.113.1"}))));
+        assert!(!suppress.suppresses("Audit.SharePoint", &log(json!({"UserId": "sync@contoso.com", "ClientIP": "198.51.100.9"}))));
+        assert!(!suppress.suppresses("Audit.Exchange", &log(json!({"Operation": "Send"}))));
+
+        let counts = suppress.counts();
+        assert_eq!(counts.get("backup"), Some(&1));
+        assert_eq!(counts.get("scanners"), Some(&5));
+        assert_eq!(counts.get("exchange-sync"), Some(&1));
+
+        for (rule, problem) in [("{name: a}", "a: set users, ips or both"),
+                                ("{name: b, ips: ['10.0.0.0/33']}", "b: '10.0.0.0/33' is not an IP address or CIDR range")] {
+            let config: Vec<SuppressSubConfig> = serde_yaml::from_str(&format!("[{}]", rule)).unwrap();
+            assert_eq!(Suppress::new(&config).unwrap_err(), problem);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+// systemd integration, for running the daemon as a `Type=notify` service:
+// - READY=1 once the config is loaded and collection starts, STOPPING=1 on SIGTERM.
+// - With `WatchdogSec` set, WATCHDOG=1 at half the watchdog interval for as long as the daemon
+//   loop is expected to make progress. The loop sets a deadline before every run and every sleep;
+//   a loop stuck past it stops pinging and systemd restarts the service.
+// - Secret and certificate paths of the form `credential:<name>` are read from the directory of
+//   systemd credentials (`LoadCredential=`/`SetCredential=`), $CREDENTIALS_DIRECTORY.
+// Without NOTIFY_SOCKET, e.g. when not started by systemd, notifying does nothing.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{debug, info};
+
+pub const CREDENTIAL_PREFIX: &str = "credential:";
+
+/// Send a state like READY=1 to systemd, if it is listening.
+pub fn notify(state: &str) {
+    let Ok(socket_path) = std::env::var("NOTIFY_SOCKET") else {
+        return
+    };
+    if let Err(e) = send(&socket_path, state) {
+        debug!("Could not notify systemd of {}: {}", state, e);
+    }
+}
+
+/// Tell systemd the daemon is stopping when it is sent SIGTERM, then exit. Only when started
+/// by systemd, otherwise SIGTERM keeps terminating the process right away.
+pub fn stop_on_sigterm() {
+    #[cfg(unix)]
+    if std::env::var_os("NOTIFY_SOCKET").is_some() {
+        use tokio::signal::unix::{signal, SignalKind};
+        let Ok(mut sigterm) = signal(SignalKind::terminate()) else {
+            return
+        };
+        tokio::spawn(async move {
+            sigterm.recv().await;
+            info!("Received SIGTERM, stopping");
+            notify("STOPPING=1");
+            std::process::exit(0);
+        });
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send(socket_path: &str, state: &str) -> std::io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let socket = UnixDatagram::unbound()?;
+    let address = match socket_path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket_path)?,
+    };
+    socket.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send(_socket_path: &str, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Path of a secret or certificate file, resolving `credential:<name>` to the systemd credential.
+pub fn credential_path(path: &str) -> Result<String, String> {
+    let Some(name) = path.strip_prefix(CREDENTIAL_PREFIX) else {
+        return Ok(path.to_string())
+    };
+    match std::env::var("CREDENTIALS_DIRECTORY") {
+        Ok(directory) => Ok(std::path::Path::new(&directory).join(name).to_string_lossy().to_string()),
+        Err(_) => Err(format!("Cannot read {}: CREDENTIALS_DIRECTORY is not set, load the credential \
+                               with LoadCredential= in the systemd unit", path)),
+    }
+}
+
+/// Pings the systemd watchdog while the daemon loop is within its deadline.
+#[derive(Clone)]
+pub struct Watchdog {
+    /// Unix seconds, 0 for no deadline
+    deadline: Arc<AtomicU64>,
+}
+
+impl Watchdog {
+
+    /// Start pinging if systemd enabled the watchdog for this process.
+    pub fn start() -> Option<Self> {
+        let interval = watchdog_interval()?;
+        info!("Pinging the systemd watchdog every {}s", interval.as_secs_f64());
+        let watchdog = Watchdog { deadline: Arc::new(AtomicU64::new(0)) };
+        let deadline = watchdog.deadline.clone();
+        tokio::spawn(async move {
+            loop {
+                let deadline = deadline.load(Ordering::Relaxed);
+                if deadline == 0 || unix_now() <= deadline {
+                    notify("WATCHDOG=1");
+                }
+                tokio::time::sleep(interval).await;
+            }
+        });
+        Some(watchdog)
+    }
+
+    /// Keep pinging for at most `duration` from now, forever with None.
+    pub fn expect_progress_within(&self, duration: Option<Duration>) {
+        let deadline = duration.map(|duration| unix_now() + duration.as_secs()).unwrap_or(0);
+        self.deadline.store(deadline, Ordering::Relaxed);
+    }
+}
+
+/// Half of WatchdogSec, if the watchdog is enabled for this process.
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None
+        }
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok().filter(|usec| *usec > 0)?;
+    Some(Duration::from_micros(usec / 2))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_credential_path() {
+        assert_eq!(credential_path("/etc/secret"), Ok("/etc/secret".to_string()));
+        std::env::set_var("CREDENTIALS_DIRECTORY", "/run/credentials/office365-collector.service");
+        assert_eq!(credential_path("credential:client_secret"),
+                   Ok("/run/credentials/office365-collector.service/client_secret".to_string()));
+        std::env::remove_var("CREDENTIALS_DIRECTORY");
+        assert!(credential_path("credential:client_secret").unwrap_err().contains("LoadCredential"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_notify() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("notify");
+        let socket = std::os::unix::net::UnixDatagram::bind(&socket_path).unwrap();
+        send(socket_path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buffer = [0; 16];
+        let length = socket.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"READY=1");
+    }
+}
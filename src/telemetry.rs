@@ -0,0 +1,291 @@
+// OpenTelemetry traces of the collection pipeline, to see where the time of a run goes. The
+// pipeline is instrumented with `tracing` spans: a root span per tenant run, with token requests,
+// content listing pages, content downloads and output sends below it. With a `tracing` section
+// the spans are recorded by a layer and exported over OTLP/HTTP (JSON) to <endpoint>/v1/traces
+// every few seconds and at the end of every run. Without it no subscriber is set and the spans
+// cost next to nothing.
+// Only spans of this crate are recorded, so the requests of the exporter itself are not traced.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex as StdMutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Result};
+use log::warn;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::{json, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::Interest;
+use tracing::{Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use crate::config::{TlsSubConfig, TracingSubConfig};
+use crate::tls;
+
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Finished spans kept while the endpoint is unreachable, newer ones are dropped
+const MAX_QUEUED_SPANS: usize = 10_000;
+pub const DEFAULT_SERVICE_NAME: &str = "office365-log-collector";
+
+static EXPORTER: OnceLock<Exporter> = OnceLock::new();
+
+/// Record spans and export them to the configured endpoint until the process exits.
+pub fn init(config: &TracingSubConfig, tls_config: Option<&TlsSubConfig>) -> Result<()> {
+    let queue = Arc::new(StdMutex::new(Vec::new()));
+    let exporter = Exporter {
+        client: tls::http_client(tls_config)?,
+        url: format!("{}/v1/traces", config.endpoint.trim_end_matches('/')),
+        headers: config.headers.clone(),
+        service_name: config.service_name.clone().unwrap_or(DEFAULT_SERVICE_NAME.to_string()),
+        queue: queue.clone(),
+    };
+    EXPORTER.set(exporter).map_err(|_| anyhow!("Tracing is already initialized"))?;
+    let subscriber = tracing_subscriber::registry().with(SpanRecorder { queue });
+    tracing::subscriber::set_global_default(subscriber)?;
+    tokio::spawn(async {
+        loop {
+            tokio::time::sleep(EXPORT_INTERVAL).await;
+            flush().await;
+        }
+    });
+    Ok(())
+}
+
+/// Export the spans finished since the last export, if tracing is enabled.
+pub async fn flush() {
+    if let Some(exporter) = EXPORTER.get() {
+        exporter.export().await;
+    }
+}
+
+struct Exporter {
+    client: reqwest::Client,
+    url: String,
+    headers: HashMap<String, String>,
+    service_name: String,
+    queue: Arc<StdMutex<Vec<FinishedSpan>>>,
+}
+
+impl Exporter {
+
+    /// Send the queued spans. Spans the endpoint did not accept are dropped, so a broken
+    /// endpoint cannot make them pile up.
+    async fn export(&self) {
+        let spans = std::mem::take(&mut *self.queue.lock().unwrap());
+        if spans.is_empty() {
+            return
+        }
+        let mut request = self.client.post(&self.url).timeout(EXPORT_TIMEOUT)
+            .json(&export_request(&self.service_name, &spans));
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        match request.send().await.and_then(|response| response.error_for_status()) {
+            Ok(_) => (),
+            Err(e) => warn!("Could not export {} spans to {}: {}", spans.len(), self.url, e),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum AttributeValue {
+    String(String),
+    Int(i64),
+    Double(f64),
+    Bool(bool),
+}
+
+#[derive(Clone, Debug)]
+struct FinishedSpan {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    name: &'static str,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, AttributeValue)>,
+    /// Set by recording an `error` field
+    error: Option<String>,
+}
+
+/// Layer keeping the data of open spans in their extensions, and queueing them for export
+/// once closed.
+struct SpanRecorder {
+    queue: Arc<StdMutex<Vec<FinishedSpan>>>,
+}
+
+impl<S> Layer<S> for SpanRecorder where S: Subscriber + for<'a> LookupSpan<'a> {
+
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        match metadata.target().starts_with(env!("CARGO_CRATE_NAME")) {
+            true => Interest::always(),
+            false => Interest::never(),
+        }
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let parent = span.parent().and_then(|parent| parent.extensions().get::<FinishedSpan>()
+            .map(|data| (data.trace_id, data.span_id)));
+        let mut data = FinishedSpan {
+            trace_id: parent.map(|(trace_id, _)| trace_id).unwrap_or_else(random_id),
+            span_id: random_id(),
+            parent_span_id: parent.map(|(_, span_id)| span_id),
+            name: attrs.metadata().name(),
+            start: SystemTime::now(),
+            end: SystemTime::now(),
+            attributes: Vec::new(),
+            error: None,
+        };
+        attrs.record(&mut data);
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<FinishedSpan>() {
+                values.record(data);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else { return };
+        let Some(mut data) = span.extensions_mut().remove::<FinishedSpan>() else { return };
+        data.end = SystemTime::now();
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() < MAX_QUEUED_SPANS {
+            queue.push(data);
+        }
+    }
+}
+
+impl Visit for FinishedSpan {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.attributes.push((field.name(), AttributeValue::Double(value)));
+    }
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.attributes.push((field.name(), AttributeValue::Int(value)));
+    }
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.attributes.push((field.name(), AttributeValue::Int(value as i64)));
+    }
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.attributes.push((field.name(), AttributeValue::Bool(value)));
+    }
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_string(field, value.to_string());
+    }
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.record_string(field, format!("{:?}", value));
+    }
+}
+
+impl FinishedSpan {
+    fn record_string(&mut self, field: &Field, value: String) {
+        match field.name() {
+            "error" => self.error = Some(value),
+            name => self.attributes.push((name, AttributeValue::String(value))),
+        }
+    }
+}
+
+fn random_id<const N: usize>() -> [u8; N] {
+    let mut id = [0; N];
+    let _ = SystemRandom::new().fill(&mut id);
+    // An all-zero ID is invalid in OTLP
+    if id.iter().all(|b| *b == 0) {
+        id[N - 1] = 1;
+    }
+    id
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+/// OTLP/HTTP JSON body of an export request.
+fn export_request(service_name: &str, spans: &[FinishedSpan]) -> Value {
+    let attribute = |key: &str, value: &AttributeValue| {
+        let value = match value {
+            AttributeValue::String(value) => json!({"stringValue": value}),
+            AttributeValue::Int(value) => json!({"intValue": value.to_string()}),
+            AttributeValue::Double(value) => json!({"doubleValue": value}),
+            AttributeValue::Bool(value) => json!({"boolValue": value}),
+        };
+        json!({"key": key, "value": value})
+    };
+    let spans: Vec<Value> = spans.iter().map(|span| {
+        let mut value = json!({
+            "traceId": hex(&span.trace_id),
+            "spanId": hex(&span.span_id),
+            "name": span.name,
+            "kind": 1,
+            "startTimeUnixNano": unix_nanos(span.start),
+            "endTimeUnixNano": unix_nanos(span.end),
+            "attributes": span.attributes.iter().map(|(key, value)| attribute(key, value)).collect::<Vec<_>>(),
+            "status": match &span.error {
+                Some(message) => json!({"code": 2, "message": message}),
+                None => json!({}),
+            },
+        });
+        if let Some(parent_span_id) = span.parent_span_id {
+            value["parentSpanId"] = json!(hex(&parent_span_id));
+        }
+        value
+    }).collect();
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    attribute("service.name", &AttributeValue::String(service_name.to_string())),
+                    attribute("service.version", &AttributeValue::String(env!("CARGO_PKG_VERSION").to_string())),
+                ],
+            },
+            "scopeSpans": [{
+                "scope": {"name": env!("CARGO_CRATE_NAME")},
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_span_recorder() {
+        let queue = Arc::new(StdMutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::registry().with(SpanRecorder { queue: queue.clone() });
+        tracing::subscriber::with_default(subscriber, || {
+            let run = tracing::info_span!("collect", tenant_id = "t1");
+            let _entered = run.enter();
+            let download = tracing::info_span!("download_content", content_id = "c1", logs = 3,
+                                               error = tracing::field::Empty);
+            download.record("error", "timed out");
+        });
+        let spans = queue.lock().unwrap().clone();
+        let (download, run) = (&spans[0], &spans[1]);
+        assert_eq!((run.name, download.name), ("collect", "download_content"));
+        assert_eq!(download.trace_id, run.trace_id);
+        assert_eq!((run.parent_span_id, download.parent_span_id), (None, Some(run.span_id)));
+        assert_eq!(download.attributes, vec![("content_id", AttributeValue::String("c1".to_string())),
+                                             ("logs", AttributeValue::Int(3))]);
+        assert_eq!(download.error.as_deref(), Some("timed out"));
+
+        let request = export_request("collector", &spans);
+        let exported = &request["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!(exported[0]["parentSpanId"], json!(hex(&run.span_id)));
+        assert_eq!(exported[0]["traceId"].as_str().unwrap().len(), 32);
+        assert_eq!(exported[0]["status"], json!({"code": 2, "message": "timed out"}));
+        assert_eq!(exported[1]["attributes"][0], json!({"key": "tenant_id", "value": {"stringValue": "t1"}}));
+        assert!(exported[1].get("parentSpanId").is_none());
+    }
+}
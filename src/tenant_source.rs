@@ -0,0 +1,165 @@
+// Tenants loaded from outside the config with `tenant_source`: a CSV file, or an HTTP endpoint of
+// a provisioning system returning JSON. The source is read again at the start of every daemon
+// iteration, so tenants are onboarded and offboarded without editing the config. Tenants in the
+// config itself are always collected and win over a source tenant with the same ID.
+
+use std::collections::HashSet;
+use std::time::Duration;
+use anyhow::{anyhow, Context, Result};
+use log::{error, info, warn};
+use serde_derive::Deserialize;
+use crate::config::{Config, TenantConfig, TenantSourceSubConfig};
+use crate::tls;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Tenants of the source as of the last successful sync.
+#[derive(Default)]
+pub struct TenantSource {
+    tenants: Vec<TenantConfig>,
+}
+
+impl TenantSource {
+
+    /// Read the source again and return the config with its tenants added. If the source cannot
+    /// be read, the tenants of the last sync are used.
+    pub async fn sync(&mut self, config: &Config) -> Config {
+        let Some(source) = &config.tenant_source else {
+            return config.clone()
+        };
+        match load(source, config).await {
+            Ok(tenants) => {
+                let previous: HashSet<&str> = self.tenants.iter().map(|t| t.tenant_id.as_str()).collect();
+                let current: HashSet<&str> = tenants.iter().map(|t| t.tenant_id.as_str()).collect();
+                for added in current.difference(&previous) {
+                    info!("Tenant {} added by tenant_source", added);
+                }
+                for removed in previous.difference(&current) {
+                    info!("Tenant {} removed by tenant_source", removed);
+                }
+                self.tenants = tenants;
+            },
+            Err(e) => error!("Could not sync tenants from tenant_source, using {} tenant(s) of the last sync: {:#}",
+                             self.tenants.len(), e),
+        }
+        self.apply(config)
+    }
+
+    fn apply(&self, config: &Config) -> Config {
+        let mut config = config.clone();
+        for tenant in &self.tenants {
+            if config.tenants.iter().any(|t| t.tenant_id.eq_ignore_ascii_case(&tenant.tenant_id)) {
+                warn!("Tenant {} of tenant_source is also in the config, using the config", tenant.tenant_id);
+            } else {
+                config.tenants.push(tenant.clone());
+            }
+        }
+        config.filter_tenants();
+        config
+    }
+}
+
+/// The valid tenants of a source, invalid ones are logged and skipped.
+async fn load(source: &TenantSourceSubConfig, config: &Config) -> Result<Vec<TenantConfig>> {
+    let tenants = match (&source.csv, &source.url) {
+        (Some(path), _) => {
+            let file = std::fs::File::open(path).with_context(|| format!("Could not open {}", path))?;
+            parse_csv(file).with_context(|| format!("Invalid tenant CSV {}", path))?
+        },
+        (None, Some(url)) => {
+            let mut request = tls::http_client(config.tls.as_ref())?.get(url).timeout(TIMEOUT);
+            for (name, value) in &source.headers {
+                request = request.header(name, value);
+            }
+            let body = request.send().await?.error_for_status()?.text().await?;
+            parse_json(&body).with_context(|| format!("Invalid tenant list from {}", url))?
+        },
+        (None, None) => return Err(anyhow!("tenant_source needs csv or url")),
+    };
+    Ok(tenants.into_iter()
+        .filter(|tenant| {
+            let problems = tenant.validate(&config.api_types);
+            for (field, problem) in &problems {
+                error!("Skipping tenant '{}' of tenant_source, {}: {}", tenant.tenant_id, field.unwrap_or("tenant"), problem);
+            }
+            problems.is_empty()
+        })
+        .collect())
+}
+
+/// Tenants of a CSV with a header row of TenantConfig fields. Empty cells are unset, lines
+/// starting with '#' are ignored.
+fn parse_csv(reader: impl std::io::Read) -> Result<Vec<TenantConfig>> {
+    csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .comment(Some(b'#'))
+        .from_reader(reader)
+        .deserialize()
+        .map(|tenant| tenant.map_err(|e| anyhow!(e)))
+        .collect()
+}
+
+/// Tenants of a JSON array, or of the "tenants" array of an object.
+fn parse_json(body: &str) -> Result<Vec<TenantConfig>> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum TenantList {
+        List(Vec<TenantConfig>),
+        Object { tenants: Vec<TenantConfig> },
+    }
+    match serde_json::from_str(body)? {
+        TenantList::List(tenants) | TenantList::Object { tenants } => Ok(tenants),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_csv() {
+        let csv = "\
+tenant_id, client_id, client_secret_path, certificate_path, api_type
+# onboarding in progress
+t1, c1, /etc/secrets/t1, ,
+t2, c2, , /etc/certs/t2.pem, gcc-high
+";
+        let tenants = parse_csv(csv.as_bytes()).unwrap();
+        assert_eq!(tenants.len(), 2);
+        assert_eq!(tenants[0].client_secret_path.as_deref(), Some("/etc/secrets/t1"));
+        assert_eq!((tenants[0].certificate_path.as_deref(), tenants[0].api_type.as_deref()), (None, None));
+        assert_eq!(tenants[1].api_type.as_deref(), Some("gcc-high"));
+        assert!(parse_csv("tenant_id,client_id,secret\nt1,c1,s\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let tenants = parse_json(r#"[{"tenant_id": "t1", "client_id": "c1", "client_secret": "s"}]"#).unwrap();
+        assert_eq!(tenants[0].tenant_id, "t1");
+        let tenants = parse_json(r#"{"tenants": [{"tenant_id": "t1", "client_id": "c1"}, {"tenant_id": "t2", "client_id": "c2"}]}"#).unwrap();
+        assert_eq!(tenants.len(), 2);
+        assert!(parse_json(r#"{"customers": []}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv = dir.path().join("tenants.csv");
+        std::fs::write(&csv, "tenant_id,client_id,client_secret\nt1,c1,s\nt2,c2,s\nt3,c3,\n").unwrap();
+        let config: Config = serde_yaml::from_str(&format!(
+            "{{output: {{}}, tenants: [{{tenant_id: T1, client_id: config, client_secret: s}}], tenant_source: {{csv: {}}}}}",
+            csv.display())).unwrap();
+
+        let mut source = TenantSource::default();
+        let synced = source.sync(&config).await;
+        // t1 is in the config, t3 has no credentials
+        assert_eq!(synced.tenants.iter().map(|t| t.client_id.as_str()).collect::<Vec<_>>(), vec!["config", "c2"]);
+
+        // The last sync is kept while the source cannot be read
+        std::fs::remove_file(&csv).unwrap();
+        assert_eq!(source.sync(&config).await.tenants.len(), 2);
+        std::fs::write(&csv, "tenant_id,client_id,client_secret\n").unwrap();
+        assert_eq!(source.sync(&config).await.tenants.len(), 1);
+    }
+}
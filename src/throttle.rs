@@ -0,0 +1,485 @@
+// Backoff for throttled (429) Office Management API requests. Every endpoint backs off on its
+// own: a throttled request waits for the Retry-After the API sent, or for an exponential backoff
+// with jitter, and later requests to the same endpoint wait until then as well. The first
+// successful request resets the backoff.
+//
+// On top of that, all requests of a tenant pass a token bucket rate limiter whose rate halves on
+// every throttled response and slowly grows back with successful ones, up to the configured
+// maximum. A tenant producing many requests thus slows itself down before the API throttles the
+// publisher identifier shared with the other tenants.
+//
+// collect.max_requests_per_minute adds one more such rate limiter that the requests of all tenants
+// to the Office Management API pass (Graph requests do not), as the API throttles per publisher
+// and tenants running concurrently could together exceed its limit.
+//
+// The requests in flight per endpoint are limited the same way: maxThreads is the ceiling, every
+// throttled response halves the limit and every `limit` successful responses raise it by one.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex as StdMutex, OnceLock};
+use std::time::{Duration, Instant};
+use log::warn;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::sync::{Mutex, Notify};
+use tokio::time::sleep_until;
+
+/// Listing available content blobs (subscriptions/content)
+pub const CONTENT_LISTING: &str = "content listing";
+/// Downloading a content blob
+pub const CONTENT_DOWNLOAD: &str = "content download";
+/// Listing logs from Microsoft Graph
+pub const GRAPH: &str = "graph";
+
+/// Attempts of a single request that may be spent waiting for throttling to end, before it is
+/// handled as a failed request.
+pub const MAX_THROTTLED_ATTEMPTS: u32 = 10;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Default maximum requests per second of a tenant
+pub const DEFAULT_MAX_REQUESTS_PER_SECOND: f64 = 30.0;
+/// The rate a tenant is never slowed down below
+const MIN_REQUESTS_PER_SECOND: f64 = 0.5;
+/// Rate increase (requests per second) per successful request
+const RATE_INCREASE: f64 = 0.1;
+
+#[derive(Default)]
+struct EndpointBackoff {
+    failures: u32,
+    until: Option<Instant>,
+}
+
+#[derive(Clone)]
+pub struct Throttle {
+    endpoints: Arc<Mutex<HashMap<&'static str, EndpointBackoff>>>,
+    rate_limiter: Arc<RateLimiter>,
+    /// Rate limiter shared by all tenants, see RateLimiter::global
+    global: Option<Arc<RateLimiter>>,
+    concurrency: Arc<StdMutex<HashMap<&'static str, Arc<ConcurrencyLimiter>>>>,
+    max_concurrency: usize,
+}
+
+impl Throttle {
+
+    pub fn new(rate_limiter: Arc<RateLimiter>, max_concurrency: usize) -> Self {
+        Throttle {
+            endpoints: Arc::new(Mutex::new(HashMap::new())),
+            rate_limiter,
+            global: None,
+            concurrency: Arc::new(StdMutex::new(HashMap::new())),
+            max_concurrency,
+        }
+    }
+
+    /// Also limit Office Management API requests by a rate limiter shared with other tenants.
+    pub fn with_global_limit(mut self, global: Arc<RateLimiter>) -> Self {
+        self.global = Some(global);
+        self
+    }
+
+    fn global(&self, endpoint: &'static str) -> Option<&RateLimiter> {
+        self.global.as_deref().filter(|_| endpoint != GRAPH)
+    }
+
+    fn concurrency(&self, endpoint: &'static str) -> Arc<ConcurrencyLimiter> {
+        self.concurrency.lock().unwrap().entry(endpoint)
+            .or_insert_with(|| Arc::new(ConcurrencyLimiter::new(endpoint, self.max_concurrency)))
+            .clone()
+    }
+
+    /// Wait until fewer requests to the endpoint are in flight than its current limit. The
+    /// request counts as in flight until the permit is dropped.
+    pub async fn start_request(&self, endpoint: &'static str) -> ConcurrencyPermit {
+        self.concurrency(endpoint).acquire().await
+    }
+
+    /// Wait until the endpoint is no longer backing off and the tenant may send a request.
+    pub async fn wait(&self, endpoint: &'static str) {
+        let until = self.endpoints.lock().await.get(endpoint).and_then(|e| e.until);
+        if let Some(until) = until {
+            sleep_until(until.into()).await;
+        }
+        self.rate_limiter.acquire().await;
+        if let Some(global) = self.global(endpoint) {
+            global.acquire().await;
+        }
+    }
+
+    /// Register a throttled response and return how long the endpoint backs off.
+    pub async fn throttled(&self, endpoint: &'static str, retry_after: Option<Duration>) -> Duration {
+        self.rate_limiter.throttled();
+        if let Some(global) = self.global(endpoint) {
+            global.throttled();
+        }
+        self.concurrency(endpoint).throttled();
+        let mut endpoints = self.endpoints.lock().await;
+        let backoff = endpoints.entry(endpoint).or_default();
+        backoff.failures += 1;
+        let delay = retry_after.unwrap_or_else(|| jitter(exponential_backoff(backoff.failures))).min(MAX_BACKOFF);
+        let until = Instant::now() + delay;
+        backoff.until = Some(backoff.until.map_or(until, |current| current.max(until)));
+        delay
+    }
+
+    pub async fn succeeded(&self, endpoint: &'static str) {
+        self.rate_limiter.succeeded();
+        if let Some(global) = self.global(endpoint) {
+            global.succeeded();
+        }
+        self.concurrency(endpoint).succeeded();
+        if let Some(backoff) = self.endpoints.lock().await.get_mut(endpoint) {
+            backoff.failures = 0;
+        }
+    }
+}
+
+/// Adaptive token bucket limiting the requests of one tenant. Holds at most one second worth of
+/// requests, so bursts stay small.
+pub struct RateLimiter {
+    /// "the tenant" or "all tenants", for the log
+    scope: &'static str,
+    max_rate: f64,
+    min_rate: f64,
+    bucket: StdMutex<Bucket>,
+}
+
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    /// Take a token, or return how long to wait for one.
+    fn take(&mut self, now: Instant) -> Option<Duration> {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate.max(1.0));
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(max_rate: f64) -> Self {
+        let max_rate = max_rate.max(MIN_REQUESTS_PER_SECOND);
+        RateLimiter::with_scope("the tenant", max_rate, MIN_REQUESTS_PER_SECOND)
+    }
+
+    fn with_scope(scope: &'static str, max_rate: f64, min_rate: f64) -> Self {
+        RateLimiter {
+            scope,
+            max_rate,
+            min_rate,
+            bucket: StdMutex::new(Bucket { rate: max_rate, tokens: max_rate.max(1.0), last_refill: Instant::now() }),
+        }
+    }
+
+    /// The rate limiter all tenants share. Replaced when the configured rate changes, e.g. after
+    /// the configuration was reloaded; it may go below the rate a tenant is slowed down to at
+    /// most.
+    pub fn global(max_rate: f64) -> Arc<RateLimiter> {
+        static GLOBAL: OnceLock<StdMutex<Option<Arc<RateLimiter>>>> = OnceLock::new();
+
+        let mut global = GLOBAL.get_or_init(|| StdMutex::new(None)).lock().unwrap();
+        match global.as_ref().filter(|limiter| limiter.max_rate == max_rate) {
+            Some(limiter) => limiter.clone(),
+            None => {
+                let limiter = Arc::new(RateLimiter::with_scope(
+                    "all tenants", max_rate, MIN_REQUESTS_PER_SECOND.min(max_rate)));
+                *global = Some(limiter.clone());
+                limiter
+            },
+        }
+    }
+
+    /// The rate limiter of a tenant. It lives for the whole process, so in daemon mode the rate
+    /// learned in one run carries over to the next. Replaced when the configured rate changes, as
+    /// the global one.
+    pub fn for_tenant(tenant_id: &str, max_rate: f64) -> Arc<RateLimiter> {
+        static LIMITERS: OnceLock<StdMutex<HashMap<String, Arc<RateLimiter>>>> = OnceLock::new();
+
+        let mut limiters = LIMITERS.get_or_init(|| StdMutex::new(HashMap::new())).lock().unwrap();
+        let limiter = limiters.entry(tenant_id.to_string())
+            .or_insert_with(|| Arc::new(RateLimiter::new(max_rate)));
+        if limiter.max_rate != max_rate {
+            *limiter = Arc::new(RateLimiter::new(max_rate));
+        }
+        limiter.clone()
+    }
+
+    /// Wait until a request may be sent.
+    pub async fn acquire(&self) {
+        loop {
+            let wait = self.bucket.lock().unwrap().take(Instant::now());
+            match wait {
+                Some(wait) => tokio::time::sleep(wait).await,
+                None => return,
+            }
+        }
+    }
+
+    fn throttled(&self) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.rate = (bucket.rate / 2.0).max(self.min_rate);
+        bucket.tokens = bucket.tokens.min(bucket.rate.max(1.0));
+        warn!("Being rate limited, limiting {} to {:.1} requests per second", self.scope, bucket.rate);
+    }
+
+    fn succeeded(&self) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.rate = (bucket.rate + RATE_INCREASE).min(self.max_rate);
+    }
+
+    #[cfg(test)]
+    fn rate(&self) -> f64 {
+        self.bucket.lock().unwrap().rate
+    }
+}
+
+/// Additive increase, multiplicative decrease limit of the requests in flight to an endpoint.
+pub struct ConcurrencyLimiter {
+    endpoint: &'static str,
+    max: usize,
+    state: StdMutex<Concurrency>,
+    released: Notify,
+}
+
+struct Concurrency {
+    limit: usize,
+    in_flight: usize,
+    /// Successful responses since the limit last changed
+    successes: usize,
+}
+
+/// A request in flight, until dropped.
+pub struct ConcurrencyPermit {
+    limiter: Arc<ConcurrencyLimiter>,
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().in_flight -= 1;
+        self.limiter.released.notify_waiters();
+    }
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(endpoint: &'static str, max: usize) -> Self {
+        let max = max.max(1);
+        ConcurrencyLimiter {
+            endpoint,
+            max,
+            state: StdMutex::new(Concurrency { limit: max, in_flight: 0, successes: 0 }),
+            released: Notify::new(),
+        }
+    }
+
+    pub async fn acquire(self: Arc<Self>) -> ConcurrencyPermit {
+        loop {
+            // Created before checking, so a permit released in between still wakes it
+            let released = self.released.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.in_flight < state.limit {
+                    state.in_flight += 1;
+                    break
+                }
+            }
+            released.await;
+        }
+        ConcurrencyPermit { limiter: self }
+    }
+
+    fn throttled(&self) {
+        let mut state = self.state.lock().unwrap();
+        let limit = (state.limit / 2).max(1);
+        if limit < state.limit {
+            warn!("Being rate limited, limiting {} to {} requests at a time", self.endpoint, limit);
+        }
+        state.limit = limit;
+        state.successes = 0;
+    }
+
+    fn succeeded(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.successes += 1;
+        if state.successes >= state.limit && state.limit < self.max {
+            state.limit += 1;
+            state.successes = 0;
+            drop(state);
+            self.released.notify_waiters();
+        }
+    }
+
+    #[cfg(test)]
+    fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+}
+
+/// Retry-After header, in seconds or as an HTTP date.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds))
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some((date.with_timezone(&chrono::Utc) - chrono::Utc::now()).to_std().unwrap_or(Duration::ZERO))
+}
+
+fn exponential_backoff(failures: u32) -> Duration {
+    let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+    INITIAL_BACKOFF.saturating_mul(factor).min(MAX_BACKOFF)
+}
+
+/// Random delay between half and all of `delay`, so throttled requests do not all retry at once.
+fn jitter(delay: Duration) -> Duration {
+    delay.mul_f64(0.5 + random_fraction().unwrap_or(1.0) / 2.0)
+}
+
+/// Random number between 0 and 1, None if the system has no randomness to offer.
+pub fn random_fraction() -> Option<f64> {
+    let mut random = [0u8; 4];
+    SystemRandom::new().fill(&mut random).ok()?;
+    Some(u32::from_le_bytes(random) as f64 / u32::MAX as f64)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("30"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(30)));
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("soon"));
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(exponential_backoff(1), INITIAL_BACKOFF);
+        assert_eq!(exponential_backoff(3), INITIAL_BACKOFF * 4);
+        assert_eq!(exponential_backoff(30), MAX_BACKOFF);
+        for _ in 0..10 {
+            let delay = jitter(Duration::from_secs(10));
+            assert!(delay >= Duration::from_secs(5) && delay <= Duration::from_secs(10));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_throttle_per_endpoint() {
+        let throttle = Throttle::new(Arc::new(RateLimiter::new(1000.0)), 10);
+        assert_eq!(throttle.throttled(CONTENT_LISTING, Some(Duration::from_secs(60))).await,
+                   Duration::from_secs(60));
+        let delay = throttle.throttled(CONTENT_LISTING, None).await;
+        assert!(delay >= INITIAL_BACKOFF && delay <= INITIAL_BACKOFF * 2);
+
+        // The other endpoint does not wait
+        tokio::time::timeout(Duration::from_millis(100), throttle.wait(CONTENT_DOWNLOAD)).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(100), throttle.wait(CONTENT_LISTING)).await.is_err());
+
+        throttle.succeeded(CONTENT_LISTING).await;
+        assert_eq!(throttle.endpoints.lock().await[CONTENT_LISTING].failures, 0);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(4.0);
+        let now = Instant::now();
+        let mut bucket = limiter.bucket.lock().unwrap();
+        for _ in 0..4 {
+            assert_eq!(bucket.take(now), None);
+        }
+        assert_eq!(bucket.take(now), Some(Duration::from_millis(250)));
+        assert_eq!(bucket.take(now + Duration::from_millis(250)), None);
+        drop(bucket);
+
+        limiter.throttled();
+        limiter.throttled();
+        assert_eq!(limiter.rate(), 1.0);
+        for _ in 0..10 {
+            limiter.throttled();
+        }
+        assert_eq!(limiter.rate(), MIN_REQUESTS_PER_SECOND);
+        for _ in 0..100 {
+            limiter.succeeded();
+        }
+        assert_eq!(limiter.rate(), 4.0);
+
+        let tenant = RateLimiter::for_tenant("a", 4.0);
+        tenant.throttled();
+        assert!(Arc::ptr_eq(&tenant, &RateLimiter::for_tenant("a", 4.0)));
+        assert!(!Arc::ptr_eq(&tenant, &RateLimiter::for_tenant("b", 4.0)));
+        // A reloaded config with another rate gets a new limiter
+        let reloaded = RateLimiter::for_tenant("a", 8.0);
+        assert!(!Arc::ptr_eq(&tenant, &reloaded));
+        assert_eq!(reloaded.rate(), 8.0);
+        assert!(Arc::ptr_eq(&reloaded, &RateLimiter::for_tenant("a", 8.0)));
+    }
+
+    #[tokio::test]
+    async fn test_global_rate_limiter() {
+        let global = RateLimiter::global(0.25);
+        assert!(Arc::ptr_eq(&global, &RateLimiter::global(0.25)));
+        global.throttled();
+        assert_eq!(global.rate(), 0.25);
+
+        let throttle = Throttle::new(Arc::new(RateLimiter::new(1000.0)), 10)
+            .with_global_limit(Arc::new(RateLimiter::with_scope("all tenants", 2.0, MIN_REQUESTS_PER_SECOND)));
+        throttle.wait(CONTENT_LISTING).await;
+        throttle.wait(CONTENT_DOWNLOAD).await;
+        // Both tokens of the shared limit are taken, Graph requests do not need one
+        assert!(tokio::time::timeout(Duration::from_millis(100), throttle.wait(CONTENT_LISTING)).await.is_err());
+        tokio::time::timeout(Duration::from_millis(100), throttle.wait(GRAPH)).await.unwrap();
+        throttle.throttled(GRAPH, None).await;
+        assert_eq!(throttle.global.as_ref().unwrap().rate(), 2.0);
+        throttle.throttled(CONTENT_LISTING, None).await;
+        assert_eq!(throttle.global.as_ref().unwrap().rate(), 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limiter() {
+        let limiter = Arc::new(ConcurrencyLimiter::new(CONTENT_DOWNLOAD, 4));
+        let mut permits = Vec::new();
+        for _ in 0..4 {
+            permits.push(limiter.clone().acquire().await);
+        }
+        let waiting = tokio::spawn(limiter.clone().acquire());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+        permits.pop();
+        permits.push(tokio::time::timeout(Duration::from_millis(100), waiting).await.unwrap().unwrap());
+
+        limiter.throttled();
+        assert_eq!(limiter.limit(), 2);
+        permits.truncate(2);
+        assert!(tokio::time::timeout(Duration::from_millis(20), limiter.clone().acquire()).await.is_err());
+        for _ in 0..3 {
+            limiter.throttled();
+        }
+        assert_eq!(limiter.limit(), 1);
+        // Back to the ceiling after 1 + 2 + 3 successful responses
+        for _ in 0..6 {
+            limiter.succeeded();
+        }
+        assert_eq!(limiter.limit(), 4);
+        limiter.succeeded();
+        assert_eq!(limiter.limit(), 4);
+        permits.clear();
+        assert_eq!(limiter.state.lock().unwrap().in_flight, 0);
+    }
+}
@@ -0,0 +1,207 @@
+// TLS client settings shared by the Office Management API client and all TLS capable outputs,
+// so a custom root CA (e.g. of a TLS intercepting proxy), skipping verification and a minimum
+// TLS version are configured once in the top level `tls` section.
+// The webhook listener's server configuration is built here as well.
+//
+// The Office Management API client asks for gzip compressed responses unless collect.gzip is
+// false; content blobs are JSON and compress to a fraction of their size.
+
+use std::io::BufReader;
+use std::sync::Arc;
+use anyhow::{anyhow, Result};
+use log::warn;
+use tokio_rustls::rustls::{self, ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use crate::config::TlsSubConfig;
+
+/// TLS client configuration trusting the webpki roots, or only the CA bundle in ca_file.
+pub fn client_config(tls_config: &TlsSubConfig) -> Result<ClientConfig> {
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let versions: &[&rustls::SupportedProtocolVersion] = match tls_config.min_version.as_deref() {
+        None | Some("1.2") => rustls::ALL_VERSIONS,
+        Some("1.3") => &[&rustls::version::TLS13],
+        Some(other) => return Err(anyhow!("Invalid TLS min_version '{}', must be '1.2' or '1.3'", other)),
+    };
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(versions)?;
+
+    if tls_config.insecure_skip_verify.unwrap_or(false) {
+        warn!("TLS certificate verification is disabled");
+        return Ok(builder.dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerifier(provider)))
+            .with_no_client_auth());
+    }
+
+    let mut roots = RootCertStore::empty();
+    if let Some(ca_file) = &tls_config.ca_file {
+        let file = std::fs::File::open(ca_file)
+            .map_err(|e| anyhow!("Could not open CA file {}: {}", ca_file, e))?;
+        for cert in rustls_pemfile::certs(&mut BufReader::new(file)) {
+            roots.add(cert?)?;
+        }
+        if roots.is_empty() {
+            return Err(anyhow!("No certificates found in CA file {}", ca_file));
+        }
+    } else {
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    }
+    Ok(builder.with_root_certificates(roots).with_no_client_auth())
+}
+
+/// HTTP client for HTTP outputs and other requests, using the global TLS settings.
+pub fn http_client(tls_config: Option<&TlsSubConfig>) -> Result<reqwest::Client> {
+    Ok(client_builder(tls_config)?.gzip(false).build()?)
+}
+
+/// HTTP client for the Office Management API, using the global TLS settings. With `gzip` it
+/// asks for compressed responses and decompresses them transparently.
+pub fn api_client(tls_config: Option<&TlsSubConfig>, gzip: bool) -> Result<reqwest::Client> {
+    Ok(client_builder(tls_config)?.gzip(gzip).build()?)
+}
+
+fn client_builder(tls_config: Option<&TlsSubConfig>) -> Result<reqwest::ClientBuilder> {
+    let builder = reqwest::Client::builder();
+    match tls_config {
+        Some(tls_config) => Ok(builder.use_preconfigured_tls(client_config(tls_config)?)),
+        None => Ok(builder),
+    }
+}
+
+/// TLS server configuration from a PEM certificate chain and private key, for the webhook
+/// listener.
+pub fn server_config(certificate_path: &str, key_path: &str) -> Result<ServerConfig> {
+    let open = |path: &str| std::fs::File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| anyhow!("Could not open {}: {}", path, e));
+    let certificates = rustls_pemfile::certs(&mut open(certificate_path)?).collect::<Result<Vec<_>, _>>()?;
+    if certificates.is_empty() {
+        return Err(anyhow!("No certificates found in {}", certificate_path));
+    }
+    let key = rustls_pemfile::private_key(&mut open(key_path)?)?
+        .ok_or_else(|| anyhow!("No private key found in {}", key_path))?;
+    Ok(ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)?)
+}
+
+/// TLS settings of an output: its own `tls` section, with options it does not set taken from
+/// the global one. Outputs without a `tls` section do not use TLS.
+pub fn merge(output: Option<&TlsSubConfig>, global: Option<&TlsSubConfig>) -> Option<TlsSubConfig> {
+    let output = output?;
+    let Some(global) = global else {
+        return Some(output.clone())
+    };
+    Some(TlsSubConfig {
+        ca_file: output.ca_file.clone().or_else(|| global.ca_file.clone()),
+        server_name: output.server_name.clone(),
+        insecure_skip_verify: output.insecure_skip_verify.or(global.insecure_skip_verify),
+        min_version: output.min_version.clone().or_else(|| global.min_version.clone()),
+    })
+}
+
+
+/// Accepts any server certificate, handshake signatures are still checked.
+#[derive(Debug)]
+struct NoVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(&self, _end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>],
+                          _server_name: &ServerName<'_>, _ocsp_response: &[u8], _now: UnixTime)
+        -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+        -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+        -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tls(yaml: &str) -> TlsSubConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_merge() {
+        let global = tls("{ca_file: /etc/proxy-ca.pem, min_version: '1.3'}");
+        let output = tls("{server_name: graylog.local, insecure_skip_verify: true}");
+        let merged = merge(Some(&output), Some(&global)).unwrap();
+        assert_eq!(merged.ca_file.as_deref(), Some("/etc/proxy-ca.pem"));
+        assert_eq!(merged.server_name.as_deref(), Some("graylog.local"));
+        assert_eq!(merged.insecure_skip_verify, Some(true));
+        assert_eq!(merged.min_version.as_deref(), Some("1.3"));
+        assert!(merge(None, Some(&global)).is_none());
+    }
+
+    #[test]
+    fn test_client_config() {
+        let ca_file = format!("{}/testdata/client_cert.pem", env!("CARGO_MANIFEST_DIR"));
+        assert!(client_config(&tls(&format!("{{ca_file: {}}}", ca_file))).is_ok());
+        assert!(client_config(&tls("{insecure_skip_verify: true, min_version: '1.3'}")).is_ok());
+        assert!(client_config(&tls("{min_version: '1.1'}")).is_err());
+        assert!(http_client(Some(&tls("{min_version: '1.2'}"))).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_api_client_gzip() {
+        use std::io::Write;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 4096];
+                let read = socket.read(&mut request).await.unwrap();
+                let request = String::from_utf8_lossy(&request[..read]).to_lowercase();
+                let response = match request.contains("accept-encoding: gzip") {
+                    true => {
+                        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                        encoder.write_all(b"[{\"Id\": \"gzip\"}]").unwrap();
+                        let body = encoder.finish().unwrap();
+                        let mut response = format!("HTTP/1.1 200 OK\r\ncontent-encoding: gzip\r\ncontent-length: {}\r\n\r\n",
+                                                   body.len()).into_bytes();
+                        response.extend(body);
+                        response
+                    },
+                    false => b"HTTP/1.1 200 OK\r\ncontent-length: 15\r\n\r\n[{\"Id\": \"raw\"}]".to_vec(),
+                };
+                socket.write_all(&response).await.unwrap();
+            }
+        });
+
+        let get = |client: reqwest::Client| {
+            let url = url.clone();
+            async move { client.get(url).send().await.unwrap().text().await.unwrap() }
+        };
+        assert_eq!(get(api_client(None, true).unwrap()).await, r#"[{"Id": "gzip"}]"#);
+        assert_eq!(get(api_client(None, false).unwrap()).await, r#"[{"Id": "raw"}]"#);
+        assert_eq!(get(http_client(None).unwrap()).await, r#"[{"Id": "raw"}]"#);
+    }
+
+    #[test]
+    fn test_server_config() {
+        let testdata = |name: &str| format!("{}/testdata/{}", env!("CARGO_MANIFEST_DIR"), name);
+        assert!(server_config(&testdata("client_cert.pem"), &testdata("client_key.pem")).is_ok());
+        assert!(server_config(&testdata("client_key.pem"), &testdata("client_key.pem")).is_err());
+    }
+}
@@ -0,0 +1,534 @@
+// Changes made to every log that passed the filters, before it is written to the file output and
+// passed to the interfaces. Field projection (`projection`) drops fields of a content type, or
+// keeps only the listed ones, to shrink what SIEMs bill by the byte. Paths are dot separated and
+// reach into nested objects and into every object of a list: `AppAccessContext.ClientAppName`,
+// `ModifiedProperties.OldValue`. Renames (`rename`) change top level field names as logs are
+// emitted, for all outputs or by output, to fit destinations with fixed schemas. Static fields
+// (`add_fields`, globally and per tenant) are added to every log after projection, as are the
+// TenantId, TenantName and TenantTags of the tenant. Logs with a known RecordType get its name
+// as RecordTypeName, before projection so it can be dropped. Fields holding JSON as text, like
+// `ModifiedProperties.NewValue`, are parsed first (`parse_json`) so projection reaches into them.
+// Then the fields of `expressions` are computed, from the log as it was before any of them. The
+// `plugin` script is called last, and may still drop the log.
+// Outputs with flat schemas can get logs flattened (`flatten`), nested fields becoming dotted
+// keys like `ModifiedProperties.0.Name`.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use serde_json::{Map, Value};
+use crate::config::{Config, TenantConfig};
+use crate::expression::Expression;
+use crate::plugin::{Decision, Plugin};
+use crate::data_structures::ArbitraryJson;
+use crate::routing::OUTPUT_NAMES;
+use crate::recordtype_filter::RecordTypeFilter;
+use crate::redact::Redaction;
+
+#[derive(Clone, Debug, Default)]
+struct Projection {
+    /// Only these paths if set
+    keep: Option<Vec<Vec<String>>>,
+    drop: Vec<Vec<String>>,
+}
+
+/// The changes of a run, shared by its download tasks.
+#[derive(Clone, Debug, Default)]
+pub struct LogTransform {
+    /// Fields to parse by content type
+    parse_json: HashMap<String, Vec<Vec<String>>>,
+    /// Computed fields by content type
+    computed: HashMap<String, Vec<(String, Expression)>>,
+    projections: HashMap<String, Projection>,
+    /// Static fields of the tenant
+    add_fields: Vec<(String, Value)>,
+    /// TenantId, TenantName and TenantTags
+    tenant_fields: Vec<(String, Value)>,
+    /// Applied to a copy of the log written to the file output only
+    file_redaction: Redaction,
+    file_renames: Renames,
+    flatten_file: bool,
+    plugin: Option<Arc<Plugin>>,
+}
+
+impl LogTransform {
+
+    pub fn new(config: &Config, tenant: &TenantConfig) -> Result<Self, String> {
+        let paths = |fields: &[String]| fields.iter()
+            .map(|field| field.split('.').map(String::from).collect())
+            .collect();
+        let parse_json = config.parse_json.iter()
+            .map(|(content_type, fields)| (content_type.clone(), paths(fields)))
+            .collect();
+        let mut computed = HashMap::new();
+        for (content_type, expressions) in &config.expressions {
+            let fields = expressions.fields.iter()
+                .map(|(name, source)| Expression::parse(source)
+                    .map(|expression| (name.clone(), expression))
+                    .map_err(|e| format!("{} fields.{}: {}", content_type, name, e)))
+                .collect::<Result<Vec<_>, _>>()?;
+            computed.insert(content_type.clone(), fields);
+        }
+        let projections = config.projection.iter()
+            .map(|(content_type, projection)| (content_type.clone(), Projection {
+                keep: projection.keep_fields.as_deref().map(paths),
+                drop: paths(&projection.drop_fields),
+            }))
+            .collect();
+        let file_redaction = Redaction::for_output(&config.redact, "file")?;
+        let file_renames = Renames::for_output(config, "file")?;
+        let mut add_fields: HashMap<&String, &Value> = config.add_fields.iter().collect();
+        add_fields.extend(&tenant.add_fields);
+        let add_fields = add_fields.into_iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        let mut tenant_fields = vec![("TenantId".to_string(), Value::String(tenant.tenant_id.clone()))];
+        if let Some(display_name) = &tenant.display_name {
+            tenant_fields.push(("TenantName".to_string(), Value::String(display_name.clone())));
+        }
+        if !tenant.tags.is_empty() {
+            tenant_fields.push(("TenantTags".to_string(), tenant.tags.iter().cloned().map(Value::String).collect()));
+        }
+        let flatten_file = config.get_flatten("file");
+        let plugin = config.plugin.as_ref().map(Plugin::new).transpose()?.map(Arc::new);
+        Ok(LogTransform {
+            parse_json, computed, projections, add_fields, tenant_fields, file_redaction, file_renames, flatten_file, plugin,
+        })
+    }
+
+    /// Change a log of content_type, before OriginFeed is added. Static fields do not replace
+    /// fields of the log, the tenant fields do. False if the plugin dropped the log.
+    pub fn apply(&self, content_type: &str, log: &mut Map<String, Value>) -> bool {
+        for path in self.parse_json.get(content_type).into_iter().flatten() {
+            parse_path(log, path);
+        }
+        if let Some(computed) = self.computed.get(content_type) {
+            let values: Vec<(&String, Value)> = computed.iter()
+                .map(|(name, expression)| (name, expression.evaluate(log)))
+                .collect();
+            for (name, value) in values.into_iter().filter(|(_, value)| !value.is_null()) {
+                log.insert(name.clone(), value);
+            }
+        }
+        let record_type = log.get("RecordType").and_then(|r| r.as_i64()).and_then(|r| i32::try_from(r).ok());
+        match record_type.map(RecordTypeFilter::get_recordtype_description) {
+            None | Some("Unknown") => {},
+            Some(name) => {
+                log.insert("RecordTypeName".to_string(), Value::String(name.to_string()));
+            },
+        }
+        if let Some(projection) = self.projections.get(content_type) {
+            if let Some(keep) = &projection.keep {
+                let paths: Vec<&[String]> = keep.iter().map(|path| path.as_slice()).collect();
+                *log = keep_paths(log, &paths);
+            }
+            for path in &projection.drop {
+                drop_path(log, path);
+            }
+        }
+        for (field, value) in &self.add_fields {
+            if !log.contains_key(field) {
+                log.insert(field.clone(), value.clone());
+            }
+        }
+        for (field, value) in &self.tenant_fields {
+            log.insert(field.clone(), value.clone());
+        }
+        self.plugin.as_ref().is_none_or(|plugin| plugin.process(content_type, log) == Decision::Keep)
+    }
+
+    /// Logs the plugin dropped and failed on so far.
+    pub fn plugin_counts(&self) -> Option<(usize, usize)> {
+        self.plugin.as_ref().map(|plugin| plugin.counts())
+    }
+
+    /// A copy of a log as the file output writes it, if redact rules, renames or flattening
+    /// change it.
+    pub fn for_file(&self, content_type: &str, log: &Map<String, Value>) -> Option<Map<String, Value>> {
+        if !self.file_redaction.applies_to(content_type) && self.file_renames.is_empty() && !self.flatten_file {
+            return None
+        }
+        let mut log = log.clone();
+        self.file_redaction.apply(content_type, log.iter_mut());
+        self.file_renames.apply(&mut log);
+        if self.flatten_file {
+            flatten(&mut log);
+        }
+        Some(log)
+    }
+}
+
+/// Logs are serde_json Maps while they are processed and HashMaps once passed to the outputs.
+pub trait LogFields {
+    fn take(&mut self, field: &str) -> Option<Value>;
+    fn put(&mut self, field: String, value: Value);
+    fn take_all(&mut self) -> Vec<(String, Value)>;
+}
+
+impl LogFields for Map<String, Value> {
+    fn take(&mut self, field: &str) -> Option<Value> {
+        self.remove(field)
+    }
+    fn put(&mut self, field: String, value: Value) {
+        self.insert(field, value);
+    }
+    fn take_all(&mut self) -> Vec<(String, Value)> {
+        std::mem::take(self).into_iter().collect()
+    }
+}
+
+impl LogFields for ArbitraryJson {
+    fn take(&mut self, field: &str) -> Option<Value> {
+        self.remove(field)
+    }
+    fn put(&mut self, field: String, value: Value) {
+        self.insert(field, value);
+    }
+    fn take_all(&mut self) -> Vec<(String, Value)> {
+        self.drain().collect()
+    }
+}
+
+/// Turn nested objects and lists into top level fields with dotted keys. Empty objects and lists
+/// are kept as they are.
+pub fn flatten(log: &mut impl LogFields) {
+    fn flatten_into(key: String, value: Value, log: &mut impl LogFields) {
+        match value {
+            Value::Object(object) if !object.is_empty() => for (nested, value) in object {
+                flatten_into(format!("{}.{}", key, nested), value, log);
+            },
+            Value::Array(items) if !items.is_empty() => for (i, value) in items.into_iter().enumerate() {
+                flatten_into(format!("{}.{}", key, i), value, log);
+            },
+            value => log.put(key, value),
+        }
+    }
+    for (key, value) in log.take_all() {
+        flatten_into(key, value, log);
+    }
+}
+
+/// Field renames of one output: those of the output over those of "default".
+#[derive(Clone, Debug, Default)]
+pub struct Renames {
+    renames: Vec<(String, String)>,
+}
+
+impl Renames {
+
+    pub fn for_output(config: &Config, output: &str) -> Result<Self, String> {
+        for name in config.rename.keys() {
+            if name != "default" && !OUTPUT_NAMES.contains(&name.as_str()) {
+                return Err(format!("unknown output '{}', must be default or one of: {}", name, OUTPUT_NAMES.join(", ")))
+            }
+        }
+        let mut renames: HashMap<&String, &String> = HashMap::new();
+        renames.extend(config.rename.get("default").into_iter().flatten());
+        renames.extend(config.rename.get(output).into_iter().flatten());
+        let mut renames: Vec<(String, String)> = renames.into_iter()
+            .filter(|(from, to)| from != to)
+            .map(|(from, to)| (from.clone(), to.clone()))
+            .collect();
+        renames.sort();
+        if let Some((from, _)) = renames.iter().find(|(from, to)| from.is_empty() || to.is_empty()) {
+            return Err(format!("{}: field names cannot be empty", if from.is_empty() { "''" } else { from }))
+        }
+        for (i, (_, to)) in renames.iter().enumerate() {
+            if renames[i + 1..].iter().any(|(_, other)| other == to) {
+                return Err(format!("{} renames several fields to '{}'", output, to))
+            }
+        }
+        Ok(Renames { renames })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.renames.is_empty()
+    }
+
+    /// Rename the fields of a log. Renames do not chain: with UserId -> user and user -> name,
+    /// UserId becomes user.
+    pub fn apply(&self, log: &mut impl LogFields) {
+        let values: Vec<(&String, Option<Value>)> = self.renames.iter().map(|(from, to)| (to, log.take(from))).collect();
+        for (to, value) in values {
+            if let Some(value) = value {
+                log.put(to.clone(), value);
+            }
+        }
+    }
+}
+
+fn keep_paths(object: &Map<String, Value>, paths: &[&[String]]) -> Map<String, Value> {
+    let mut kept = Map::new();
+    for (key, value) in object {
+        let below: Vec<&[String]> = paths.iter().filter(|path| path[0] == *key).map(|path| &path[1..]).collect();
+        if below.is_empty() {
+            continue
+        }
+        if below.iter().any(|path| path.is_empty()) {
+            kept.insert(key.clone(), value.clone());
+            continue
+        }
+        let value = match value {
+            Value::Object(nested) => Value::Object(keep_paths(nested, &below)),
+            Value::Array(items) => Value::Array(items.iter()
+                .filter_map(|item| item.as_object().map(|item| Value::Object(keep_paths(item, &below))))
+                .collect()),
+            _ => continue,
+        };
+        kept.insert(key.clone(), value);
+    }
+    kept
+}
+
+/// Replace text holding a JSON object or list by the object or list.
+fn parse_path(object: &mut Map<String, Value>, path: &[String]) {
+    let Some((key, below)) = path.split_first() else {
+        return
+    };
+    match (object.get_mut(key), below.is_empty()) {
+        (Some(Value::String(text)), true) => {
+            let trimmed = text.trim_start();
+            if trimmed.starts_with('{') || trimmed.starts_with('[') {
+                if let Ok(parsed) = serde_json::from_str(text) {
+                    object.insert(key.clone(), parsed);
+                }
+            }
+        },
+        (Some(Value::Object(nested)), false) => parse_path(nested, below),
+        (Some(Value::Array(items)), false) => for item in items.iter_mut() {
+            if let Value::Object(item) = item {
+                parse_path(item, below);
+            }
+        },
+        _ => {},
+    }
+}
+
+fn drop_path(object: &mut Map<String, Value>, path: &[String]) {
+    let Some((key, below)) = path.split_first() else {
+        return
+    };
+    if below.is_empty() {
+        object.remove(key);
+        return
+    }
+    match object.get_mut(key) {
+        Some(Value::Object(nested)) => drop_path(nested, below),
+        Some(Value::Array(items)) => for item in items.iter_mut() {
+            if let Value::Object(item) = item {
+                drop_path(item, below);
+            }
+        },
+        _ => {},
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn tenant() -> TenantConfig {
+        serde_yaml::from_str("{tenant_id: t, client_id: c}").unwrap()
+    }
+
+    #[test]
+    fn test_projection() {
+        let config: Config = serde_yaml::from_str("
+output: {}
+projection:
+  Audit.SharePoint:
+    drop_fields: [AppAccessContext, ModifiedProperties.OldValue, Site.Missing]
+  Audit.Exchange:
+    keep_fields: [Id, Operation, Item.Subject, Folders.Path, Missing]
+    drop_fields: [Operation]
+").unwrap();
+        let transform = LogTransform::new(&config, &tenant()).unwrap();
+        let log = json!({
+            "Id": "1", "Operation": "FileModified", "AppAccessContext": {"ClientAppName": "x"},
+            "Item": {"Subject": "Q1", "Attachments": "a.pdf"},
+            "Folders": [{"Path": "\\Inbox", "FolderItems": [1, 2]}, {"Path": "\\Sent"}, "unexpected"],
+            "ModifiedProperties": [{"Name": "Title", "OldValue": "a", "NewValue": "b"}],
+        });
+
+        let mut sharepoint = log.as_object().unwrap().clone();
+        transform.apply("Audit.SharePoint", &mut sharepoint);
+        assert!(!sharepoint.contains_key("AppAccessContext"));
+        assert_eq!(Value::Object(sharepoint.clone())["ModifiedProperties"], json!([{"Name": "Title", "NewValue": "b"}]));
+        assert_eq!(sharepoint["Item"], log["Item"]);
+
+        let mut exchange = log.as_object().unwrap().clone();
+        transform.apply("Audit.Exchange", &mut exchange);
+        assert_eq!(Value::Object(exchange), json!({
+            "Id": "1", "Item": {"Subject": "Q1"}, "Folders": [{"Path": "\\Inbox"}, {"Path": "\\Sent"}],
+            "TenantId": "t"}));
+
+        let mut general = log.as_object().unwrap().clone();
+        transform.apply("Audit.General", &mut general);
+        general.remove("TenantId");
+        assert_eq!(Value::Object(general), log);
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let config: Config = serde_yaml::from_str("
+output: {}
+parse_json:
+  Audit.AzureActiveDirectory: [ExtendedProperties, ModifiedProperties.NewValue, Target.ID]
+projection:
+  Audit.AzureActiveDirectory:
+    drop_fields: [ModifiedProperties.NewValue.secret]
+").unwrap();
+        let transform = LogTransform::new(&config, &tenant()).unwrap();
+        let log = json!({
+            "ExtendedProperties": "[{\"Name\": \"UserAgent\", \"Value\": \"Mozilla\"}]",
+            "ModifiedProperties": [{"Name": "a", "NewValue": "{\"role\": \"admin\", \"secret\": 1}"},
+                                   {"Name": "b", "NewValue": "plain text"},
+                                   {"Name": "c", "NewValue": "{not json"}],
+            "Target": [{"ID": "7"}],
+        });
+        let mut parsed = log.as_object().unwrap().clone();
+        transform.apply("Audit.AzureActiveDirectory", &mut parsed);
+        assert_eq!(parsed["ExtendedProperties"], json!([{"Name": "UserAgent", "Value": "Mozilla"}]));
+        assert_eq!(parsed["ModifiedProperties"], json!([{"Name": "a", "NewValue": {"role": "admin"}},
+                                                        {"Name": "b", "NewValue": "plain text"},
+                                                        {"Name": "c", "NewValue": "{not json"}]));
+        assert_eq!(parsed["Target"], json!([{"ID": "7"}]));
+
+        let mut other = log.as_object().unwrap().clone();
+        transform.apply("Audit.General", &mut other);
+        assert_eq!(other["ExtendedProperties"], log["ExtendedProperties"]);
+    }
+
+    #[test]
+    fn test_computed_fields() {
+        let config: Config = serde_yaml::from_str(r#"
+output: {}
+expressions:
+  Audit.AzureActiveDirectory:
+    filter: "RecordType in [15] && !(UserId endsWith '@contoso.com')"
+    fields:
+      Summary: "Operation + ' by ' + UserId"
+      Operation: "lower(Operation)"
+      External: "!(UserId endsWith '@contoso.com')"
+      Nothing: "Missing"
+"#).unwrap();
+        let transform = LogTransform::new(&config, &tenant()).unwrap();
+        let mut log = json!({"RecordType": 15, "Operation": "UserLoggedIn", "UserId": "bob@fabrikam.com"})
+            .as_object().unwrap().clone();
+        transform.apply("Audit.AzureActiveDirectory", &mut log);
+        assert_eq!(log["Summary"], "UserLoggedIn by bob@fabrikam.com");
+        assert_eq!(log["Operation"], "userloggedin");
+        assert_eq!(log["External"], true);
+        assert!(!log.contains_key("Nothing"));
+
+        let filters = crate::log_filter::LogFilters::new(&config).unwrap();
+        let log = |user: &str| json!({"RecordType": 15, "UserId": user}).as_object().unwrap().clone();
+        assert!(filters.accepts("Audit.AzureActiveDirectory", &log("bob@fabrikam.com")));
+        assert!(!filters.accepts("Audit.AzureActiveDirectory", &log("alice@contoso.com")));
+        assert!(filters.accepts("Audit.Exchange", &log("alice@contoso.com")));
+    }
+
+    #[test]
+    fn test_add_fields() {
+        let config: Config = serde_yaml::from_str("
+output: {}
+add_fields: {customer: ACME, environment: prod, collector_host: collector-01}
+projection:
+  Audit.General:
+    keep_fields: [Id]
+").unwrap();
+        let tenant: TenantConfig = serde_yaml::from_str(
+            "{tenant_id: t, client_id: c, add_fields: {customer: Contoso, tier: 2}}").unwrap();
+        let transform = LogTransform::new(&config, &tenant).unwrap();
+        let mut log = json!({"Id": "1", "Operation": "Send", "environment": "from the log"}).as_object().unwrap().clone();
+        transform.apply("Audit.General", &mut log);
+        assert_eq!(Value::Object(log), json!({
+            "Id": "1", "customer": "Contoso", "environment": "prod", "collector_host": "collector-01", "tier": 2,
+            "TenantId": "t"}));
+        let mut log = json!({"Id": "1", "environment": "from the log"}).as_object().unwrap().clone();
+        transform.apply("Audit.Exchange", &mut log);
+        assert_eq!(log["environment"], "from the log");
+    }
+
+    #[test]
+    fn test_record_type_name() {
+        let config: Config = serde_yaml::from_str("
+output: {}
+projection:
+  Audit.General:
+    drop_fields: [RecordTypeName]
+").unwrap();
+        let transform = LogTransform::new(&config, &tenant()).unwrap();
+        for (content_type, record_type, name) in [("Audit.Exchange", json!(2), Some("ExchangeItem")),
+                                                  ("Audit.SharePoint", json!(6), Some("SharePointFileOperation")),
+                                                  ("Audit.Exchange", json!(9999), None),
+                                                  ("Audit.Exchange", json!("2"), None),
+                                                  ("Audit.General", json!(25), None)] {
+            let mut log = json!({"RecordType": record_type}).as_object().unwrap().clone();
+            transform.apply(content_type, &mut log);
+            assert_eq!(log.get("RecordTypeName").and_then(|n| n.as_str()), name);
+        }
+    }
+
+    #[test]
+    fn test_tenant_fields() {
+        let config: Config = serde_yaml::from_str("output: {}").unwrap();
+        let tenant: TenantConfig = serde_yaml::from_str(
+            "{tenant_id: t, client_id: c, display_name: Contoso Ltd, tags: [emea, premium]}").unwrap();
+        let mut log = json!({"Id": "1", "TenantId": "other"}).as_object().unwrap().clone();
+        LogTransform::new(&config, &tenant).unwrap().apply("Graph.SignIns", &mut log);
+        assert_eq!(Value::Object(log), json!({
+            "Id": "1", "TenantId": "t", "TenantName": "Contoso Ltd", "TenantTags": ["emea", "premium"]}));
+    }
+
+    #[test]
+    fn test_flatten() {
+        let mut log: ArbitraryJson = serde_json::from_value(json!({
+            "Id": "1", "AppAccessContext": {"ClientAppName": "x", "Ids": []},
+            "ModifiedProperties": [{"Name": "Title", "NewValue": "b"}, {"Name": "Path", "NewValue": {}}],
+            "Labels": ["a", "b"],
+        })).unwrap();
+        flatten(&mut log);
+        assert_eq!(serde_json::to_value(log).unwrap(), json!({
+            "Id": "1", "AppAccessContext.ClientAppName": "x", "AppAccessContext.Ids": [],
+            "ModifiedProperties.0.Name": "Title", "ModifiedProperties.0.NewValue": "b",
+            "ModifiedProperties.1.Name": "Path", "ModifiedProperties.1.NewValue": {},
+            "Labels.0": "a", "Labels.1": "b",
+        }));
+
+        let config: Config = serde_yaml::from_str("{output: {}, flatten: {default: true, graylog: false}}").unwrap();
+        assert!(config.get_flatten("file") && config.get_flatten("s3") && !config.get_flatten("graylog"));
+        let file = LogTransform::new(&config, &tenant()).unwrap()
+            .for_file("Audit.General", json!({"Item": {"Id": 1}}).as_object().unwrap()).unwrap();
+        assert_eq!(Value::Object(file), json!({"Item.Id": 1}));
+    }
+
+    #[test]
+    fn test_renames() {
+        let config: Config = serde_yaml::from_str("
+output: {}
+rename:
+  default: {UserId: user_name, ClientIP: src_ip}
+  graylog: {ClientIP: client_ip, Operation: UserId}
+").unwrap();
+        let log = json!({"UserId": "alice@contoso.com", "ClientIP": "203.0.113.45", "Operation": "FileAccessed"});
+
+        let mut graylog: ArbitraryJson = serde_json::from_value(log.clone()).unwrap();
+        Renames::for_output(&config, "graylog").unwrap().apply(&mut graylog);
+        assert_eq!(serde_json::to_value(graylog).unwrap(), json!({
+            "user_name": "alice@contoso.com", "client_ip": "203.0.113.45", "UserId": "FileAccessed"}));
+
+        let transform = LogTransform::new(&config, &tenant()).unwrap();
+        let file = transform.for_file("Audit.General", log.as_object().unwrap()).unwrap();
+        assert_eq!(Value::Object(file), json!({
+            "user_name": "alice@contoso.com", "src_ip": "203.0.113.45", "Operation": "FileAccessed"}));
+
+        for (rename, problem) in [("{splunk: {a: b}}", "unknown output 'splunk'"),
+                                  ("{default: {a: c, b: c}}", "file renames several fields to 'c'"),
+                                  ("{default: {a: ''}}", "a: field names cannot be empty")] {
+            let config: Config = serde_yaml::from_str(&format!("{{output: {{}}, rename: {}}}", rename)).unwrap();
+            assert!(Renames::for_output(&config, "file").unwrap_err().starts_with(problem));
+        }
+        let empty: Config = serde_yaml::from_str("output: {}").unwrap();
+        assert!(LogTransform::new(&empty, &tenant()).unwrap().for_file("Audit.General", log.as_object().unwrap()).is_none());
+    }
+}
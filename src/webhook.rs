@@ -0,0 +1,360 @@
+// Office 365 webhook notifications. With a `webhook` section the subscriptions are started with
+// a webhook address, and the API POSTs a notification to it whenever new content is available.
+// The listener below accepts those notifications (a minimal HTTP/1.1 server, one request per
+// connection) and queues the announced content blobs per tenant. Every collector run retrieves
+// the queued blobs of its tenant, in addition to or instead of listing available content.
+//
+// Notifications are JSON arrays of {tenantId, contentType, contentId, contentUri,
+// contentExpiration, ...}. When a webhook is registered the API first sends a validation
+// notification {"validationCode": ...}, which only needs a 200 response. Notifications for
+// tenants that are not configured are rejected, so a caller that is not the API cannot fill the
+// queue, and at most MAX_QUEUED_BLOBS blobs are queued per tenant.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use log::{debug, error, info, warn};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use crate::config::WebhookSubConfig;
+use crate::data_structures::ContentToRetrieve;
+use crate::tls;
+
+const DEFAULT_LISTEN: &str = "0.0.0.0:8443";
+const AUTH_ID_HEADER: &str = "webhook-authid";
+/// Larger requests are rejected, notifications list at most a few hundred blobs
+const MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+const MAX_HEADER_LINES: usize = 100;
+/// Time a client gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Blobs queued per tenant at most, further notifications are rejected until a run takes them
+const MAX_QUEUED_BLOBS: usize = 100_000;
+
+/// Content blobs announced by notifications, by tenant id.
+#[derive(Clone, Default)]
+pub struct WebhookQueue {
+    content: Arc<Mutex<HashMap<String, Vec<ContentToRetrieve>>>>,
+    /// Lowercase ids of the configured tenants, the only ones notifications are accepted for
+    tenants: Arc<Mutex<HashSet<String>>>,
+}
+
+impl WebhookQueue {
+    /// Accept notifications for these tenants only. Set again whenever the tenants change.
+    pub fn set_tenants<'a>(&self, tenant_ids: impl IntoIterator<Item = &'a String>) {
+        *self.tenants.lock().unwrap() = tenant_ids.into_iter().map(|id| id.to_lowercase()).collect();
+    }
+
+    fn is_configured(&self, tenant_id: &str) -> bool {
+        self.tenants.lock().unwrap().contains(&tenant_id.to_lowercase())
+    }
+
+    /// Queue the blobs of a notification, all or none: false if that would take a tenant over
+    /// MAX_QUEUED_BLOBS.
+    fn push(&self, notified: Vec<(String, ContentToRetrieve)>) -> bool {
+        let mut queued = self.content.lock().unwrap();
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for (tenant_id, _) in &notified {
+            *counts.entry(tenant_id.to_lowercase()).or_default() += 1;
+        }
+        if counts.iter().any(|(tenant_id, count)| {
+            queued.get(tenant_id).map_or(0, Vec::len) + count > MAX_QUEUED_BLOBS
+        }) {
+            return false
+        }
+        for (tenant_id, content) in notified {
+            queued.entry(tenant_id.to_lowercase()).or_default().push(content);
+        }
+        true
+    }
+
+    /// Queue blobs again that were taken by a run that failed to start.
+    pub fn requeue(&self, tenant_id: &str, content: Vec<ContentToRetrieve>) {
+        self.content.lock().unwrap().entry(tenant_id.to_lowercase()).or_default().extend(content);
+    }
+
+    /// Take the blobs queued for a tenant.
+    pub fn take(&self, tenant_id: &str) -> Vec<ContentToRetrieve> {
+        self.content.lock().unwrap().remove(&tenant_id.to_lowercase()).unwrap_or_default()
+    }
+}
+
+/// Body of a subscriptions/start request registering the webhook.
+pub fn subscription_body(config: &WebhookSubConfig) -> Value {
+    let mut webhook = json!({"address": config.address});
+    if let Some(auth_id) = &config.auth_id {
+        webhook["authId"] = json!(auth_id);
+    }
+    json!({"webhook": webhook})
+}
+
+/// Whether a subscription from subscriptions/list already sends notifications to our webhook.
+pub fn is_registered(config: &WebhookSubConfig, subscription: &HashMap<String, Value>) -> bool {
+    subscription.get("webhook")
+        .and_then(|w| w.get("address"))
+        .and_then(|a| a.as_str())
+        .is_some_and(|address| address == config.address)
+}
+
+/// Accept notifications until the process exits.
+pub async fn serve(config: WebhookSubConfig, queue: WebhookQueue) -> Result<()> {
+
+    let listen = config.listen.clone().unwrap_or_else(|| DEFAULT_LISTEN.to_string());
+    let acceptor = match (&config.tls_certificate, &config.tls_key) {
+        (Some(certificate), Some(key)) => Some(TlsAcceptor::from(Arc::new(tls::server_config(certificate, key)?))),
+        (None, None) => None,
+        _ => return Err(anyhow!("webhook 'tls_certificate' and 'tls_key' must be set together")),
+    };
+    let listener = TcpListener::bind(&listen).await
+        .map_err(|e| anyhow!("Could not listen for webhook notifications on {}: {}", listen, e))?;
+    info!("Listening for webhook notifications on {}{}", listen, if acceptor.is_some() { " (TLS)" } else { "" });
+    if config.auth_id.is_none() && !listener.local_addr().is_ok_and(|addr| addr.ip().is_loopback()) {
+        warn!("The webhook listens on {} without an 'auth_id', anyone who can reach it can queue content \
+               for the configured tenants", listen);
+    }
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Could not accept webhook connection: {}", e);
+                continue
+            }
+        };
+        let acceptor = acceptor.clone();
+        let auth_id = config.auth_id.clone();
+        let queue = queue.clone();
+        tokio::spawn(async move {
+            let result = match acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => handle_connection(stream, auth_id.as_deref(), &queue).await,
+                    Err(e) => Err(e.into()),
+                },
+                None => handle_connection(stream, auth_id.as_deref(), &queue).await,
+            };
+            if let Err(e) = result {
+                debug!("Webhook connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(stream: S, auth_id: Option<&str>,
+                                                              queue: &WebhookQueue) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+    let (status, reason) = match read_request(&mut stream).await {
+        Ok(request) if request.method != "POST" => (405, "Method Not Allowed"),
+        Ok(request) if auth_id.is_some_and(|auth_id| {
+            request.headers.get(AUTH_ID_HEADER).is_none_or(|header| !auth_id_matches(header, auth_id))
+        }) => {
+            warn!("Rejected webhook notification with a wrong or missing Webhook-AuthID");
+            (401, "Unauthorized")
+        },
+        Ok(request) => match parse_notification(&request.body) {
+            Ok(notified) => queue_notified(notified, queue),
+            Err(e) => {
+                error!("Invalid webhook notification: {}", e);
+                (400, "Bad Request")
+            }
+        },
+        Err(e) => {
+            debug!("Invalid webhook request: {}", e);
+            (400, "Bad Request")
+        }
+    };
+    let response = format!("HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status, reason);
+    let stream = stream.get_mut();
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// An HTTP request read by read_request.
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// By lowercase name
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+/// Read an HTTP/1.1 request, also used by the control API.
+pub async fn read_request<S: AsyncRead + Unpin>(stream: &mut BufReader<S>) -> Result<Request> {
+    tokio::time::timeout(REQUEST_TIMEOUT, read_request_unlimited(stream)).await
+        .map_err(|_| anyhow!("Timed out reading the request"))?
+}
+
+async fn read_request_unlimited<S: AsyncRead + Unpin>(stream: &mut BufReader<S>) -> Result<Request> {
+
+    let mut line = String::new();
+    stream.read_line(&mut line).await?;
+    let mut request_line = line.split_whitespace();
+    let method = request_line.next().ok_or_else(|| anyhow!("Empty request"))?.to_string();
+    let path = request_line.next().unwrap_or("/").to_string();
+
+    let mut headers = HashMap::new();
+    for _ in 0..MAX_HEADER_LINES {
+        line.clear();
+        stream.read_line(&mut line).await?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            let length: usize = headers.get("content-length").map_or(Ok(0), |l: &String| l.parse())?;
+            if length > MAX_BODY_SIZE {
+                return Err(anyhow!("Request body of {} bytes is too large", length));
+            }
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).await?;
+            return Ok(Request { method, path, headers, body })
+        }
+        let (name, value) = header.split_once(':').ok_or_else(|| anyhow!("Invalid header line"))?;
+        headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+    }
+    Err(anyhow!("Too many request headers"))
+}
+
+/// The blobs a notification announces with their tenant id, none for a validation notification.
+fn parse_notification(body: &[u8]) -> Result<Vec<(String, ContentToRetrieve)>> {
+
+    let notification: Value = serde_json::from_slice(body)?;
+    if notification.get("validationCode").is_some() {
+        info!("Received webhook validation notification");
+        return Ok(Vec::new())
+    }
+    let notifications = notification.as_array().ok_or_else(|| anyhow!("Expected a list of notifications"))?;
+    let mut notified = Vec::with_capacity(notifications.len());
+    for notification in notifications {
+        let field = |name: &str| notification.get(name).and_then(|v| v.as_str()).map(|v| v.to_string())
+            .ok_or_else(|| anyhow!("Notification without {}", name));
+        let content = ContentToRetrieve {
+            content_type: field("contentType")?,
+            content_id: field("contentId")?,
+            expiration: field("contentExpiration")?,
+            url: field("contentUri")?,
+        };
+        notified.push((field("tenantId")?, content));
+    }
+    Ok(notified)
+}
+
+/// Compare a Webhook-AuthID with the configured one in constant time, so the time taken does not
+/// give away how much of it matched.
+fn auth_id_matches(received: &str, auth_id: &str) -> bool {
+    let (received, expected) = (received.as_bytes(), auth_id.as_bytes());
+    let mut difference = received.len() ^ expected.len();
+    for (i, byte) in expected.iter().enumerate() {
+        difference |= usize::from(byte ^ received.get(i).copied().unwrap_or(0));
+    }
+    difference == 0
+}
+
+/// Queue the blobs of a notification, returning the response status. Nothing is queued if it
+/// names a tenant that is not configured.
+fn queue_notified(notified: Vec<(String, ContentToRetrieve)>, queue: &WebhookQueue) -> (u16, &'static str) {
+    if let Some((tenant_id, _)) = notified.iter().find(|(tenant_id, _)| !queue.is_configured(tenant_id)) {
+        warn!("Rejected webhook notification for tenant {}, which is not configured", tenant_id);
+        return (403, "Forbidden")
+    }
+    let count = notified.len();
+    if !queue.push(notified) {
+        warn!("Webhook queue is full, rejected a notification of {} blobs", count);
+        return (503, "Service Unavailable")
+    }
+    debug!("Queued {} blobs from webhook notification", count);
+    (200, "OK")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn request(raw: &str, auth_id: Option<&str>, queue: &WebhookQueue) -> String {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        client_write.write_all(raw.as_bytes()).await.unwrap();
+        handle_connection(server, auth_id, queue).await.unwrap();
+        let mut response = String::new();
+        client_read.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    fn post(body: &str, auth_id: &str) -> String {
+        format!("POST /notify HTTP/1.1\r\nHost: collector\r\nWebhook-AuthID: {}\r\nContent-Length: {}\r\n\r\n{}",
+                auth_id, body.len(), body)
+    }
+
+    #[tokio::test]
+    async fn test_notifications() {
+        let queue = WebhookQueue::default();
+        queue.set_tenants(&["Tenant-A".to_string()]);
+        let response = request(&post(r#"{"validationCode": "abc"}"#, "secret"), Some("secret"), &queue).await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+
+        let body = r#"[{"tenantId": "Tenant-A", "clientId": "c", "contentType": "Audit.Exchange",
+            "contentId": "id1", "contentUri": "https://manage.office.com/blob/id1",
+            "contentCreated": "2024-01-01T00:00:00.000Z", "contentExpiration": "2024-01-08T00:00:00.000Z"}]"#;
+        assert!(request(&post(body, "wrong"), Some("secret"), &queue).await.starts_with("HTTP/1.1 401"));
+        assert!(queue.take("tenant-a").is_empty());
+
+        assert!(request(&post(body, "secret"), Some("secret"), &queue).await.starts_with("HTTP/1.1 200"));
+        let content = queue.take("tenant-a");
+        assert_eq!(content.len(), 1);
+        assert_eq!(content[0].content_id, "id1");
+        assert_eq!(content[0].content_type, "Audit.Exchange");
+        assert!(queue.take("tenant-a").is_empty());
+        queue.requeue("TENANT-A", content);
+        assert_eq!(queue.take("tenant-a").len(), 1);
+
+        assert!(request(&post("[{}]", "secret"), Some("secret"), &queue).await.starts_with("HTTP/1.1 400"));
+        assert!(request("GET / HTTP/1.1\r\n\r\n", None, &queue).await.starts_with("HTTP/1.1 405"));
+
+        // Tenants that are not configured are rejected, even with other tenants' blobs
+        let other = body.replace("Tenant-A", "tenant-b");
+        assert!(request(&post(&other, "secret"), Some("secret"), &queue).await.starts_with("HTTP/1.1 403"));
+        let both = format!("[{},{}]", &body[1..body.len() - 1], &other[1..other.len() - 1]);
+        assert!(request(&post(&both, "secret"), Some("secret"), &queue).await.starts_with("HTTP/1.1 403"));
+        assert!(queue.take("tenant-a").is_empty());
+        assert!(queue.take("tenant-b").is_empty());
+    }
+
+    #[test]
+    fn test_auth_id_matches() {
+        assert!(auth_id_matches("secret", "secret"));
+        assert!(!auth_id_matches("secreT", "secret"));
+        assert!(!auth_id_matches("secret2", "secret"));
+        assert!(!auth_id_matches("secre", "secret"));
+        assert!(!auth_id_matches("", "secret"));
+    }
+
+    #[test]
+    fn test_queue_limit() {
+        let queue = WebhookQueue::default();
+        let content = ContentToRetrieve { content_type: "Audit.Exchange".to_string(), content_id: "id".to_string(),
+                                          expiration: String::new(), url: String::new() };
+        let notification = |tenant_id: &str, count: usize| vec![(tenant_id.to_string(), content.clone()); count];
+        assert!(queue.push(notification("tenant-a", MAX_QUEUED_BLOBS - 2)));
+        // A notification that does not fit is not queued at all, not even for the other tenant
+        let mut both = notification("tenant-b", 1);
+        both.extend(notification("Tenant-A", 3));
+        assert!(!queue.push(both));
+        assert!(queue.take("tenant-b").is_empty());
+        assert!(queue.push(notification("Tenant-A", 2)));
+        assert!(!queue.push(notification("tenant-a", 1)));
+        assert!(queue.push(notification("tenant-b", 1)));
+        assert_eq!(queue.take("tenant-a").len(), MAX_QUEUED_BLOBS);
+    }
+
+    #[test]
+    fn test_subscription() {
+        let config: WebhookSubConfig = serde_yaml::from_str(
+            "{address: 'https://collector.example.com/notify', auth_id: secret}").unwrap();
+        assert_eq!(subscription_body(&config)["webhook"]["authId"], "secret");
+        let subscription: HashMap<String, Value> = serde_json::from_value(json!({
+            "contentType": "Audit.Exchange", "status": "enabled",
+            "webhook": {"address": "https://collector.example.com/notify", "status": "enabled"}})).unwrap();
+        assert!(is_registered(&config, &subscription));
+        assert!(!is_registered(&config, &HashMap::new()));
+    }
+}